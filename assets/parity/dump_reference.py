@@ -0,0 +1,169 @@
+"""Dump reference inputs and outputs for the golden-logit parity check (tests/parity_test.rs).
+
+python3 assets/parity/dump_reference.py qwen3vl <model_path> <out.safetensors> [image]
+python3 assets/parity/dump_reference.py minicpm4 <model_path> <out.safetensors>
+python3 assets/parity/dump_reference.py voxcpm <model_path> <out.safetensors>
+
+For the tiny fixtures, run the ignored parity_reference_tiny_<model> test, it prints
+the directory the tiny model was written to and the full command; <out.safetensors>
+is assets/parity/reference/tiny_<model>.safetensors. Commit the file and drop the
+#[ignore] of the test.
+
+The key layout follows src/models/common/parity.rs. hidden_states.0 is the input
+embedding of the first decoder layer, hidden_states.i the input of layer i and the
+last entry the output of the final norm. They are captured with forward hooks
+instead of output_hidden_states so the layout does not depend on the transformers
+version. Everything runs in float32 on CPU, load the Rust model with DType::F32.
+"""
+
+import sys
+
+import torch
+from safetensors.torch import save_file
+
+TEXT = "你好, who are you?"
+TTS_TEXT = "太阳当空照, 花儿对我笑"
+
+
+def capture(layers, norm):
+    states = []
+
+    def pre_hook(module, args, kwargs):
+        states.append(args[0] if args else kwargs["hidden_states"])
+
+    def norm_hook(module, args, output):
+        states.append(output)
+
+    handles = [layer.register_forward_pre_hook(pre_hook, with_kwargs=True) for layer in layers]
+    handles.append(norm.register_forward_hook(norm_hook))
+    return states, handles
+
+
+def add_states(tensors, prefix, states, handles):
+    for handle in handles:
+        handle.remove()
+    for i, state in enumerate(states):
+        tensors[f"{prefix}.{i}"] = state
+
+
+def save(out, tensors):
+    tensors = {
+        k: (v.float() if v.is_floating_point() else v).detach().contiguous() for k, v in tensors.items()
+    }
+    save_file(tensors, out)
+    for k, v in sorted(tensors.items()):
+        print(k, list(v.shape), v.dtype)
+
+
+def dump_qwen3vl(model_path, out, image=None):
+    from transformers import AutoProcessor, Qwen3VLForConditionalGeneration
+
+    processor = AutoProcessor.from_pretrained(model_path)
+    model = Qwen3VLForConditionalGeneration.from_pretrained(model_path, torch_dtype=torch.float32).eval()
+    content = [{"type": "text", "text": TEXT}]
+    if image:
+        content.insert(0, {"type": "image", "image": image})
+    inputs = processor.apply_chat_template(
+        [{"role": "user", "content": content}],
+        tokenize=True,
+        add_generation_prompt=True,
+        return_dict=True,
+        return_tensors="pt",
+    )
+    tensors = {k: v for k, v in inputs.items() if k in ("input_ids", "pixel_values", "image_grid_thw")}
+    lm = model.model.language_model
+    with torch.no_grad():
+        if "pixel_values" in inputs:
+            image_embeds, deepstack = model.model.visual(inputs["pixel_values"], grid_thw=inputs["image_grid_thw"])
+            tensors["image_embeds"] = image_embeds
+            for i, embeds in enumerate(deepstack):
+                tensors[f"deepstack_image_embeds.{i}"] = embeds
+        states, handles = capture(lm.layers, lm.norm)
+        outputs = model(**inputs, use_cache=False)
+    add_states(tensors, "hidden_states", states, handles)
+    tensors["logits"] = outputs.logits
+    save(out, tensors)
+
+
+def dump_minicpm4(model_path, out):
+    from transformers import AutoModelForCausalLM, AutoTokenizer
+
+    tokenizer = AutoTokenizer.from_pretrained(model_path, trust_remote_code=True)
+    model = AutoModelForCausalLM.from_pretrained(
+        model_path, torch_dtype=torch.float32, trust_remote_code=True
+    ).eval()
+    input_ids = tokenizer.apply_chat_template(
+        [{"role": "user", "content": TEXT}], add_generation_prompt=True, return_tensors="pt"
+    )
+    tensors = {"input_ids": input_ids}
+    with torch.no_grad():
+        states, handles = capture(model.model.layers, model.model.norm)
+        outputs = model(input_ids=input_ids, use_cache=False)
+    add_states(tensors, "hidden_states", states, handles)
+    tensors["logits"] = outputs.logits
+    save(out, tensors)
+
+
+def dump_voxcpm(model_path, out):
+    # 与VoxCPMModel._inference的prefill部分相同, 参考音频特征用随机数代替, 不需要audio vae
+    from voxcpm.model import VoxCPMModel
+
+    model = VoxCPMModel.from_local(model_path, optimize=False).to(torch.float32).eval()
+    text_token = list(model.text_tokenizer(TTS_TEXT)) + [model.audio_start_token]
+    prompt_len = 3
+    text_len = len(text_token)
+    text_token = torch.tensor(text_token + [0] * prompt_len, dtype=torch.int64)
+    text_mask = torch.cat([torch.ones(text_len), torch.zeros(prompt_len)])
+    audio_mask = 1.0 - text_mask
+    torch.manual_seed(0)
+    audio_feat = torch.randn(text_len + prompt_len, model.patch_size, model.feat_dim) * audio_mask[:, None, None]
+    tensors = {
+        "text_token": text_token,
+        "text_mask": text_mask,
+        "audio_feat": audio_feat,
+        "audio_mask": audio_mask,
+    }
+    text, feat = text_token.unsqueeze(0), audio_feat.unsqueeze(0)
+    text_mask, feat_mask = text_mask[None, :, None], audio_mask[None, :, None]
+    lm_config = model.config.lm_config
+    scale_emb = lm_config.scale_emb if lm_config.use_mup else 1.0
+    with torch.no_grad():
+        feat_embed = model.enc_to_lm_proj(model.feat_encoder(feat))
+        text_embed = model.base_lm.embed_tokens(text) * scale_emb
+        combined_embed = text_mask * text_embed + feat_mask * feat_embed
+        states, handles = capture(model.base_lm.layers, model.base_lm.norm)
+        enc_outputs, _ = model.base_lm(inputs_embeds=combined_embed, is_causal=True)
+        add_states(tensors, "base_lm.hidden_states", states, handles)
+        enc_outputs = model.fsq_layer(enc_outputs) * feat_mask + enc_outputs * text_mask
+        lm_hidden = enc_outputs[:, -1, :]
+        states, handles = capture(model.residual_lm.layers, model.residual_lm.norm)
+        residual_outputs, _ = model.residual_lm(inputs_embeds=enc_outputs + feat_mask * feat_embed, is_causal=True)
+        add_states(tensors, "residual_lm.hidden_states", states, handles)
+        residual_hidden = residual_outputs[:, -1, :]
+        tensors["feat_embed"] = feat_embed
+        tensors["enc_outputs"] = enc_outputs
+        tensors["lm_hidden"] = lm_hidden
+        tensors["residual_hidden"] = residual_hidden
+        tensors["dit_hidden"] = model.lm_to_dit_proj(lm_hidden) + model.res_to_dit_proj(residual_hidden)
+        tensors["stop_logits"] = model.stop_head(model.stop_actn(model.stop_proj(lm_hidden)))
+    save(out, tensors)
+
+
+def main():
+    if len(sys.argv) < 4:
+        print(__doc__)
+        sys.exit(1)
+    model, model_path, out = sys.argv[1:4]
+    if model == "qwen3vl":
+        dump_qwen3vl(model_path, out, sys.argv[4] if len(sys.argv) > 4 else None)
+    elif model == "minicpm4":
+        dump_minicpm4(model_path, out)
+    elif model == "voxcpm":
+        dump_voxcpm(model_path, out)
+    else:
+        print(__doc__)
+        sys.exit(1)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,276 @@
+"""Generate image preprocessing fixtures for tests/preprocess_parity_test.rs.
+
+python3 assets/preprocess/gen_fixtures.py [--hf]
+
+Without --hf the script has no third-party dependencies: it ports the steps of
+transformers' Qwen2VLImageProcessor (slow, PIL based) used by Qwen2.5-VL and
+Qwen3-VL: smart_resize, Pillow's BICUBIC resample (fixed-point, horizontal pass
+first), rescale + normalize and the temporal/merge patch flattening.
+With --hf the expected tensors come from transformers + Pillow directly.
+The generator ("hf" or "port") is written to the safetensors metadata.
+"""
+
+import json
+import math
+import os
+import struct
+import sys
+import zlib
+
+ROOT = os.path.dirname(os.path.abspath(__file__))
+
+# (name, width, height)
+IMAGES = [
+    ("odd_aspect", 23, 71),
+    ("tiny", 5, 7),
+    ("at_max", 64, 64),
+    ("over_max", 150, 97),
+]
+
+# 缩小min_pixels/max_pixels让fixture保持很小
+PROCESSORS = {
+    "qwen3vl": {
+        "config": {
+            "size": {"shortest_edge": 1024, "longest_edge": 4096},
+            "patch_size": 16,
+            "temporal_patch_size": 2,
+            "merge_size": 2,
+            "image_mean": [0.5, 0.5, 0.5],
+            "image_std": [0.5, 0.5, 0.5],
+        },
+        "video_config": True,
+        "min_pixels": 1024,
+        "max_pixels": 4096,
+    },
+    "qwen2_5vl": {
+        "config": {
+            "min_pixels": 784,
+            "max_pixels": 3136,
+            "patch_size": 14,
+            "temporal_patch_size": 2,
+            "merge_size": 2,
+            "image_mean": [0.48145466, 0.4578275, 0.40821073],
+            "image_std": [0.26862954, 0.26130258, 0.27577711],
+        },
+        "video_config": False,
+        "min_pixels": 784,
+        "max_pixels": 3136,
+    },
+}
+
+
+def make_image(width, height):
+    # 渐变加高频纹理, 缩放时不同的插值实现差异会比较明显
+    pixels = []
+    for y in range(height):
+        row = []
+        for x in range(width):
+            r = (x * 255 // max(width - 1, 1)) & 0xFF
+            g = (y * 255 // max(height - 1, 1)) & 0xFF
+            b = (x * 37 + y * 91 + (x * y) % 17 * 11) & 0xFF
+            row.append((r, g, b))
+        pixels.append(row)
+    return pixels
+
+
+def write_png(path, pixels):
+    height, width = len(pixels), len(pixels[0])
+    raw = b"".join(b"\x00" + bytes(c for p in row for c in p) for row in pixels)
+
+    def chunk(tag, data):
+        body = tag + data
+        return struct.pack(">I", len(data)) + body + struct.pack(">I", zlib.crc32(body))
+
+    png = b"\x89PNG\r\n\x1a\n"
+    png += chunk(b"IHDR", struct.pack(">IIBBBBB", width, height, 8, 2, 0, 0, 0))
+    png += chunk(b"IDAT", zlib.compress(raw, 9))
+    png += chunk(b"IEND", b"")
+    with open(path, "wb") as f:
+        f.write(png)
+
+
+def smart_resize(height, width, factor, min_pixels, max_pixels):
+    h_bar = max(factor, round(height / factor) * factor)
+    w_bar = max(factor, round(width / factor) * factor)
+    if h_bar * w_bar > max_pixels:
+        beta = math.sqrt((height * width) / max_pixels)
+        h_bar = max(factor, math.floor(height / beta / factor) * factor)
+        w_bar = max(factor, math.floor(width / beta / factor) * factor)
+    elif h_bar * w_bar < min_pixels:
+        beta = math.sqrt(min_pixels / (height * width))
+        h_bar = math.ceil(height * beta / factor) * factor
+        w_bar = math.ceil(width * beta / factor) * factor
+    return h_bar, w_bar
+
+
+# Pillow libImaging/Resample.c
+PRECISION_BITS = 32 - 8 - 2
+
+
+def bicubic(x):
+    a = -0.5
+    x = abs(x)
+    if x < 1.0:
+        return ((a + 2.0) * x - (a + 3.0)) * x * x + 1
+    if x < 2.0:
+        return (((x - 5) * x + 8) * x - 4) * a
+    return 0.0
+
+
+def precompute_coeffs(in_size, out_size):
+    scale = filterscale = in_size / out_size
+    if filterscale < 1.0:
+        filterscale = 1.0
+    support = 2.0 * filterscale
+    coeffs = []
+    for xx in range(out_size):
+        center = (xx + 0.5) * scale
+        ss = 1.0 / filterscale
+        xmin = max(int(center - support + 0.5), 0)
+        xmax = min(int(center + support + 0.5), in_size) - xmin
+        k = [bicubic((x + xmin - center + 0.5) * ss) for x in range(xmax)]
+        ww = sum(k)
+        if ww != 0.0:
+            k = [w / ww for w in k]
+        k = [
+            int(-0.5 + w * (1 << PRECISION_BITS)) if w < 0 else int(0.5 + w * (1 << PRECISION_BITS))
+            for w in k
+        ]
+        coeffs.append((xmin, k))
+    return coeffs
+
+
+def clip8(v):
+    return min(max(v >> PRECISION_BITS, 0), 255)
+
+
+def resize_bicubic(pixels, out_w, out_h):
+    in_h, in_w = len(pixels), len(pixels[0])
+    if out_w != in_w:
+        coeffs = precompute_coeffs(in_w, out_w)
+        pixels = [
+            [
+                tuple(
+                    clip8(
+                        (1 << (PRECISION_BITS - 1))
+                        + sum(row[xmin + i][c] * w for i, w in enumerate(k))
+                    )
+                    for c in range(3)
+                )
+                for xmin, k in coeffs
+            ]
+            for row in pixels
+        ]
+    if out_h != in_h:
+        coeffs = precompute_coeffs(in_h, out_h)
+        pixels = [
+            [
+                tuple(
+                    clip8(
+                        (1 << (PRECISION_BITS - 1))
+                        + sum(pixels[ymin + i][x][c] * w for i, w in enumerate(k))
+                    )
+                    for c in range(3)
+                )
+                for x in range(out_w)
+            ]
+            for ymin, k in coeffs
+        ]
+    return pixels
+
+
+def preprocess(pixels, cfg, min_pixels, max_pixels):
+    patch, temporal, merge = cfg["patch_size"], cfg["temporal_patch_size"], cfg["merge_size"]
+    height, width = len(pixels), len(pixels[0])
+    resized_h, resized_w = smart_resize(height, width, patch * merge, min_pixels, max_pixels)
+    pixels = resize_bicubic(pixels, resized_w, resized_h)
+    mean, std = cfg["image_mean"], cfg["image_std"]
+    # (c, h, w)
+    chw = [
+        [[(pixels[y][x][c] / 255.0 - mean[c]) / std[c] for x in range(resized_w)] for y in range(resized_h)]
+        for c in range(3)
+    ]
+    grid_t, grid_h, grid_w = 1, resized_h // patch, resized_w // patch
+    # (grid_t, temporal, c, gh/m, m, p, gw/m, m, p) -> (0, 3, 6, 4, 7, 2, 1, 5, 8)
+    values = []
+    for bh in range(grid_h // merge):
+        for bw in range(grid_w // merge):
+            for mh in range(merge):
+                for mw in range(merge):
+                    row = []
+                    for c in range(3):
+                        for _ in range(temporal):
+                            for ph in range(patch):
+                                y = (bh * merge + mh) * patch + ph
+                                for pw in range(patch):
+                                    x = (bw * merge + mw) * patch + pw
+                                    row.append(chw[c][y][x])
+                    values.append(row)
+    return values, [grid_t, grid_h, grid_w]
+
+
+def preprocess_hf(path, cfg, min_pixels, max_pixels):
+    from PIL import Image
+    from transformers.models.qwen2_vl.image_processing_qwen2_vl import Qwen2VLImageProcessor
+
+    processor = Qwen2VLImageProcessor(
+        min_pixels=min_pixels,
+        max_pixels=max_pixels,
+        patch_size=cfg["patch_size"],
+        temporal_patch_size=cfg["temporal_patch_size"],
+        merge_size=cfg["merge_size"],
+        image_mean=cfg["image_mean"],
+        image_std=cfg["image_std"],
+    )
+    out = processor(images=[Image.open(path).convert("RGB")], return_tensors="np")
+    return out["pixel_values"].tolist(), out["image_grid_thw"][0].tolist()
+
+
+def write_safetensors(path, tensors, metadata):
+    header, data = {"__metadata__": metadata}, b""
+    for name, (dtype, shape, values) in sorted(tensors.items()):
+        fmt = {"F16": "<%de", "U32": "<%dI"}[dtype]
+        raw = struct.pack(fmt % len(values), *values)
+        header[name] = {"dtype": dtype, "shape": shape, "data_offsets": [len(data), len(data) + len(raw)]}
+        data += raw
+    header = json.dumps(header, separators=(",", ":")).encode()
+    header += b" " * (-len(header) % 8)
+    with open(path, "wb") as f:
+        f.write(struct.pack("<Q", len(header)) + header + data)
+
+
+def main():
+    use_hf = "--hf" in sys.argv
+    image_dir = os.path.join(ROOT, "images")
+    os.makedirs(image_dir, exist_ok=True)
+    images = {}
+    for name, width, height in IMAGES:
+        pixels = make_image(width, height)
+        path = os.path.join(image_dir, name + ".png")
+        write_png(path, pixels)
+        images[name] = (path, pixels)
+    for model, spec in PROCESSORS.items():
+        out_dir = os.path.join(ROOT, model)
+        os.makedirs(out_dir, exist_ok=True)
+        cfg = spec["config"]
+        with open(os.path.join(out_dir, "preprocessor_config.json"), "w") as f:
+            json.dump(cfg, f, indent=2)
+        if spec["video_config"]:
+            with open(os.path.join(out_dir, "video_preprocessor_config.json"), "w") as f:
+                json.dump(cfg, f, indent=2)
+        tensors = {}
+        for name, (path, pixels) in images.items():
+            if use_hf:
+                values, grid = preprocess_hf(path, cfg, spec["min_pixels"], spec["max_pixels"])
+            else:
+                values, grid = preprocess(pixels, cfg, spec["min_pixels"], spec["max_pixels"])
+            flat = [v for row in values for v in row]
+            tensors[name + ".pixel_values"] = ("F16", [len(values), len(values[0])], flat)
+            tensors[name + ".grid_thw"] = ("U32", [1, 3], grid)
+            print(model, name, grid)
+        metadata = {"generator": "hf" if use_hf else "port"}
+        write_safetensors(os.path.join(out_dir, "expected.safetensors"), tensors, metadata)
+
+
+if __name__ == "__main__":
+    main()
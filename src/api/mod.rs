@@ -0,0 +1,106 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use rocket::{
+    State,
+    data::{Data, ToByteUnit},
+    http::{ContentType, Status},
+    tokio::task::spawn_blocking,
+};
+
+use crate::models::{
+    EmbeddingModel,
+    common::embedding::{EmbeddingParameters, create_embeddings},
+};
+
+// 请求体上限, rocket默认的字符串上限只有8KiB
+const EMBEDDING_BODY_LIMIT_MIB: u64 = 16;
+
+// 推理在blocking线程中进行, 不占用async的worker
+pub type EmbeddingState = Arc<Mutex<Box<dyn EmbeddingModel + Send>>>;
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![embeddings]
+}
+
+// 输入输出都是OpenAI格式的json, 请求错误返回400, 推理出错返回500
+pub fn embeddings_response(model: &mut dyn EmbeddingModel, body: &str) -> (Status, String) {
+    let params = serde_json::from_str::<EmbeddingParameters>(body)
+        .map_err(|e| anyhow!(e))
+        .and_then(|params| params.check(model.hidden_size()).map(|_| params));
+    let params = match params {
+        Ok(params) => params,
+        Err(e) => return (Status::BadRequest, error_json(&e.to_string())),
+    };
+    let result = create_embeddings(model, params)
+        .and_then(|response| serde_json::to_string(&response).map_err(|e| anyhow!(e)));
+    match result {
+        Ok(json) => (Status::Ok, json),
+        Err(e) => (
+            Status::InternalServerError,
+            server_error_json(&e.to_string()),
+        ),
+    }
+}
+
+pub fn error_json(message: &str) -> String {
+    error_json_with_type(message, "invalid_request_error")
+}
+
+pub fn server_error_json(message: &str) -> String {
+    error_json_with_type(message, "server_error")
+}
+
+fn error_json_with_type(message: &str, error_type: &str) -> String {
+    serde_json::json!({
+        "error": {
+            "message": message,
+            "type": error_type,
+        }
+    })
+    .to_string()
+}
+
+#[rocket::post("/v1/embeddings", data = "<data>")]
+pub async fn embeddings(
+    data: Data<'_>,
+    state: &State<EmbeddingState>,
+) -> (Status, (ContentType, String)) {
+    let body = match data
+        .open(EMBEDDING_BODY_LIMIT_MIB.mebibytes())
+        .into_string()
+        .await
+    {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => {
+            let message = format!("request body exceeds {} MiB", EMBEDDING_BODY_LIMIT_MIB);
+            return (
+                Status::PayloadTooLarge,
+                (ContentType::JSON, error_json(&message)),
+            );
+        }
+        Err(e) => {
+            let message = format!("read request body error: {}", e);
+            return (
+                Status::BadRequest,
+                (ContentType::JSON, error_json(&message)),
+            );
+        }
+    };
+    let model = Arc::clone(state.inner());
+    let result = spawn_blocking(move || match model.lock() {
+        Ok(mut model) => embeddings_response(model.as_mut(), &body),
+        Err(e) => (
+            Status::InternalServerError,
+            server_error_json(&format!("embedding model lock error: {}", e)),
+        ),
+    })
+    .await;
+    let (status, json) = result.unwrap_or_else(|e| {
+        (
+            Status::InternalServerError,
+            server_error_json(&format!("embedding task error: {}", e)),
+        )
+    });
+    (status, (ContentType::JSON, json))
+}
@@ -0,0 +1,61 @@
+use aha::utils::convert_utils::{
+    ConvertConfig, convert_checkpoint, convert_pickle_file, parse_shard_size,
+};
+use anyhow::{Result, anyhow};
+use candle_core::DType;
+
+const USAGE: &str = "usage: convert_safetensors <model_path|file.pth|file.bin> [save_path] [--dtype f32|f16|bf16] [--max-shard-size 2GB] [--key state_dict|none]";
+
+// cargo run --bin convert_safetensors -- /path/to/VoxCPM-0.5B --dtype bf16
+fn main() -> Result<()> {
+    let mut cfg = ConvertConfig::default();
+    let mut paths = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or(anyhow!(format!("{} needs a value\n{}", arg, USAGE)))
+        };
+        match arg.as_str() {
+            "--dtype" => {
+                cfg.dtype = Some(match value()?.as_str() {
+                    "f32" | "float32" => DType::F32,
+                    "f16" | "float16" => DType::F16,
+                    "bf16" | "bfloat16" => DType::BF16,
+                    d => return Err(anyhow!(format!("unsupported dtype: {}", d))),
+                })
+            }
+            "--max-shard-size" => cfg.max_shard_size = parse_shard_size(&value()?)?,
+            "--key" => {
+                cfg.key = match value()?.as_str() {
+                    "none" => None,
+                    k => Some(k.to_string()),
+                }
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let input = paths.first().ok_or(anyhow!(USAGE))?;
+    let input_path = std::path::Path::new(input);
+    // 默认保存到输入所在目录
+    let save_path = match paths.get(1) {
+        Some(p) => p.clone(),
+        None if input_path.is_dir() => input.clone(),
+        None => input_path
+            .parent()
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or(".".to_string()),
+    };
+    if input_path.is_dir() {
+        convert_checkpoint(input, &save_path, &cfg)?;
+    } else {
+        let files = convert_pickle_file(input, &save_path, &cfg)?;
+        println!("{:?}", files);
+    }
+    println!("saved to {}", save_path);
+    Ok(())
+}
@@ -0,0 +1,34 @@
+use std::sync::{Arc, Mutex};
+
+use aha::{
+    api::{EmbeddingState, routes},
+    models::{
+        EmbeddingModel, minicpm4::generate::MiniCPMGenerateModel,
+        qwen3vl::generate::Qwen3VLGenerateModel,
+    },
+};
+use anyhow::{Result, anyhow};
+
+const USAGE: &str = "usage: embedding_server <minicpm4|qwen3vl> <model_path>";
+
+// ROCKET_PORT=8000 cargo run --release --bin embedding_server -- qwen3vl /path/to/Qwen3-VL-2B-Instruct
+#[rocket::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (model_type, path) = match &args[..] {
+        [model_type, path] => (model_type.as_str(), path.as_str()),
+        _ => return Err(anyhow!(USAGE)),
+    };
+    let model: Box<dyn EmbeddingModel + Send> = match model_type {
+        "minicpm4" => Box::new(MiniCPMGenerateModel::init(path, None, None)?),
+        "qwen3vl" => Box::new(Qwen3VLGenerateModel::init(path, None, None)?),
+        t => return Err(anyhow!(format!("unsupported model: {}\n{}", t, USAGE))),
+    };
+    let state: EmbeddingState = Arc::new(Mutex::new(model));
+    rocket::build()
+        .manage(state)
+        .mount("/", routes())
+        .launch()
+        .await?;
+    Ok(())
+}
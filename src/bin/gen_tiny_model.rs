@@ -0,0 +1,21 @@
+use aha::models::common::fixture::TinyModel;
+use anyhow::{Result, anyhow};
+
+const USAGE: &str = "usage: gen_tiny_model <minicpm4|qwen2.5vl|qwen3vl|voxcpm> <save_path>";
+
+// 生成随机权重的小模型, 用于在CPU上跑端到端测试
+// cargo run --bin gen_tiny_model -- qwen3vl /tmp/tiny-qwen3vl
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let (name, save_path) = match args.as_slice() {
+        [name, save_path] => (name, save_path),
+        _ => return Err(anyhow!(USAGE)),
+    };
+    TinyModel::from_name(name)?.write(save_path)?;
+    println!("saved to {}", save_path);
+    Ok(())
+}
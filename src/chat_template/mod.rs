@@ -2,10 +2,19 @@ use aha_openai_dive::v1::resources::chat::ChatCompletionParameters;
 use anyhow::{Result, anyhow};
 use minijinja::{Environment, Value as MiniJinjaValue, context};
 
-use crate::utils::string_to_static_str;
+use crate::{models::common::gguf::GgufFile, utils::string_to_static_str};
 
 pub fn get_template(path: String) -> Result<String> {
     let tokenizer_config_file = path.clone() + "/tokenizer_config.json";
+    // 没有tokenizer_config.json时使用gguf元数据中的tokenizer.chat_template
+    if !std::path::Path::new(&tokenizer_config_file).exists()
+        && let Some(gguf) = GgufFile::find(&path)?
+    {
+        let chat_template = gguf
+            .chat_template()
+            .ok_or(anyhow!("tokenizer.chat_template not found in gguf"))?;
+        return Ok(fix_template(&chat_template));
+    }
     assert!(
         std::path::Path::new(&tokenizer_config_file).exists(),
         "tokenizer_config.json not exists in model path"
@@ -16,8 +25,12 @@ pub fn get_template(path: String) -> Result<String> {
     let chat_template = tokenizer_config["chat_template"]
         .as_str()
         .ok_or(anyhow!(format!("chat_template to str error")))?;
+    Ok(fix_template(chat_template))
+}
+
+fn fix_template(chat_template: &str) -> String {
     // 修复模板中的问题行
-    let fixed_template = chat_template
+    chat_template
         .replace(
             "message.content.startswith('<tool_response>')",
             "message.content is startingwith('<tool_response>')", // 使用minijinja中的 is startingwith 替换
@@ -41,8 +54,7 @@ pub fn get_template(path: String) -> Result<String> {
         .replace(
             "content.lstrip('\\n')",
             "content | lstrip('\\n')", // 使用自定义的过滤器替换
-        );
-    Ok(fixed_template)
+        )
 }
 
 pub struct ChatTemplate<'a> {
@@ -89,9 +101,18 @@ impl<'a> ChatTemplate<'a> {
     }
 
     pub fn apply_chat_template(&self, messages: &ChatCompletionParameters) -> Result<String> {
+        self.render(messages, true)
+    }
+
+    // 打分时对话已包含回复, 不添加生成提示
+    pub fn render(
+        &self,
+        messages: &ChatCompletionParameters,
+        add_generation_prompt: bool,
+    ) -> Result<String> {
         let context = context! {
             messages => &messages.messages,
-            add_generation_prompt => true,
+            add_generation_prompt => add_generation_prompt,
         };
         let template = self
             .env
@@ -1,3 +1,4 @@
+pub mod api;
 pub mod chat_template;
 pub mod models;
 pub mod position_embed;
@@ -0,0 +1,256 @@
+use anyhow::{Result, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use candle_core::{DType, Device, Tensor};
+
+use crate::models::EmbeddingModel;
+
+// 一次forward的文本条数上限
+pub const EMBEDDING_BATCH_SIZE: usize = 16;
+
+// 解码器模型没有CLS token, Cls取第一个位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pooling {
+    Mean,
+    #[default]
+    LastToken,
+    Cls,
+}
+
+// hidden_states: (seq_len, hidden_size)
+pub fn pool_hidden_states(hidden_states: &Tensor, pooling: Pooling) -> Result<Vec<f32>> {
+    let seq_len = hidden_states.dim(0)?;
+    if seq_len == 0 {
+        return Err(anyhow!("embedding input is empty"));
+    }
+    let hidden_states = hidden_states.to_dtype(DType::F32)?;
+    let pooled = match pooling {
+        Pooling::Mean => hidden_states.mean(0)?,
+        Pooling::LastToken => hidden_states.get(seq_len - 1)?,
+        Pooling::Cls => hidden_states.get(0)?,
+    };
+    Ok(pooled.to_vec1::<f32>()?)
+}
+
+// 右侧补齐成一个batch: (bs, max_len), 因果注意力下补齐的token不影响前面的位置
+pub fn pad_batch(ids: &[Vec<u32>], device: &Device) -> Result<Tensor> {
+    let max_len = ids.iter().map(|i| i.len()).max().unwrap_or(0);
+    let mut data = Vec::with_capacity(ids.len() * max_len);
+    for i in ids {
+        data.extend_from_slice(i);
+        data.extend(std::iter::repeat_n(0u32, max_len - i.len()));
+    }
+    Ok(Tensor::from_vec(data, (ids.len(), max_len), device)?)
+}
+
+// hidden_states: (bs, max_len, hidden_size), 每条只取补齐前的长度做池化
+pub fn pool_batch(
+    hidden_states: &Tensor,
+    lens: &[usize],
+    pooling: Pooling,
+) -> Result<Vec<(Vec<f32>, usize)>> {
+    lens.iter()
+        .enumerate()
+        .map(|(i, &len)| {
+            let hidden = hidden_states.get(i)?.narrow(0, 0, len)?;
+            Ok((pool_hidden_states(&hidden, pooling)?, len))
+        })
+        .collect()
+}
+
+pub fn l2_normalize(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|v| *v /= norm);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ImageUrl {
+    pub url: String,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EmbeddingInputItem {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    String(String),
+    StringArray(Vec<String>),
+    // 扩展: 文本和图片混合输入, 每项单独得到一个向量
+    Items(Vec<EmbeddingInputItem>),
+}
+
+impl EmbeddingInput {
+    pub fn items(&self) -> Vec<EmbeddingInputItem> {
+        match self {
+            EmbeddingInput::String(text) => vec![EmbeddingInputItem::Text { text: text.clone() }],
+            EmbeddingInput::StringArray(texts) => texts
+                .iter()
+                .map(|text| EmbeddingInputItem::Text { text: text.clone() })
+                .collect(),
+            EmbeddingInput::Items(items) => items.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncodingFormat {
+    #[default]
+    Float,
+    Base64,
+}
+
+// 与OpenAI /v1/embeddings请求一致, pooling和normalize为扩展字段
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct EmbeddingParameters {
+    pub model: String,
+    pub input: EmbeddingInput,
+    #[serde(default)]
+    pub encoding_format: Option<EncodingFormat>,
+    #[serde(default)]
+    pub dimensions: Option<usize>,
+    #[serde(default)]
+    pub pooling: Option<Pooling>,
+    #[serde(default)]
+    pub normalize: Option<bool>,
+}
+
+impl EmbeddingParameters {
+    // 请求本身的错误, 在推理之前检查
+    pub fn check(&self, hidden_size: usize) -> Result<()> {
+        if self.input.items().is_empty() {
+            return Err(anyhow!("embedding input is empty"));
+        }
+        if let Some(dimensions) = self.dimensions
+            && (dimensions == 0 || dimensions > hidden_size)
+        {
+            return Err(anyhow!(format!(
+                "dimensions must be in [1, {}], got {}",
+                hidden_size, dimensions
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+pub enum EmbeddingOutput {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Embedding {
+    pub object: String,
+    pub index: usize,
+    pub embedding: EmbeddingOutput,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct EmbeddingResponse {
+    pub object: String,
+    pub data: Vec<Embedding>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+// 默认last_token池化并做L2归一化, dimensions小于hidden_size时截断后再归一化
+// 文本按EMBEDDING_BATCH_SIZE分批输入, 图片逐个输入
+pub fn create_embeddings(
+    model: &mut dyn EmbeddingModel,
+    params: EmbeddingParameters,
+) -> Result<EmbeddingResponse> {
+    params.check(model.hidden_size())?;
+    let pooling = params.pooling.unwrap_or_default();
+    let normalize = params.normalize.unwrap_or(true);
+    let items = params.input.items();
+    let mut outputs: Vec<Option<(Vec<f32>, usize)>> = vec![None; items.len()];
+    let texts: Vec<(usize, &str)> = items
+        .iter()
+        .enumerate()
+        .filter_map(|(i, item)| match item {
+            EmbeddingInputItem::Text { text } => Some((i, text.as_str())),
+            EmbeddingInputItem::ImageUrl { .. } => None,
+        })
+        .collect();
+    for batch in texts.chunks(EMBEDDING_BATCH_SIZE) {
+        let batch_texts: Vec<&str> = batch.iter().map(|(_, text)| *text).collect();
+        let embeddings = model.embed_texts(&batch_texts, pooling)?;
+        if embeddings.len() != batch.len() {
+            return Err(anyhow!(format!(
+                "model returned {} embeddings for {} texts",
+                embeddings.len(),
+                batch.len()
+            )));
+        }
+        for ((i, _), embedding) in batch.iter().zip(embeddings) {
+            outputs[*i] = Some(embedding);
+        }
+    }
+    for (i, item) in items.iter().enumerate() {
+        if let EmbeddingInputItem::ImageUrl { image_url } = item {
+            outputs[i] = Some(model.embed_image(&image_url.url, pooling)?);
+        }
+    }
+
+    let mut data = Vec::with_capacity(items.len());
+    let mut prompt_tokens = 0;
+    for (index, output) in outputs.into_iter().enumerate() {
+        let (mut embedding, num_tokens) =
+            output.ok_or_else(|| anyhow!(format!("embedding {} missing", index)))?;
+        prompt_tokens += num_tokens;
+        if let Some(dimensions) = params.dimensions {
+            embedding.truncate(dimensions);
+        }
+        if normalize {
+            l2_normalize(&mut embedding);
+        }
+        let embedding = match params.encoding_format.unwrap_or_default() {
+            EncodingFormat::Float => EmbeddingOutput::Float(embedding),
+            EncodingFormat::Base64 => {
+                let bytes: Vec<u8> = embedding.iter().flat_map(|v| v.to_le_bytes()).collect();
+                EmbeddingOutput::Base64(STANDARD.encode(bytes))
+            }
+        };
+        data.push(Embedding {
+            object: "embedding".to_string(),
+            index,
+            embedding,
+        });
+    }
+    Ok(EmbeddingResponse {
+        object: "list".to_string(),
+        data,
+        model: params.model,
+        usage: EmbeddingUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    })
+}
+
+// 余弦相似度, 用于检索时比较两个向量
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|v| v * v).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm_a > 0.0 && norm_b > 0.0 {
+        dot / (norm_a * norm_b)
+    } else {
+        0.0
+    }
+}
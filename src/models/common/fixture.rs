@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+
+use anyhow::{Result, anyhow};
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use serde_json::{Value, json};
+
+use crate::{
+    models::{
+        common::manifest::{WeightChecker, WeightIndex},
+        minicpm4::{config::MiniCPM4Config, model::MiniCPMModel},
+        qwen2_5vl::{config::Qwen2_5VLConfig, model::Qwen2_5VLModel},
+        qwen3vl::{config::Qwen3VLConfig, model::Qwen3VLModel},
+        voxcpm::{
+            audio_vae::AudioVAE, config::VoxCPMConfig, model::VoxCPMModel,
+            tokenizer::SingleChineseTokenizer,
+        },
+    },
+    tokenizer::tiny_tokenizer_json,
+};
+
+// 随机权重的小模型, 不下载权重也能在CPU上跑generate/generate_stream/TTS的端到端测试
+// 权重名和shape与check_model一样由dry run构建模型得到, 数值由固定种子生成, 同一版本的代码结果不变
+// config.json带model_type/architectures, transformers等参考实现可以直接加载同一份权重
+const FIXTURE_SEED: u64 = 20251018;
+
+// 256个字节token之后的special token, id从256开始
+const SPECIAL_TOKENS: [&str; 8] = [
+    "<|endoftext|>",
+    "<|im_start|>",
+    "<|im_end|>",
+    "<|vision_start|>",
+    "<|vision_end|>",
+    "<|vision_pad|>",
+    "<|image_pad|>",
+    "<|video_pad|>",
+];
+const ENDOFTEXT_ID: usize = 256;
+const IM_END_ID: usize = 258;
+const VOCAB_SIZE: usize = 320;
+
+// ChatML, 图片/视频占位与Qwen-VL的模板一致
+const CHAT_TEMPLATE: &str = concat!(
+    "{% for message in messages %}",
+    "{% if loop.first and message.role != 'system' %}<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n{% endif %}",
+    "<|im_start|>{{ message.role }}\n",
+    "{% if message.content is string %}{{ message.content }}",
+    "{% else %}{% for content in message.content %}",
+    "{% if content.type == 'image_url' or content.type == 'image' %}<|vision_start|><|image_pad|><|vision_end|>",
+    "{% elif content.type == 'video_url' or content.type == 'video' %}<|vision_start|><|video_pad|><|vision_end|>",
+    "{% elif content.text is defined %}{{ content.text }}{% endif %}",
+    "{% endfor %}{% endif %}<|im_end|>\n",
+    "{% endfor %}",
+    "{% if add_generation_prompt %}<|im_start|>assistant\n{% endif %}"
+);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TinyModel {
+    MiniCPM4,
+    Qwen2_5VL,
+    Qwen3VL,
+    VoxCPM,
+}
+
+impl TinyModel {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "minicpm4" => Ok(Self::MiniCPM4),
+            "qwen2.5vl" | "qwen2_5vl" => Ok(Self::Qwen2_5VL),
+            "qwen3vl" => Ok(Self::Qwen3VL),
+            "voxcpm" => Ok(Self::VoxCPM),
+            _ => Err(anyhow!(format!("unknown tiny model: {}", name))),
+        }
+    }
+
+    // 写入config.json, 分词器, 预处理配置和随机权重, 目录不存在时创建
+    pub fn write(&self, path: &str) -> Result<()> {
+        std::fs::create_dir_all(path)?;
+        write_tokenizer(path)?;
+        match self {
+            Self::MiniCPM4 => write_minicpm4(path),
+            Self::Qwen2_5VL => write_qwen2_5vl(path),
+            Self::Qwen3VL => write_qwen3vl(path),
+            Self::VoxCPM => write_voxcpm(path),
+        }
+    }
+}
+
+// splitmix64
+struct FixtureRng(u64);
+
+impl FixtureRng {
+    fn next_f32(&mut self) -> f32 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^= z >> 31;
+        (z >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+// norm的weight, snake的alpha和weight norm的weight_g为1, bias为0,
+// 其他权重为U(-1/sqrt(fan_in), 1/sqrt(fan_in)), 保证随机权重下前向数值稳定
+fn random_tensor(name: &str, shape: &[usize], rng: &mut FixtureRng) -> Result<Tensor> {
+    let numel: usize = shape.iter().product();
+    let ones = name.ends_with("alpha")
+        || name.ends_with("weight_g")
+        || (shape.len() == 1 && name.ends_with("weight"));
+    let data: Vec<f32> = if ones {
+        vec![1.0; numel]
+    } else if name.ends_with("bias") {
+        vec![0.0; numel]
+    } else {
+        let fan_in = (numel / shape.first().cloned().unwrap_or(1).max(1)).max(1);
+        let bound = 1.0 / (fan_in as f32).sqrt();
+        (0..numel)
+            .map(|_| (rng.next_f32() * 2.0 - 1.0) * bound)
+            .collect()
+    };
+    Ok(Tensor::from_vec(data, shape, &Device::Cpu)?)
+}
+
+// dry run构建模型, 记录请求的权重后按名字排序生成随机值
+fn random_weights<T>(
+    rng: &mut FixtureRng,
+    build: impl FnOnce(VarBuilder) -> Result<T>,
+) -> Result<HashMap<String, Tensor>> {
+    let (vb, checker) =
+        WeightChecker::var_builder(None, WeightIndex::default(), DType::F32, &Device::Cpu);
+    let report = checker.finish_report(build(vb));
+    if let Some(e) = report.build_error {
+        return Err(anyhow!(format!("build tiny model error: {}", e)));
+    }
+    let mut weights = HashMap::new();
+    for (name, shape) in report.missing {
+        let tensor = random_tensor(&name, &shape, rng)?;
+        weights.insert(name, tensor);
+    }
+    Ok(weights)
+}
+
+fn write_json(path: &str, file: &str, value: &Value) -> Result<()> {
+    std::fs::write(
+        format!("{}/{}", path, file),
+        serde_json::to_string_pretty(value)?,
+    )?;
+    Ok(())
+}
+
+fn write_tokenizer(path: &str) -> Result<()> {
+    write_json(
+        path,
+        "tokenizer.json",
+        &tiny_tokenizer_json(&SPECIAL_TOKENS),
+    )?;
+    write_json(
+        path,
+        "tokenizer_config.json",
+        &json!({
+            "chat_template": CHAT_TEMPLATE,
+            "eos_token": "<|im_end|>",
+            "pad_token": "<|endoftext|>",
+            "model_max_length": 2048
+        }),
+    )
+}
+
+fn token_id(token: &str) -> usize {
+    let idx = SPECIAL_TOKENS.iter().position(|t| *t == token).unwrap_or(0);
+    ENDOFTEXT_ID + idx
+}
+
+// MiniCPM4和VoxCPM的语言模型共用, head_dim为16
+fn minicpm4_lm_config(rope_type_key: &str) -> Value {
+    json!({
+        "bos_token_id": ENDOFTEXT_ID,
+        "hidden_act": "silu",
+        "hidden_size": 64,
+        "intermediate_size": 128,
+        "max_position_embeddings": 2048,
+        "num_attention_heads": 4,
+        "num_hidden_layers": 2,
+        "num_key_value_heads": 2,
+        "rms_norm_eps": 1e-5,
+        "rope_theta": 10000.0,
+        "rope_scaling": {
+            rope_type_key: "longrope",
+            "long_factor": vec![1.0; 8],
+            "short_factor": vec![1.0; 8],
+            "original_max_position_embeddings": 2048
+        },
+        "vocab_size": VOCAB_SIZE,
+        "scale_emb": 12,
+        "dim_model_base": 32,
+        "scale_depth": 1.4
+    })
+}
+
+fn write_minicpm4(path: &str) -> Result<()> {
+    let mut config = minicpm4_lm_config("rope_type");
+    config["eos_token_id"] = json!([ENDOFTEXT_ID, IM_END_ID]);
+    config["torch_dtype"] = json!("float32");
+    config["architectures"] = json!(["MiniCPMForCausalLM"]);
+    config["model_type"] = json!("minicpm");
+    let cfg: MiniCPM4Config = serde_json::from_value(config.clone())?;
+    let mut rng = FixtureRng(FIXTURE_SEED);
+    let weights = random_weights(&mut rng, |vb| MiniCPMModel::new(vb, cfg))?;
+    write_json(path, "config.json", &config)?;
+    candle_core::safetensors::save(&weights, format!("{}/model.safetensors", path))?;
+    Ok(())
+}
+
+fn write_qwen2_5vl(path: &str) -> Result<()> {
+    let config = json!({
+        "architectures": ["Qwen2_5_VLForConditionalGeneration"],
+        "model_type": "qwen2_5_vl",
+        "attention_dropout": 0.0,
+        "bos_token_id": ENDOFTEXT_ID,
+        "eos_token_id": IM_END_ID,
+        "vision_start_token_id": token_id("<|vision_start|>"),
+        "vision_end_token_id": token_id("<|vision_end|>"),
+        "vision_token_id": token_id("<|vision_pad|>"),
+        "image_token_id": token_id("<|image_pad|>"),
+        "video_token_id": token_id("<|video_pad|>"),
+        "hidden_act": "silu",
+        "hidden_size": 64,
+        "initializer_range": 0.02,
+        "intermediate_size": 128,
+        "max_position_embeddings": 2048,
+        "max_window_layers": 2,
+        "num_attention_heads": 4,
+        "num_hidden_layers": 2,
+        "num_key_value_heads": 2,
+        "rms_norm_eps": 1e-6,
+        "rope_theta": 1000000.0,
+        "sliding_window": 2048,
+        "tie_word_embeddings": true,
+        "torch_dtype": "float32",
+        "use_sliding_window": false,
+        "vision_config": {
+            "depth": 2,
+            "hidden_act": "silu",
+            "hidden_size": 32,
+            "intermediate_size": 64,
+            "num_heads": 2,
+            "in_chans": 3,
+            "out_hidden_size": 64,
+            "patch_size": 14,
+            "spatial_merge_size": 2,
+            "spatial_patch_size": 14,
+            "window_size": 112,
+            "fullatt_block_indexes": [1],
+            "tokens_per_second": 2,
+            "temporal_patch_size": 2
+        },
+        "rope_scaling": {"type": "mrope", "mrope_section": [2, 3, 3]},
+        "vocab_size": VOCAB_SIZE
+    });
+    let cfg: Qwen2_5VLConfig = serde_json::from_value(config.clone())?;
+    let mut rng = FixtureRng(FIXTURE_SEED);
+    let weights = random_weights(&mut rng, |vb| Qwen2_5VLModel::new(cfg, vb))?;
+    write_json(path, "config.json", &config)?;
+    // 图片最多16个patch
+    write_json(
+        path,
+        "preprocessor_config.json",
+        &json!({
+            "min_pixels": 784,
+            "max_pixels": 3136,
+            "patch_size": 14,
+            "temporal_patch_size": 2,
+            "merge_size": 2,
+            "image_mean": [0.48145466, 0.4578275, 0.40821073],
+            "image_std": [0.26862954, 0.26130258, 0.27577711]
+        }),
+    )?;
+    candle_core::safetensors::save(&weights, format!("{}/model.safetensors", path))?;
+    Ok(())
+}
+
+fn write_qwen3vl(path: &str) -> Result<()> {
+    let config = json!({
+        "architectures": ["Qwen3VLForConditionalGeneration"],
+        "model_type": "qwen3_vl",
+        "image_token_id": token_id("<|image_pad|>"),
+        "text_config": {
+            "model_type": "qwen3_vl_text",
+            "attention_bias": false,
+            "attention_dropout": 0.0,
+            "bos_token_id": ENDOFTEXT_ID,
+            "dtype": "float32",
+            "eos_token_id": IM_END_ID,
+            "head_dim": 16,
+            "hidden_act": "silu",
+            "hidden_size": 64,
+            "initializer_range": 0.02,
+            "intermediate_size": 128,
+            "max_position_embeddings": 2048,
+            "num_attention_heads": 4,
+            "num_hidden_layers": 2,
+            "num_key_value_heads": 2,
+            "rms_norm_eps": 1e-6,
+            "rope_scaling": {
+                "rope_type": "default",
+                "mrope_section": [4, 2, 2],
+                "mrope_interleaved": true
+            },
+            "rope_theta": 5000000.0,
+            "tie_word_embeddings": true,
+            "use_cache": true,
+            "vocab_size": VOCAB_SIZE
+        },
+        "tie_word_embeddings": true,
+        "video_token_id": token_id("<|video_pad|>"),
+        "vision_config": {
+            "model_type": "qwen3_vl",
+            "deepstack_visual_indexes": [0],
+            "depth": 2,
+            "hidden_act": "gelu_pytorch_tanh",
+            "hidden_size": 32,
+            "in_channels": 3,
+            "initializer_range": 0.02,
+            "intermediate_size": 64,
+            "num_heads": 2,
+            "num_position_embeddings": 64,
+            "out_hidden_size": 64,
+            "patch_size": 16,
+            "spatial_merge_size": 2,
+            "temporal_patch_size": 2
+        },
+        "vision_end_token_id": token_id("<|vision_end|>"),
+        "vision_start_token_id": token_id("<|vision_start|>")
+    });
+    let cfg: Qwen3VLConfig = serde_json::from_value(config.clone())?;
+    let mut rng = FixtureRng(FIXTURE_SEED);
+    let weights = random_weights(&mut rng, |vb| Qwen3VLModel::new(cfg, vb.pp("model")))?;
+    write_json(path, "config.json", &config)?;
+    write_json(
+        path,
+        "generation_config.json",
+        &json!({
+            "bos_token_id": ENDOFTEXT_ID,
+            "pad_token_id": ENDOFTEXT_ID,
+            "do_sample": true,
+            "eos_token_id": [IM_END_ID, ENDOFTEXT_ID],
+            "top_p": 0.8,
+            "top_k": 20,
+            "temperature": 0.7,
+            "repetition_penalty": 1.0
+        }),
+    )?;
+    // 图片最多16个patch, 视频每帧最多16个patch
+    let preprocessor = json!({
+        "size": {"shortest_edge": 1024, "longest_edge": 4096},
+        "patch_size": 16,
+        "temporal_patch_size": 2,
+        "merge_size": 2,
+        "image_mean": [0.5, 0.5, 0.5],
+        "image_std": [0.5, 0.5, 0.5]
+    });
+    write_json(path, "preprocessor_config.json", &preprocessor)?;
+    let mut video_preprocessor = preprocessor.clone();
+    video_preprocessor["fps"] = json!(2.0);
+    video_preprocessor["min_frames"] = json!(2);
+    video_preprocessor["max_frames"] = json!(8);
+    write_json(path, "video_preprocessor_config.json", &video_preprocessor)?;
+    candle_core::safetensors::save(&weights, format!("{}/model.safetensors", path))?;
+    Ok(())
+}
+
+fn write_voxcpm(path: &str) -> Result<()> {
+    let mut lm_config = minicpm4_lm_config("type");
+    lm_config["eos_token_id"] = json!(ENDOFTEXT_ID);
+    lm_config["use_mup"] = json!(false);
+    // audio vae的hop_length为4, 每个patch 8个采样点
+    let config = json!({
+        "architecture": "voxcpm",
+        "lm_config": lm_config,
+        "patch_size": 2,
+        "feat_dim": 8,
+        "scalar_quantization_latent_dim": 16,
+        "scalar_quantization_scale": 9,
+        "residual_lm_num_layers": 1,
+        "encoder_config": {"hidden_dim": 32, "ffn_dim": 64, "num_heads": 2, "num_layers": 1},
+        "dit_config": {
+            "hidden_dim": 32,
+            "ffn_dim": 64,
+            "num_heads": 2,
+            "num_layers": 1,
+            "cfm_config": {
+                "sigma_min": 1e-6,
+                "solver": "euler",
+                "t_scheduler": "log-norm",
+                "inference_cfg_rate": 2.0
+            }
+        },
+        "max_length": 2048,
+        "dtype": "float32",
+        "audio_vae_config": {
+            "encoder_dim": 4,
+            "encoder_rates": [2, 2],
+            "latent_dim": 8,
+            "decoder_dim": 16,
+            "decoder_rates": [2, 2],
+            "sample_rate": 16000
+        }
+    });
+    let cfg: VoxCPMConfig = serde_json::from_value(config.clone())?;
+    let vae_config = cfg.audio_vae_config.clone().unwrap_or_default();
+    let mut rng = FixtureRng(FIXTURE_SEED);
+    let vae_weights = random_weights(&mut rng, |vb| AudioVAE::from_config(vb, &vae_config))?;
+    // VoxCPMModel构建时需要audio vae和分词器, 这里只用来记录权重
+    let (vb_vae, _) =
+        WeightChecker::var_builder(None, WeightIndex::default(), DType::F32, &Device::Cpu);
+    let audio_vae = AudioVAE::from_config(vb_vae, &vae_config)?;
+    let tokenizer = SingleChineseTokenizer::new(path)?;
+    let weights = random_weights(&mut rng, |vb| {
+        VoxCPMModel::new(vb, cfg, tokenizer, audio_vae)
+    })?;
+    write_json(path, "config.json", &config)?;
+    candle_core::safetensors::save(&vae_weights, format!("{}/audiovae.safetensors", path))?;
+    candle_core::safetensors::save(&weights, format!("{}/model.safetensors", path))?;
+    Ok(())
+}
@@ -0,0 +1,448 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    sync::Mutex,
+};
+
+use anyhow::{Result, anyhow};
+use candle_core::{
+    DType, Device, Shape, Tensor,
+    quantized::{
+        GgmlDType, QTensor,
+        ggml_file::qtensor_from_ggml,
+        gguf_file::{Content, Value},
+    },
+    safetensors::MmapedSafetensors,
+};
+use candle_nn::{Init, VarBuilder, var_builder::SimpleBackend};
+
+use crate::{models::common::manifest::WeightIndex, utils::find_type_files};
+
+// 解码层中的Linear, 保持量化格式用QMatMul计算
+const LINEAR_MODULES: [&str; 7] = [
+    "attn_q",
+    "attn_k",
+    "attn_v",
+    "attn_output",
+    "ffn_gate",
+    "ffn_up",
+    "ffn_down",
+];
+
+// llama.cpp中解码层权重名 -> transformers中的权重名
+const LAYER_NAME_MAP: [(&str, &str); 11] = [
+    ("attn_norm", "input_layernorm"),
+    ("ffn_norm", "post_attention_layernorm"),
+    ("attn_q", "self_attn.q_proj"),
+    ("attn_k", "self_attn.k_proj"),
+    ("attn_v", "self_attn.v_proj"),
+    ("attn_output", "self_attn.o_proj"),
+    ("attn_q_norm", "self_attn.q_norm"),
+    ("attn_k_norm", "self_attn.k_norm"),
+    ("ffn_gate", "mlp.gate_proj"),
+    ("ffn_up", "mlp.up_proj"),
+    ("ffn_down", "mlp.down_proj"),
+];
+
+fn value_to_f64(value: &Value) -> Option<f64> {
+    match value {
+        Value::U8(v) => Some(*v as f64),
+        Value::I8(v) => Some(*v as f64),
+        Value::U16(v) => Some(*v as f64),
+        Value::I16(v) => Some(*v as f64),
+        Value::U32(v) => Some(*v as f64),
+        Value::I32(v) => Some(*v as f64),
+        Value::U64(v) => Some(*v as f64),
+        Value::I64(v) => Some(*v as f64),
+        Value::F32(v) => Some(*v as f64),
+        Value::F64(v) => Some(*v),
+        Value::Bool(v) => Some(*v as u8 as f64),
+        _ => None,
+    }
+}
+
+pub struct GgufFile {
+    path: String,
+    content: Content,
+}
+
+impl GgufFile {
+    pub fn open(path: &str) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let content = Content::read(&mut file)
+            .map_err(|e| anyhow!(format!("read gguf {} error: {}", path, e)))?;
+        Ok(Self {
+            path: path.to_string(),
+            content,
+        })
+    }
+
+    // 模型目录下的gguf文件, 忽略视觉部分的mmproj文件, 没有则返回None
+    pub fn find(model_path: &str) -> Result<Option<Self>> {
+        let mut files: Vec<String> = find_type_files(model_path, "gguf")?
+            .into_iter()
+            .filter(|f| {
+                let name = std::path::Path::new(f)
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_lowercase();
+                !name.starts_with("mmproj")
+            })
+            .collect();
+        files.sort();
+        match files.first() {
+            Some(f) => Ok(Some(Self::open(f)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn metadata(&self, key: &str) -> Option<&Value> {
+        self.content.metadata.get(key)
+    }
+
+    pub fn get_f64(&self, key: &str) -> Option<f64> {
+        self.metadata(key).and_then(value_to_f64)
+    }
+
+    pub fn get_usize(&self, key: &str) -> Option<usize> {
+        self.get_f64(key).map(|v| v as usize)
+    }
+
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        match self.metadata(key) {
+            Some(Value::Bool(v)) => Some(*v),
+            Some(v) => value_to_f64(v).map(|v| v != 0.0),
+            None => None,
+        }
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        match self.metadata(key) {
+            Some(Value::String(s)) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn get_str_array(&self, key: &str) -> Option<Vec<&str>> {
+        match self.metadata(key) {
+            Some(Value::Array(values)) => values
+                .iter()
+                .map(|v| match v {
+                    Value::String(s) => Some(s.as_str()),
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        }
+    }
+
+    pub fn get_f64_array(&self, key: &str) -> Option<Vec<f64>> {
+        match self.metadata(key) {
+            Some(Value::Array(values)) => values.iter().map(value_to_f64).collect(),
+            _ => None,
+        }
+    }
+
+    pub fn architecture(&self) -> Result<&str> {
+        self.get_str("general.architecture")
+            .ok_or(anyhow!("general.architecture not found in gguf"))
+    }
+
+    // 读取 {architecture}.{key}
+    pub fn arch_usize(&self, key: &str) -> Option<usize> {
+        let arch = self.architecture().ok()?;
+        self.get_usize(&format!("{}.{}", arch, key))
+    }
+
+    pub fn arch_f64(&self, key: &str) -> Option<f64> {
+        let arch = self.architecture().ok()?;
+        self.get_f64(&format!("{}.{}", arch, key))
+    }
+
+    pub fn chat_template(&self) -> Option<String> {
+        self.get_str("tokenizer.chat_template")
+            .map(|s| s.to_string())
+    }
+
+    pub fn contains_tensor(&self, name: &str) -> bool {
+        self.content.tensor_infos.contains_key(name)
+    }
+
+    pub fn tensor_shape(&self, name: &str) -> Option<&Shape> {
+        self.content.tensor_infos.get(name).map(|info| &info.shape)
+    }
+
+    // 读取并反量化为f32
+    pub fn tensor(&self, name: &str, device: &Device) -> Result<Tensor> {
+        let mut file = File::open(&self.path)?;
+        let qtensor = self.content.tensor(&mut file, name, &Device::Cpu)?;
+        Ok(qtensor.dequantize(&Device::Cpu)?.to_device(device)?)
+    }
+
+    // llama.cpp转换llama/minicpm时对q_proj和k_proj的行做了重排(rotate_half -> 交错), 读取时需要还原
+    fn permute_heads(&self) -> Result<Option<(usize, usize)>> {
+        match self.architecture()? {
+            "llama" | "minicpm" => {
+                let num_heads = self
+                    .arch_usize("attention.head_count")
+                    .ok_or(anyhow!("attention.head_count not found in gguf"))?;
+                let num_kv_heads = self
+                    .arch_usize("attention.head_count_kv")
+                    .unwrap_or(num_heads);
+                Ok(Some((num_heads, num_kv_heads)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // 解码层Linear和lm_head的量化权重, 加载模型时由qlinear读取, 不经过VarBuilder反量化
+    pub fn linears(&self, text_prefix: &str, lm_head: &str) -> Result<GgufLinears> {
+        let mut tensors = HashMap::new();
+        for (gguf_name, info) in self.content.tensor_infos.iter() {
+            let is_linear = gguf_name == "output.weight"
+                || gguf_name.strip_prefix("blk.").is_some_and(|rest| {
+                    rest.split('.')
+                        .nth(1)
+                        .is_some_and(|m| LINEAR_MODULES.contains(&m))
+                        && rest.ends_with(".weight")
+                });
+            if is_linear && let Some(hf_name) = gguf_name_to_hf(gguf_name, text_prefix, lm_head) {
+                tensors.insert(hf_name, (info.ggml_dtype, info.shape.clone(), info.offset));
+            }
+        }
+        Ok(GgufLinears {
+            path: self.path.clone(),
+            file: Mutex::new(File::open(&self.path)?),
+            tensor_data_offset: self.content.tensor_data_offset,
+            tensors,
+            permute_heads: self.permute_heads()?,
+        })
+    }
+
+    // 构建transformers权重名的VarBuilder, 读取的权重(norm/embedding等)反量化, Linear用linears()读取,
+    // text_prefix为解码器在模型中的前缀, 如 "model." / "model.language_model."
+    // gguf中没有的权重(如视觉部分)从目录下的safetensors读取
+    pub fn var_builder(
+        self,
+        text_prefix: &str,
+        lm_head: &str,
+        model_path: &str,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<VarBuilder<'static>> {
+        let (backend, _) = self.weight_backend(text_prefix, lm_head, model_path)?;
+        Ok(VarBuilder::from_backend(backend, dtype, device.clone()))
+    }
+
+    // 同var_builder, 额外返回transformers权重名 -> shape
+    pub fn weight_backend(
+        self,
+        text_prefix: &str,
+        lm_head: &str,
+        model_path: &str,
+    ) -> Result<(Box<dyn SimpleBackend>, WeightIndex)> {
+        let mut names = HashMap::new();
+        let mut index = WeightIndex::default();
+        for (gguf_name, info) in self.content.tensor_infos.iter() {
+            if let Some(hf_name) = gguf_name_to_hf(gguf_name, text_prefix, lm_head) {
+                index.insert(hf_name.clone(), info.shape.dims().to_vec());
+                names.insert(hf_name, gguf_name.clone());
+            }
+        }
+        let permute_heads = self.permute_heads()?;
+        let fallback_list = find_type_files(model_path, "safetensors")?;
+        let fallback = if fallback_list.is_empty() {
+            None
+        } else {
+            let fallback_index = WeightIndex::from_safetensors(&fallback_list)?;
+            for name in fallback_index.names() {
+                if !index.contains(name)
+                    && let Some(shape) = fallback_index.shape(name)
+                {
+                    index.insert(name.to_string(), shape.to_vec());
+                }
+            }
+            Some(unsafe { MmapedSafetensors::multi(&fallback_list)? })
+        };
+        let file = Mutex::new(File::open(&self.path)?);
+        let backend = GgufBackend {
+            gguf: self,
+            file,
+            names,
+            permute_heads,
+            fallback,
+        };
+        Ok((Box::new(backend), index))
+    }
+}
+
+// blk.0.attn_q.weight -> {text_prefix}layers.0.self_attn.q_proj.weight
+pub fn gguf_name_to_hf(name: &str, text_prefix: &str, lm_head: &str) -> Option<String> {
+    match name {
+        "token_embd.weight" => return Some(format!("{}embed_tokens.weight", text_prefix)),
+        "output_norm.weight" => return Some(format!("{}norm.weight", text_prefix)),
+        "output.weight" => return Some(format!("{}.weight", lm_head)),
+        _ => {}
+    }
+    let rest = name.strip_prefix("blk.")?;
+    let (layer, rest) = rest.split_once('.')?;
+    let (module, suffix) = rest.rsplit_once('.')?;
+    let (_, hf_module) = LAYER_NAME_MAP.iter().find(|(k, _)| *k == module)?;
+    Some(format!(
+        "{}layers.{}.{}.{}",
+        text_prefix, layer, hf_module, suffix
+    ))
+}
+
+// gguf中的 (n_head, head_dim/2, 2, in) 还原为 (n_head, 2, head_dim/2, in)
+fn unpermute_qk(weight: &Tensor, num_heads: usize) -> candle_core::Result<Tensor> {
+    let dims = weight.dims().to_vec();
+    let out_dim = dims[0];
+    let mut shape = vec![num_heads, out_dim / num_heads / 2, 2];
+    shape.extend_from_slice(&dims[1..]);
+    weight.reshape(shape)?.transpose(1, 2)?.reshape(dims)
+}
+
+// 按行重排量化权重, 每行的块数据整体移动, 同unpermute_qk
+fn unpermute_qk_rows(data: &[u8], out_dim: usize, num_heads: usize) -> Vec<u8> {
+    let row_bytes = data.len() / out_dim;
+    let head_dim = out_dim / num_heads;
+    let mut permuted = Vec::with_capacity(data.len());
+    for h in 0..num_heads {
+        for a in 0..2 {
+            for b in 0..head_dim / 2 {
+                let row = h * head_dim + b * 2 + a;
+                permuted.extend_from_slice(&data[row * row_bytes..(row + 1) * row_bytes]);
+            }
+        }
+    }
+    permuted
+}
+
+// gguf中Linear的ggml量化权重, 按transformers权重名读取为QTensor
+#[derive(Debug)]
+pub struct GgufLinears {
+    path: String,
+    file: Mutex<File>,
+    tensor_data_offset: u64,
+    // transformers权重名 -> (ggml类型, shape, 数据偏移)
+    tensors: HashMap<String, (GgmlDType, Shape, u64)>,
+    permute_heads: Option<(usize, usize)>,
+}
+
+impl GgufLinears {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tensors.contains_key(name)
+    }
+
+    // 不在gguf中时返回None
+    pub fn qtensor(&self, name: &str, device: &Device) -> Result<Option<QTensor>> {
+        let (ggml_dtype, shape, offset) = match self.tensors.get(name) {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        let elem_count = shape.elem_count();
+        let block_size = ggml_dtype.block_size();
+        if !elem_count.is_multiple_of(block_size) {
+            return Err(anyhow!(format!(
+                "{} has {} elements, not divisible by block size {}",
+                name, elem_count, block_size
+            )));
+        }
+        let mut data = vec![0u8; elem_count / block_size * ggml_dtype.type_size()];
+        {
+            let mut file = self
+                .file
+                .lock()
+                .map_err(|e| anyhow!(format!("lock gguf file error: {}", e)))?;
+            file.seek(SeekFrom::Start(self.tensor_data_offset + offset))?;
+            file.read_exact(&mut data)?;
+        }
+        if let Some((num_heads, num_kv_heads)) = self.permute_heads {
+            if name.ends_with("q_proj.weight") {
+                data = unpermute_qk_rows(&data, shape.dims()[0], num_heads);
+            } else if name.ends_with("k_proj.weight") {
+                data = unpermute_qk_rows(&data, shape.dims()[0], num_kv_heads);
+            }
+        }
+        Ok(Some(qtensor_from_ggml(
+            *ggml_dtype,
+            &data,
+            shape.dims().to_vec(),
+            device,
+        )?))
+    }
+}
+
+struct GgufBackend {
+    gguf: GgufFile,
+    file: Mutex<File>,
+    names: HashMap<String, String>,
+    permute_heads: Option<(usize, usize)>,
+    fallback: Option<MmapedSafetensors>,
+}
+
+impl SimpleBackend for GgufBackend {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        h: Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> candle_core::Result<Tensor> {
+        let gguf_name = match self.names.get(name) {
+            Some(n) => n,
+            None => {
+                return match &self.fallback {
+                    Some(fallback) => SimpleBackend::get(fallback, s, name, h, dtype, dev),
+                    None => candle_core::bail!("cannot find tensor {name} in gguf"),
+                };
+            }
+        };
+        let qtensor = {
+            let mut file = self
+                .file
+                .lock()
+                .map_err(|e| candle_core::Error::Msg(format!("lock gguf file error: {}", e)))?;
+            self.gguf
+                .content
+                .tensor(&mut *file, gguf_name, &Device::Cpu)?
+        };
+        let mut tensor = qtensor.dequantize(&Device::Cpu)?;
+        if let Some((num_heads, num_kv_heads)) = self.permute_heads {
+            if name.ends_with("q_proj.weight") || name.ends_with("q_proj.bias") {
+                tensor = unpermute_qk(&tensor, num_heads)?;
+            } else if name.ends_with("k_proj.weight") || name.ends_with("k_proj.bias") {
+                tensor = unpermute_qk(&tensor, num_kv_heads)?;
+            }
+        }
+        if tensor.shape() != &s {
+            Err(candle_core::Error::UnexpectedShape {
+                msg: format!("shape mismatch for {name}"),
+                expected: s,
+                got: tensor.shape().clone(),
+            }
+            .bt())?
+        }
+        tensor.to_dtype(dtype)?.to_device(dev)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.names.contains_key(name)
+            || self
+                .fallback
+                .as_ref()
+                .is_some_and(|f| SimpleBackend::contains_tensor(f, name))
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Result, anyhow};
+use candle_core::{
+    DType, Device, Shape, Tensor, pickle::read_pth_tensor_info, safetensors::MmapedSafetensors,
+};
+use candle_nn::{Init, VarBuilder, var_builder::SimpleBackend};
+
+use crate::{models::common::gguf::GgufFile, utils::find_type_files};
+
+// 报告中每类最多列出的权重数
+const REPORT_MAX_ITEMS: usize = 20;
+
+// 权重文件中 权重名 -> shape, 只读取文件头, 不加载数据
+#[derive(Debug, Clone, Default)]
+pub struct WeightIndex {
+    shapes: HashMap<String, Vec<usize>>,
+}
+
+impl WeightIndex {
+    pub fn from_safetensors(files: &[String]) -> Result<Self> {
+        let mut index = Self::default();
+        if files.is_empty() {
+            return Ok(index);
+        }
+        let st = unsafe { MmapedSafetensors::multi(files)? };
+        for (name, view) in st.tensors() {
+            index.insert(name, view.shape().to_vec());
+        }
+        Ok(index)
+    }
+
+    pub fn from_pth(files: &[String], key: Option<&str>) -> Result<Self> {
+        let mut index = Self::default();
+        for f in files {
+            for info in read_pth_tensor_info(f, false, key)? {
+                index.insert(info.name, info.layout.dims().to_vec());
+            }
+        }
+        Ok(index)
+    }
+
+    pub fn from_tensors(tensors: &HashMap<String, Tensor>) -> Self {
+        let mut index = Self::default();
+        for (name, t) in tensors {
+            index.insert(name.clone(), t.dims().to_vec());
+        }
+        index
+    }
+
+    pub fn insert(&mut self, name: String, shape: Vec<usize>) {
+        self.shapes.insert(name, shape);
+    }
+
+    pub fn extend(&mut self, other: WeightIndex) {
+        self.shapes.extend(other.shapes);
+    }
+
+    pub fn shape(&self, name: &str) -> Option<&[usize]> {
+        self.shapes.get(name).map(|s| s.as_slice())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.shapes.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shapes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shapes.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.shapes.keys().map(|s| s.as_str()).collect();
+        names.sort();
+        names
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShapeMismatch {
+    pub name: String,
+    pub expected: Vec<usize>,
+    pub found: Vec<usize>,
+}
+
+// 权重和配置的检查结果, unexpected只作提示, 不算错误
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WeightReport {
+    pub missing: Vec<(String, Vec<usize>)>,
+    pub unexpected: Vec<String>,
+    pub mismatched: Vec<ShapeMismatch>,
+    pub config_errors: Vec<String>,
+    // 权重之外的构建错误
+    pub build_error: Option<String>,
+}
+
+impl WeightReport {
+    pub fn from_config_errors(config_errors: Vec<String>) -> Self {
+        Self {
+            config_errors,
+            ..Default::default()
+        }
+    }
+
+    pub fn merge(&mut self, other: WeightReport) {
+        self.missing.extend(other.missing);
+        self.unexpected.extend(other.unexpected);
+        self.mismatched.extend(other.mismatched);
+        self.config_errors.extend(other.config_errors);
+        self.build_error = match (self.build_error.take(), other.build_error) {
+            (Some(a), Some(b)) => Some(format!("{}; {}", a, b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty()
+            && self.mismatched.is_empty()
+            && self.config_errors.is_empty()
+            && self.build_error.is_none()
+    }
+
+    pub fn ensure_ok(&self) -> Result<()> {
+        if self.is_ok() {
+            Ok(())
+        } else {
+            Err(anyhow!(format!("model check failed:\n{}", self)))
+        }
+    }
+}
+
+fn write_items<T>(
+    f: &mut fmt::Formatter<'_>,
+    title: &str,
+    items: &[T],
+    item: impl Fn(&T) -> String,
+) -> fmt::Result {
+    if items.is_empty() {
+        return Ok(());
+    }
+    writeln!(f, "{} ({}):", title, items.len())?;
+    for i in items.iter().take(REPORT_MAX_ITEMS) {
+        writeln!(f, "  {}", item(i))?;
+    }
+    if items.len() > REPORT_MAX_ITEMS {
+        writeln!(f, "  ... and {} more", items.len() - REPORT_MAX_ITEMS)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for WeightReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() && self.unexpected.is_empty() {
+            return writeln!(f, "ok");
+        }
+        write_items(f, "config errors", &self.config_errors, |e| e.clone())?;
+        write_items(f, "missing tensors", &self.missing, |(name, shape)| {
+            format!("{} expected {:?}", name, shape)
+        })?;
+        write_items(f, "shape mismatched tensors", &self.mismatched, |m| {
+            format!("{} expected {:?}, found {:?}", m.name, m.expected, m.found)
+        })?;
+        write_items(f, "unexpected tensors (ignored)", &self.unexpected, |n| {
+            n.clone()
+        })?;
+        if let Some(e) = &self.build_error {
+            writeln!(f, "build error: {}", e)?;
+        }
+        if !self.missing.is_empty() && !self.unexpected.is_empty() {
+            writeln!(
+                f,
+                "check that the model path matches the model type and the weight name prefix"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// 记录模型构建时请求的权重, 缺失或shape不一致时返回零张量让构建继续, 最后汇总成报告
+struct CheckBackend {
+    inner: Option<Box<dyn SimpleBackend>>,
+    index: Arc<WeightIndex>,
+    requested: Arc<Mutex<HashMap<String, Vec<usize>>>>,
+}
+
+impl SimpleBackend for CheckBackend {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        h: Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> candle_core::Result<Tensor> {
+        if let Ok(mut requested) = self.requested.lock() {
+            requested.insert(name.to_string(), s.dims().to_vec());
+        }
+        match (&self.inner, self.index.shape(name)) {
+            (Some(inner), Some(found)) if found == s.dims() => inner.get(s, name, h, dtype, dev),
+            // broadcast不分配整块内存
+            _ => Tensor::zeros((), dtype, dev)?.broadcast_as(s),
+        }
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.index.contains(name)
+    }
+}
+
+pub struct WeightChecker {
+    index: Arc<WeightIndex>,
+    requested: Arc<Mutex<HashMap<String, Vec<usize>>>>,
+}
+
+impl WeightChecker {
+    // inner为None时只检查不读取权重(dry run)
+    pub fn var_builder(
+        inner: Option<Box<dyn SimpleBackend>>,
+        index: WeightIndex,
+        dtype: DType,
+        device: &Device,
+    ) -> (VarBuilder<'static>, Self) {
+        let index = Arc::new(index);
+        let requested = Arc::new(Mutex::new(HashMap::new()));
+        let backend = CheckBackend {
+            inner,
+            index: index.clone(),
+            requested: requested.clone(),
+        };
+        let vb = VarBuilder::from_backend(Box::new(backend), dtype, device.clone());
+        (vb, Self { index, requested })
+    }
+
+    pub fn report(&self) -> WeightReport {
+        let mut report = WeightReport::default();
+        let requested = match self.requested.lock() {
+            Ok(r) => r.clone(),
+            Err(e) => {
+                report.build_error = Some(format!("lock requested tensors error: {}", e));
+                return report;
+            }
+        };
+        let mut names: Vec<&String> = requested.keys().collect();
+        names.sort();
+        for name in names {
+            let expected = &requested[name];
+            match self.index.shape(name) {
+                None => report.missing.push((name.clone(), expected.clone())),
+                Some(found) if found != expected.as_slice() => {
+                    report.mismatched.push(ShapeMismatch {
+                        name: name.clone(),
+                        expected: expected.clone(),
+                        found: found.to_vec(),
+                    })
+                }
+                _ => {}
+            }
+        }
+        report.unexpected = self
+            .index
+            .names()
+            .into_iter()
+            .filter(|n| !requested.contains_key(*n))
+            .map(|n| n.to_string())
+            .collect();
+        report
+    }
+
+    // 模型构建完成后调用, 权重有问题时返回完整报告而不是构建中的第一个错误
+    pub fn finish<T>(&self, built: Result<T>) -> Result<T> {
+        let mut report = self.report();
+        if !report.missing.is_empty() || !report.mismatched.is_empty() {
+            if let Err(e) = &built {
+                report.build_error = Some(e.to_string());
+            }
+            report.ensure_ok()?;
+        }
+        built
+    }
+
+    // dry run时把构建错误也放进报告
+    pub fn finish_report<T>(&self, built: Result<T>) -> WeightReport {
+        let mut report = self.report();
+        if let Err(e) = built {
+            report.build_error = Some(e.to_string());
+        }
+        report
+    }
+}
+
+// 模型目录下的权重, gguf优先, 其次safetensors
+// text_prefix/lm_head为gguf中文本解码器映射到的权重名前缀
+pub fn model_weights(
+    path: &str,
+    gguf: Option<GgufFile>,
+    text_prefix: &str,
+    lm_head: &str,
+) -> Result<(Box<dyn SimpleBackend>, WeightIndex)> {
+    match gguf {
+        Some(gguf) => gguf.weight_backend(text_prefix, lm_head, path),
+        None => {
+            let model_list = find_type_files(path, "safetensors")?;
+            if model_list.is_empty() {
+                return Err(anyhow!(format!(
+                    "no safetensors or gguf weights found in {}",
+                    path
+                )));
+            }
+            let index = WeightIndex::from_safetensors(&model_list)?;
+            let st = unsafe { MmapedSafetensors::multi(&model_list)? };
+            Ok((Box::new(st), index))
+        }
+    }
+}
+
+// 带检查的VarBuilder, dry_run时不读取权重
+pub fn checked_var_builder(
+    path: &str,
+    gguf: Option<GgufFile>,
+    text_prefix: &str,
+    lm_head: &str,
+    dtype: DType,
+    device: &Device,
+    dry_run: bool,
+) -> Result<(VarBuilder<'static>, WeightChecker)> {
+    let (backend, index) = model_weights(path, gguf, text_prefix, lm_head)?;
+    let inner = if dry_run { None } else { Some(backend) };
+    Ok(WeightChecker::var_builder(inner, index, dtype, device))
+}
+
+// 注意力头相关配置的检查
+pub fn check_attention(
+    prefix: &str,
+    hidden_size: usize,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: Option<usize>,
+) -> Vec<String> {
+    let mut errors = vec![];
+    if num_heads == 0 || num_kv_heads == 0 {
+        errors.push(format!(
+            "{}: num_attention_heads({}) and num_key_value_heads({}) must be > 0",
+            prefix, num_heads, num_kv_heads
+        ));
+        return errors;
+    }
+    if head_dim.is_none() && !hidden_size.is_multiple_of(num_heads) {
+        errors.push(format!(
+            "{}: hidden_size({}) is not divisible by num_attention_heads({})",
+            prefix, hidden_size, num_heads
+        ));
+    }
+    if !num_heads.is_multiple_of(num_kv_heads) {
+        errors.push(format!(
+            "{}: num_attention_heads({}) is not divisible by num_key_value_heads({})",
+            prefix, num_heads, num_kv_heads
+        ));
+    }
+    errors
+}
+
+pub fn check_positive(errors: &mut Vec<String>, name: &str, value: usize) {
+    if value == 0 {
+        errors.push(format!("{} must be > 0", name));
+    }
+}
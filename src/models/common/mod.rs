@@ -1,8 +1,23 @@
-use anyhow::Result;
+pub mod embedding;
+pub mod fixture;
+pub mod gguf;
+pub mod manifest;
+pub mod parity;
+pub mod quant;
+pub mod scoring;
+pub mod search;
+pub mod speculative;
+pub mod vision_cache;
+
+use anyhow::{Result, anyhow};
 use candle_core::{D, Tensor};
-use candle_nn::{Activation, Linear, Module, VarBuilder, linear, linear_no_bias};
+use candle_nn::{Activation, Linear, Module, VarBuilder, linear};
 
-use crate::{position_embed::rope::apply_rotary_pos_emb, utils::tensor_utils::repeat_kv};
+use crate::{
+    models::common::quant::{QLinear, QuantSource, qlinear_no_bias},
+    position_embed::rope::apply_rotary_pos_emb,
+    utils::tensor_utils::repeat_kv,
+};
 
 #[derive(Debug, Clone)]
 pub struct MLPWithBias {
@@ -41,9 +56,9 @@ impl Module for MLPWithBias {
 
 #[derive(Debug, Clone)]
 pub struct MLPNoBias {
-    gate_proj: Linear,
-    up_proj: Linear,
-    down_proj: Linear,
+    gate_proj: QLinear,
+    up_proj: QLinear,
+    down_proj: QLinear,
     act_fn: Activation,
 }
 
@@ -53,10 +68,11 @@ impl MLPNoBias {
         hidden_size: usize,
         intermediate_size: usize,
         act_fn: Activation,
+        quant: &QuantSource,
     ) -> Result<Self> {
-        let gate_proj = linear_no_bias(hidden_size, intermediate_size, vb.pp("gate_proj"))?;
-        let up_proj = linear_no_bias(hidden_size, intermediate_size, vb.pp("up_proj"))?;
-        let down_proj = linear_no_bias(intermediate_size, hidden_size, vb.pp("down_proj"))?;
+        let gate_proj = qlinear_no_bias(hidden_size, intermediate_size, vb.pp("gate_proj"), quant)?;
+        let up_proj = qlinear_no_bias(hidden_size, intermediate_size, vb.pp("up_proj"), quant)?;
+        let down_proj = qlinear_no_bias(intermediate_size, hidden_size, vb.pp("down_proj"), quant)?;
         Ok(Self {
             gate_proj,
             up_proj,
@@ -76,16 +92,19 @@ impl Module for MLPNoBias {
 
 #[derive(Debug, Clone)]
 pub struct AttentionNobias {
-    q_proj: Linear,
-    k_proj: Linear,
-    v_proj: Linear,
-    o_proj: Linear,
+    q_proj: QLinear,
+    k_proj: QLinear,
+    v_proj: QLinear,
+    o_proj: QLinear,
     num_heads: usize,
     num_kv_heads: usize,
     num_kv_groups: usize,
     head_dim: usize,
     hidden_size: usize,
     kv_cache: Option<(Tensor, Tensor)>,
+    // 分块prefill期间预先分配的kv空间, kv_cache为它的前缀
+    kv_reserve: usize,
+    kv_buffer: Option<(Tensor, Tensor)>,
 }
 
 impl AttentionNobias {
@@ -94,13 +113,29 @@ impl AttentionNobias {
         hidden_size: usize,
         num_attention_heads: usize,
         num_key_value_heads: usize,
+        quant: &QuantSource,
     ) -> Result<Self> {
         let num_kv_groups = num_attention_heads / num_key_value_heads;
         let head_dim = hidden_size / num_attention_heads;
-        let q_proj = linear_no_bias(hidden_size, num_attention_heads * head_dim, vb.pp("q_proj"))?;
-        let k_proj = linear_no_bias(hidden_size, num_key_value_heads * head_dim, vb.pp("k_proj"))?;
-        let v_proj = linear_no_bias(hidden_size, num_key_value_heads * head_dim, vb.pp("v_proj"))?;
-        let o_proj = linear_no_bias(hidden_size, hidden_size, vb.pp("o_proj"))?;
+        let q_proj = qlinear_no_bias(
+            hidden_size,
+            num_attention_heads * head_dim,
+            vb.pp("q_proj"),
+            quant,
+        )?;
+        let k_proj = qlinear_no_bias(
+            hidden_size,
+            num_key_value_heads * head_dim,
+            vb.pp("k_proj"),
+            quant,
+        )?;
+        let v_proj = qlinear_no_bias(
+            hidden_size,
+            num_key_value_heads * head_dim,
+            vb.pp("v_proj"),
+            quant,
+        )?;
+        let o_proj = qlinear_no_bias(hidden_size, hidden_size, vb.pp("o_proj"), quant)?;
         Ok(Self {
             q_proj,
             k_proj,
@@ -112,6 +147,8 @@ impl AttentionNobias {
             head_dim,
             hidden_size,
             kv_cache: None,
+            kv_reserve: 0,
+            kv_buffer: None,
         })
     }
 
@@ -193,32 +230,7 @@ impl AttentionNobias {
         attention_mask: Option<&Tensor>,
         tof32: bool,
     ) -> Result<Tensor> {
-        let (b_sz, q_len, _) = xs.dims3()?;
-        let query_states = self.q_proj.forward(xs)?;
-        let key_states = self.k_proj.forward(xs)?;
-        let value_states = self.v_proj.forward(xs)?;
-        let query_states = query_states
-            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
-            .transpose(1, 2)?;
-        let key_states = key_states
-            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
-            .transpose(1, 2)?;
-        let value_states = value_states
-            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
-            .transpose(1, 2)?;
-        let (query_states, key_states) =
-            apply_rotary_pos_emb(&query_states, &key_states, cos, sin, tof32)?;
-        let (key_states, value_states) = match &self.kv_cache {
-            None => (key_states, value_states),
-            Some((prev_k, prev_v)) => {
-                let key_states = Tensor::cat(&[prev_k, &key_states], 2)?;
-                let value_states = Tensor::cat(&[prev_v, &value_states], 2)?;
-                (key_states, value_states)
-            }
-        };
-
-        self.kv_cache = Some((key_states.clone(), value_states.clone()));
-
+        let (query_states, key_states, value_states) = self.qkv_with_cache(xs, cos, sin, tof32)?;
         let key_states = repeat_kv(key_states, self.num_kv_groups)?.contiguous()?;
         let value_states = repeat_kv(value_states, self.num_kv_groups)?.contiguous()?;
         let query_states = query_states.contiguous()?;
@@ -256,6 +268,95 @@ impl AttentionNobias {
                 attn_output
             }
         };
+        self.output(&attn_output)
+    }
+
+    // 计算q/k/v并更新kv cache, 返回的k/v为包含历史的全部kv且未repeat
+    // q: (bs, num_heads, q_len, head_dim), k/v: (bs, num_kv_heads, kv_len, head_dim)
+    pub fn qkv_with_cache(
+        &mut self,
+        xs: &Tensor,
+        cos: &Tensor,
+        sin: &Tensor,
+        tof32: bool,
+    ) -> Result<(Tensor, Tensor, Tensor)> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+        let query_states = self.q_proj.forward(xs)?;
+        let key_states = self.k_proj.forward(xs)?;
+        let value_states = self.v_proj.forward(xs)?;
+        let query_states = query_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let key_states = key_states
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let value_states = value_states
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let (query_states, key_states) =
+            apply_rotary_pos_emb(&query_states, &key_states, cos, sin, tof32)?;
+        let (key_states, value_states) = if self.kv_reserve > 0 {
+            self.append_reserved(&key_states, &value_states)?
+        } else {
+            match &self.kv_cache {
+                None => (key_states, value_states),
+                Some((prev_k, prev_v)) => {
+                    let key_states = Tensor::cat(&[prev_k, &key_states], 2)?;
+                    let value_states = Tensor::cat(&[prev_v, &value_states], 2)?;
+                    (key_states, value_states)
+                }
+            }
+        };
+
+        self.kv_cache = Some((key_states.clone(), value_states.clone()));
+        Ok((query_states, key_states, value_states))
+    }
+
+    // 分块prefill前预留total_len个位置, 之后每块直接写入预留的空间, 避免每块都cat整个kv cache
+    // 预留的空间是新分配的, 已有的kv cache会复制进去, 不会修改快照引用的数据
+    pub fn reserve_kv_cache(&mut self, total_len: usize) {
+        self.kv_reserve = total_len;
+        self.kv_buffer = None;
+    }
+
+    // 分块prefill结束, 之后的forward仍然用cat生成新的kv
+    pub fn release_kv_reserve(&mut self) {
+        self.kv_reserve = 0;
+        self.kv_buffer = None;
+    }
+
+    fn append_reserved(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        let past = kv_cache_len(&self.kv_cache);
+        let new_len = past + k.dim(2)?;
+        let fits = match &self.kv_buffer {
+            Some((buf_k, _)) => new_len <= buf_k.dim(2)?,
+            None => false,
+        };
+        if !fits {
+            let (b_sz, num_kv_heads, _, head_dim) = k.dims4()?;
+            let total = self.kv_reserve.max(new_len);
+            let buf_k =
+                Tensor::zeros((b_sz, num_kv_heads, total, head_dim), k.dtype(), k.device())?;
+            let buf_v =
+                Tensor::zeros((b_sz, num_kv_heads, total, head_dim), v.dtype(), v.device())?;
+            if let Some((prev_k, prev_v)) = &self.kv_cache {
+                buf_k.slice_set(&prev_k.contiguous()?, 2, 0)?;
+                buf_v.slice_set(&prev_v.contiguous()?, 2, 0)?;
+            }
+            self.kv_buffer = Some((buf_k, buf_v));
+        }
+        let (buf_k, buf_v) = self
+            .kv_buffer
+            .as_ref()
+            .ok_or_else(|| anyhow!("kv buffer is not allocated"))?;
+        buf_k.slice_set(&k.contiguous()?, 2, past)?;
+        buf_v.slice_set(&v.contiguous()?, 2, past)?;
+        Ok((buf_k.narrow(2, 0, new_len)?, buf_v.narrow(2, 0, new_len)?))
+    }
+
+    // attn_output: (bs, num_heads, q_len, head_dim) -> o_proj
+    pub fn output(&self, attn_output: &Tensor) -> Result<Tensor> {
+        let (b_sz, _, q_len, _) = attn_output.dims4()?;
         let attn_output =
             attn_output
                 .transpose(1, 2)?
@@ -265,8 +366,119 @@ impl AttentionNobias {
         Ok(attn_output)
     }
 
+    pub fn num_kv_groups(&self) -> usize {
+        self.num_kv_groups
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.head_dim
+    }
+
     pub fn clear_kv_cache(&mut self) {
-        self.kv_cache = None
+        self.kv_cache = None;
+        self.kv_buffer = None;
+    }
+
+    pub fn kv_cache_len(&self) -> usize {
+        kv_cache_len(&self.kv_cache)
+    }
+
+    // 只保留前len个位置的kv cache, 用于投机解码等场景回退
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.kv_buffer = None;
+        truncate_kv_cache(&mut self.kv_cache, len)
+    }
+
+    pub fn kv_cache(&self) -> Option<(Tensor, Tensor)> {
+        self.kv_cache.clone()
+    }
+
+    pub fn set_kv_cache(&mut self, kv_cache: Option<(Tensor, Tensor)>) {
+        self.kv_cache = kv_cache;
+        self.kv_buffer = None;
+    }
+}
+
+// kv cache: (k, v), shape: (bs, num_kv_heads, seq_len, head_dim)
+pub fn kv_cache_len(kv_cache: &Option<(Tensor, Tensor)>) -> usize {
+    match kv_cache {
+        Some((k, _)) => k.dim(2).unwrap_or(0),
+        None => 0,
+    }
+}
+
+pub fn truncate_kv_cache(kv_cache: &mut Option<(Tensor, Tensor)>, len: usize) -> Result<()> {
+    let cache_len = kv_cache_len(kv_cache);
+    if len > cache_len {
+        return Err(anyhow!(format!(
+            "truncate kv cache to {} but cache len is {}",
+            len, cache_len
+        )));
+    }
+    *kv_cache = match kv_cache {
+        Some((k, v)) if len > 0 => Some((k.narrow(2, 0, len)?, v.narrow(2, 0, len)?)),
+        _ => None,
+    };
+    Ok(())
+}
+
+// 带kv cache的decoder层, 模型按层读写kv cache
+pub trait KvCacheLayer {
+    fn kv_cache(&self) -> Option<(Tensor, Tensor)>;
+    fn set_kv_cache(&mut self, kv_cache: Option<(Tensor, Tensor)>);
+}
+
+pub fn layers_kv_cache_len<L: KvCacheLayer>(layers: &[L]) -> usize {
+    layers
+        .first()
+        .map(|layer| kv_cache_len(&layer.kv_cache()))
+        .unwrap_or(0)
+}
+
+pub fn layers_kv_caches<L: KvCacheLayer>(layers: &[L]) -> Vec<Option<(Tensor, Tensor)>> {
+    layers.iter().map(|layer| layer.kv_cache()).collect()
+}
+
+pub fn set_layers_kv_caches<L: KvCacheLayer>(
+    layers: &mut [L],
+    kv_caches: &[Option<(Tensor, Tensor)>],
+) -> Result<()> {
+    if kv_caches.len() != layers.len() {
+        return Err(anyhow!(format!(
+            "kv caches has {} layers but model has {}",
+            kv_caches.len(),
+            layers.len()
+        )));
+    }
+    for (layer, kv_cache) in layers.iter_mut().zip(kv_caches.iter()) {
+        layer.set_kv_cache(kv_cache.clone());
+    }
+    Ok(())
+}
+
+// 模型所有层kv cache的快照
+// 每次forward都用cat生成新的kv, 不会原地修改, 快照只保存Tensor的引用, 不复制数据
+#[derive(Debug, Clone, Default)]
+pub struct KvCacheSnapshot {
+    pub layers: Vec<Option<(Tensor, Tensor)>>,
+    // Qwen2.5VL/Qwen3VL中prefill时计算的mrope位置偏移
+    pub rope_deltas: Option<Tensor>,
+}
+
+impl KvCacheSnapshot {
+    pub fn seq_len(&self) -> usize {
+        self.layers.first().map(kv_cache_len).unwrap_or(0)
+    }
+
+    pub fn check_num_layers(&self, num_layers: usize) -> Result<()> {
+        if self.layers.len() != num_layers {
+            return Err(anyhow!(format!(
+                "kv cache snapshot has {} layers but model has {}",
+                self.layers.len(),
+                num_layers
+            )));
+        }
+        Ok(())
     }
 }
 
@@ -0,0 +1,301 @@
+use std::{collections::HashMap, fmt, path::Path};
+
+use anyhow::{Result, anyhow};
+use candle_core::{DType, Device, Tensor};
+
+// 参考实现导出的输入和中间结果, 用于检查forward与参考实现的数值一致性
+// golden文件为safetensors或npz, 导出脚本见assets/parity/dump_reference.py, 约定的key:
+//   输入: input_ids, pixel_values, image_grid_thw, pixel_values_videos, video_grid_thw
+//   输出: hidden_states.{i}(同transformers的output_hidden_states), logits(所有位置),
+//         image_embeds, deepstack_image_embeds.{i}, video_embeds, deepstack_video_embeds.{i}
+//   VoxCPM: 输入text_token, text_mask, audio_feat, audio_mask(不含batch维), 输出见VoxCPMModel::prefill_outputs
+// 名字为{key}.last*的输出只有最后一个位置, golden中没有时与{key}的最后一个位置对比
+#[derive(Debug, Clone, Default)]
+pub struct GoldenData {
+    tensors: HashMap<String, Tensor>,
+}
+
+impl GoldenData {
+    pub fn load(path: &str, device: &Device) -> Result<Self> {
+        let tensors = match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("safetensors") => candle_core::safetensors::load(path, device)?,
+            Some("npz") => {
+                let mut tensors = HashMap::new();
+                for (name, t) in Tensor::read_npz(path)? {
+                    tensors.insert(name, t.to_device(device)?);
+                }
+                tensors
+            }
+            _ => {
+                return Err(anyhow!(format!(
+                    "golden file must be .safetensors or .npz: {}",
+                    path
+                )));
+            }
+        };
+        Ok(Self { tensors })
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        Ok(candle_core::safetensors::save(&self.tensors, path)?)
+    }
+
+    pub fn insert(&mut self, name: &str, t: &Tensor) -> Result<()> {
+        self.tensors
+            .insert(name.to_string(), t.to_device(&Device::Cpu)?.contiguous()?);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tensor> {
+        self.tensors.get(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tensors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tensors.is_empty()
+    }
+
+    // 可选的输入, 转换为模型使用的dtype和device
+    pub fn input(&self, name: &str, dtype: DType, device: &Device) -> Result<Option<Tensor>> {
+        match self.tensors.get(name) {
+            Some(t) => Ok(Some(t.to_dtype(dtype)?.to_device(device)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn require(&self, name: &str, dtype: DType, device: &Device) -> Result<Tensor> {
+        self.input(name, dtype, device)?
+            .ok_or_else(|| anyhow!(format!("golden has no input {}", name)))
+    }
+
+    // token ids转为u32, 没有batch维时补上: (1, seq_len)
+    pub fn input_ids(&self, name: &str, device: &Device) -> Result<Tensor> {
+        let ids = self.require(name, DType::U32, device)?;
+        match ids.rank() {
+            1 => Ok(ids.unsqueeze(0)?),
+            _ => Ok(ids),
+        }
+    }
+
+    // 输出对应的参考值
+    pub fn expected(&self, name: &str) -> Result<Option<Tensor>> {
+        if let Some(t) = self.tensors.get(name) {
+            return Ok(Some(t.clone()));
+        }
+        match name.split_once(".last") {
+            Some((key, _)) => match self.tensors.get(key) {
+                Some(t) => {
+                    let seq_len = t.dim(1)?;
+                    Ok(Some(t.narrow(1, seq_len - 1, 1)?))
+                }
+                None => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // golden中{prefix}.0, {prefix}.1, ...连续的项数
+    pub fn count(&self, prefix: &str) -> usize {
+        (0..)
+            .take_while(|i| self.tensors.contains_key(&format!("{}.{}", prefix, i)))
+            .count()
+    }
+}
+
+// 误差上限为 atol + rtol * 参考值的最大绝对值, 各层hidden states的数值范围差别很大
+#[derive(Debug, Clone, Copy)]
+pub struct ParityTolerance {
+    pub atol: f32,
+    pub rtol: f32,
+}
+
+impl Default for ParityTolerance {
+    fn default() -> Self {
+        Self {
+            atol: 1e-3,
+            rtol: 1e-3,
+        }
+    }
+}
+
+impl ParityTolerance {
+    pub fn new(atol: f32, rtol: f32) -> Self {
+        Self { atol, rtol }
+    }
+
+    pub fn limit(&self, ref_max: f32) -> f32 {
+        self.atol + self.rtol * ref_max
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParityEntry {
+    pub name: String,
+    pub shape: Vec<usize>,
+    pub max_abs: f32,
+    pub mean_abs: f32,
+    // 参考值的最大绝对值
+    pub ref_max: f32,
+    pub ok: bool,
+    // shape或层数与参考值不一致
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ParityReport {
+    pub tolerance: ParityTolerance,
+    // 按forward的顺序排列
+    pub entries: Vec<ParityEntry>,
+}
+
+fn squeeze_dims(dims: &[usize]) -> Vec<usize> {
+    dims.iter().cloned().filter(|d| *d != 1).collect()
+}
+
+impl ParityReport {
+    pub fn new(tolerance: ParityTolerance) -> Self {
+        Self {
+            tolerance,
+            entries: vec![],
+        }
+    }
+
+    // 逐项对比模型输出, golden中没有的输出跳过, 参考实现可以只导出一部分
+    pub fn compare_outputs(
+        outputs: &[(String, Tensor)],
+        golden: &GoldenData,
+        tolerance: ParityTolerance,
+    ) -> Result<Self> {
+        let mut report = Self::new(tolerance);
+        let mut prefixes: Vec<&str> = vec![];
+        for (name, actual) in outputs {
+            // 带编号的项先检查层数
+            if let Some((prefix, idx)) = name.rsplit_once('.')
+                && idx.parse::<usize>().is_ok()
+                && !prefixes.contains(&prefix)
+            {
+                prefixes.push(prefix);
+                let expected = golden.count(prefix);
+                let found = outputs
+                    .iter()
+                    .filter(|(n, _)| {
+                        n.rsplit_once('.')
+                            .is_some_and(|(p, i)| p == prefix && i.parse::<usize>().is_ok())
+                    })
+                    .count();
+                if expected > 0 && expected != found {
+                    report.push_error(
+                        prefix,
+                        vec![],
+                        format!("reference has {} items, found {}", expected, found),
+                    );
+                }
+            }
+            if let Some(expected) = golden.expected(name)? {
+                report.compare(name, actual, &expected)?;
+            }
+        }
+        Ok(report)
+    }
+
+    pub fn compare(&mut self, name: &str, actual: &Tensor, expected: &Tensor) -> Result<()> {
+        if squeeze_dims(actual.dims()) != squeeze_dims(expected.dims()) {
+            self.push_error(
+                name,
+                actual.dims().to_vec(),
+                format!(
+                    "shape {:?} not match reference {:?}",
+                    actual.dims(),
+                    expected.dims()
+                ),
+            );
+            return Ok(());
+        }
+        let expected = expected.to_dtype(DType::F32)?;
+        let actual = actual
+            .to_device(expected.device())?
+            .to_dtype(DType::F32)?
+            .reshape(expected.shape())?;
+        let diff = (actual - &expected)?.abs()?;
+        let max_abs = diff.max_all()?.to_scalar::<f32>()?;
+        let mean_abs = diff.mean_all()?.to_scalar::<f32>()?;
+        let ref_max = expected.abs()?.max_all()?.to_scalar::<f32>()?;
+        // 有nan时max不一定是nan, 用mean判断
+        let ok = max_abs <= self.tolerance.limit(ref_max) && mean_abs.is_finite();
+        self.entries.push(ParityEntry {
+            name: name.to_string(),
+            shape: expected.dims().to_vec(),
+            max_abs,
+            mean_abs,
+            ref_max,
+            ok,
+            error: None,
+        });
+        Ok(())
+    }
+
+    fn push_error(&mut self, name: &str, shape: Vec<usize>, error: String) {
+        self.entries.push(ParityEntry {
+            name: name.to_string(),
+            shape,
+            max_abs: f32::INFINITY,
+            mean_abs: f32::INFINITY,
+            ref_max: 0.0,
+            ok: false,
+            error: Some(error),
+        });
+    }
+
+    pub fn is_ok(&self) -> bool {
+        !self.entries.is_empty() && self.entries.iter().all(|e| e.ok)
+    }
+
+    // 第一个超出误差的项, 即最先出现偏差的层
+    pub fn first_failure(&self) -> Option<&ParityEntry> {
+        self.entries.iter().find(|e| !e.ok)
+    }
+
+    pub fn ensure_ok(&self) -> Result<()> {
+        if self.is_ok() {
+            Ok(())
+        } else {
+            Err(anyhow!(format!("parity check failed:\n{}", self)))
+        }
+    }
+}
+
+impl fmt::Display for ParityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "tolerance: atol {:e} + rtol {:e} * ref_max",
+            self.tolerance.atol, self.tolerance.rtol
+        )?;
+        if self.entries.is_empty() {
+            return writeln!(f, "no outputs found in golden");
+        }
+        for e in &self.entries {
+            let status = if e.ok { "ok" } else { "FAIL" };
+            match &e.error {
+                Some(error) => writeln!(f, "{:<4} {:<36} {}", status, e.name, error)?,
+                None => writeln!(
+                    f,
+                    "{:<4} {:<36} {:<20} max_abs {:.3e}  mean_abs {:.3e}  ref_max {:.3e}",
+                    status,
+                    e.name,
+                    format!("{:?}", e.shape),
+                    e.max_abs,
+                    e.mean_abs,
+                    e.ref_max
+                )?,
+            }
+        }
+        if let Some(e) = self.first_failure() {
+            writeln!(f, "first failure: {}", e.name)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,495 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Result, anyhow};
+use candle_core::{
+    DType, Device, Tensor,
+    quantized::{GgmlDType, QMatMul, QTensor, ggml_file::qtensor_from_ggml},
+};
+use candle_nn::{Init, Linear, Module, VarBuilder};
+
+use crate::{
+    models::common::gguf::{GgufFile, GgufLinears},
+    utils::find_type_files,
+};
+
+// 解码层中会被量化的Linear, 视觉部分不量化
+const QUANT_TARGETS: [&str; 7] = [
+    "q_proj",
+    "k_proj",
+    "v_proj",
+    "o_proj",
+    "gate_proj",
+    "up_proj",
+    "down_proj",
+];
+
+// weight-only量化方式, 量化后的checkpoint用quantization.json记录
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "quant_type", rename_all = "lowercase")]
+pub enum QuantType {
+    // 按输出通道对称量化
+    Int8,
+    // 沿输入维度每group_size个元素一组对称量化, 两个4bit打包进一个u8
+    Int4 { group_size: usize },
+}
+
+impl QuantType {
+    pub fn supports(&self, in_dim: usize) -> bool {
+        match self {
+            QuantType::Int8 => true,
+            QuantType::Int4 { group_size } => {
+                *group_size > 0
+                    && group_size.is_multiple_of(2)
+                    && in_dim.is_multiple_of(*group_size)
+            }
+        }
+    }
+
+    // 读取模型目录下的quantization.json, 不存在返回None
+    pub fn from_model_path(path: &str) -> Result<Option<Self>> {
+        let quant_path = path.to_string() + "/quantization.json";
+        if !std::path::Path::new(&quant_path).exists() {
+            return Ok(None);
+        }
+        let quant: QuantType = serde_json::from_slice(&std::fs::read(quant_path)?)?;
+        Ok(Some(quant))
+    }
+
+    fn qweight_shape(&self, out_dim: usize, in_dim: usize) -> (usize, usize) {
+        match self {
+            QuantType::Int8 => (out_dim, in_dim),
+            QuantType::Int4 { .. } => (out_dim, in_dim / 2),
+        }
+    }
+
+    fn scales_shape(&self, out_dim: usize, in_dim: usize) -> (usize, usize) {
+        match self {
+            QuantType::Int8 => (out_dim, 1),
+            QuantType::Int4 { group_size } => (out_dim, in_dim / group_size),
+        }
+    }
+}
+
+// weight: (out_dim, in_dim), 返回 (qweight u8, scales f32)
+// int8: qweight = q + 128, q in [-127, 127]
+// int4: 每个u8低4位存偶数列, 高4位存奇数列, 值为 q + 8, q in [-8, 7]
+pub fn quantize_weight(weight: &Tensor, quant: QuantType) -> Result<(Tensor, Tensor)> {
+    let (out_dim, in_dim) = weight.dims2()?;
+    if !quant.supports(in_dim) {
+        return Err(anyhow!(format!(
+            "{:?} not supports in_dim {}",
+            quant, in_dim
+        )));
+    }
+    let w = weight.to_dtype(DType::F32)?;
+    match quant {
+        QuantType::Int8 => {
+            let scales = (w.abs()?.max_keepdim(1)? / 127.0)?.maximum(1e-10)?;
+            let q = w.broadcast_div(&scales)?.round()?.clamp(-127f32, 127f32)?;
+            let qweight = (q + 128.0)?.to_dtype(DType::U8)?;
+            Ok((qweight, scales))
+        }
+        QuantType::Int4 { group_size } => {
+            let w = w.reshape((out_dim, in_dim / group_size, group_size))?;
+            let scales = (w.abs()?.max_keepdim(2)? / 7.0)?.maximum(1e-10)?;
+            let q = (w.broadcast_div(&scales)?.round()?.clamp(-8f32, 7f32)? + 8.0)?;
+            let q = q.reshape((out_dim, in_dim / 2, 2))?;
+            let qweight = (q.narrow(2, 0, 1)? + (q.narrow(2, 1, 1)? * 16.0)?)?
+                .squeeze(2)?
+                .to_dtype(DType::U8)?;
+            Ok((qweight, scales.squeeze(2)?))
+        }
+    }
+}
+
+// 解码层Linear的权重来源, 加载模型时设置
+#[derive(Debug, Clone, Default)]
+pub enum QuantSource {
+    // 原始权重
+    #[default]
+    None,
+    // 加载时量化或读取quantize_checkpoint保存的量化权重
+    Quant(QuantType),
+    // gguf中的ggml量化权重, 不反量化
+    Gguf(Arc<GgufLinears>),
+}
+
+impl PartialEq for QuantSource {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (QuantSource::None, QuantSource::None) => true,
+            (QuantSource::Quant(a), QuantSource::Quant(b)) => a == b,
+            (QuantSource::Gguf(a), QuantSource::Gguf(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl From<Option<QuantType>> for QuantSource {
+    fn from(quant: Option<QuantType>) -> Self {
+        match quant {
+            Some(quant) => QuantSource::Quant(quant),
+            None => QuantSource::None,
+        }
+    }
+}
+
+impl QuantSource {
+    // 有gguf时Linear使用gguf中的量化权重, 否则为quant指定的weight-only量化
+    pub fn new(
+        quant: Option<QuantType>,
+        gguf: Option<&GgufFile>,
+        text_prefix: &str,
+        lm_head: &str,
+    ) -> Result<Self> {
+        match gguf {
+            Some(gguf) => Ok(QuantSource::Gguf(Arc::new(
+                gguf.linears(text_prefix, lm_head)?,
+            ))),
+            None => Ok(quant.into()),
+        }
+    }
+
+    // lm_head只在gguf中保持量化, 不做weight-only量化
+    pub fn gguf_only(&self) -> Self {
+        match self {
+            QuantSource::Gguf(linears) => QuantSource::Gguf(linears.clone()),
+            _ => QuantSource::None,
+        }
+    }
+}
+
+// qweight/scales反量化为f32权重: (out_dim, in_dim)
+pub fn dequantize_weight(
+    qweight: &Tensor,
+    scales: &Tensor,
+    quant: QuantType,
+    in_dim: usize,
+    out_dim: usize,
+) -> Result<Tensor> {
+    let q = qweight.to_dtype(DType::F32)?;
+    let scales = scales.to_dtype(DType::F32)?;
+    let weight = match quant {
+        QuantType::Int8 => (q - 128.0)?.broadcast_mul(&scales)?,
+        QuantType::Int4 { group_size } => {
+            let hi = (&q / 16.0)?.floor()?;
+            let lo = (q - (&hi * 16.0)?)?;
+            let q =
+                Tensor::stack(&[lo, hi], 2)?.reshape((out_dim, in_dim / group_size, group_size))?;
+            (q - 8.0)?
+                .broadcast_mul(&scales.unsqueeze(2)?)?
+                .reshape((out_dim, in_dim))?
+        }
+    };
+    Ok(weight)
+}
+
+// f32 -> f16的位表示, 就近舍入
+fn f16_bits(v: f32) -> u16 {
+    let x = v.to_bits();
+    let sign = ((x >> 16) & 0x8000) as u16;
+    let exp = ((x >> 23) & 0xff) as i32;
+    let man = x & 0x7f_ffff;
+    if exp == 0xff {
+        return sign | 0x7c00 | if man != 0 { 0x200 } else { 0 };
+    }
+    let e = exp - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00;
+    }
+    let (mut r, rem, half) = if e <= 0 {
+        if e < -10 {
+            return sign;
+        }
+        let shift = (14 - e) as u32;
+        let man = man | 0x80_0000;
+        (man >> shift, man & ((1 << shift) - 1), 1 << (shift - 1))
+    } else {
+        (((e as u32) << 10) | (man >> 13), man & 0x1fff, 0x1000)
+    };
+    if rem > half || (rem == half && r & 1 == 1) {
+        r += 1;
+    }
+    sign | r as u16
+}
+
+// 量化权重按ggml的块格式重新排列, 数值不变(scale转为f16):
+// int8 -> Q8_0, int4 -> Q4_0, 每块32个元素共用一个scale
+// in_dim或group_size不是32的倍数时无法表示, 返回None
+fn to_ggml_blocks(
+    qweight: &Tensor,
+    scales: &Tensor,
+    quant: QuantType,
+    in_dim: usize,
+    out_dim: usize,
+) -> Result<Option<(GgmlDType, Vec<u8>)>> {
+    const BLOCK: usize = 32;
+    if !in_dim.is_multiple_of(BLOCK) {
+        return Ok(None);
+    }
+    let qw = qweight
+        .to_device(&Device::Cpu)?
+        .flatten_all()?
+        .to_vec1::<u8>()?;
+    let sc = scales
+        .to_device(&Device::Cpu)?
+        .to_dtype(DType::F32)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    let n_blocks = in_dim / BLOCK;
+    match quant {
+        QuantType::Int8 => {
+            let mut data = Vec::with_capacity(out_dim * n_blocks * (2 + BLOCK));
+            for (r, scale) in sc.iter().enumerate() {
+                let d = f16_bits(*scale).to_le_bytes();
+                for b in 0..n_blocks {
+                    data.extend_from_slice(&d);
+                    let start = r * in_dim + b * BLOCK;
+                    // q + 128 -> i8
+                    data.extend(qw[start..start + BLOCK].iter().map(|q| q.wrapping_sub(128)));
+                }
+            }
+            Ok(Some((GgmlDType::Q8_0, data)))
+        }
+        QuantType::Int4 { group_size } => {
+            if !group_size.is_multiple_of(BLOCK) {
+                return Ok(None);
+            }
+            let n_groups = in_dim / group_size;
+            let nibble = |r: usize, c: usize| {
+                let byte = qw[r * (in_dim / 2) + c / 2];
+                if c.is_multiple_of(2) {
+                    byte & 0xf
+                } else {
+                    byte >> 4
+                }
+            };
+            let mut data = Vec::with_capacity(out_dim * n_blocks * (2 + BLOCK / 2));
+            for r in 0..out_dim {
+                for b in 0..n_blocks {
+                    let d = sc[r * n_groups + b * BLOCK / group_size];
+                    data.extend_from_slice(&f16_bits(d).to_le_bytes());
+                    // Q4_0: 低4位存块内前16个元素, 高4位存后16个元素, 值同样为 q + 8
+                    for j in 0..BLOCK / 2 {
+                        let c = b * BLOCK + j;
+                        data.push(nibble(r, c) | (nibble(r, c + BLOCK / 2) << 4));
+                    }
+                }
+            }
+            Ok(Some((GgmlDType::Q4_0, data)))
+        }
+    }
+}
+
+// 量化权重常驻内存, forward用QMatMul直接计算, 不再反量化
+#[derive(Debug, Clone)]
+pub struct QuantizedLinear {
+    weight: QMatMul,
+    bias: Option<Tensor>,
+}
+
+impl QuantizedLinear {
+    // qweight/scales为quantize_weight的格式, 无法转换为ggml块时在加载时反量化一次
+    pub fn new(
+        qweight: Tensor,
+        scales: Tensor,
+        bias: Option<Tensor>,
+        quant: QuantType,
+        in_dim: usize,
+        out_dim: usize,
+    ) -> Result<Self> {
+        let device = qweight.device().clone();
+        let weight = match to_ggml_blocks(&qweight, &scales, quant, in_dim, out_dim)? {
+            Some((dtype, data)) => QMatMul::from_qtensor(qtensor_from_ggml(
+                dtype,
+                &data,
+                vec![out_dim, in_dim],
+                &device,
+            )?)?,
+            None => QMatMul::Tensor(dequantize_weight(
+                &qweight, &scales, quant, in_dim, out_dim,
+            )?),
+        };
+        Ok(Self { weight, bias })
+    }
+
+    // gguf中的量化权重
+    pub fn from_qtensor(qtensor: QTensor, bias: Option<Tensor>) -> Result<Self> {
+        Ok(Self {
+            weight: QMatMul::from_qtensor(qtensor)?,
+            bias,
+        })
+    }
+
+    pub fn dequantize(&self, dtype: DType) -> candle_core::Result<Tensor> {
+        let weight = match &self.weight {
+            QMatMul::QTensor(q) => q.dequantize(&q.device())?,
+            QMatMul::Tensor(w) | QMatMul::TensorF16(w) => w.clone(),
+        };
+        weight.to_dtype(dtype)
+    }
+}
+
+impl Module for QuantizedLinear {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        // 量化矩阵乘的输入为f32
+        let dtype = xs.dtype();
+        let xs = xs.to_dtype(DType::F32)?.contiguous()?;
+        let ys = self.weight.forward(&xs)?.to_dtype(dtype)?;
+        match &self.bias {
+            Some(bias) => ys.broadcast_add(bias),
+            None => Ok(ys),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum QLinear {
+    Linear(Linear),
+    Quantized(QuantizedLinear),
+}
+
+impl Module for QLinear {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            QLinear::Linear(l) => l.forward(xs),
+            QLinear::Quantized(l) => l.forward(xs),
+        }
+    }
+}
+
+// quant为None时等同于candle_nn::linear, gguf中有的权重直接读取为QTensor,
+// 权重中已有qweight时直接加载量化权重, 否则加载原始权重后在加载时量化
+fn qlinear_b(
+    in_dim: usize,
+    out_dim: usize,
+    bias: bool,
+    vb: VarBuilder,
+    quant: &QuantSource,
+) -> Result<QLinear> {
+    let bias = if bias {
+        Some(vb.get(out_dim, "bias")?)
+    } else {
+        None
+    };
+    match quant {
+        QuantSource::Gguf(linears) => {
+            let name = format!("{}.weight", vb.prefix());
+            if let Some(qtensor) = linears.qtensor(&name, vb.device())? {
+                if qtensor.shape().dims() != [out_dim, in_dim] {
+                    return Err(anyhow!(format!(
+                        "shape mismatch for {}: expected {:?}, got {:?}",
+                        name,
+                        [out_dim, in_dim],
+                        qtensor.shape().dims()
+                    )));
+                }
+                return Ok(QLinear::Quantized(QuantizedLinear::from_qtensor(
+                    qtensor, bias,
+                )?));
+            }
+        }
+        QuantSource::Quant(quant) if quant.supports(in_dim) => {
+            let quant = *quant;
+            let (qweight, scales) = if vb.contains_tensor("qweight") {
+                let qweight = vb.get_with_hints_dtype(
+                    quant.qweight_shape(out_dim, in_dim),
+                    "qweight",
+                    Init::Const(0.),
+                    DType::U8,
+                )?;
+                let scales = vb.get_with_hints_dtype(
+                    quant.scales_shape(out_dim, in_dim),
+                    "scales",
+                    Init::Const(0.),
+                    DType::F32,
+                )?;
+                (qweight, scales)
+            } else {
+                let weight = vb.get((out_dim, in_dim), "weight")?;
+                quantize_weight(&weight, quant)?
+            };
+            return Ok(QLinear::Quantized(QuantizedLinear::new(
+                qweight, scales, bias, quant, in_dim, out_dim,
+            )?));
+        }
+        _ => {}
+    }
+    let weight = vb.get((out_dim, in_dim), "weight")?;
+    Ok(QLinear::Linear(Linear::new(weight, bias)))
+}
+
+pub fn qlinear(
+    in_dim: usize,
+    out_dim: usize,
+    vb: VarBuilder,
+    quant: &QuantSource,
+) -> Result<QLinear> {
+    qlinear_b(in_dim, out_dim, true, vb, quant)
+}
+
+pub fn qlinear_no_bias(
+    in_dim: usize,
+    out_dim: usize,
+    vb: VarBuilder,
+    quant: &QuantSource,
+) -> Result<QLinear> {
+    qlinear_b(in_dim, out_dim, false, vb, quant)
+}
+
+fn is_quant_target(name: &str) -> bool {
+    match name.rsplit('.').next() {
+        Some(last) => QUANT_TARGETS.contains(&last) && !name.contains("visual"),
+        None => false,
+    }
+}
+
+// 将safetensors模型的解码层Linear量化后保存到save_path,
+// 其他权重和配置/tokenizer等文件原样复制, 并写入quantization.json
+pub fn quantize_checkpoint(model_path: &str, save_path: &str, quant: QuantType) -> Result<()> {
+    std::fs::create_dir_all(save_path)?;
+    let model_list = find_type_files(model_path, "safetensors")?;
+    if model_list.is_empty() {
+        return Err(anyhow!(format!("no safetensors found in {}", model_path)));
+    }
+    for m in model_list {
+        let tensors = candle_core::safetensors::load(&m, &Device::Cpu)?;
+        let mut quantized = HashMap::new();
+        for (name, tensor) in tensors {
+            if let Some(prefix) = name.strip_suffix(".weight")
+                && is_quant_target(prefix)
+                && tensor.rank() == 2
+                && quant.supports(tensor.dim(1)?)
+            {
+                let (qweight, scales) = quantize_weight(&tensor, quant)?;
+                quantized.insert(format!("{}.qweight", prefix), qweight);
+                quantized.insert(format!("{}.scales", prefix), scales);
+            } else {
+                quantized.insert(name, tensor);
+            }
+        }
+        let file_name = std::path::Path::new(&m)
+            .file_name()
+            .ok_or(anyhow!(format!("invalid file path {}", m)))?;
+        let save_file = std::path::Path::new(save_path).join(file_name);
+        candle_core::safetensors::save(&quantized, save_file)?;
+    }
+    // 权重名已经改变, 不复制model.safetensors.index.json
+    for entry in std::fs::read_dir(model_path)? {
+        let file_path = entry?.path();
+        let file_name = file_path.file_name().unwrap_or_default().to_string_lossy();
+        if !file_path.is_file()
+            || file_name.ends_with(".safetensors")
+            || file_name.ends_with(".safetensors.index.json")
+        {
+            continue;
+        }
+        std::fs::copy(
+            &file_path,
+            std::path::Path::new(save_path).join(&*file_name),
+        )?;
+    }
+    let quant_path = std::path::Path::new(save_path).join("quantization.json");
+    std::fs::write(quant_path, serde_json::to_string_pretty(&quant)?)?;
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use anyhow::{Result, anyhow};
+use candle_core::{D, DType, Tensor};
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct TokenLogprob {
+    pub token_id: u32,
+    pub token: String,
+    // 第一个token和图片/视频的占位token没有logprob
+    pub logprob: Option<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ScoreOutput {
+    pub tokens: Vec<TokenLogprob>,
+    pub total_logprob: f32,
+    // 参与计算的token数
+    pub num_scored: usize,
+    // exp(-total_logprob / num_scored)
+    pub perplexity: f32,
+}
+
+// 每次计算logits的位置数, 不同时保存所有位置的logits: (seq_len, vocab_size)
+pub const SCORING_CHUNK_SIZE: usize = 128;
+
+// hidden_states: (seq_len, hidden_size), 为最后一层norm之后的输出, lm_head把一段hidden states转为logits
+// 第i个位置预测第i+1个token, 按块计算logsumexp和目标token的logit, 返回长度为seq_len-1
+pub fn token_logprobs(
+    hidden_states: &Tensor,
+    input_ids: &[u32],
+    lm_head: impl Fn(&Tensor) -> Result<Tensor>,
+) -> Result<Vec<f32>> {
+    let seq_len = hidden_states.dim(0)?;
+    if seq_len != input_ids.len() {
+        return Err(anyhow!(format!(
+            "hidden states len {} not equal to input_ids len {}",
+            seq_len,
+            input_ids.len()
+        )));
+    }
+    if seq_len < 2 {
+        return Err(anyhow!("scoring needs at least 2 tokens"));
+    }
+    let mut logprobs = Vec::with_capacity(seq_len - 1);
+    for start in (0..seq_len - 1).step_by(SCORING_CHUNK_SIZE) {
+        let len = SCORING_CHUNK_SIZE.min(seq_len - 1 - start);
+        let logits = lm_head(&hidden_states.narrow(0, start, len)?)?.to_dtype(DType::F32)?;
+        let max = logits.max_keepdim(D::Minus1)?;
+        let logsumexp = (logits
+            .broadcast_sub(&max)?
+            .exp()?
+            .sum_keepdim(D::Minus1)?
+            .log()?
+            + max)?;
+        let targets = Tensor::from_slice(
+            &input_ids[start + 1..start + 1 + len],
+            (len, 1),
+            logits.device(),
+        )?;
+        let chunk = (logits.gather(&targets, D::Minus1)? - logsumexp)?.squeeze(D::Minus1)?;
+        logprobs.extend(chunk.to_vec1::<f32>()?);
+    }
+    Ok(logprobs)
+}
+
+// skip_token_ids中的token不计入困惑度, decode把单个token转为文本
+pub fn score_tokens(
+    input_ids: &[u32],
+    logprobs: &[f32],
+    skip_token_ids: &[u32],
+    decode: impl Fn(u32) -> Result<String>,
+) -> Result<ScoreOutput> {
+    let mut tokens = Vec::with_capacity(input_ids.len());
+    let mut total_logprob = 0f32;
+    let mut num_scored = 0;
+    for (i, &token_id) in input_ids.iter().enumerate() {
+        let logprob = if i == 0 || skip_token_ids.contains(&token_id) {
+            None
+        } else {
+            Some(logprobs[i - 1])
+        };
+        if let Some(logprob) = logprob {
+            total_logprob += logprob;
+            num_scored += 1;
+        }
+        tokens.push(TokenLogprob {
+            token_id,
+            token: decode(token_id)?,
+            logprob,
+        });
+    }
+    if num_scored == 0 {
+        return Err(anyhow!("no token to score"));
+    }
+    Ok(ScoreOutput {
+        tokens,
+        total_logprob,
+        num_scored,
+        perplexity: (-total_logprob / num_scored as f32).exp(),
+    })
+}
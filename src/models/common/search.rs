@@ -0,0 +1,206 @@
+use anyhow::Result;
+use candle_core::{Device, Tensor};
+use candle_transformers::generation::LogitsProcessor;
+
+use crate::models::common::KvCacheSnapshot;
+
+// 束搜索和best-of-n需要的接口, 每个候选序列保存一份kv cache快照, 不改变kv cache的batch布局
+pub trait SearchLM {
+    // 输入一个token, 返回下一个位置的logits
+    fn decode(&mut self, token: u32, position_id: usize) -> Result<Vec<f32>>;
+    fn snapshot(&self) -> KvCacheSnapshot;
+    fn restore(&mut self, snapshot: &KvCacheSnapshot) -> Result<()>;
+}
+
+#[derive(Debug, Clone)]
+pub struct BeamSearchConfig {
+    pub num_beams: usize,
+    // 得分为累计logprob / 长度^length_penalty, 大于0时倾向更长的序列
+    pub length_penalty: f32,
+    // true时已完成的序列数达到num_beams就停止, false时直到剩余序列不可能更好才停止
+    pub early_stopping: bool,
+    pub max_new_tokens: usize,
+    pub num_return_sequences: usize,
+}
+
+impl Default for BeamSearchConfig {
+    fn default() -> Self {
+        Self {
+            num_beams: 4,
+            length_penalty: 1.0,
+            early_stopping: false,
+            max_new_tokens: 512,
+            num_return_sequences: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Hypothesis {
+    // 生成的token, 以eos结束时包含eos
+    pub tokens: Vec<u32>,
+    pub text: String,
+    pub logprob: f32,
+    pub score: f32,
+}
+
+impl Hypothesis {
+    fn new(tokens: Vec<u32>, logprob: f32, length_penalty: f32) -> Self {
+        let score = logprob / (tokens.len().max(1) as f32).powf(length_penalty);
+        Self {
+            tokens,
+            text: String::new(),
+            logprob,
+            score,
+        }
+    }
+}
+
+pub fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|l| (l - max).exp()).sum();
+    let log_sum = max + sum.ln();
+    logits.iter().map(|l| l - log_sum).collect()
+}
+
+fn top_k(logprobs: &[f32], k: usize) -> Vec<(u32, f32)> {
+    let mut index: Vec<usize> = (0..logprobs.len()).collect();
+    index.sort_by(|&a, &b| logprobs[b].total_cmp(&logprobs[a]));
+    index
+        .into_iter()
+        .take(k)
+        .map(|i| (i as u32, logprobs[i]))
+        .collect()
+}
+
+fn sort_by_score(hyps: &mut [Hypothesis]) {
+    hyps.sort_by(|a, b| b.score.total_cmp(&a.score));
+}
+
+struct Beam {
+    tokens: Vec<u32>,
+    logprob: f32,
+    logits: Vec<f32>,
+    kv_cache: KvCacheSnapshot,
+}
+
+// logits为prefill后最后位置的logits, position为prefill的token数, 调用前模型的kv cache为prefill后的状态
+// 返回得分最高的num_return_sequences个序列
+pub fn beam_search(
+    model: &mut dyn SearchLM,
+    logits: Vec<f32>,
+    position: usize,
+    eos_token_ids: &[u32],
+    cfg: &BeamSearchConfig,
+) -> Result<Vec<Hypothesis>> {
+    let num_beams = cfg.num_beams.max(1);
+    let mut beams = vec![Beam {
+        tokens: vec![],
+        logprob: 0.0,
+        logits,
+        kv_cache: model.snapshot(),
+    }];
+    let mut finished: Vec<Hypothesis> = vec![];
+    for step in 0..cfg.max_new_tokens {
+        let mut candidates = vec![];
+        for (b, beam) in beams.iter().enumerate() {
+            for (token, logprob) in top_k(&log_softmax(&beam.logits), 2 * num_beams) {
+                candidates.push((b, token, beam.logprob + logprob));
+            }
+        }
+        candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+        let mut next = vec![];
+        for (rank, &(b, token, logprob)) in candidates.iter().take(2 * num_beams).enumerate() {
+            let mut tokens = beams[b].tokens.clone();
+            tokens.push(token);
+            if eos_token_ids.contains(&token) {
+                // 与transformers一致, 排名在num_beams之后的eos不作为完成的序列
+                if rank < num_beams {
+                    finished.push(Hypothesis::new(tokens, logprob, cfg.length_penalty));
+                }
+            } else {
+                next.push((b, tokens, logprob));
+            }
+            if next.len() == num_beams {
+                break;
+            }
+        }
+        sort_by_score(&mut finished);
+        finished.truncate(num_beams);
+
+        let done = finished.len() >= num_beams
+            && (cfg.early_stopping
+                || next.iter().all(|(_, tokens, logprob)| {
+                    let score = logprob / (tokens.len() as f32).powf(cfg.length_penalty);
+                    score <= finished[num_beams - 1].score
+                }));
+        if done || next.is_empty() {
+            beams.clear();
+            break;
+        }
+        if step + 1 == cfg.max_new_tokens {
+            // 达到最大长度, 没有结束的序列也作为结果
+            for (_, tokens, logprob) in next {
+                finished.push(Hypothesis::new(tokens, logprob, cfg.length_penalty));
+            }
+            beams.clear();
+            break;
+        }
+        let mut new_beams = Vec::with_capacity(next.len());
+        for (b, tokens, logprob) in next {
+            model.restore(&beams[b].kv_cache)?;
+            let logits = model.decode(tokens[tokens.len() - 1], position + tokens.len() - 1)?;
+            new_beams.push(Beam {
+                tokens,
+                logprob,
+                logits,
+                kv_cache: model.snapshot(),
+            });
+        }
+        beams = new_beams;
+    }
+    // max_new_tokens为0
+    for beam in beams {
+        finished.push(Hypothesis::new(
+            beam.tokens,
+            beam.logprob,
+            cfg.length_penalty,
+        ));
+    }
+    sort_by_score(&mut finished);
+    finished.truncate(cfg.num_return_sequences.max(1));
+    Ok(finished)
+}
+
+// 从prefill后的状态独立采样n次, 按累计logprob(未经temperature/top_p处理的分布)排序
+pub fn best_of_n(
+    model: &mut dyn SearchLM,
+    logits: Vec<f32>,
+    position: usize,
+    eos_token_ids: &[u32],
+    n: usize,
+    max_new_tokens: usize,
+    logit_processor: &mut LogitsProcessor,
+) -> Result<Vec<Hypothesis>> {
+    let prefill = model.snapshot();
+    let mut hyps = Vec::with_capacity(n);
+    for _ in 0..n.max(1) {
+        model.restore(&prefill)?;
+        let mut logits = logits.clone();
+        let mut tokens = vec![];
+        let mut logprob = 0f32;
+        while tokens.len() < max_new_tokens {
+            let logits_ = Tensor::from_slice(&logits, logits.len(), &Device::Cpu)?;
+            let token = logit_processor.sample(&logits_)?;
+            logprob += log_softmax(&logits)[token as usize];
+            tokens.push(token);
+            if eos_token_ids.contains(&token) || tokens.len() >= max_new_tokens {
+                break;
+            }
+            logits = model.decode(token, position + tokens.len() - 1)?;
+        }
+        hyps.push(Hypothesis::new(tokens, logprob, 0.0));
+    }
+    sort_by_score(&mut hyps);
+    Ok(hyps)
+}
@@ -0,0 +1,211 @@
+use anyhow::{Result, anyhow};
+
+// 投机解码中草稿模型和目标模型需要实现的接口, 两个模型的词表必须一致
+pub trait SpeculativeLM {
+    // 返回最后一个位置的logits
+    fn forward_last(&mut self, input_ids: &[u32], position_id: usize) -> Result<Vec<f32>>;
+    // 返回每个输入位置的logits
+    fn forward_all(&mut self, input_ids: &[u32], position_id: usize) -> Result<Vec<Vec<f32>>>;
+    // 只保留前len个位置的kv cache
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()>;
+}
+
+// 与get_logit_processor使用相同的种子
+const SPECULATIVE_SEED: u64 = 34562;
+
+// xorshift64*, 只用于采样
+struct XorShiftRng(u64);
+
+impl XorShiftRng {
+    fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    // [0, 1)
+    fn uniform(&mut self) -> f32 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let v = self.0.wrapping_mul(0x2545F4914F6CDD1D);
+        (v >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+// 草稿模型一次提出num_draft个token, 目标模型一次前向验证, 拒绝采样保证输出分布与只用目标模型采样一致
+// temperature为None或接近0时为贪心解码
+pub struct SpeculativeSampler {
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    num_draft: usize,
+    rng: XorShiftRng,
+    drafted: usize,
+    accepted: usize,
+}
+
+impl SpeculativeSampler {
+    pub fn new(temperature: Option<f32>, top_p: Option<f32>, num_draft: usize) -> Self {
+        Self {
+            temperature: temperature.map(|t| t as f64),
+            top_p: top_p.map(|p| p as f64),
+            num_draft: num_draft.max(1),
+            rng: XorShiftRng::new(SPECULATIVE_SEED),
+            drafted: 0,
+            accepted: 0,
+        }
+    }
+
+    pub fn drafted(&self) -> usize {
+        self.drafted
+    }
+
+    pub fn accepted(&self) -> usize {
+        self.accepted
+    }
+
+    // 采样分布, 与LogitsProcessor的temperature/top_p处理一致
+    pub fn probs(&self, logits: &[f32]) -> Vec<f32> {
+        let temperature = match self.temperature {
+            Some(t) if t >= 1e-7 => t,
+            _ => {
+                let mut probs = vec![0f32; logits.len()];
+                let argmax = logits
+                    .iter()
+                    .enumerate()
+                    .max_by(|a, b| a.1.total_cmp(b.1))
+                    .map(|(i, _)| i)
+                    .unwrap_or(0);
+                if !probs.is_empty() {
+                    probs[argmax] = 1.0;
+                }
+                return probs;
+            }
+        };
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let mut probs: Vec<f32> = logits
+            .iter()
+            .map(|l| ((l - max) as f64 / temperature).exp() as f32)
+            .collect();
+        let sum: f32 = probs.iter().sum();
+        probs.iter_mut().for_each(|p| *p /= sum);
+        if let Some(top_p) = self.top_p.filter(|p| *p > 0.0 && *p < 1.0) {
+            let mut index: Vec<usize> = (0..probs.len()).collect();
+            index.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+            let mut cumsum = 0f32;
+            for i in index {
+                if cumsum as f64 >= top_p {
+                    probs[i] = 0.0;
+                } else {
+                    cumsum += probs[i];
+                }
+            }
+            probs.iter_mut().for_each(|p| *p /= cumsum);
+        }
+        probs
+    }
+
+    fn sample(&mut self, probs: &[f32]) -> u32 {
+        let sum: f32 = probs.iter().sum();
+        let r = self.rng.uniform() * sum;
+        let mut cumsum = 0f32;
+        let mut last = 0;
+        for (i, &p) in probs.iter().enumerate() {
+            if p <= 0.0 {
+                continue;
+            }
+            cumsum += p;
+            last = i;
+            if r < cumsum {
+                return i as u32;
+            }
+        }
+        last as u32
+    }
+
+    // 两个模型都输入prompt, 用目标模型采样第一个token
+    pub fn prefill(
+        &mut self,
+        target: &mut dyn SpeculativeLM,
+        draft: &mut dyn SpeculativeLM,
+        prompt: &[u32],
+    ) -> Result<u32> {
+        if prompt.is_empty() {
+            return Err(anyhow!("speculative decoding prompt is empty"));
+        }
+        draft.forward_last(prompt, 0)?;
+        let logits = target.forward_last(prompt, 0)?;
+        let probs = self.probs(&logits);
+        Ok(self.sample(&probs))
+    }
+
+    // position为两个模型kv cache中已有的token数, last_token为已生成但还没输入模型的token
+    // 返回本轮新生成的token(至少一个), 返回后两个模型的kv cache长度都为position + 返回的token数
+    pub fn step(
+        &mut self,
+        target: &mut dyn SpeculativeLM,
+        draft: &mut dyn SpeculativeLM,
+        last_token: u32,
+        position: usize,
+    ) -> Result<Vec<u32>> {
+        let mut draft_tokens = Vec::with_capacity(self.num_draft);
+        let mut draft_probs = Vec::with_capacity(self.num_draft);
+        let mut token = last_token;
+        for i in 0..self.num_draft {
+            let logits = draft.forward_last(&[token], position + i)?;
+            let probs = self.probs(&logits);
+            token = self.sample(&probs);
+            draft_tokens.push(token);
+            draft_probs.push(probs);
+        }
+        self.drafted += self.num_draft;
+
+        let mut input_ids = Vec::with_capacity(self.num_draft + 1);
+        input_ids.push(last_token);
+        input_ids.extend_from_slice(&draft_tokens);
+        let target_logits = target.forward_all(&input_ids, position)?;
+        if target_logits.len() != input_ids.len() {
+            return Err(anyhow!(format!(
+                "target logits len {} != input len {}",
+                target_logits.len(),
+                input_ids.len()
+            )));
+        }
+
+        let mut output = Vec::with_capacity(self.num_draft + 1);
+        for (i, (&token, q)) in draft_tokens.iter().zip(draft_probs.iter()).enumerate() {
+            let p = self.probs(&target_logits[i]);
+            let t = token as usize;
+            // 以min(1, p/q)的概率接受草稿token
+            if self.rng.uniform() * q[t] < p[t] {
+                output.push(token);
+                self.accepted += 1;
+                continue;
+            }
+            // 拒绝后从max(0, p - q)归一化的分布中重新采样
+            let residual: Vec<f32> = p
+                .iter()
+                .zip(q.iter())
+                .map(|(p, q)| (p - q).max(0.0))
+                .collect();
+            let next = if residual.iter().sum::<f32>() > 0.0 {
+                self.sample(&residual)
+            } else {
+                self.sample(&p)
+            };
+            output.push(next);
+            // 回退被拒绝的草稿token
+            let len = position + 1 + i;
+            target.truncate_kv_cache(len)?;
+            draft.truncate_kv_cache(len)?;
+            return Ok(output);
+        }
+        // 全部接受时从目标模型最后一个位置额外采样一个token
+        let p = self.probs(&target_logits[self.num_draft]);
+        output.push(self.sample(&p));
+        // 草稿模型还没有输入最后一个草稿token
+        draft.forward_last(
+            &draft_tokens[self.num_draft - 1..],
+            position + self.num_draft,
+        )?;
+        Ok(output)
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Result, anyhow};
+use candle_core::{Device, Tensor};
+
+use crate::utils::vision_utils::MediaRequest;
+
+// 单张图片/单个视频的视觉特征: (embeds, 每个deepstack层的embeds), 没有deepstack的模型为空
+pub type VisionFeature = (Tensor, Vec<Tensor>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VisionCacheConfig {
+    pub max_entries: usize,
+    // 缓存的特征在模型所在设备上, 按tensor的字节数计算
+    pub max_bytes: usize,
+}
+
+impl Default for VisionCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 256,
+            max_bytes: 1 << 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VisionCacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+// 在预处理之前确定: namespace区分不同的模型权重, source为图片/视频内容的sha256, params为预处理设置
+// 缓存以完整的key查找, 只有来源和设置都相同才命中
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VisionCacheKey {
+    pub namespace: String,
+    pub source: String,
+    pub video: bool,
+    pub params: String,
+}
+
+pub fn vision_cache_key(namespace: &str, request: &MediaRequest) -> Result<VisionCacheKey> {
+    Ok(VisionCacheKey {
+        namespace: namespace.to_string(),
+        source: request.source.identity()?,
+        video: request.video,
+        params: serde_json::to_string(&request.overrides)?,
+    })
+}
+
+// 命中时不再预处理, 展开占位token需要的grid_thw和视频时间戳与特征一起保存
+#[derive(Debug, Clone)]
+pub struct CachedVision {
+    pub feature: VisionFeature,
+    pub grid_thw: Vec<u32>,
+    // 视频每个时间块的时间戳(秒), 图片为空
+    pub timestamps: Vec<f32>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: CachedVision,
+    bytes: usize,
+    last_used: u64,
+}
+
+// 按最近使用淘汰, 超过max_entries或max_bytes时淘汰最久未使用的
+#[derive(Debug, Default)]
+pub struct VisionEmbeddingCache {
+    config: VisionCacheConfig,
+    entries: HashMap<VisionCacheKey, Entry>,
+    tick: u64,
+    stats: VisionCacheStats,
+}
+
+// 多个模型实例或多个请求共用一个缓存
+pub type SharedVisionCache = Arc<Mutex<VisionEmbeddingCache>>;
+
+fn feature_bytes(feature: &VisionFeature) -> usize {
+    std::iter::once(&feature.0)
+        .chain(feature.1.iter())
+        .map(|t| t.elem_count() * t.dtype().size_in_bytes())
+        .sum()
+}
+
+impl VisionEmbeddingCache {
+    pub fn new(config: VisionCacheConfig) -> Self {
+        Self {
+            config,
+            entries: HashMap::new(),
+            tick: 0,
+            stats: VisionCacheStats::default(),
+        }
+    }
+
+    pub fn shared(config: VisionCacheConfig) -> SharedVisionCache {
+        Arc::new(Mutex::new(Self::new(config)))
+    }
+
+    pub fn get(&mut self, key: &VisionCacheKey) -> Option<CachedVision> {
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.last_used = self.tick;
+                self.stats.hits += 1;
+                Some(entry.value.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    // 单项超过max_bytes时不缓存
+    pub fn insert(&mut self, key: VisionCacheKey, value: CachedVision) {
+        let bytes = feature_bytes(&value.feature);
+        if bytes > self.config.max_bytes || self.config.max_entries == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some(old) = self.entries.remove(&key) {
+            self.stats.bytes -= old.bytes;
+        }
+        while !self.entries.is_empty()
+            && (self.entries.len() >= self.config.max_entries
+                || self.stats.bytes + bytes > self.config.max_bytes)
+        {
+            self.evict_lru();
+        }
+        self.entries.insert(key, Entry {
+            value,
+            bytes,
+            last_used: self.tick,
+        });
+        self.stats.bytes += bytes;
+    }
+
+    fn evict_lru(&mut self) {
+        let lru = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+        if let Some(entry) = lru.and_then(|key| self.entries.remove(&key)) {
+            self.stats.bytes -= entry.bytes;
+            self.stats.evictions += 1;
+        }
+    }
+
+    pub fn contains(&self, key: &VisionCacheKey) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn stats(&self) -> VisionCacheStats {
+        VisionCacheStats {
+            entries: self.entries.len(),
+            ..self.stats
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.stats.bytes = 0;
+    }
+}
+
+// 先按key查缓存, 未命中的项(同一请求中重复的只算一次)交给compute预处理并计算特征, 之后写入缓存
+// compute的输入为未命中项的下标, 返回与之一一对应的结果; 返回按keys顺序的结果和计算的项数
+pub fn cached_vision(
+    cache: Option<&SharedVisionCache>,
+    keys: &[VisionCacheKey],
+    compute: impl FnOnce(&[usize]) -> Result<Vec<CachedVision>>,
+) -> Result<(Vec<CachedVision>, usize)> {
+    let mut values: Vec<Option<CachedVision>> = match cache {
+        Some(cache) => {
+            let mut cache = cache
+                .lock()
+                .map_err(|e| anyhow!(format!("vision cache lock error: {}", e)))?;
+            keys.iter().map(|key| cache.get(key)).collect()
+        }
+        None => vec![None; keys.len()],
+    };
+    let mut missing: Vec<usize> = vec![];
+    for (i, value) in values.iter().enumerate() {
+        if value.is_none() && !missing.iter().any(|&m| keys[m] == keys[i]) {
+            missing.push(i);
+        }
+    }
+    if !missing.is_empty() {
+        let computed = compute(&missing)?;
+        if computed.len() != missing.len() {
+            return Err(anyhow!(format!(
+                "computed {} vision features for {} inputs",
+                computed.len(),
+                missing.len()
+            )));
+        }
+        for (&m, value) in missing.iter().zip(computed.iter()) {
+            for (i, key) in keys.iter().enumerate() {
+                if *key == keys[m] && values[i].is_none() {
+                    values[i] = Some(value.clone());
+                }
+            }
+        }
+        if let Some(cache) = cache {
+            let mut cache = cache
+                .lock()
+                .map_err(|e| anyhow!(format!("vision cache lock error: {}", e)))?;
+            for (&m, value) in missing.iter().zip(computed) {
+                cache.insert(keys[m].clone(), value);
+            }
+        }
+    }
+    let values = values
+        .into_iter()
+        .map(|v| v.ok_or_else(|| anyhow!("vision feature missing")))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((values, missing.len()))
+}
+
+// 按顺序拼接多张图片/多个视频的特征, 没有输入时为None
+pub fn concat_features(features: &[&VisionFeature]) -> Result<Option<VisionFeature>> {
+    if features.is_empty() {
+        return Ok(None);
+    }
+    let embeds: Vec<&Tensor> = features.iter().map(|f| &f.0).collect();
+    let num_deepstack = features[0].1.len();
+    let mut deepstack_embeds = Vec::with_capacity(num_deepstack);
+    for layer in 0..num_deepstack {
+        let layer_embeds: Vec<&Tensor> = features.iter().map(|f| &f.1[layer]).collect();
+        deepstack_embeds.push(Tensor::cat(&layer_embeds, 0)?);
+    }
+    Ok(Some((Tensor::cat(&embeds, 0)?, deepstack_embeds)))
+}
+
+// 多张图片/多个视频的grid_thw: (n, 3), 没有输入时为None
+pub fn grid_thw_tensor(items: &[&CachedVision], device: &Device) -> Result<Option<Tensor>> {
+    if items.is_empty() {
+        return Ok(None);
+    }
+    let grid: Vec<u32> = items.iter().flat_map(|v| v.grid_thw.clone()).collect();
+    Ok(Some(Tensor::from_vec(grid, (items.len(), 3), device)?))
+}
@@ -1,5 +1,13 @@
+use anyhow::{Result, anyhow};
+use candle_core::Device;
 use candle_nn::Activation;
 
+use crate::models::common::{
+    gguf::GgufFile,
+    manifest::{check_attention, check_positive},
+    quant::QuantSource,
+};
+
 #[derive(Debug, Clone, PartialEq, serde::Deserialize)]
 pub struct RopeScalingConfig {
     pub rope_type: String,
@@ -8,6 +16,122 @@ pub struct RopeScalingConfig {
     pub original_max_position_embeddings: usize,
 }
 
+fn default_rope_theta() -> f32 {
+    10000.0
+}
+
+// InfLLM v2稀疏注意力配置, 字段与MiniCPM4-8B的config.json中sparse_config一致
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct SparseConfig {
+    // 压缩key时的池化窗口和步长
+    #[serde(default = "default_kernel_size")]
+    pub kernel_size: usize,
+    #[serde(default = "default_kernel_stride")]
+    pub kernel_stride: usize,
+    // 始终参与注意力的开头block数
+    #[serde(default = "default_init_blocks")]
+    pub init_blocks: usize,
+    #[serde(default = "default_block_size")]
+    pub block_size: usize,
+    // 始终参与注意力的局部窗口长度
+    #[serde(default = "default_window_size")]
+    pub window_size: usize,
+    // 每组query选择的block数(包括开头和局部窗口)
+    #[serde(default = "default_topk")]
+    pub topk: usize,
+    // 压缩key不加位置编码的变体, 目前没有实现, 为true时配置检查报错
+    #[serde(default)]
+    pub use_nope: bool,
+    // 总长度超过dense_len时使用稀疏注意力
+    #[serde(default = "default_dense_len")]
+    pub dense_len: usize,
+    // 稀疏注意力时按块prefill, 不在config.json中
+    #[serde(default = "default_prefill_chunk_size")]
+    pub prefill_chunk_size: usize,
+}
+
+fn default_kernel_size() -> usize {
+    32
+}
+
+fn default_kernel_stride() -> usize {
+    16
+}
+
+fn default_init_blocks() -> usize {
+    1
+}
+
+fn default_block_size() -> usize {
+    64
+}
+
+fn default_window_size() -> usize {
+    2048
+}
+
+fn default_topk() -> usize {
+    64
+}
+
+fn default_dense_len() -> usize {
+    8192
+}
+
+fn default_prefill_chunk_size() -> usize {
+    2048
+}
+
+impl Default for SparseConfig {
+    fn default() -> Self {
+        Self {
+            kernel_size: default_kernel_size(),
+            kernel_stride: default_kernel_stride(),
+            init_blocks: default_init_blocks(),
+            block_size: default_block_size(),
+            window_size: default_window_size(),
+            topk: default_topk(),
+            use_nope: false,
+            dense_len: default_dense_len(),
+            prefill_chunk_size: default_prefill_chunk_size(),
+        }
+    }
+}
+
+impl SparseConfig {
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = vec![];
+        check_positive(
+            &mut errors,
+            "sparse_config.kernel_stride",
+            self.kernel_stride,
+        );
+        check_positive(&mut errors, "sparse_config.block_size", self.block_size);
+        check_positive(&mut errors, "sparse_config.topk", self.topk);
+        check_positive(
+            &mut errors,
+            "sparse_config.prefill_chunk_size",
+            self.prefill_chunk_size,
+        );
+        if !errors.is_empty() {
+            return errors;
+        }
+        if self.kernel_size < self.kernel_stride
+            || !self.kernel_size.is_multiple_of(self.kernel_stride)
+            || !self.block_size.is_multiple_of(self.kernel_stride)
+        {
+            errors.push(format!(
+                "sparse_config: kernel_size({}) and block_size({}) should be multiples of kernel_stride({})",
+                self.kernel_size, self.block_size, self.kernel_stride
+            ));
+        }
+        if self.use_nope {
+            errors.push("sparse_config.use_nope is not supported".to_string());
+        }
+        errors
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, serde::Deserialize)]
 pub struct MiniCPM4Config {
     pub bos_token_id: u32,
@@ -20,10 +144,134 @@ pub struct MiniCPM4Config {
     pub num_hidden_layers: usize,
     pub num_key_value_heads: usize,
     pub rms_norm_eps: f64,
+    // 旧版本config.json中没有这一项
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f32,
     pub rope_scaling: RopeScalingConfig,
     pub torch_dtype: String,
     pub vocab_size: usize,
     pub scale_emb: f64,
     pub dim_model_base: usize,
     pub scale_depth: f32,
+    // 没有时只使用稠密注意力
+    #[serde(default)]
+    pub sparse_config: Option<SparseConfig>,
+    // 不从config.json读取, 加载模型时设置
+    #[serde(skip)]
+    pub quant: QuantSource,
+}
+
+impl MiniCPM4Config {
+    // 目录下没有config.json时从gguf元数据构建配置
+    pub fn from_gguf(gguf: &GgufFile) -> Result<Self> {
+        let get = |key: &str| {
+            gguf.arch_usize(key)
+                .ok_or(anyhow!(format!("{} not found in gguf", key)))
+        };
+        let hidden_size = get("embedding_length")?;
+        let num_attention_heads = get("attention.head_count")?;
+        let num_hidden_layers = get("block_count")?;
+        let max_position_embeddings = get("context_length")?;
+        let head_dim = hidden_size / num_attention_heads;
+        // llama.cpp把long_factor/short_factor保存为张量
+        let rope_factor = |name: &str| -> Result<Vec<f32>> {
+            if gguf.contains_tensor(name) {
+                Ok(gguf
+                    .tensor(name, &Device::Cpu)?
+                    .flatten_all()?
+                    .to_vec1::<f32>()?)
+            } else {
+                Ok(vec![1.0; head_dim / 2])
+            }
+        };
+        let tokens = gguf
+            .get_str_array("tokenizer.ggml.tokens")
+            .unwrap_or_default();
+        let vocab_size = match gguf.tensor_shape("token_embd.weight") {
+            Some(shape) => shape.dims()[0],
+            None => tokens.len(),
+        };
+        let eos_token_id = gguf.get_usize("tokenizer.ggml.eos_token_id").unwrap_or(2);
+        let im_end_id = tokens
+            .iter()
+            .position(|t| *t == "<|im_end|>")
+            .unwrap_or(eos_token_id);
+        // logit_scale = hidden_size / dim_model_base, residual_scale = scale_depth / sqrt(num_layers)
+        let logit_scale = gguf.arch_f64("logit_scale").unwrap_or(1.0);
+        let residual_scale = gguf.arch_f64("residual_scale").unwrap_or(1.0);
+        let num_key_value_heads = gguf
+            .arch_usize("attention.head_count_kv")
+            .unwrap_or(num_attention_heads);
+        let rms_norm_eps = gguf
+            .arch_f64("attention.layer_norm_rms_epsilon")
+            .unwrap_or(1e-5);
+        let config = serde_json::json!({
+            "bos_token_id": gguf.get_usize("tokenizer.ggml.bos_token_id").unwrap_or(1),
+            "eos_token_id": [eos_token_id, im_end_id],
+            "hidden_act": "silu",
+            "hidden_size": hidden_size,
+            "intermediate_size": get("feed_forward_length")?,
+            "max_position_embeddings": max_position_embeddings,
+            "num_attention_heads": num_attention_heads,
+            "num_hidden_layers": num_hidden_layers,
+            "num_key_value_heads": num_key_value_heads,
+            "rms_norm_eps": rms_norm_eps,
+            "rope_theta": gguf.arch_f64("rope.freq_base").unwrap_or(10000.0),
+            "rope_scaling": {
+                "rope_type": "longrope",
+                "long_factor": rope_factor("rope_factors_long.weight")?,
+                "short_factor": rope_factor("rope_factors_short.weight")?,
+                "original_max_position_embeddings": gguf
+                    .arch_usize("rope.scaling.original_context_length")
+                    .unwrap_or(max_position_embeddings),
+            },
+            "torch_dtype": "bfloat16",
+            "vocab_size": vocab_size,
+            "scale_emb": gguf.arch_f64("embedding_scale").unwrap_or(1.0),
+            "dim_model_base": (hidden_size as f64 / logit_scale).round() as usize,
+            "scale_depth": residual_scale * (num_hidden_layers as f64).sqrt(),
+        });
+        Ok(serde_json::from_value(config)?)
+    }
+
+    // 配置字段的合理性检查, 返回所有问题
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = check_attention(
+            "minicpm4",
+            self.hidden_size,
+            self.num_attention_heads,
+            self.num_key_value_heads,
+            None,
+        );
+        check_positive(&mut errors, "num_hidden_layers", self.num_hidden_layers);
+        check_positive(&mut errors, "vocab_size", self.vocab_size);
+        check_positive(&mut errors, "dim_model_base", self.dim_model_base);
+        if self.eos_token_id.len() < 2 {
+            errors.push(format!(
+                "eos_token_id should contain <|endoftext|> and <|im_end|>, got {:?}",
+                self.eos_token_id
+            ));
+        }
+        if self.num_attention_heads > 0 {
+            let half_dim = self.hidden_size / self.num_attention_heads / 2;
+            let rope = &self.rope_scaling;
+            for (name, factor) in [
+                ("long_factor", &rope.long_factor),
+                ("short_factor", &rope.short_factor),
+            ] {
+                if factor.len() != half_dim {
+                    errors.push(format!(
+                        "rope_scaling.{} length {} != head_dim / 2 ({})",
+                        name,
+                        factor.len(),
+                        half_dim
+                    ));
+                }
+            }
+        }
+        if let Some(sparse) = &self.sparse_config {
+            errors.extend(sparse.check());
+        }
+        errors
+    }
 }
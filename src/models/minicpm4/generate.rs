@@ -1,20 +1,31 @@
 use aha_openai_dive::v1::resources::chat::{
     ChatCompletionChunkResponse, ChatCompletionParameters, ChatCompletionResponse,
 };
-use anyhow::{Result, anyhow};
+use anyhow::Result;
 use candle_core::{DType, Device, Tensor};
-use candle_nn::VarBuilder;
 use rocket::async_stream::stream;
 use rocket::futures::Stream;
 
-use crate::models::minicpm4::config::MiniCPM4Config;
+use crate::models::common::{
+    embedding::{Pooling, pad_batch, pool_batch, pool_hidden_states},
+    gguf::GgufFile,
+    manifest::{WeightReport, checked_var_builder},
+    parity::GoldenData,
+    quant::{QuantSource, QuantType},
+    scoring::{ScoreOutput, score_tokens, token_logprobs},
+};
+use crate::models::minicpm4::config::{MiniCPM4Config, SparseConfig};
 use crate::models::minicpm4::model::MiniCPMModel;
 // use crate::models::GenerateStream;
 use crate::utils::{
-    build_completion_chunk_response, build_completion_response, find_type_files, get_device,
+    build_completion_chunk_response, build_completion_response, completion_usage, get_device,
     get_dtype, get_logit_processor,
 };
-use crate::{chat_template::ChatTemplate, models::GenerateModel, tokenizer::TokenizerModel};
+use crate::{
+    chat_template::ChatTemplate,
+    models::{EmbeddingModel, GenerateModel, ParityModel, ScoringModel},
+    tokenizer::{StreamDecoder, TokenizerModel},
+};
 
 pub struct MiniCPMGenerateModel<'a> {
     chat_template: ChatTemplate<'a>,
@@ -27,18 +38,71 @@ pub struct MiniCPMGenerateModel<'a> {
 
 impl<'a> MiniCPMGenerateModel<'a> {
     pub fn init(path: &str, device: Option<&Device>, dtype: Option<DType>) -> Result<Self> {
-        let chat_template = ChatTemplate::init(path)?;
-        let tokenizer = TokenizerModel::init(path)?;
+        Self::init_with_quant(path, device, dtype, None)
+    }
+
+    fn load_config(path: &str, gguf: Option<&GgufFile>) -> Result<MiniCPM4Config> {
         let config_path = path.to_string() + "/config.json";
-        let cfg: MiniCPM4Config = serde_json::from_slice(&std::fs::read(config_path)?)?;
-        let device = &get_device(device);
+        let cfg = match gguf {
+            Some(gguf) if !std::path::Path::new(&config_path).exists() => {
+                MiniCPM4Config::from_gguf(gguf)?
+            }
+            _ => serde_json::from_slice(&std::fs::read(config_path)?)?,
+        };
+        Ok(cfg)
+    }
+
+    // 不读取权重, 检查配置和目录下的权重名/shape是否与模型一致
+    pub fn check_model(path: &str) -> Result<WeightReport> {
+        let gguf = GgufFile::find(path)?;
+        let mut cfg = Self::load_config(path, gguf.as_ref())?;
+        let config_errors = cfg.check();
+        if !config_errors.is_empty() {
+            return Ok(WeightReport::from_config_errors(config_errors));
+        }
+        cfg.quant = QuantType::from_model_path(path)?.into();
+        let dtype = get_dtype(None, cfg.torch_dtype.as_str());
+        let (vb, checker) =
+            checked_var_builder(path, gguf, "model.", "lm_head", dtype, &Device::Cpu, true)?;
+        Ok(checker.finish_report(MiniCPMModel::new(vb, cfg)))
+    }
+
+    // 只加载模型权重, 投机解码的草稿模型也用这个加载
+    pub fn load_model(
+        path: &str,
+        device: &Device,
+        dtype: Option<DType>,
+        quant: Option<QuantType>,
+    ) -> Result<MiniCPMModel> {
+        let gguf = GgufFile::find(path)?;
+        let mut cfg = Self::load_config(path, gguf.as_ref())?;
+        WeightReport::from_config_errors(cfg.check()).ensure_ok()?;
+        cfg.quant = QuantSource::new(
+            QuantType::from_model_path(path)?.or(quant),
+            gguf.as_ref(),
+            "model.",
+            "lm_head",
+        )?;
         let cfg_dtype = cfg.torch_dtype.as_str();
         let dtype = get_dtype(dtype, cfg_dtype);
-        let endoftext_id = cfg.eos_token_id[0];
-        let im_end_id = cfg.eos_token_id[1];
-        let model_list = find_type_files(path, "safetensors")?;
-        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&model_list, dtype, device)? };
-        let minicpm = MiniCPMModel::new(vb, cfg)?;
+        let (vb, checker) =
+            checked_var_builder(path, gguf, "model.", "lm_head", dtype, device, false)?;
+        checker.finish(MiniCPMModel::new(vb, cfg))
+    }
+
+    // quant为解码层Linear的weight-only量化方式, 目录下有quantization.json时以其为准
+    pub fn init_with_quant(
+        path: &str,
+        device: Option<&Device>,
+        dtype: Option<DType>,
+        quant: Option<QuantType>,
+    ) -> Result<Self> {
+        let chat_template = ChatTemplate::init(path)?;
+        let tokenizer = TokenizerModel::init(path)?;
+        let device = &get_device(device);
+        let minicpm = Self::load_model(path, device, dtype, quant)?;
+        let endoftext_id = minicpm.config().eos_token_id[0];
+        let im_end_id = minicpm.config().eos_token_id[1];
 
         Ok(MiniCPMGenerateModel {
             chat_template,
@@ -49,6 +113,24 @@ impl<'a> MiniCPMGenerateModel<'a> {
             im_end_id,
         })
     }
+
+    // 覆盖config.json中的sparse_config, None时关闭稀疏注意力
+    pub fn set_sparse_config(&mut self, sparse_config: Option<SparseConfig>) -> Result<()> {
+        if let Some(sparse) = &sparse_config {
+            WeightReport::from_config_errors(sparse.check()).ensure_ok()?;
+        }
+        self.minicpm.set_sparse_config(sparse_config);
+        Ok(())
+    }
+
+    fn score_ids(&mut self, input_ids: &Tensor) -> Result<ScoreOutput> {
+        let hidden_states = self.minicpm.forward_hidden(input_ids, 0)?.squeeze(0)?;
+        let ids = input_ids.squeeze(0)?.to_vec1::<u32>()?;
+        let logprobs = token_logprobs(&hidden_states, &ids, |h| self.minicpm.hidden_logits(h))?;
+        score_tokens(&ids, &logprobs, &[], |id| {
+            self.tokenizer.token_decode(vec![id])
+        })
+    }
 }
 
 impl<'a> GenerateModel for MiniCPMGenerateModel<'a> {
@@ -57,6 +139,7 @@ impl<'a> GenerateModel for MiniCPMGenerateModel<'a> {
         let mes_render = self.chat_template.apply_chat_template(&mes)?;
         let mut input_ids = self.tokenizer.text_encode(mes_render, &self.device)?;
         let mut seq_len = input_ids.dim(1)?;
+        let prompt_tokens = seq_len;
         let mut seqlen_offset = 0;
         let mut generate = Vec::new();
         let sample_len = mes.max_tokens.unwrap_or(2048);
@@ -72,9 +155,11 @@ impl<'a> GenerateModel for MiniCPMGenerateModel<'a> {
             seq_len = 1;
             input_ids = Tensor::from_vec(vec![next_token], (1, 1), &self.device)?;
         }
+        let completion_tokens = generate.len();
         let res = self.tokenizer.token_decode(generate)?;
         self.minicpm.clear_kv_cache();
-        let response = build_completion_response(res, "minicpm");
+        let mut response = build_completion_response(res, "minicpm");
+        response.usage = Some(completion_usage(prompt_tokens, completion_tokens)?);
         Ok(response)
     }
     fn generate_stream(
@@ -88,7 +173,7 @@ impl<'a> GenerateModel for MiniCPMGenerateModel<'a> {
         let mut seqlen_offset = 0;
         let sample_len = mes.max_tokens.unwrap_or(512);
         let stream = stream! {
-            let mut error_tokens = Vec::new();
+            let mut decoder = StreamDecoder::default();
             for _ in 0..sample_len {
                 let logits = self.minicpm.forward_with_cache(
                     &input_ids,
@@ -96,35 +181,86 @@ impl<'a> GenerateModel for MiniCPMGenerateModel<'a> {
                 )?;
                 let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
                 let next_token = logit_processor.sample(&logits)?;
-                let mut decode_ids = Vec::new();
-                if !error_tokens.is_empty(){
-                    decode_ids.extend_from_slice(&error_tokens);
-                }
-                decode_ids.push(next_token);
-                let decoded_token = self.tokenizer.token_decode(decode_ids).map_err(|e| anyhow!(format!("stream decode error{}", e)))?;
-                if decoded_token.contains("�") {
-                    error_tokens.push(next_token);
-                    if error_tokens.len() > 3 {
-                        error_tokens.clear();
-                    }
-                    seqlen_offset += seq_len;
-                    seq_len = 1;
-                    input_ids = Tensor::from_vec(vec![next_token], (1, 1), &self.device)?;
-                    continue;
+                if let Some(text) = decoder.step(&self.tokenizer, next_token)? {
+                    let chunk = build_completion_chunk_response(text, "minicpm", None, None);
+                    yield Ok(chunk);
                 }
-                error_tokens.clear();
-                let chunk = build_completion_chunk_response(decoded_token, "minicpm", None, None);
-                yield Ok(chunk);
                 if next_token == self.endoftext_id || next_token == self.im_end_id {
                     break;
                 }
                 seqlen_offset += seq_len;
                 seq_len = 1;
                 input_ids = Tensor::from_vec(vec![next_token], (1, 1), &self.device)?;
-
+            }
+            if let Some(text) = decoder.flush(&self.tokenizer)? {
+                yield Ok(build_completion_chunk_response(text, "minicpm", None, None));
             }
             self.minicpm.clear_kv_cache();
         };
         Ok(stream)
     }
 }
+
+impl<'a> EmbeddingModel for MiniCPMGenerateModel<'a> {
+    fn hidden_size(&self) -> usize {
+        self.minicpm.config().hidden_size
+    }
+
+    fn embed_text(&mut self, text: &str, pooling: Pooling) -> Result<(Vec<f32>, usize)> {
+        let input_ids = self.tokenizer.text_encode(text.to_string(), &self.device)?;
+        let hidden_states = self.minicpm.forward_hidden(&input_ids, 0)?;
+        let embedding = pool_hidden_states(&hidden_states.squeeze(0)?, pooling)?;
+        Ok((embedding, input_ids.dim(1)?))
+    }
+
+    fn embed_texts(&mut self, texts: &[&str], pooling: Pooling) -> Result<Vec<(Vec<f32>, usize)>> {
+        let ids = texts
+            .iter()
+            .map(|text| {
+                Ok(self
+                    .tokenizer
+                    .text_encode(text.to_string(), &self.device)?
+                    .squeeze(0)?
+                    .to_vec1::<u32>()?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let input_ids = pad_batch(&ids, &self.device)?;
+        let hidden_states = self.minicpm.forward_hidden(&input_ids, 0)?;
+        let lens: Vec<usize> = ids.iter().map(|i| i.len()).collect();
+        pool_batch(&hidden_states, &lens, pooling)
+    }
+}
+
+impl<'a> ScoringModel for MiniCPMGenerateModel<'a> {
+    fn score_text(&mut self, text: &str) -> Result<ScoreOutput> {
+        let input_ids = self.tokenizer.text_encode(text.to_string(), &self.device)?;
+        self.score_ids(&input_ids)
+    }
+
+    fn score(&mut self, mes: ChatCompletionParameters) -> Result<ScoreOutput> {
+        let mes_render = self.chat_template.render(&mes, false)?;
+        let input_ids = self.tokenizer.text_encode(mes_render, &self.device)?;
+        self.score_ids(&input_ids)
+    }
+}
+
+impl<'a> ParityModel for MiniCPMGenerateModel<'a> {
+    fn parity_outputs(&mut self, golden: &GoldenData) -> Result<Vec<(String, Tensor)>> {
+        let input_ids = golden.input_ids("input_ids", &self.device)?;
+        let mut outputs = vec![];
+        let hidden_states = self.minicpm.forward_hidden_states(&input_ids, 0)?;
+        for (i, hidden) in hidden_states.into_iter().enumerate() {
+            outputs.push((format!("hidden_states.{}", i), hidden));
+        }
+        let logits = self.minicpm.forward_logits(&input_ids, 0)?;
+        outputs.push(("logits".to_string(), logits));
+        let logits = self.minicpm.forward(&input_ids, 0)?;
+        outputs.push(("logits.last".to_string(), logits));
+        // 生成时走的kv cache路径, 设置了稀疏注意力且超过dense_len时分块prefill
+        self.minicpm.clear_kv_cache();
+        let logits = self.minicpm.forward_with_cache(&input_ids, 0);
+        self.minicpm.clear_kv_cache();
+        outputs.push(("logits.last_with_cache".to_string(), logits?));
+        Ok(outputs)
+    }
+}
@@ -1,3 +1,5 @@
 pub mod config;
 pub mod generate;
 pub mod model;
+pub mod sparse;
+pub mod speculative;
@@ -1,16 +1,22 @@
-use anyhow::{Ok, Result};
-use candle_core::{D, Device, Tensor};
+use anyhow::{Ok, Result, anyhow};
+use candle_core::{D, DType, Device, Tensor};
 use candle_nn::{Embedding, Linear, Module, RmsNorm, VarBuilder, embedding, rms_norm};
 
 use crate::{
     models::{
-        common::{AttentionNobias, MLPNoBias},
-        minicpm4::config::MiniCPM4Config,
+        common::{AttentionNobias, KvCacheSnapshot, MLPNoBias, kv_cache_len, search::SearchLM},
+        minicpm4::{
+            config::{MiniCPM4Config, SparseConfig},
+            sparse::{CompressedKeyCache, infllm_v2_attention_with_cache},
+        },
     },
     position_embed::rope::compute_default_rope_parameters,
     utils::tensor_utils::prepare_causal_attention_mask,
 };
 
+// cos/sin缓存的初始长度, 之后按需增长
+const ROPE_INIT_CACHE_LEN: usize = 4096;
+
 pub struct MiniCPMLongRoPE {
     short_factor: Vec<f32>,
     long_factor: Vec<f32>,
@@ -25,9 +31,18 @@ pub struct MiniCPMLongRoPE {
 impl MiniCPMLongRoPE {
     pub fn new(cfg: &MiniCPM4Config, device: &Device) -> Result<Self> {
         let head_dim = cfg.hidden_size / cfg.num_attention_heads;
-        let rope_theta = 10000.0;
+        let rope_theta = cfg.rope_theta;
         let short_factor = cfg.rope_scaling.short_factor.clone();
-        let long_factor = cfg.rope_scaling.short_factor.clone();
+        let long_factor = cfg.rope_scaling.long_factor.clone();
+        // head_dim = 1024 / 16 = 64, inv_freq.len() = 32 = short_factor.len()
+        if short_factor.len() != head_dim / 2 || long_factor.len() != head_dim / 2 {
+            return Err(anyhow!(format!(
+                "rope_scaling factors length ({}, {}) != head_dim / 2 ({})",
+                short_factor.len(),
+                long_factor.len(),
+                head_dim / 2
+            )));
+        }
         let original_max_position_embeddings = cfg.rope_scaling.original_max_position_embeddings;
         let max_position_embeddings = cfg.max_position_embeddings;
         let scale = max_position_embeddings as f64 / original_max_position_embeddings as f64;
@@ -35,44 +50,39 @@ impl MiniCPMLongRoPE {
             (1.0 + scale.ln() / (original_max_position_embeddings as f64).ln()).sqrt();
         let inv_freq = compute_default_rope_parameters(head_dim, rope_theta);
         let inv_freq = Tensor::from_slice(&inv_freq, (1, inv_freq.len()), device)?;
-        let max_seq_len_cached = max_position_embeddings;
-        let t = Tensor::arange(0.0_f32, max_position_embeddings as f32, device)?
-            .reshape((max_position_embeddings, 1))?;
-        // short_factor.len() = 32
-        // head_dim = 1024 / 16 = 64, inv_freq.len() = 32
-        let ext_factors = Tensor::from_slice(&short_factor, (1, short_factor.len()), device)?;
-        let ext_factors = Tensor::ones_like(&ext_factors)?.div(&ext_factors)?;
-        // (seq_len, 1) matmul (1, 32) -> (seq_len, 32) * (1, 32)-> (seq_len, 32)
-        let freqs = t.matmul(&ext_factors)?.broadcast_mul(&inv_freq)?;
-
-        let emb = Tensor::cat(&[&freqs, &freqs], D::Minus1)?;
-        let cos_cached = emb.cos()?.affine(scaling_factor, 0.0)?;
-        let sin_cached = emb.sin()?.affine(scaling_factor, 0.0)?;
-        Ok(Self {
+        // 不再按max_position_embeddings预先计算, 先缓存一小段, forward时按需增长
+        let empty = Tensor::zeros((0, head_dim), DType::F32, device)?;
+        let mut rope = Self {
             short_factor,
             long_factor,
             original_max_position_embeddings,
-            max_seq_len_cached,
+            max_seq_len_cached: 0,
             scaling_factor,
             inv_freq,
-            cos_cached,
-            sin_cached,
+            cos_cached: empty.clone(),
+            sin_cached: empty,
             device: device.clone(),
-        })
+        };
+        rope.update_cos_sin_cache(ROPE_INIT_CACHE_LEN.min(original_max_position_embeddings))?;
+        Ok(rope)
+    }
+
+    pub fn max_seq_len_cached(&self) -> usize {
+        self.max_seq_len_cached
     }
+
+    // 缓存长度超过original_max_position_embeddings时整体使用long_factor, 否则使用short_factor
     pub fn update_cos_sin_cache(&mut self, seqlen: usize) -> Result<()> {
         self.max_seq_len_cached = seqlen;
         let t = Tensor::arange(0.0_f32, seqlen as f32, &self.device)?.reshape((seqlen, 1))?;
-        let mut ext_factors = Tensor::from_slice(
-            &self.short_factor,
-            (1, self.short_factor.len()),
-            &self.device,
-        )?;
-        if seqlen > self.original_max_position_embeddings {
-            ext_factors =
-                Tensor::from_slice(&self.long_factor, (1, self.long_factor.len()), &self.device)?;
-        }
+        let factor = if seqlen > self.original_max_position_embeddings {
+            &self.long_factor
+        } else {
+            &self.short_factor
+        };
+        let ext_factors = Tensor::from_slice(factor, (1, factor.len()), &self.device)?;
         let ext_factors = Tensor::ones_like(&ext_factors)?.div(&ext_factors)?;
+        // (seq_len, 1) matmul (1, 32) -> (seq_len, 32) * (1, 32)-> (seq_len, 32)
         let freqs = t.matmul(&ext_factors)?.broadcast_mul(&self.inv_freq)?;
         let emb = Tensor::cat(&[&freqs, &freqs], D::Minus1)?;
         let cos_cached = emb.cos()?.affine(self.scaling_factor, 0.0)?;
@@ -81,19 +91,40 @@ impl MiniCPMLongRoPE {
         self.sin_cached = sin_cached;
         Ok(())
     }
+
     pub fn forward(&mut self, pos_offset: usize, seqlen: usize) -> Result<(Tensor, Tensor)> {
-        if pos_offset + seqlen > self.max_seq_len_cached {
-            self.update_cos_sin_cache(pos_offset + seqlen)?;
+        let seq_end = pos_offset + seqlen;
+        let original = self.original_max_position_embeddings;
+        // 新的一轮生成不超过original时, 从long_factor切回short_factor
+        let back_to_short =
+            pos_offset == 0 && self.max_seq_len_cached > original && seq_end <= original;
+        if seq_end > self.max_seq_len_cached || back_to_short {
+            // 按两倍增长, 减少重复计算, 但不跨过original导致提前切换到long_factor
+            let mut cache_len = seq_end.max(self.max_seq_len_cached * 2);
+            if seq_end <= original {
+                cache_len = cache_len.min(original);
+            }
+            self.update_cos_sin_cache(cache_len)?;
         }
         let cos = self.cos_cached.narrow(0, pos_offset, seqlen)?;
         let sin = self.sin_cached.narrow(0, pos_offset, seqlen)?;
 
         Ok((cos, sin))
     }
+
+    // kv cache回退到seq_len后, 如果不超过original则切回short_factor, 与没有生成过更长序列时一致
+    pub fn fit_seq_len(&mut self, seq_len: usize) -> Result<()> {
+        let original = self.original_max_position_embeddings;
+        if seq_len <= original && self.max_seq_len_cached > original {
+            self.update_cos_sin_cache(ROPE_INIT_CACHE_LEN.min(original))?;
+        }
+        Ok(())
+    }
 }
 
 pub struct MiniCPMDecoderLayer {
     self_attn: AttentionNobias,
+    compressed_key: CompressedKeyCache,
     mlp: MLPNoBias,
     input_layernorm: RmsNorm,
     post_attention_layernorm: RmsNorm,
@@ -108,12 +139,14 @@ impl MiniCPMDecoderLayer {
             cfg.hidden_size,
             cfg.num_attention_heads,
             cfg.num_key_value_heads,
+            &cfg.quant,
         )?;
         let mlp = MLPNoBias::new(
             vb.pp("mlp"),
             cfg.hidden_size,
             cfg.intermediate_size,
             cfg.hidden_act,
+            &cfg.quant,
         )?;
         let input_layernorm =
             rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
@@ -124,6 +157,7 @@ impl MiniCPMDecoderLayer {
         )?;
         Ok(Self {
             self_attn,
+            compressed_key: CompressedKeyCache::default(),
             mlp,
             input_layernorm,
             post_attention_layernorm,
@@ -165,12 +199,27 @@ impl MiniCPMDecoderLayer {
         cos: &Tensor,
         sin: &Tensor,
         attention_mask: Option<&Tensor>,
+        sparse: Option<&SparseConfig>,
     ) -> Result<Tensor> {
         let residual = xs.clone();
         let xs = self.input_layernorm.forward(xs)?;
-        let xs = self
-            .self_attn
-            .forward_with_cache(&xs, cos, sin, attention_mask, true)?;
+        let xs = match sparse {
+            Some(sparse) => {
+                let (q, k, v) = self.self_attn.qkv_with_cache(&xs, cos, sin, true)?;
+                let attn_output = infllm_v2_attention_with_cache(
+                    &q,
+                    &k,
+                    &v,
+                    &mut self.compressed_key,
+                    sparse,
+                    self.self_attn.num_kv_groups(),
+                )?;
+                self.self_attn.output(&attn_output)?
+            }
+            None => self
+                .self_attn
+                .forward_with_cache(&xs, cos, sin, attention_mask, true)?,
+        };
         let xs = (residual
             + xs.affine(
                 self.scale_depth as f64 / (self.num_hidden_layers as f64).sqrt(),
@@ -187,6 +236,30 @@ impl MiniCPMDecoderLayer {
     }
     pub fn clear_kv_cache(&mut self) {
         self.self_attn.clear_kv_cache();
+        self.compressed_key.clear();
+    }
+
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.compressed_key.truncate(len)?;
+        self.self_attn.truncate_kv_cache(len)
+    }
+
+    pub fn kv_cache(&self) -> Option<(Tensor, Tensor)> {
+        self.self_attn.kv_cache()
+    }
+
+    // 换成其他kv cache后压缩key在下次稀疏注意力时重新计算
+    pub fn set_kv_cache(&mut self, kv_cache: Option<(Tensor, Tensor)>) {
+        self.self_attn.set_kv_cache(kv_cache);
+        self.compressed_key.clear();
+    }
+
+    pub fn reserve_kv_cache(&mut self, total_len: usize) {
+        self.self_attn.reserve_kv_cache(total_len);
+    }
+
+    pub fn release_kv_reserve(&mut self) {
+        self.self_attn.release_kv_reserve();
     }
 }
 
@@ -197,6 +270,7 @@ pub struct MiniCPMModel {
     norm: RmsNorm,
     rope_emb: MiniCPMLongRoPE,
     lm_head: Linear,
+    sparse_config: Option<SparseConfig>,
 }
 
 impl MiniCPMModel {
@@ -212,6 +286,7 @@ impl MiniCPMModel {
         let norm = rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("norm"))?;
         let rope_emb = MiniCPMLongRoPE::new(&cfg, vb.device())?;
         let lm_head = Linear::new(embed_tokens.embeddings().clone(), None);
+        let sparse_config = cfg.sparse_config.clone();
         Ok(Self {
             cfg,
             embed_tokens,
@@ -219,10 +294,65 @@ impl MiniCPMModel {
             norm,
             rope_emb,
             lm_head,
+            sparse_config,
         })
     }
 
+    // None时只使用稠密注意力
+    pub fn set_sparse_config(&mut self, sparse_config: Option<SparseConfig>) {
+        self.sparse_config = sparse_config;
+    }
+
     pub fn forward(&mut self, input_ids: &Tensor, position_id: usize) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        let hidden_states = self.forward_hidden(input_ids, position_id)?;
+        let hidden_state = hidden_states.narrow(1, seq_len - 1, 1)?;
+        let hidden_state = hidden_state.affine(
+            1.0 / (self.cfg.hidden_size / self.cfg.dim_model_base) as f64,
+            0.0,
+        )?;
+        let logits = self.lm_head.forward(&hidden_state)?;
+        Ok(logits)
+    }
+
+    // 不使用kv cache, 返回所有位置的logits: (bs, seq_len, vocab_size)
+    pub fn forward_logits(&mut self, input_ids: &Tensor, position_id: usize) -> Result<Tensor> {
+        let hidden_states = self.forward_hidden(input_ids, position_id)?;
+        self.hidden_logits(&hidden_states)
+    }
+
+    // forward_hidden的输出转为logits
+    pub fn hidden_logits(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        let hidden_states = hidden_states.affine(
+            1.0 / (self.cfg.hidden_size / self.cfg.dim_model_base) as f64,
+            0.0,
+        )?;
+        Ok(self.lm_head.forward(&hidden_states)?)
+    }
+
+    // 不使用kv cache, 返回最后一层norm之后所有位置的hidden states: (bs, seq_len, hidden_size)
+    pub fn forward_hidden(&mut self, input_ids: &Tensor, position_id: usize) -> Result<Tensor> {
+        self.forward_hidden_impl(input_ids, position_id, None)
+    }
+
+    // 与transformers的output_hidden_states一致, 返回num_hidden_layers + 1个hidden states:
+    // 第0个为embedding(已乘scale_emb), 第i个为第i层的输入, 最后一个为最后一层norm之后的输出
+    pub fn forward_hidden_states(
+        &mut self,
+        input_ids: &Tensor,
+        position_id: usize,
+    ) -> Result<Vec<Tensor>> {
+        let mut all_hidden_states = vec![];
+        self.forward_hidden_impl(input_ids, position_id, Some(&mut all_hidden_states))?;
+        Ok(all_hidden_states)
+    }
+
+    fn forward_hidden_impl(
+        &mut self,
+        input_ids: &Tensor,
+        position_id: usize,
+        mut all_hidden_states: Option<&mut Vec<Tensor>>,
+    ) -> Result<Tensor> {
         let (bs, seq_len) = input_ids.dims2()?;
         let input_embeds = self
             .embed_tokens
@@ -244,10 +374,107 @@ impl MiniCPMModel {
         let (cos, sin) = self.rope_emb.forward(position_id, seq_len)?;
         let mut hidden_states = input_embeds;
         for decode_layer in &self.layers {
+            if let Some(all) = all_hidden_states.as_mut() {
+                all.push(hidden_states.clone());
+            }
             hidden_states = decode_layer.forward(&hidden_states, &cos, &sin, attention_mask)?;
         }
-        hidden_states = self.norm.forward(&hidden_states)?;
-        let hidden_state = hidden_states.narrow(1, seq_len - 1, 1)?;
+        let hidden_states = self.norm.forward(&hidden_states)?;
+        if let Some(all) = all_hidden_states {
+            all.push(hidden_states.clone());
+        }
+        Ok(hidden_states)
+    }
+
+    pub fn forward_with_cache(&mut self, input_ids: &Tensor, position_id: usize) -> Result<Tensor> {
+        self.forward_cache_impl(input_ids, position_id, false)
+    }
+
+    // 返回输入中每个位置的logits: (bs, seq_len, vocab_size), 用于投机解码验证草稿
+    pub fn forward_all_with_cache(
+        &mut self,
+        input_ids: &Tensor,
+        position_id: usize,
+    ) -> Result<Tensor> {
+        self.forward_cache_impl(input_ids, position_id, true)
+    }
+
+    fn forward_cache_impl(
+        &mut self,
+        input_ids: &Tensor,
+        position_id: usize,
+        all_logits: bool,
+    ) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        let sparse = match &self.sparse_config {
+            Some(sparse) if position_id + seq_len > sparse.dense_len => sparse.clone(),
+            _ => return self.forward_dense_with_cache(input_ids, position_id, all_logits),
+        };
+        // 多块时kv cache预留整个输入的位置, 每块写入预留的空间
+        let chunked = seq_len > sparse.prefill_chunk_size;
+        if chunked {
+            for layer in &mut self.layers {
+                layer.reserve_kv_cache(position_id + seq_len);
+            }
+        }
+        let logits = self.sparse_prefill(input_ids, position_id, all_logits, &sparse);
+        if chunked {
+            for layer in &mut self.layers {
+                layer.release_kv_reserve();
+            }
+        }
+        logits
+    }
+
+    // 长上下文按块prefill, 每块只保留块内的激活, 注意力按block稀疏计算
+    fn sparse_prefill(
+        &mut self,
+        input_ids: &Tensor,
+        position_id: usize,
+        all_logits: bool,
+        sparse: &SparseConfig,
+    ) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        let mut logits = vec![];
+        let mut start = 0;
+        while start < seq_len {
+            let chunk = sparse.prefill_chunk_size.min(seq_len - start);
+            let ids = input_ids.narrow(1, start, chunk)?;
+            let input_embeds = self
+                .embed_tokens
+                .forward(&ids)?
+                .affine(self.cfg.scale_emb, 0.0)?;
+            let (cos, sin) = self.rope_emb.forward(position_id + start, chunk)?;
+            let mut hidden_states = input_embeds;
+            for decode_layer in &mut self.layers {
+                hidden_states = decode_layer.forward_with_cache(
+                    &hidden_states,
+                    &cos,
+                    &sin,
+                    None,
+                    Some(sparse),
+                )?;
+            }
+            start += chunk;
+            if all_logits || start == seq_len {
+                logits.push(self.lm_logits(&hidden_states, all_logits)?);
+            }
+        }
+        if logits.is_empty() {
+            return Err(anyhow!("forward_with_cache input_ids is empty"));
+        }
+        Ok(Tensor::cat(&logits, 1)?)
+    }
+
+    // all为false时只计算最后一个位置
+    fn lm_logits(&self, hidden_states: &Tensor, all: bool) -> Result<Tensor> {
+        let seq_len = hidden_states.dim(1)?;
+        let hidden_states = self.norm.forward(hidden_states)?;
+        let hidden_state = if all {
+            hidden_states
+        } else {
+            hidden_states.narrow(1, seq_len - 1, 1)?
+        };
         let hidden_state = hidden_state.affine(
             1.0 / (self.cfg.hidden_size / self.cfg.dim_model_base) as f64,
             0.0,
@@ -256,7 +483,12 @@ impl MiniCPMModel {
         Ok(logits)
     }
 
-    pub fn forward_with_cache(&mut self, input_ids: &Tensor, position_id: usize) -> Result<Tensor> {
+    fn forward_dense_with_cache(
+        &mut self,
+        input_ids: &Tensor,
+        position_id: usize,
+        all_logits: bool,
+    ) -> Result<Tensor> {
         let (bs, seq_len) = input_ids.dims2()?;
         let input_embeds = self
             .embed_tokens
@@ -266,10 +498,11 @@ impl MiniCPMModel {
             if seq_len <= 1 {
                 None
             } else {
+                // 已有kv cache时多个token一起输入(投机解码验证), mask需要包含历史位置
                 Some(&prepare_causal_attention_mask(
                     bs,
                     seq_len,
-                    0,
+                    position_id,
                     input_ids.device(),
                 )?)
             }
@@ -277,17 +510,15 @@ impl MiniCPMModel {
         let (cos, sin) = self.rope_emb.forward(position_id, seq_len)?;
         let mut hidden_states = input_embeds;
         for decode_layer in &mut self.layers {
-            hidden_states =
-                decode_layer.forward_with_cache(&hidden_states, &cos, &sin, attention_mask)?;
+            hidden_states = decode_layer.forward_with_cache(
+                &hidden_states,
+                &cos,
+                &sin,
+                attention_mask,
+                None,
+            )?;
         }
-        hidden_states = self.norm.forward(&hidden_states)?;
-        let hidden_state = hidden_states.narrow(1, seq_len - 1, 1)?;
-        let hidden_state = hidden_state.affine(
-            1.0 / (self.cfg.hidden_size / self.cfg.dim_model_base) as f64,
-            0.0,
-        )?;
-        let logits = self.lm_head.forward(&hidden_state)?;
-        Ok(logits)
+        self.lm_logits(&hidden_states, all_logits)
     }
 
     pub fn clear_kv_cache(&mut self) {
@@ -295,4 +526,58 @@ impl MiniCPMModel {
             layer.clear_kv_cache()
         }
     }
+
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            layer.truncate_kv_cache(len)?;
+        }
+        self.rope_emb.fit_seq_len(len)
+    }
+
+    pub fn kv_cache_len(&self) -> usize {
+        self.layers
+            .first()
+            .map(|layer| kv_cache_len(&layer.kv_cache()))
+            .unwrap_or(0)
+    }
+
+    pub fn snapshot(&self) -> KvCacheSnapshot {
+        KvCacheSnapshot {
+            layers: self.layers.iter().map(|layer| layer.kv_cache()).collect(),
+            rope_deltas: None,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &KvCacheSnapshot) -> Result<()> {
+        snapshot.check_num_layers(self.layers.len())?;
+        for (layer, kv_cache) in self.layers.iter_mut().zip(snapshot.layers.iter()) {
+            layer.set_kv_cache(kv_cache.clone());
+        }
+        self.rope_emb.fit_seq_len(snapshot.seq_len())
+    }
+
+    pub fn config(&self) -> &MiniCPM4Config {
+        &self.cfg
+    }
+
+    pub fn device(&self) -> Device {
+        self.embed_tokens.embeddings().device().clone()
+    }
+}
+
+impl SearchLM for MiniCPMModel {
+    fn decode(&mut self, token: u32, position_id: usize) -> Result<Vec<f32>> {
+        let input_ids = Tensor::from_vec(vec![token], (1, 1), &self.device())?;
+        let logits = self.forward_with_cache(&input_ids, position_id)?;
+        let logits = logits.flatten_all()?.to_dtype(DType::F32)?;
+        Ok(logits.to_vec1::<f32>()?)
+    }
+
+    fn snapshot(&self) -> KvCacheSnapshot {
+        MiniCPMModel::snapshot(self)
+    }
+
+    fn restore(&mut self, snapshot: &KvCacheSnapshot) -> Result<()> {
+        MiniCPMModel::restore(self, snapshot)
+    }
 }
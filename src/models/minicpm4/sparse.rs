@@ -0,0 +1,343 @@
+use anyhow::{Result, anyhow};
+use candle_core::{D, DType, Tensor};
+
+use crate::models::minicpm4::config::SparseConfig;
+
+// InfLLM v2 块稀疏注意力
+// 先把key按kernel_size/kernel_stride做均值池化得到压缩key, 用query和压缩key的注意力分数给每个block打分,
+// 每组query只和开头init_blocks个block, 局部窗口内的block, 以及分数最高的block做注意力, 总数为topk
+// q: (bs, num_heads, q_len, head_dim), k/v: (bs, num_kv_heads, kv_len, head_dim), q是kv中最后q_len个位置
+// 返回 (bs, num_heads, q_len, head_dim)
+pub fn infllm_v2_attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    cfg: &SparseConfig,
+    num_kv_groups: usize,
+) -> Result<Tensor> {
+    let mut compressed_key = CompressedKeyCache::default();
+    infllm_v2_attention_with_cache(q, k, v, &mut compressed_key, cfg, num_kv_groups)
+}
+
+// 与infllm_v2_attention相同, 压缩key从compressed_key中增量更新, k为完整的kv cache
+pub fn infllm_v2_attention_with_cache(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    compressed_key: &mut CompressedKeyCache,
+    cfg: &SparseConfig,
+    num_kv_groups: usize,
+) -> Result<Tensor> {
+    let (bs, _, q_len, _) = q.dims4()?;
+    let kv_len = k.dim(2)?;
+    if q_len > kv_len {
+        return Err(anyhow!(format!(
+            "infllm v2 attention q_len({}) > kv_len({})",
+            q_len, kv_len
+        )));
+    }
+    let compressed = compressed_key.update(k, cfg)?;
+    let mut outputs = Vec::with_capacity(bs);
+    for i in 0..bs {
+        let compressed = match &compressed {
+            Some(c) => Some(c.get(i)?),
+            None => None,
+        };
+        let out = attention_one(
+            &q.get(i)?,
+            &k.get(i)?,
+            &v.get(i)?,
+            compressed.as_ref(),
+            cfg,
+            num_kv_groups,
+        )?;
+        outputs.push(out);
+    }
+    Ok(Tensor::stack(&outputs, 0)?)
+}
+
+// 压缩key的缓存, 与kv cache一起增长, 每次只计算新凑满的kernel_stride个key
+// 压缩key: 先按kernel_stride求均值, 再对kernel_size/kernel_stride个相邻的均值求平均
+// 第j个压缩key覆盖位置[j * stride, j * stride + kernel_size)
+#[derive(Debug, Clone, Default)]
+pub struct CompressedKeyCache {
+    kernel_stride: usize,
+    kernel_size: usize,
+    // (bs, num_kv_heads, num_chunks, head_dim)
+    chunk_mean: Option<Tensor>,
+    // (bs, num_kv_heads, num_kernels, head_dim)
+    compressed: Option<Tensor>,
+}
+
+impl CompressedKeyCache {
+    // k: (bs, num_kv_heads, kv_len, head_dim), 返回f32的压缩key, kv_len不足kernel_size时为None
+    pub fn update(&mut self, k: &Tensor, cfg: &SparseConfig) -> Result<Option<Tensor>> {
+        if self.kernel_stride != cfg.kernel_stride || self.kernel_size != cfg.kernel_size {
+            self.clear();
+            self.kernel_stride = cfg.kernel_stride;
+            self.kernel_size = cfg.kernel_size;
+        }
+        let (bs, num_kv_heads, kv_len, head_dim) = k.dims4()?;
+        let stride = self.kernel_stride;
+        let ratio = self.kernel_size / stride;
+        let num_chunks = kv_len / stride;
+        // kv cache比缓存短时(回退过)先截断
+        self.truncate(kv_len)?;
+        let cached = match &self.chunk_mean {
+            Some(m) => m.dim(2)?,
+            None => 0,
+        };
+        if num_chunks > cached {
+            let new_chunks = num_chunks - cached;
+            let new_mean = k
+                .narrow(2, cached * stride, new_chunks * stride)?
+                .to_dtype(DType::F32)?
+                .reshape((bs, num_kv_heads, new_chunks, stride, head_dim))?
+                .mean(3)?;
+            let chunk_mean = match &self.chunk_mean {
+                Some(m) => Tensor::cat(&[m, &new_mean], 2)?,
+                None => new_mean,
+            };
+            let num_kernels = (num_chunks + 1).saturating_sub(ratio);
+            let cached_kernels = match &self.compressed {
+                Some(c) => c.dim(2)?,
+                None => 0,
+            };
+            if num_kernels > cached_kernels {
+                let n = num_kernels - cached_kernels;
+                let mut new_kernels = chunk_mean.narrow(2, cached_kernels, n)?;
+                for r in 1..ratio {
+                    new_kernels = (new_kernels + chunk_mean.narrow(2, cached_kernels + r, n)?)?;
+                }
+                let new_kernels = (new_kernels / ratio as f64)?;
+                self.compressed = Some(match &self.compressed {
+                    Some(c) => Tensor::cat(&[c, &new_kernels], 2)?,
+                    None => new_kernels,
+                });
+            }
+            self.chunk_mean = Some(chunk_mean);
+        }
+        Ok(self.compressed.clone())
+    }
+
+    // kv cache回退到len时丢弃覆盖len之后位置的压缩key
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if self.kernel_stride == 0 {
+            return Ok(());
+        }
+        let keep_chunks = len / self.kernel_stride;
+        let keep_kernels = (keep_chunks + 1).saturating_sub(self.kernel_size / self.kernel_stride);
+        self.chunk_mean = narrow_cache(self.chunk_mean.take(), keep_chunks)?;
+        self.compressed = narrow_cache(self.compressed.take(), keep_kernels)?;
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.chunk_mean = None;
+        self.compressed = None;
+    }
+}
+
+fn narrow_cache(cache: Option<Tensor>, len: usize) -> Result<Option<Tensor>> {
+    match cache {
+        Some(_) if len == 0 => Ok(None),
+        Some(t) if t.dim(2)? > len => Ok(Some(t.narrow(2, 0, len)?)),
+        cache => Ok(cache),
+    }
+}
+
+// q: (num_heads, q_len, head_dim), k/v: (num_kv_heads, kv_len, head_dim)
+fn attention_one(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    compressed: Option<&Tensor>,
+    cfg: &SparseConfig,
+    num_kv_groups: usize,
+) -> Result<Tensor> {
+    let dtype = q.dtype();
+    let device = q.device().clone();
+    let (num_heads, q_len, head_dim) = q.dims3()?;
+    let (num_kv_heads, kv_len, _) = k.dims3()?;
+    let block_size = cfg.block_size;
+    let stride = cfg.kernel_stride;
+    let scale = 1.0 / (head_dim as f64).sqrt();
+    let q = q.to_dtype(DType::F32)?;
+    let k = k.to_dtype(DType::F32)?;
+    let v = v.to_dtype(DType::F32)?;
+
+    // 补齐到block_size的整数倍, 补的位置在所有query之后, 会被因果mask掉
+    let num_blocks = kv_len.div_ceil(block_size);
+    let pad = num_blocks * block_size - kv_len;
+    let (k_pad, v_pad) = if pad > 0 {
+        let zeros = Tensor::zeros((num_kv_heads, pad, head_dim), DType::F32, &device)?;
+        (
+            Tensor::cat(&[&k, &zeros], 1)?,
+            Tensor::cat(&[&v, &zeros], 1)?,
+        )
+    } else {
+        (k.clone(), v.clone())
+    };
+    // (num_kv_heads * num_blocks, block_size, head_dim)
+    let k_blocks = k_pad.reshape((num_kv_heads * num_blocks, block_size, head_dim))?;
+    let v_blocks = v_pad.reshape((num_kv_heads * num_blocks, block_size, head_dim))?;
+
+    let kernels_per_block = block_size / stride;
+
+    let mut outputs = vec![];
+    let mut start = 0;
+    while start < q_len {
+        let chunk = block_size.min(q_len - start);
+        // 当前块query的位置
+        let pos_min = kv_len - q_len + start;
+        let pos_max = pos_min + chunk - 1;
+        let valid_blocks = pos_max / block_size + 1;
+        // (num_kv_heads, groups * chunk, head_dim)
+        let q_chunk =
+            q.narrow(1, start, chunk)?
+                .reshape((num_kv_heads, num_kv_groups * chunk, head_dim))?;
+
+        let selected = if valid_blocks <= cfg.topk {
+            vec![(0..valid_blocks).collect::<Vec<usize>>(); num_kv_heads]
+        } else {
+            let scores = block_scores(
+                &q_chunk,
+                compressed,
+                pos_min,
+                chunk,
+                num_blocks,
+                kernels_per_block,
+                cfg,
+                scale,
+            )?;
+            select_blocks(&scores, pos_min, pos_max, valid_blocks, cfg)
+        };
+        let num_selected = selected[0].len();
+        let mut index = Vec::with_capacity(num_kv_heads * num_selected);
+        let mut key_pos = Vec::with_capacity(num_kv_heads * num_selected * block_size);
+        for (h, blocks) in selected.iter().enumerate() {
+            for &b in blocks {
+                index.push((h * num_blocks + b) as u32);
+                key_pos.extend((b * block_size..(b + 1) * block_size).map(|p| p as u32));
+            }
+        }
+        let index = Tensor::from_vec(index, num_kv_heads * num_selected, &device)?;
+        let sel_len = num_selected * block_size;
+        let k_sel = k_blocks
+            .index_select(&index, 0)?
+            .reshape((num_kv_heads, sel_len, head_dim))?;
+        let v_sel = v_blocks
+            .index_select(&index, 0)?
+            .reshape((num_kv_heads, sel_len, head_dim))?;
+        // 因果mask: key位置大于query位置时屏蔽
+        let key_pos = Tensor::from_vec(key_pos, (num_kv_heads, 1, 1, sel_len), &device)?;
+        let query_pos = Tensor::arange(pos_min as u32, (pos_max + 1) as u32, &device)?
+            .reshape((1, 1, chunk, 1))?;
+        let mask = key_pos.broadcast_gt(&query_pos)?.broadcast_as((
+            num_kv_heads,
+            num_kv_groups,
+            chunk,
+            sel_len,
+        ))?;
+
+        let attn_weights = (q_chunk.matmul(&k_sel.t()?)? * scale)?.reshape((
+            num_kv_heads,
+            num_kv_groups,
+            chunk,
+            sel_len,
+        ))?;
+        let neg_inf = Tensor::new(f32::NEG_INFINITY, &device)?.broadcast_as(mask.shape())?;
+        let attn_weights = mask.where_cond(&neg_inf, &attn_weights)?.reshape((
+            num_kv_heads,
+            num_kv_groups * chunk,
+            sel_len,
+        ))?;
+        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+        let out = attn_weights
+            .matmul(&v_sel)?
+            .reshape((num_heads, chunk, head_dim))?;
+        outputs.push(out);
+        start += chunk;
+    }
+    let out = Tensor::cat(&outputs, 1)?.to_dtype(dtype)?;
+    Ok(out)
+}
+
+// 每个block的分数为block内压缩key在当前块所有query上注意力概率之和的最大值
+// 返回 (num_kv_heads, num_blocks)
+fn block_scores(
+    q_chunk: &Tensor,
+    compressed: Option<&Tensor>,
+    pos_min: usize,
+    chunk: usize,
+    num_blocks: usize,
+    kernels_per_block: usize,
+    cfg: &SparseConfig,
+    scale: f64,
+) -> Result<Vec<Vec<f32>>> {
+    let num_kv_heads = q_chunk.dim(0)?;
+    let compressed = match compressed {
+        Some(c) => c,
+        None => return Ok(vec![vec![0.0; num_blocks]; num_kv_heads]),
+    };
+    let device = q_chunk.device();
+    let num_kernels = compressed.dim(1)?;
+    let groups = q_chunk.dim(1)? / chunk;
+    // 压缩key的最后一个位置超过query位置时不可见
+    let kernel_end: Vec<u32> = (0..num_kernels)
+        .map(|j| (j * cfg.kernel_stride + cfg.kernel_size - 1) as u32)
+        .collect();
+    let kernel_end = Tensor::from_vec(kernel_end, (1, 1, num_kernels), device)?;
+    let query_pos =
+        Tensor::arange(pos_min as u32, (pos_min + chunk) as u32, device)?.reshape((1, chunk, 1))?;
+    let mask = kernel_end
+        .broadcast_gt(&query_pos)?
+        .broadcast_as((groups, chunk, num_kernels))?
+        .reshape((1, groups * chunk, num_kernels))?
+        .broadcast_as((num_kv_heads, groups * chunk, num_kernels))?;
+    let scores = (q_chunk.matmul(&compressed.t()?)? * scale)?;
+    // 用很小的有限值, 避免整行都被屏蔽时出现nan
+    let min_value = Tensor::new(-1e9f32, device)?.broadcast_as(mask.shape())?;
+    let scores = mask.where_cond(&min_value, &scores)?;
+    let scores = candle_nn::ops::softmax_last_dim(&scores)?;
+    // 被屏蔽的压缩key概率置零后按query求和
+    let scores = mask.where_cond(&scores.zeros_like()?, &scores)?.sum(1)?;
+    // 按压缩key起始位置归到block, 补零后求每个block内的最大值
+    let pad = num_blocks * kernels_per_block - num_kernels;
+    let scores = if pad > 0 {
+        let zeros = Tensor::zeros((num_kv_heads, pad), DType::F32, device)?;
+        Tensor::cat(&[&scores, &zeros], 1)?
+    } else {
+        scores
+    };
+    let scores = scores
+        .reshape((num_kv_heads, num_blocks, kernels_per_block))?
+        .max(D::Minus1)?;
+    Ok(scores.to_vec2::<f32>()?)
+}
+
+// 开头init_blocks个block和覆盖[pos_min - window_size + 1, pos_max]的block必选, 其余按分数补足topk
+// 每个kv头选择的block数相同
+fn select_blocks(
+    scores: &[Vec<f32>],
+    pos_min: usize,
+    pos_max: usize,
+    valid_blocks: usize,
+    cfg: &SparseConfig,
+) -> Vec<Vec<usize>> {
+    let window_start = (pos_min + 1).saturating_sub(cfg.window_size) / cfg.block_size;
+    let forced =
+        |b: usize| b < cfg.init_blocks || (b >= window_start && b <= pos_max / cfg.block_size);
+    scores
+        .iter()
+        .map(|head| {
+            let mut selected: Vec<usize> = (0..valid_blocks).filter(|&b| forced(b)).collect();
+            let mut rest: Vec<usize> = (0..valid_blocks).filter(|&b| !forced(b)).collect();
+            rest.sort_by(|&a, &b| head[b].total_cmp(&head[a]));
+            let remain = cfg.topk.saturating_sub(selected.len());
+            selected.extend(rest.into_iter().take(remain));
+            selected.sort();
+            selected
+        })
+        .collect()
+}
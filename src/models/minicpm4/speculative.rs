@@ -0,0 +1,211 @@
+use aha_openai_dive::v1::resources::chat::{
+    ChatCompletionChunkResponse, ChatCompletionParameters, ChatCompletionResponse,
+};
+use anyhow::{Result, anyhow};
+use candle_core::{DType, Device, Tensor};
+use rocket::async_stream::stream;
+use rocket::futures::Stream;
+
+use crate::models::common::{
+    quant::QuantType,
+    speculative::{SpeculativeLM, SpeculativeSampler},
+};
+use crate::models::minicpm4::{generate::MiniCPMGenerateModel, model::MiniCPMModel};
+use crate::utils::{
+    build_completion_chunk_response, build_completion_response, completion_usage, get_device,
+};
+use crate::{
+    chat_template::ChatTemplate,
+    models::GenerateModel,
+    tokenizer::{StreamDecoder, TokenizerModel},
+};
+
+// 默认每轮草稿token数
+const DEFAULT_NUM_DRAFT_TOKENS: usize = 4;
+
+impl SpeculativeLM for MiniCPMModel {
+    fn forward_last(&mut self, input_ids: &[u32], position_id: usize) -> Result<Vec<f32>> {
+        let device = self.device();
+        let input_ids = Tensor::from_slice(input_ids, (1, input_ids.len()), &device)?;
+        let logits = self.forward_with_cache(&input_ids, position_id)?;
+        let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+        Ok(logits.to_vec1::<f32>()?)
+    }
+
+    fn forward_all(&mut self, input_ids: &[u32], position_id: usize) -> Result<Vec<Vec<f32>>> {
+        let device = self.device();
+        let input_ids = Tensor::from_slice(input_ids, (1, input_ids.len()), &device)?;
+        let logits = self.forward_all_with_cache(&input_ids, position_id)?;
+        let logits = logits.squeeze(0)?.to_dtype(DType::F32)?;
+        Ok(logits.to_vec2::<f32>()?)
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        MiniCPMModel::truncate_kv_cache(self, len)
+    }
+}
+
+// 小模型(如MiniCPM4-0.5B)作为草稿模型, 大模型(如MiniCPM4-8B)验证, 两者需要使用相同的tokenizer
+pub struct MiniCPMSpeculativeModel<'a> {
+    chat_template: ChatTemplate<'a>,
+    tokenizer: TokenizerModel,
+    target: MiniCPMModel,
+    draft: MiniCPMModel,
+    endoftext_id: u32,
+    im_end_id: u32,
+    num_draft_tokens: usize,
+    // 上一次生成中草稿token的数量和被接受的数量
+    drafted: usize,
+    accepted: usize,
+}
+
+impl<'a> MiniCPMSpeculativeModel<'a> {
+    pub fn init(
+        target_path: &str,
+        draft_path: &str,
+        device: Option<&Device>,
+        dtype: Option<DType>,
+    ) -> Result<Self> {
+        Self::init_with_quant(target_path, draft_path, device, dtype, None)
+    }
+
+    pub fn init_with_quant(
+        target_path: &str,
+        draft_path: &str,
+        device: Option<&Device>,
+        dtype: Option<DType>,
+        quant: Option<QuantType>,
+    ) -> Result<Self> {
+        let chat_template = ChatTemplate::init(target_path)?;
+        let tokenizer = TokenizerModel::init(target_path)?;
+        let device = &get_device(device);
+        let target = MiniCPMGenerateModel::load_model(target_path, device, dtype, quant)?;
+        let draft = MiniCPMGenerateModel::load_model(draft_path, device, dtype, quant)?;
+        let (target_cfg, draft_cfg) = (target.config(), draft.config());
+        if target_cfg.vocab_size != draft_cfg.vocab_size
+            || target_cfg.eos_token_id != draft_cfg.eos_token_id
+        {
+            return Err(anyhow!(format!(
+                "draft model vocab_size {} / eos {:?} does not match target model {} / {:?}",
+                draft_cfg.vocab_size,
+                draft_cfg.eos_token_id,
+                target_cfg.vocab_size,
+                target_cfg.eos_token_id
+            )));
+        }
+        let endoftext_id = target_cfg.eos_token_id[0];
+        let im_end_id = target_cfg.eos_token_id[1];
+        Ok(Self {
+            chat_template,
+            tokenizer,
+            target,
+            draft,
+            endoftext_id,
+            im_end_id,
+            num_draft_tokens: DEFAULT_NUM_DRAFT_TOKENS,
+            drafted: 0,
+            accepted: 0,
+        })
+    }
+
+    pub fn set_num_draft_tokens(&mut self, num_draft_tokens: usize) {
+        self.num_draft_tokens = num_draft_tokens.max(1);
+    }
+
+    // 上一次生成中草稿token的接受率
+    pub fn acceptance_rate(&self) -> f32 {
+        if self.drafted == 0 {
+            0.0
+        } else {
+            self.accepted as f32 / self.drafted as f32
+        }
+    }
+
+    fn prompt_ids(&self, mes: &ChatCompletionParameters) -> Result<Vec<u32>> {
+        let mes_render = self.chat_template.apply_chat_template(mes)?;
+        let input_ids = self
+            .tokenizer
+            .text_encode(mes_render, &self.target.device())?;
+        Ok(input_ids.squeeze(0)?.to_vec1::<u32>()?)
+    }
+
+    fn is_eos(&self, token: u32) -> bool {
+        token == self.endoftext_id || token == self.im_end_id
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.target.clear_kv_cache();
+        self.draft.clear_kv_cache();
+    }
+}
+
+impl<'a> GenerateModel for MiniCPMSpeculativeModel<'a> {
+    fn generate(&mut self, mes: ChatCompletionParameters) -> Result<ChatCompletionResponse> {
+        let mut sampler =
+            SpeculativeSampler::new(mes.temperature, mes.top_p, self.num_draft_tokens);
+        let prompt = self.prompt_ids(&mes)?;
+        let sample_len = mes.max_tokens.unwrap_or(2048) as usize;
+        let mut position = prompt.len();
+        let mut token = sampler.prefill(&mut self.target, &mut self.draft, &prompt)?;
+        let mut generate = vec![token];
+        while !self.is_eos(token) && generate.len() < sample_len {
+            let tokens = sampler.step(&mut self.target, &mut self.draft, token, position)?;
+            position += tokens.len();
+            for t in tokens {
+                generate.push(t);
+                token = t;
+                if self.is_eos(t) || generate.len() >= sample_len {
+                    break;
+                }
+            }
+        }
+        self.drafted = sampler.drafted();
+        self.accepted = sampler.accepted();
+        let completion_tokens = generate.len();
+        let res = self.tokenizer.token_decode(generate)?;
+        self.clear_kv_cache();
+        let mut response = build_completion_response(res, "minicpm");
+        response.usage = Some(completion_usage(prompt.len(), completion_tokens)?);
+        Ok(response)
+    }
+
+    fn generate_stream(
+        &mut self,
+        mes: ChatCompletionParameters,
+    ) -> Result<impl Stream<Item = Result<ChatCompletionChunkResponse, anyhow::Error>>> {
+        let mut sampler =
+            SpeculativeSampler::new(mes.temperature, mes.top_p, self.num_draft_tokens);
+        let prompt = self.prompt_ids(&mes)?;
+        let sample_len = mes.max_tokens.unwrap_or(512) as usize;
+        let stream = stream! {
+            let mut position = prompt.len();
+            let first_token = sampler.prefill(&mut self.target, &mut self.draft, &prompt)?;
+            let mut tokens = vec![first_token];
+            let mut generated = 0;
+            let mut decoder = StreamDecoder::default();
+            'outer: loop {
+                let mut last_token = 0;
+                for next_token in tokens {
+                    last_token = next_token;
+                    generated += 1;
+                    if let Some(text) = decoder.step(&self.tokenizer, next_token)? {
+                        let chunk = build_completion_chunk_response(text, "minicpm", None, None);
+                        yield Ok(chunk);
+                    }
+                    if self.is_eos(next_token) || generated >= sample_len {
+                        break 'outer;
+                    }
+                }
+                tokens = sampler.step(&mut self.target, &mut self.draft, last_token, position)?;
+                position += tokens.len();
+            }
+            if let Some(text) = decoder.flush(&self.tokenizer)? {
+                yield Ok(build_completion_chunk_response(text, "minicpm", None, None));
+            }
+            self.drafted = sampler.drafted();
+            self.accepted = sampler.accepted();
+            self.clear_kv_cache();
+        };
+        Ok(stream)
+    }
+}
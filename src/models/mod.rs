@@ -7,9 +7,16 @@ pub mod voxcpm;
 use aha_openai_dive::v1::resources::chat::{
     ChatCompletionChunkResponse, ChatCompletionParameters, ChatCompletionResponse,
 };
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use candle_core::Tensor;
 use rocket::futures::Stream;
 
+use crate::models::common::{
+    embedding::Pooling,
+    parity::{GoldenData, ParityReport, ParityTolerance},
+    scoring::ScoreOutput,
+};
+
 pub trait GenerateModel {
     fn generate(&mut self, mes: ChatCompletionParameters) -> Result<ChatCompletionResponse>;
     fn generate_stream(
@@ -19,3 +26,38 @@ pub trait GenerateModel {
     where
         Self: Sized;
 }
+
+// 返回池化后未归一化的向量和输入的token数
+pub trait EmbeddingModel {
+    // 池化前向量的维度
+    fn hidden_size(&self) -> usize;
+    fn embed_text(&mut self, text: &str, pooling: Pooling) -> Result<(Vec<f32>, usize)>;
+    // 多条文本一起输入, 默认逐条计算
+    fn embed_texts(&mut self, texts: &[&str], pooling: Pooling) -> Result<Vec<(Vec<f32>, usize)>> {
+        texts
+            .iter()
+            .map(|text| self.embed_text(text, pooling))
+            .collect()
+    }
+    fn embed_image(&mut self, _url: &str, _pooling: Pooling) -> Result<(Vec<f32>, usize)> {
+        Err(anyhow!("model does not support image embeddings"))
+    }
+}
+
+// 单次prefill得到每个token的logprob和困惑度, 不生成
+pub trait ScoringModel {
+    // 不使用对话模板, 直接对文本打分
+    fn score_text(&mut self, text: &str) -> Result<ScoreOutput>;
+    // 用对话模板渲染后打分
+    fn score(&mut self, mes: ChatCompletionParameters) -> Result<ScoreOutput>;
+}
+
+// 与参考实现导出的golden逐项对比数值, 报告每层的误差
+pub trait ParityModel {
+    // 用golden中的输入计算输出, 名字与golden的key一致, 按forward的顺序排列
+    fn parity_outputs(&mut self, golden: &GoldenData) -> Result<Vec<(String, Tensor)>>;
+    fn parity(&mut self, golden: &GoldenData, tolerance: ParityTolerance) -> Result<ParityReport> {
+        let outputs = self.parity_outputs(golden)?;
+        ParityReport::compare_outputs(&outputs, golden, tolerance)
+    }
+}
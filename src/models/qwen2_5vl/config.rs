@@ -1,5 +1,11 @@
+use anyhow::Result;
 use candle_nn::Activation;
 
+use crate::models::common::{
+    manifest::{check_attention, check_positive},
+    quant::QuantSource,
+};
+
 #[derive(Debug, Clone, PartialEq, serde::Deserialize)]
 pub struct VisionConfig {
     pub depth: usize,
@@ -51,6 +57,59 @@ pub struct Qwen2_5VLConfig {
     pub vision_config: VisionConfig,
     pub rope_scaling: RopeScaling,
     pub vocab_size: usize,
+    // 不从config.json读取, 加载模型时设置
+    #[serde(skip)]
+    pub quant: QuantSource,
+}
+
+impl Qwen2_5VLConfig {
+    // 配置字段的合理性检查, 返回所有问题
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = check_attention(
+            "text",
+            self.hidden_size,
+            self.num_attention_heads,
+            self.num_key_value_heads,
+            None,
+        );
+        check_positive(&mut errors, "num_hidden_layers", self.num_hidden_layers);
+        check_positive(&mut errors, "vocab_size", self.vocab_size);
+        if self.num_attention_heads > 0 {
+            let half_dim = self.hidden_size / self.num_attention_heads / 2;
+            let section: usize = self.rope_scaling.mrope_section.iter().sum();
+            if section != half_dim {
+                errors.push(format!(
+                    "sum of rope_scaling.mrope_section {:?} != head_dim / 2 ({})",
+                    self.rope_scaling.mrope_section, half_dim
+                ));
+            }
+        }
+        let vision = &self.vision_config;
+        errors.extend(check_attention(
+            "vision",
+            vision.hidden_size,
+            vision.num_heads,
+            vision.num_heads,
+            None,
+        ));
+        check_positive(&mut errors, "vision_config.patch_size", vision.patch_size);
+        check_positive(
+            &mut errors,
+            "vision_config.spatial_merge_size",
+            vision.spatial_merge_size,
+        );
+        if let Some(idx) = vision
+            .fullatt_block_indexes
+            .iter()
+            .find(|i| **i >= vision.depth)
+        {
+            errors.push(format!(
+                "vision_config.fullatt_block_indexes contains {} >= depth {}",
+                idx, vision.depth
+            ));
+        }
+        errors
+    }
 }
 
 pub struct VisionSetting {
@@ -72,6 +131,62 @@ pub struct VisionSetting {
     pub image_std: Vec<f32>,
 }
 
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct PreprocessorSize {
+    pub longest_edge: u32,
+    pub shortest_edge: u32,
+}
+
+// preprocessor_config.json, 没有的字段使用VisionSetting的默认值
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+pub struct Qwen2_5VLPreprocessorConfig {
+    pub min_pixels: Option<u32>,
+    pub max_pixels: Option<u32>,
+    // 新版transformers保存为size, 与min_pixels/max_pixels含义相同
+    pub size: Option<PreprocessorSize>,
+    pub patch_size: Option<usize>,
+    pub temporal_patch_size: Option<usize>,
+    pub merge_size: Option<usize>,
+    pub image_mean: Option<Vec<f32>>,
+    pub image_std: Option<Vec<f32>>,
+}
+
+impl VisionSetting {
+    // 模型目录下没有preprocessor_config.json时使用默认值
+    pub fn from_model_path(path: &str) -> Result<Self> {
+        let cfg_file = path.to_string() + "/preprocessor_config.json";
+        let mut setting = Self::default();
+        if !std::path::Path::new(&cfg_file).exists() {
+            return Ok(setting);
+        }
+        let cfg: Qwen2_5VLPreprocessorConfig = serde_json::from_slice(&std::fs::read(cfg_file)?)?;
+        let size = cfg.size.as_ref();
+        if let Some(min_pixels) = cfg.min_pixels.or(size.map(|s| s.shortest_edge)) {
+            setting.min_pixels = min_pixels;
+        }
+        if let Some(max_pixels) = cfg.max_pixels.or(size.map(|s| s.longest_edge)) {
+            setting.max_pixels = max_pixels;
+        }
+        if let Some(patch_size) = cfg.patch_size {
+            setting.patch_size = patch_size;
+        }
+        if let Some(temporal_patch_size) = cfg.temporal_patch_size {
+            setting.temporal_patch_size = temporal_patch_size;
+        }
+        if let Some(merge_size) = cfg.merge_size {
+            setting.merge_size = merge_size;
+        }
+        if let Some(image_mean) = cfg.image_mean {
+            setting.image_mean = image_mean;
+        }
+        if let Some(image_std) = cfg.image_std {
+            setting.image_std = image_std;
+        }
+        setting.image_factor = (setting.patch_size * setting.merge_size) as u32;
+        Ok(setting)
+    }
+}
+
 impl Default for VisionSetting {
     fn default() -> Self {
         Self {
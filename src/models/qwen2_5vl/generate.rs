@@ -2,24 +2,34 @@
 use aha_openai_dive::v1::resources::chat::{
     ChatCompletionChunkResponse, ChatCompletionParameters, ChatCompletionResponse,
 };
-use anyhow::{Result, anyhow};
-use candle_core::{D, DType, Device, IndexOp, Tensor};
-use candle_nn::VarBuilder;
+use anyhow::Result;
+use candle_core::{DType, Device, IndexOp, Tensor};
 use rocket::async_stream::stream;
 use rocket::futures::Stream;
 
+use crate::models::common::{
+    gguf::GgufFile,
+    manifest::{WeightReport, checked_var_builder},
+    quant::{QuantSource, QuantType},
+    scoring::{ScoreOutput, score_tokens, token_logprobs},
+    search::{BeamSearchConfig, Hypothesis, beam_search, best_of_n},
+    vision_cache::{
+        CachedVision, SharedVisionCache, cached_vision, grid_thw_tensor, vision_cache_key,
+    },
+};
 use crate::models::qwen2_5vl::config::Qwen2_5VLConfig;
 use crate::utils::{
-    build_completion_chunk_response, build_completion_response, find_type_files, get_device,
+    build_completion_chunk_response, build_completion_response, completion_usage, get_device,
     get_dtype, get_logit_processor,
+    vision_utils::{MediaRequest, RequestVisionOverrides},
 };
 use crate::{
     chat_template::ChatTemplate,
     models::{
-        GenerateModel,
+        GenerateModel, ScoringModel,
         qwen2_5vl::{model::Qwen2_5VLModel, processor::Qwen2_5VLProcessor},
     },
-    tokenizer::TokenizerModel,
+    tokenizer::{StreamDecoder, TokenizerModel},
 };
 
 pub struct Qwen2_5VLGenerateModel<'a> {
@@ -30,24 +40,61 @@ pub struct Qwen2_5VLGenerateModel<'a> {
     device: Device,
     endoftext_id: u32,
     im_end_id: u32,
+    model_path: String,
+    vision_cache: Option<SharedVisionCache>,
+    request_overrides: Option<RequestVisionOverrides>,
 }
 
 impl<'a> Qwen2_5VLGenerateModel<'a> {
     pub fn init(path: &str, device: Option<&Device>, dtype: Option<DType>) -> Result<Self> {
+        Self::init_with_quant(path, device, dtype, None)
+    }
+
+    // 不读取权重, 检查配置和目录下的权重名/shape是否与模型一致
+    pub fn check_model(path: &str) -> Result<WeightReport> {
+        let config_path = path.to_string() + "/config.json";
+        let mut cfg: Qwen2_5VLConfig = serde_json::from_slice(&std::fs::read(config_path)?)?;
+        let config_errors = cfg.check();
+        if !config_errors.is_empty() {
+            return Ok(WeightReport::from_config_errors(config_errors));
+        }
+        cfg.quant = QuantType::from_model_path(path)?.into();
+        let dtype = get_dtype(None, cfg.torch_dtype.as_str());
+        let gguf = GgufFile::find(path)?;
+        let (vb, checker) =
+            checked_var_builder(path, gguf, "model.", "lm_head", dtype, &Device::Cpu, true)?;
+        Ok(checker.finish_report(Qwen2_5VLModel::new(cfg, vb)))
+    }
+
+    // quant为解码层Linear的weight-only量化方式, 目录下有quantization.json时以其为准
+    pub fn init_with_quant(
+        path: &str,
+        device: Option<&Device>,
+        dtype: Option<DType>,
+        quant: Option<QuantType>,
+    ) -> Result<Self> {
         let chat_template = ChatTemplate::init(path)?;
         let tokenizer = TokenizerModel::init(path)?;
         let config_path = path.to_string() + "/config.json";
-        let cfg: Qwen2_5VLConfig = serde_json::from_slice(&std::fs::read(config_path)?)?;
+        let mut cfg: Qwen2_5VLConfig = serde_json::from_slice(&std::fs::read(config_path)?)?;
+        WeightReport::from_config_errors(cfg.check()).ensure_ok()?;
+        // gguf中只有文本解码器, 视觉部分仍从safetensors读取
+        let gguf = GgufFile::find(path)?;
+        cfg.quant = QuantSource::new(
+            QuantType::from_model_path(path)?.or(quant),
+            gguf.as_ref(),
+            "model.",
+            "lm_head",
+        )?;
         let device = &get_device(device);
         let cfg_dtype = cfg.torch_dtype.as_str();
         let dtype = get_dtype(dtype, cfg_dtype);
-        let pre_processor = Qwen2_5VLProcessor::new(device, dtype)?;
+        let pre_processor = Qwen2_5VLProcessor::new(path, device, dtype)?;
         let endoftext_id = cfg.bos_token_id;
         let im_end_id = cfg.eos_token_id;
-        // let model_list = find_safetensors_files(&path)?;
-        let model_list = find_type_files(path, "safetensors")?;
-        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&model_list, dtype, device)? };
-        let qwen2_5_vl = Qwen2_5VLModel::new(cfg, vb)?;
+        let (vb, checker) =
+            checked_var_builder(path, gguf, "model.", "lm_head", dtype, device, false)?;
+        let qwen2_5_vl = checker.finish(Qwen2_5VLModel::new(cfg, vb))?;
 
         Ok(Qwen2_5VLGenerateModel {
             chat_template,
@@ -57,65 +104,202 @@ impl<'a> Qwen2_5VLGenerateModel<'a> {
             device: device.clone(),
             endoftext_id,
             im_end_id,
+            model_path: path.to_string(),
+            vision_cache: None,
+            request_overrides: None,
         })
     }
+
+    // 多个请求/多个同权重的模型实例共用视觉特征缓存, 以模型路径区分不同权重, None关闭缓存
+    pub fn set_vision_cache(&mut self, cache: Option<SharedVisionCache>) {
+        self.vision_cache = cache;
+    }
+
+    // 下一次请求使用的图片/视频设置, 代替从ChatCompletionParameters中读取, 用完即清除
+    pub fn set_request_overrides(&mut self, overrides: RequestVisionOverrides) {
+        self.request_overrides = Some(overrides);
+    }
+
+    fn take_request_overrides(
+        &mut self,
+        mes: &ChatCompletionParameters,
+    ) -> Result<RequestVisionOverrides> {
+        match self.request_overrides.take() {
+            Some(overrides) => Ok(overrides),
+            None => RequestVisionOverrides::from_request(mes),
+        }
+    }
+
+    // 预处理requests中下标为indices的图片/视频, 一起计算视觉特征
+    fn compute_vision(
+        &self,
+        requests: &[MediaRequest],
+        indices: &[usize],
+    ) -> Result<Vec<CachedVision>> {
+        let inputs = indices
+            .iter()
+            .map(|&i| self.pre_processor.process_media(&requests[i]))
+            .collect::<Result<Vec<_>>>()?;
+        let pixel_values: Vec<&Tensor> = inputs.iter().map(|m| &m.data).collect();
+        let grid_thw: Vec<&Tensor> = inputs.iter().map(|m| &m.grid_thw).collect();
+        let features = self
+            .qwen2_5_vl
+            .vision_features(&Tensor::cat(&pixel_values, 0)?, &Tensor::cat(&grid_thw, 0)?)?;
+        inputs
+            .into_iter()
+            .zip(features)
+            .map(|(input, feature)| {
+                Ok(CachedVision {
+                    feature,
+                    grid_thw: input.grid_thw.i(0)?.to_vec1::<u32>()?,
+                    timestamps: vec![],
+                })
+            })
+            .collect()
+    }
+
+    // 输入完整prompt, 图片/视频按来源和预处理设置查视觉特征缓存, 只预处理未命中的
+    // 返回最后位置的logits和prompt长度
+    fn prefill_logits(&mut self, mes: &ChatCompletionParameters) -> Result<(Tensor, usize)> {
+        let mes_render = self.chat_template.apply_chat_template(mes)?;
+        let overrides = self.take_request_overrides(mes)?;
+        let (image_requests, video_requests) =
+            self.pre_processor.media_requests(mes, &overrides)?;
+        let second_per_grid_ts = video_requests
+            .iter()
+            .map(|request| self.pre_processor.second_per_grid(&request.overrides))
+            .collect::<Result<Vec<_>>>()?;
+        let num_images = image_requests.len();
+        let requests: Vec<MediaRequest> =
+            image_requests.into_iter().chain(video_requests).collect();
+        let keys = requests
+            .iter()
+            .map(|request| vision_cache_key(&self.model_path, request))
+            .collect::<Result<Vec<_>>>()?;
+        let (values, _) = cached_vision(self.vision_cache.as_ref(), &keys, |missing| {
+            self.compute_vision(&requests, missing)
+        })?;
+        let (images, videos) = values.split_at(num_images);
+        let images: Vec<&CachedVision> = images.iter().collect();
+        let videos: Vec<&CachedVision> = videos.iter().collect();
+        let text = self
+            .pre_processor
+            .replace_text(&mes_render, &images, &videos)?;
+        let input_ids = self.tokenizer.text_encode(text, &self.device)?;
+        let embeds = |items: &[&CachedVision]| -> Result<Option<Tensor>> {
+            if items.is_empty() {
+                return Ok(None);
+            }
+            let embeds: Vec<&Tensor> = items.iter().map(|v| &v.feature.0).collect();
+            Ok(Some(Tensor::cat(&embeds, 0)?))
+        };
+        let logits = self.qwen2_5_vl.forward_features(
+            &input_ids,
+            embeds(&images)?,
+            grid_thw_tensor(&images, &self.device)?.as_ref(),
+            embeds(&videos)?,
+            grid_thw_tensor(&videos, &self.device)?.as_ref(),
+            (!videos.is_empty()).then_some(second_per_grid_ts),
+        )?;
+        Ok((logits, input_ids.dim(1)?))
+    }
+
+    fn prefill(&mut self, mes: &ChatCompletionParameters) -> Result<(Vec<f32>, usize)> {
+        let (logits, seq_len) = self.prefill_logits(mes)?;
+        let logits = logits
+            .flatten_all()?
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?;
+        Ok((logits, seq_len))
+    }
+
+    // 解码阶段只用rope_deltas计算位置, mask不参与计算
+    fn forward_next(&mut self, token: u32, seqlen_offset: usize) -> Result<Tensor> {
+        let input_ids = Tensor::from_vec(vec![token], (1, 1), &self.device)?;
+        let mask = Tensor::ones((1, seqlen_offset + 1), DType::U32, &self.device)?;
+        let cache_position = Tensor::from_vec(vec![seqlen_offset as u32], 1, &self.device)?;
+        self.qwen2_5_vl.forward(
+            &input_ids,
+            None,
+            None,
+            None,
+            None,
+            &mask,
+            Some(&cache_position),
+            seqlen_offset,
+            None,
+        )
+    }
+
+    fn decode_hypotheses(&self, mut hyps: Vec<Hypothesis>) -> Result<Vec<Hypothesis>> {
+        for hyp in hyps.iter_mut() {
+            hyp.text = self.tokenizer.token_decode(hyp.tokens.clone())?;
+        }
+        Ok(hyps)
+    }
+
+    // 确定性的束搜索, 返回得分最高的cfg.num_return_sequences个结果
+    pub fn beam_search(
+        &mut self,
+        mes: ChatCompletionParameters,
+        cfg: &BeamSearchConfig,
+    ) -> Result<Vec<Hypothesis>> {
+        let (logits, seq_len) = self.prefill(&mes)?;
+        let eos_token_ids = [self.endoftext_id, self.im_end_id];
+        let hyps = beam_search(&mut self.qwen2_5_vl, logits, seq_len, &eos_token_ids, cfg);
+        self.qwen2_5_vl.clear_kv_cache();
+        self.decode_hypotheses(hyps?)
+    }
+
+    // 采样n次, 按累计logprob从高到低返回
+    pub fn best_of_n(
+        &mut self,
+        mes: ChatCompletionParameters,
+        n: usize,
+    ) -> Result<Vec<Hypothesis>> {
+        let mut logit_processor = get_logit_processor(mes.temperature, mes.top_p, None);
+        let (logits, seq_len) = self.prefill(&mes)?;
+        let eos_token_ids = [self.endoftext_id, self.im_end_id];
+        let sample_len = mes.max_tokens.unwrap_or(1024) as usize;
+        let hyps = best_of_n(
+            &mut self.qwen2_5_vl,
+            logits,
+            seq_len,
+            &eos_token_ids,
+            n,
+            sample_len,
+            &mut logit_processor,
+        );
+        self.qwen2_5_vl.clear_kv_cache();
+        self.decode_hypotheses(hyps?)
+    }
 }
 
 impl<'a> GenerateModel for Qwen2_5VLGenerateModel<'a> {
     fn generate(&mut self, mes: ChatCompletionParameters) -> Result<ChatCompletionResponse> {
         let mut logit_processor = get_logit_processor(mes.temperature, mes.top_p, None);
-        let mes_render = self.chat_template.apply_chat_template(&mes)?;
-        let input = self.pre_processor.process_info(&mes, &mes_render)?;
-        let mut input_ids = self
-            .tokenizer
-            .text_encode(input.replace_text.clone(), &self.device)?;
-        let mut seq_len = input_ids.dim(1)?;
-        let mut seqlen_offset = 0;
-        let mut pixel_values = input.pixel_values.as_ref();
-        let image_grid_thw = input.image_grid_thw.as_ref();
-        let mut pixel_values_video = input.pixel_values_video.as_ref();
-        let video_grid_thw = input.video_grid_thw.as_ref();
-        let second_per_grid_ts = input.second_per_grid_ts.clone();
-
-        let mut mask = Tensor::ones_like(&input_ids)?;
-        let mut cache_position = Tensor::ones_like(&input_ids.i(0)?)?
-            .to_dtype(candle_core::DType::F64)?
-            .cumsum(D::Minus1)?
-            .to_dtype(candle_core::DType::U32)?
-            .broadcast_sub(&Tensor::new(vec![1_u32], input_ids.device())?)?;
-
+        let (mut logits, mut seqlen_offset) = self.prefill_logits(&mes)?;
+        let prompt_tokens = seqlen_offset;
         let mut generate = Vec::new();
-        let sample_len = mes.max_tokens.unwrap_or(1024);
-        for _ in 0..sample_len {
-            let logits = self.qwen2_5_vl.forward(
-                &input_ids,
-                pixel_values,
-                image_grid_thw,
-                pixel_values_video,
-                video_grid_thw,
-                &mask,
-                Some(&cache_position),
-                seqlen_offset,
-                second_per_grid_ts.clone(),
-            )?;
-            let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
-            let next_token = logit_processor.sample(&logits)?;
+        let sample_len = mes.max_tokens.unwrap_or(1024) as usize;
+        loop {
+            let next_token =
+                logit_processor.sample(&logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?)?;
             generate.push(next_token);
-            if next_token == self.endoftext_id || next_token == self.im_end_id {
+            if next_token == self.endoftext_id
+                || next_token == self.im_end_id
+                || generate.len() >= sample_len
+            {
                 break;
             }
-            seqlen_offset += seq_len;
-            seq_len = 1;
-            input_ids = Tensor::from_vec(vec![next_token], (1, 1), &self.device)?;
-            let appendd_mask = Tensor::ones((1, 1), mask.dtype(), &self.device)?;
-            mask = Tensor::cat(&[mask, appendd_mask], 1)?;
-            cache_position = Tensor::from_vec(vec![seqlen_offset as u32], 1, &self.device)?;
-            pixel_values = None;
-            pixel_values_video = None;
+            logits = self.forward_next(next_token, seqlen_offset)?;
+            seqlen_offset += 1;
         }
+        let completion_tokens = generate.len();
         let res = self.tokenizer.token_decode(generate)?;
         self.qwen2_5_vl.clear_kv_cache();
-        let response = build_completion_response(res, "qwen2.5vl");
+        let mut response = build_completion_response(res, "qwen2.5vl");
+        response.usage = Some(completion_usage(prompt_tokens, completion_tokens)?);
         Ok(response)
     }
 
@@ -124,85 +308,78 @@ impl<'a> GenerateModel for Qwen2_5VLGenerateModel<'a> {
         mes: ChatCompletionParameters,
     ) -> Result<impl Stream<Item = Result<ChatCompletionChunkResponse, anyhow::Error>>> {
         let mut logit_processor = get_logit_processor(mes.temperature, mes.top_p, None);
-        let mes_render = self.chat_template.apply_chat_template(&mes)?;
-        let input = self.pre_processor.process_info(&mes, &mes_render)?;
-        let mut input_ids = self
-            .tokenizer
-            .text_encode(input.replace_text.clone(), &self.device)?;
-        let mut seq_len = input_ids.dim(1)?;
-        let mut seqlen_offset = 0;
-        let pixel_values = input.pixel_values.clone();
-        let image_grid_thw = input.image_grid_thw.clone();
-        let pixel_values_video = input.pixel_values_video.clone();
-        let video_grid_thw = input.video_grid_thw.clone();
-        let second_per_grid_ts = input.second_per_grid_ts.clone();
-
-        let mut mask = Tensor::ones_like(&input_ids)?;
-        let mut cache_position = Tensor::ones_like(&input_ids.i(0)?)?
-            .to_dtype(candle_core::DType::F64)?
-            .cumsum(D::Minus1)?
-            .to_dtype(candle_core::DType::U32)?
-            .broadcast_sub(&Tensor::new(vec![1_u32], input_ids.device())?)?;
-
-        let sample_len = mes.max_tokens.unwrap_or(512);
+        let (mut logits, mut seqlen_offset) = self.prefill_logits(&mes)?;
+        let sample_len = mes.max_tokens.unwrap_or(512) as usize;
         let stream = stream! {
-            let mut error_tokens = Vec::new();
-            let mut pixel_values = pixel_values.as_ref();
-            let image_grid_thw = image_grid_thw.as_ref();
-            let mut pixel_values_video = pixel_values_video.as_ref();
-            let video_grid_thw = video_grid_thw.as_ref();
-            for _ in 0..sample_len {
-                let logits = self.qwen2_5_vl.forward(
-                    &input_ids,
-                    pixel_values,
-                    image_grid_thw,
-                    pixel_values_video,
-                    video_grid_thw,
-                    &mask,
-                    Some(&cache_position),
-                    seqlen_offset,
-                    second_per_grid_ts.clone(),
-                )?;
-                let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
-                let next_token = logit_processor.sample(&logits)?;
-                let mut decode_ids = Vec::new();
-                if !error_tokens.is_empty() {
-                    decode_ids.extend_from_slice(&error_tokens);
+            let mut decoder = StreamDecoder::default();
+            let mut generated = 0;
+            loop {
+                let logits_ = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+                let next_token = logit_processor.sample(&logits_)?;
+                generated += 1;
+                if let Some(text) = decoder.step(&self.tokenizer, next_token)? {
+                    let chunk = build_completion_chunk_response(text, "qwen2.5vl", None, None);
+                    yield Ok(chunk);
                 }
-                decode_ids.push(next_token);
-                let decoded_token = self.tokenizer.token_decode(decode_ids).map_err(|e| anyhow!(format!("stream decode error{}", e)))?;
-                if decoded_token.contains("�") {
-                    error_tokens.push(next_token);
-                    if error_tokens.len() > 3 {
-                        error_tokens.clear();
-                    }
-                    seqlen_offset += seq_len;
-                    seq_len = 1;
-                    input_ids = Tensor::from_vec(vec![next_token], (1, 1), &self.device)?;
-                    let appendd_mask = Tensor::ones((1, 1), mask.dtype(), &self.device)?;
-                    mask = Tensor::cat(&[mask, appendd_mask], 1)?;
-                    cache_position = Tensor::from_vec(vec![seqlen_offset as u32], 1, &self.device)?;
-                    pixel_values = None;
-                    pixel_values_video = None;
-                    continue;
-                }
-                error_tokens.clear();
-                let chunk = build_completion_chunk_response(decoded_token, "qwen2.5vl", None, None);
-                yield Ok(chunk);
-                if next_token == self.endoftext_id || next_token == self.im_end_id {
+                if next_token == self.endoftext_id
+                    || next_token == self.im_end_id
+                    || generated >= sample_len
+                {
                     break;
                 }
-                seqlen_offset += seq_len;
-                seq_len = 1;
-                input_ids = Tensor::from_vec(vec![next_token], (1, 1), &self.device)?;
-                let appendd_mask = Tensor::ones((1, 1), mask.dtype(), &self.device)?;
-                mask = Tensor::cat(&[mask, appendd_mask], 1)?;
-                cache_position = Tensor::from_vec(vec![seqlen_offset as u32], 1, &self.device)?;
-                pixel_values = None;
-                pixel_values_video = None;
+                logits = self.forward_next(next_token, seqlen_offset)?;
+                seqlen_offset += 1;
+            }
+            if let Some(text) = decoder.flush(&self.tokenizer)? {
+                yield Ok(build_completion_chunk_response(text, "qwen2.5vl", None, None));
             }
             self.qwen2_5_vl.clear_kv_cache();
         };
         Ok(stream)
     }
 }
+
+impl<'a> ScoringModel for Qwen2_5VLGenerateModel<'a> {
+    fn score_text(&mut self, text: &str) -> Result<ScoreOutput> {
+        let input_ids = self.tokenizer.text_encode(text.to_string(), &self.device)?;
+        let hidden_states = self
+            .qwen2_5_vl
+            .forward_hidden(&input_ids, None, None, None, None, None)?
+            .squeeze(0)?;
+        let ids = input_ids.squeeze(0)?.to_vec1::<u32>()?;
+        let logprobs = token_logprobs(&hidden_states, &ids, |h| self.qwen2_5_vl.hidden_logits(h))?;
+        score_tokens(&ids, &logprobs, &[], |id| {
+            self.tokenizer.token_decode(vec![id])
+        })
+    }
+
+    // 图片/视频的占位token不计入困惑度
+    fn score(&mut self, mes: ChatCompletionParameters) -> Result<ScoreOutput> {
+        let mes_render = self.chat_template.render(&mes, false)?;
+        let overrides = self.take_request_overrides(&mes)?;
+        let input = self
+            .pre_processor
+            .process_info(&mes, &mes_render, &overrides)?;
+        let input_ids = self
+            .tokenizer
+            .text_encode(input.replace_text.clone(), &self.device)?;
+        let hidden_states = self
+            .qwen2_5_vl
+            .forward_hidden(
+                &input_ids,
+                input.pixel_values.as_ref(),
+                input.image_grid_thw.as_ref(),
+                input.pixel_values_video.as_ref(),
+                input.video_grid_thw.as_ref(),
+                input.second_per_grid_ts.clone(),
+            )?
+            .squeeze(0)?;
+        let ids = input_ids.squeeze(0)?.to_vec1::<u32>()?;
+        let logprobs = token_logprobs(&hidden_states, &ids, |h| self.qwen2_5_vl.hidden_logits(h))?;
+        let cfg = &self.qwen2_5_vl.cfg;
+        let skip_token_ids = [cfg.image_token_id as u32, cfg.video_token_id as u32];
+        score_tokens(&ids, &logprobs, &skip_token_ids, |id| {
+            self.tokenizer.token_decode(vec![id])
+        })
+    }
+}
@@ -1,18 +1,25 @@
 use anyhow::{Result, anyhow};
 use candle_core::{D, DType, Device, IndexOp, Tensor};
-use candle_nn::{
-    Activation, Init, Linear, Module, RmsNorm, VarBuilder, linear, linear_no_bias, rms_norm,
-};
+use candle_nn::{Activation, Init, Linear, Module, RmsNorm, VarBuilder, linear, rms_norm};
 
 use crate::{
-    models::qwen2_5vl::config::{Qwen2_5VLConfig, RopeScaling},
+    models::{
+        common::{
+            KvCacheLayer, KvCacheSnapshot, layers_kv_cache_len, layers_kv_caches,
+            quant::{QLinear, qlinear, qlinear_no_bias},
+            search::SearchLM,
+            set_layers_kv_caches, truncate_kv_cache,
+            vision_cache::VisionFeature,
+        },
+        qwen2_5vl::config::{Qwen2_5VLConfig, RopeScaling},
+    },
     position_embed::rope::{
         Qwen2_5VLTextRotaryEmbedding, Qwen2_5VisionRotaryEmbedding, apply_rotary_pos_emb,
         apply_rotary_pos_emb_vision,
     },
     utils::tensor_utils::{
         get_equal_mask, get_vision_next_indices, masked_scatter_dim0, nonzero_index, repeat_kv,
-        safe_arg_sort_last_dim, zero_index,
+        safe_arg_sort_last_dim, split_tensor, zero_index,
     },
 };
 
@@ -542,9 +549,9 @@ impl Qwen2_5VLVisionModel {
 
 #[derive(Debug, Clone)]
 struct Qwen2_5VLTextMLP {
-    gate_proj: Linear,
-    up_proj: Linear,
-    down_proj: Linear,
+    gate_proj: QLinear,
+    up_proj: QLinear,
+    down_proj: QLinear,
     act_fn: Activation,
 }
 
@@ -552,9 +559,10 @@ impl Qwen2_5VLTextMLP {
     fn new(cfg: &Qwen2_5VLConfig, vb: VarBuilder) -> Result<Self> {
         let hidden_sz = cfg.hidden_size;
         let intermediate_sz = cfg.intermediate_size;
-        let gate_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("gate_proj"))?;
-        let up_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("up_proj"))?;
-        let down_proj = linear_no_bias(intermediate_sz, hidden_sz, vb.pp("down_proj"))?;
+        let quant = &cfg.quant;
+        let gate_proj = qlinear_no_bias(hidden_sz, intermediate_sz, vb.pp("gate_proj"), quant)?;
+        let up_proj = qlinear_no_bias(hidden_sz, intermediate_sz, vb.pp("up_proj"), quant)?;
+        let down_proj = qlinear_no_bias(intermediate_sz, hidden_sz, vb.pp("down_proj"), quant)?;
 
         Ok(Self {
             gate_proj,
@@ -575,10 +583,10 @@ impl Module for Qwen2_5VLTextMLP {
 
 #[derive(Debug, Clone)]
 struct Qwen2_5VLTextAttention {
-    q_proj: Linear,
-    k_proj: Linear,
-    v_proj: Linear,
-    o_proj: Linear,
+    q_proj: QLinear,
+    k_proj: QLinear,
+    v_proj: QLinear,
+    o_proj: QLinear,
     num_heads: usize,
     num_kv_heads: usize,
     num_kv_groups: usize,
@@ -594,10 +602,11 @@ impl Qwen2_5VLTextAttention {
         let num_kv_heads = cfg.num_key_value_heads;
         let num_kv_groups = num_heads / num_kv_heads;
         let head_dim = hidden_size / num_heads;
-        let q_proj = linear(hidden_size, num_heads * head_dim, vb.pp("q_proj"))?;
-        let k_proj = linear(hidden_size, num_kv_heads * head_dim, vb.pp("k_proj"))?;
-        let v_proj = linear(hidden_size, num_kv_heads * head_dim, vb.pp("v_proj"))?;
-        let o_proj = linear_no_bias(hidden_size, hidden_size, vb.pp("o_proj"))?;
+        let quant = &cfg.quant;
+        let q_proj = qlinear(hidden_size, num_heads * head_dim, vb.pp("q_proj"), quant)?;
+        let k_proj = qlinear(hidden_size, num_kv_heads * head_dim, vb.pp("k_proj"), quant)?;
+        let v_proj = qlinear(hidden_size, num_kv_heads * head_dim, vb.pp("v_proj"), quant)?;
+        let o_proj = qlinear_no_bias(hidden_size, hidden_size, vb.pp("o_proj"), quant)?;
         Ok(Self {
             q_proj,
             k_proj,
@@ -692,6 +701,18 @@ impl Qwen2_5VLTextAttention {
     fn clear_kv_cache(&mut self) {
         self.kv_cache = None
     }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        truncate_kv_cache(&mut self.kv_cache, len)
+    }
+
+    fn kv_cache(&self) -> Option<(Tensor, Tensor)> {
+        self.kv_cache.clone()
+    }
+
+    fn set_kv_cache(&mut self, kv_cache: Option<(Tensor, Tensor)>) {
+        self.kv_cache = kv_cache;
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -741,6 +762,20 @@ impl Qwen2_5VLTextDecoderLayer {
     fn clear_kv_cache(&mut self) {
         self.self_attn.clear_kv_cache()
     }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.self_attn.truncate_kv_cache(len)
+    }
+}
+
+impl KvCacheLayer for Qwen2_5VLTextDecoderLayer {
+    fn kv_cache(&self) -> Option<(Tensor, Tensor)> {
+        self.self_attn.kv_cache()
+    }
+
+    fn set_kv_cache(&mut self, kv_cache: Option<(Tensor, Tensor)>) {
+        self.self_attn.set_kv_cache(kv_cache);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -856,13 +891,32 @@ impl Qwen2_5VLTextModel {
             layer.clear_kv_cache()
         }
     }
+
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            layer.truncate_kv_cache(len)?;
+        }
+        Ok(())
+    }
+
+    pub fn kv_cache_len(&self) -> usize {
+        layers_kv_cache_len(&self.layers)
+    }
+
+    pub fn kv_caches(&self) -> Vec<Option<(Tensor, Tensor)>> {
+        layers_kv_caches(&self.layers)
+    }
+
+    pub fn set_kv_caches(&mut self, kv_caches: &[Option<(Tensor, Tensor)>]) -> Result<()> {
+        set_layers_kv_caches(&mut self.layers, kv_caches)
+    }
 }
 
 pub struct Qwen2_5VLModel {
     visual: Qwen2_5VLVisionModel,
     model: Qwen2_5VLTextModel,
     pub cfg: Qwen2_5VLConfig,
-    lm_head: Linear,
+    lm_head: QLinear,
     rope_deltas: Option<Tensor>,
 }
 
@@ -872,9 +926,14 @@ impl Qwen2_5VLModel {
         let model = Qwen2_5VLTextModel::new(&cfg, vb.pp("model"))?;
         let vocab_size = cfg.vocab_size;
         let lm_head = if cfg.tie_word_embeddings {
-            Linear::new(model.embed_tokens.embeddings().clone(), None)
+            QLinear::Linear(Linear::new(model.embed_tokens.embeddings().clone(), None))
         } else {
-            linear_no_bias(cfg.hidden_size, vocab_size, vb.pp("lm_head"))?
+            qlinear_no_bias(
+                cfg.hidden_size,
+                vocab_size,
+                vb.pp("lm_head"),
+                &cfg.quant.gguf_only(),
+            )?
         };
 
         Ok(Self {
@@ -886,6 +945,38 @@ impl Qwen2_5VLModel {
         })
     }
 
+    // 按图片/视频切分视觉特征, 每项为(embeds, []), 没有deepstack
+    pub fn vision_features(
+        &self,
+        pixel_values: &Tensor,
+        grid_thw: &Tensor,
+    ) -> Result<Vec<VisionFeature>> {
+        let embeds = self.visual.forward(pixel_values, grid_thw)?;
+        let merge_length = self.cfg.vision_config.spatial_merge_size.pow(2);
+        let split_sizes: Vec<usize> = grid_thw
+            .to_vec2::<u32>()?
+            .iter()
+            .map(|thw| thw.iter().product::<u32>() as usize / merge_length)
+            .collect();
+        Ok(split_tensor(&embeds, &split_sizes, 0)?
+            .into_iter()
+            .map(|e| (e, vec![]))
+            .collect())
+    }
+
+    fn get_vision_features(
+        &self,
+        pixel_values: Option<&Tensor>,
+        grid_thw: Option<&Tensor>,
+    ) -> Result<Option<Tensor>> {
+        match (pixel_values, grid_thw) {
+            (Some(pixel_values), Some(grid_thw)) => {
+                Ok(Some(self.visual.forward(pixel_values, grid_thw)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
     pub fn get_rope_index(
         &self,
         input_ids: &Tensor,
@@ -1118,15 +1209,130 @@ impl Qwen2_5VLModel {
         cache_position: Option<&Tensor>,
         seqlen_offset: usize,
         second_per_grid_ts: Option<Vec<f32>>,
+    ) -> Result<Tensor> {
+        let image_embeds = self.get_vision_features(pixel_values, image_grid_thw)?;
+        let video_embeds = self.get_vision_features(pixel_values_video, video_grid_thw)?;
+        let outputs = self.hidden_states(
+            input_ids,
+            image_embeds,
+            image_grid_thw,
+            video_embeds,
+            video_grid_thw,
+            mask,
+            cache_position,
+            seqlen_offset,
+            second_per_grid_ts,
+        )?;
+        let seq_len = outputs.dim(1)?;
+        let hidden_state = outputs.narrow(1, seq_len - 1, 1)?;
+        let logits = self.lm_head.forward(&hidden_state)?;
+        Ok(logits)
+    }
+
+    // 清空kv cache后prefill, 图片/视频输入已计算的视觉特征, 返回最后位置的logits
+    pub fn forward_features(
+        &mut self,
+        input_ids: &Tensor,
+        image_embeds: Option<Tensor>,
+        image_grid_thw: Option<&Tensor>,
+        video_embeds: Option<Tensor>,
+        video_grid_thw: Option<&Tensor>,
+        second_per_grid_ts: Option<Vec<f32>>,
+    ) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        let mask = Tensor::ones_like(input_ids)?;
+        let cache_position = Tensor::arange(0u32, seq_len as u32, input_ids.device())?;
+        self.clear_kv_cache();
+        let outputs = self.hidden_states(
+            input_ids,
+            image_embeds,
+            image_grid_thw,
+            video_embeds,
+            video_grid_thw,
+            &mask,
+            Some(&cache_position),
+            0,
+            second_per_grid_ts,
+        )?;
+        let hidden_state = outputs.narrow(1, seq_len - 1, 1)?;
+        Ok(self.lm_head.forward(&hidden_state)?)
+    }
+
+    // 不使用kv cache, 返回所有位置的logits: (1, seq_len, vocab_size)
+    pub fn forward_logits(
+        &mut self,
+        input_ids: &Tensor,
+        pixel_values: Option<&Tensor>,
+        image_grid_thw: Option<&Tensor>,
+        pixel_values_video: Option<&Tensor>,
+        video_grid_thw: Option<&Tensor>,
+        second_per_grid_ts: Option<Vec<f32>>,
+    ) -> Result<Tensor> {
+        let hidden_states = self.forward_hidden(
+            input_ids,
+            pixel_values,
+            image_grid_thw,
+            pixel_values_video,
+            video_grid_thw,
+            second_per_grid_ts,
+        )?;
+        self.hidden_logits(&hidden_states)
+    }
+
+    // 不使用kv cache, 返回最后一层norm之后所有位置的hidden states: (1, seq_len, hidden_size)
+    pub fn forward_hidden(
+        &mut self,
+        input_ids: &Tensor,
+        pixel_values: Option<&Tensor>,
+        image_grid_thw: Option<&Tensor>,
+        pixel_values_video: Option<&Tensor>,
+        video_grid_thw: Option<&Tensor>,
+        second_per_grid_ts: Option<Vec<f32>>,
+    ) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        let mask = Tensor::ones_like(input_ids)?;
+        let cache_position = Tensor::arange(0u32, seq_len as u32, input_ids.device())?;
+        let image_embeds = self.get_vision_features(pixel_values, image_grid_thw)?;
+        let video_embeds = self.get_vision_features(pixel_values_video, video_grid_thw)?;
+        self.clear_kv_cache();
+        let hidden_states = self.hidden_states(
+            input_ids,
+            image_embeds,
+            image_grid_thw,
+            video_embeds,
+            video_grid_thw,
+            &mask,
+            Some(&cache_position),
+            0,
+            second_per_grid_ts,
+        );
+        self.clear_kv_cache();
+        hidden_states
+    }
+
+    // forward_hidden的输出转为logits
+    pub fn hidden_logits(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        Ok(self.lm_head.forward(hidden_states)?)
+    }
+
+    // 返回最后一层norm之后所有位置的hidden states, image_embeds/video_embeds为按顺序拼接的视觉特征
+    fn hidden_states(
+        &mut self,
+        input_ids: &Tensor,
+        image_embeds: Option<Tensor>,
+        image_grid_thw: Option<&Tensor>,
+        video_embeds: Option<Tensor>,
+        video_grid_thw: Option<&Tensor>,
+        mask: &Tensor,
+        cache_position: Option<&Tensor>,
+        seqlen_offset: usize,
+        second_per_grid_ts: Option<Vec<f32>>,
     ) -> Result<Tensor> {
         // input_ids shape: (bs, seq_len)
         let mut inputs_embeds = self.model.embed_tokens.forward(input_ids)?;
         // inputs_embeds shape: (bs, seq_len, hidden_dim)
-        if let Some(pixel_values) = pixel_values
-            && let Some(image_grid_thw) = image_grid_thw
-        {
-            // image_embed shape: (seq_len, hidden_dim)
-            let image_embed = self.visual.forward(pixel_values, image_grid_thw)?;
+        // image_embed shape: (seq_len, hidden_dim)
+        if let Some(image_embed) = image_embeds {
             let vision_mask = get_equal_mask(input_ids, self.cfg.image_token_id as u32)?;
 
             let n_image_tokens = vision_mask.sum_all()?.to_scalar::<u32>()?;
@@ -1139,11 +1345,7 @@ impl Qwen2_5VLModel {
             }
             inputs_embeds = masked_scatter_dim0(&inputs_embeds, &image_embed, &vision_mask)?;
         }
-        if let Some(pixel_values_video) = pixel_values_video
-            && let Some(video_grid_thw) = video_grid_thw
-        {
-            let video_embed = self.visual.forward(pixel_values_video, video_grid_thw)?;
-
+        if let Some(video_embed) = video_embeds {
             let vision_mask = get_equal_mask(input_ids, self.cfg.video_token_id as u32)?;
             let n_video_tokens = vision_mask.sum_all()?.to_scalar::<u32>()?;
             if n_video_tokens as usize != video_embed.dim(0)? {
@@ -1188,15 +1390,63 @@ impl Qwen2_5VLModel {
                 .broadcast_as((3, bs, seq_len))?
                 .contiguous()?;
         }
-        let outputs = self
-            .model
-            .forward(&inputs_embeds, seqlen_offset, Some(&position_ids))?;
-        let seq_len = outputs.dim(1)?;
-        let hidden_state = outputs.narrow(1, seq_len - 1, 1)?;
-        let logits = self.lm_head.forward(&hidden_state)?;
-        Ok(logits)
+        self.model
+            .forward(&inputs_embeds, seqlen_offset, Some(&position_ids))
     }
     pub fn clear_kv_cache(&mut self) {
         self.model.clear_kv_cache();
     }
+
+    // 截断后继续生成时rope_deltas不变
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.model.truncate_kv_cache(len)
+    }
+
+    pub fn kv_cache_len(&self) -> usize {
+        self.model.kv_cache_len()
+    }
+
+    pub fn snapshot(&self) -> KvCacheSnapshot {
+        KvCacheSnapshot {
+            layers: self.model.kv_caches(),
+            rope_deltas: self.rope_deltas.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &KvCacheSnapshot) -> Result<()> {
+        self.model.set_kv_caches(&snapshot.layers)?;
+        self.rope_deltas = snapshot.rope_deltas.clone();
+        Ok(())
+    }
+}
+
+impl SearchLM for Qwen2_5VLModel {
+    fn decode(&mut self, token: u32, position_id: usize) -> Result<Vec<f32>> {
+        let device = self.model.embed_tokens.embeddings().device().clone();
+        let input_ids = Tensor::from_vec(vec![token], (1, 1), &device)?;
+        // 解码阶段只用rope_deltas计算位置, mask不参与计算
+        let mask = Tensor::ones((1, position_id + 1), DType::U32, &device)?;
+        let cache_position = Tensor::from_vec(vec![position_id as u32], 1, &device)?;
+        let logits = self.forward(
+            &input_ids,
+            None,
+            None,
+            None,
+            None,
+            &mask,
+            Some(&cache_position),
+            position_id,
+            None,
+        )?;
+        let logits = logits.flatten_all()?.to_dtype(DType::F32)?;
+        Ok(logits.to_vec1::<f32>()?)
+    }
+
+    fn snapshot(&self) -> KvCacheSnapshot {
+        Qwen2_5VLModel::snapshot(self)
+    }
+
+    fn restore(&mut self, snapshot: &KvCacheSnapshot) -> Result<()> {
+        Qwen2_5VLModel::restore(self, snapshot)
+    }
 }
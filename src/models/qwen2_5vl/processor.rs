@@ -5,14 +5,16 @@ use aha_openai_dive::v1::resources::chat::{
 };
 use anyhow::{Result, anyhow};
 use candle_core::{DType, Device, IndexOp, Shape, Tensor};
-use ffmpeg_next as ffmpeg;
 use image::DynamicImage;
 use num::integer::lcm;
 
 use crate::{
-    models::qwen2_5vl::config::VisionSetting,
+    models::{common::vision_cache::CachedVision, qwen2_5vl::config::VisionSetting},
     utils::{
-        img_utils::get_image,
+        img_utils::{get_image, resize_bicubic},
+        media_utils::{MediaSource, VideoSource, frames_to_tensor, load_frames},
+        video_utils::{VideoReader, uniform_indices},
+        vision_utils::{MediaRequest, RequestVisionOverrides, VisionOverrides},
         {ceil_by_factor, floor_by_factor, round_by_factor},
     },
 };
@@ -42,8 +44,8 @@ pub struct Qwen2_5VLProcessor {
 }
 
 impl Qwen2_5VLProcessor {
-    pub fn new(device: &Device, dtype: DType) -> Result<Self> {
-        let vision_setting = VisionSetting::default();
+    pub fn new(path: &str, device: &Device, dtype: DType) -> Result<Self> {
+        let vision_setting = VisionSetting::from_model_path(path)?;
         let image_token = "<|image_pad|>".to_string();
         let video_token = "<|video_pad|>".to_string();
         Ok(Self {
@@ -70,6 +72,9 @@ impl Qwen2_5VLProcessor {
                     if let ChatMessageContentPart::Image(img_part) = part {
                         let img_url = img_part.image_url;
                         vision_map.get_mut("image").unwrap().push(img_url.url);
+                    } else if let ChatMessageContentPart::Video(video_part) = part {
+                        let video_url = video_part.video_url;
+                        vision_map.get_mut("video").unwrap().push(video_url.url);
                     }
                 }
             }
@@ -82,13 +87,14 @@ impl Qwen2_5VLProcessor {
         img: &DynamicImage,
         img_mean: &Tensor,
         img_std: &Tensor,
+        overrides: &VisionOverrides,
     ) -> Result<Tensor> {
         let img_h = img.height();
         let img_w = img.width();
         //  h,w resize成 28的倍数
-        let (resize_h, resize_w) = smart_resize(img_h, img_w, &self.vision_setting, true, None)?;
-        let img = img.resize_exact(resize_w, resize_h, image::imageops::FilterType::CatmullRom);
-        let img_vec = img.to_rgb8().into_raw();
+        let (resize_h, resize_w) =
+            smart_resize(img_h, img_w, &self.vision_setting, true, None, overrides)?;
+        let img_vec = resize_bicubic(img, resize_w, resize_h).into_raw();
         // (h, w, c) => (c, h, w)
         let img_tensor = Tensor::from_slice(
             &img_vec,
@@ -163,15 +169,15 @@ impl Qwen2_5VLProcessor {
 
     pub fn process_images(
         &self,
-        imgs: Vec<DynamicImage>,
+        imgs: Vec<(DynamicImage, VisionOverrides)>,
         img_mean: &Tensor,
         img_std: &Tensor,
     ) -> Result<VisionInput> {
         let mut pixel_values_vec = Vec::new();
         let mut vision_grid_thws_vec = Vec::new();
 
-        for img in imgs {
-            let img_tensor = self.process_img(&img, img_mean, img_std)?;
+        for (img, overrides) in imgs {
+            let img_tensor = self.process_img(&img, img_mean, img_std, &overrides)?;
             let img_tensor = Tensor::cat(&[&img_tensor, &img_tensor], 0)?.contiguous()?;
             let (img_tensor, grid_thw) = self.process_vision_tensor(&img_tensor)?;
             pixel_values_vec.push(img_tensor);
@@ -213,10 +219,12 @@ impl Qwen2_5VLProcessor {
         })
     }
 
+    // overrides为本次请求的图片/视频设置, 覆盖preprocessor_config中的min_pixels/max_pixels/fps
     pub fn process_info(
         &self,
         messages: &ChatCompletionParameters,
         text: &str,
+        overrides: &RequestVisionOverrides,
     ) -> Result<GeneralInput> {
         let mut pixel_values = None;
         let mut image_grid_thw = None;
@@ -233,10 +241,10 @@ impl Qwen2_5VLProcessor {
             // println!("key: {}, \nvalue: {:?}", key, vec);
             if key.eq("image") {
                 let mut file_vec = Vec::new();
-                for file in &vec {
+                for (i, file) in vec.iter().enumerate() {
                     let image = get_image(file);
                     match image {
-                        Ok(img) => file_vec.push(img),
+                        Ok(img) => file_vec.push((img, overrides.image(i))),
                         Err(e) => println!("get_image err: {:?}", e),
                     };
                 }
@@ -253,10 +261,22 @@ impl Qwen2_5VLProcessor {
             }
             if key.eq("video") {
                 let mut file_vec = Vec::new();
-                for file in &vec {
-                    let video_data = get_video_data(file, &self.vision_setting, &self.device);
+                let mut fps_vec = Vec::new();
+                for (i, file) in vec.iter().enumerate() {
+                    let overrides = overrides.video(i);
+                    let video_data = MediaSource::parse(file).and_then(|source| {
+                        get_video_data(
+                            &source.open_video()?,
+                            &self.vision_setting,
+                            &overrides,
+                            &self.device,
+                        )
+                    });
                     match video_data {
-                        Ok(tensor) => file_vec.push(tensor),
+                        Ok(tensor) => {
+                            file_vec.push(tensor);
+                            fps_vec.push(overrides.fps(self.vision_setting.fps)?);
+                        }
                         Err(e) => println!("get_video_data err: {:?}", e),
                     };
                 }
@@ -264,15 +284,12 @@ impl Qwen2_5VLProcessor {
                     let vision_input = self.process_videos(file_vec, &img_mean, &img_std);
                     match vision_input {
                         Ok(video_input) => {
-                            let video_num = video_input.grid_thw.dim(0)?;
                             pixel_values_video = Some(video_input.data);
                             video_grid_thw = Some(video_input.grid_thw);
-                            let second_per_grid = vec![
-                                self.vision_setting.temporal_patch_size
-                                    as f32
-                                    / self.vision_setting.fps;
-                                video_num
-                            ];
+                            let second_per_grid = fps_vec
+                                .iter()
+                                .map(|fps| self.vision_setting.temporal_patch_size as f32 / fps)
+                                .collect();
                             second_per_grid_ts = Some(second_per_grid);
                         }
                         Err(e) => println!("video process_videos err: {:?}", e),
@@ -316,6 +333,102 @@ impl Qwen2_5VLProcessor {
         };
         Ok(input)
     }
+
+    // 请求中按出现顺序的图片和视频, 以及各自的预处理设置
+    pub fn media_requests(
+        &self,
+        messages: &ChatCompletionParameters,
+        overrides: &RequestVisionOverrides,
+    ) -> Result<(Vec<MediaRequest>, Vec<MediaRequest>)> {
+        let mut vision_map = self.extract_vision_info(messages)?;
+        let images = vision_map
+            .remove("image")
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+            .map(|(i, source)| {
+                Ok(MediaRequest {
+                    source: MediaSource::parse(&source)?,
+                    video: false,
+                    overrides: overrides.image(i),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let default = VisionOverrides {
+            fps: Some(self.vision_setting.fps),
+            ..Default::default()
+        };
+        let videos = vision_map
+            .remove("video")
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+            .map(|(i, source)| {
+                Ok(MediaRequest {
+                    source: MediaSource::parse(&source)?,
+                    video: true,
+                    overrides: overrides.video(i).or(&default),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok((images, videos))
+    }
+
+    // 单张图片或单个视频的预处理
+    pub fn process_media(&self, request: &MediaRequest) -> Result<VisionInput> {
+        let img_mean =
+            Tensor::from_slice(&self.vision_setting.image_mean, (3, 1, 1), &self.device)?
+                .to_dtype(self.dtype)?;
+        let img_std = Tensor::from_slice(&self.vision_setting.image_std, (3, 1, 1), &self.device)?
+            .to_dtype(self.dtype)?;
+        if !request.video {
+            let img = request.source.load_image()?;
+            return self.process_images(
+                vec![(img, request.overrides.clone())],
+                &img_mean,
+                &img_std,
+            );
+        }
+        let tensor = get_video_data(
+            &request.source.open_video()?,
+            &self.vision_setting,
+            &request.overrides,
+            &self.device,
+        )?;
+        self.process_videos(vec![tensor], &img_mean, &img_std)
+    }
+
+    // 视频每个grid时间块对应的秒数, 用于计算mrope的时间位置
+    pub fn second_per_grid(&self, overrides: &VisionOverrides) -> Result<f32> {
+        Ok(self.vision_setting.temporal_patch_size as f32
+            / overrides.fps(self.vision_setting.fps)?)
+    }
+
+    // 命中视觉特征缓存时不再预处理, 按缓存的grid_thw展开占位token
+    pub fn replace_text(
+        &self,
+        text: &str,
+        images: &[&CachedVision],
+        videos: &[&CachedVision],
+    ) -> Result<String> {
+        let merge_length = self.vision_setting.merge_size.pow(2);
+        let mut text = text.to_string();
+        for (items, token) in [(images, &self.image_token), (videos, &self.video_token)] {
+            if items.is_empty() {
+                continue;
+            }
+            for item in items {
+                let repeat_num = item.grid_thw.iter().product::<u32>() as usize / merge_length;
+                let replace = "<|placeholder|>".repeat(repeat_num);
+                text = text.replacen(token, &replace, 1);
+            }
+            if text.contains(token) {
+                return Err(anyhow!(format!("{} num more than inputs", token)));
+            }
+            text = text.replace("<|placeholder|>", token);
+        }
+        Ok(text)
+    }
 }
 
 pub fn smart_resize(
@@ -324,7 +437,18 @@ pub fn smart_resize(
     vision_setting: &VisionSetting,
     is_img: bool,
     video_ratio: Option<u32>,
+    overrides: &VisionOverrides,
 ) -> Result<(u32, u32)> {
+    let (min_pixels, max_pixels) = if is_img {
+        (vision_setting.min_pixels, vision_setting.max_pixels)
+    } else {
+        (
+            vision_setting.video_min_pixels,
+            vision_setting.video_max_pixels,
+        )
+    };
+    let (img_h, img_w, min_pixels, max_pixels) =
+        overrides.resize_args(img_h, img_w, min_pixels, max_pixels)?;
     if std::cmp::max(img_h, img_w) / std::cmp::min(img_h, img_w) > vision_setting.max_ratio {
         return Err(anyhow!(format!(
             "absolute aspect ratio mush be smaller than {}, got {}",
@@ -339,14 +463,6 @@ pub fn smart_resize(
     let mut h_bar = std::cmp::max(image_factor, round_by_factor(img_h, image_factor));
     let mut w_bar = std::cmp::max(image_factor, round_by_factor(img_w, image_factor));
 
-    let (min_pixels, max_pixels) = if is_img {
-        (vision_setting.min_pixels, vision_setting.max_pixels)
-    } else {
-        (
-            vision_setting.video_min_pixels,
-            vision_setting.video_max_pixels,
-        )
-    };
     if h_bar * w_bar > max_pixels {
         let beta = ((img_h * img_w) as f32 / max_pixels as f32).sqrt();
         h_bar = floor_by_factor(img_h as f32 / beta, image_factor);
@@ -359,34 +475,44 @@ pub fn smart_resize(
     Ok((h_bar, w_bar))
 }
 
-pub fn get_video_data(
-    file: &String,
+// 图片帧列表组成的视频, 不做抽帧
+pub fn get_frames_data(
+    frames: &[MediaSource],
     vision_setting: &VisionSetting,
+    overrides: &VisionOverrides,
     device: &Device,
 ) -> Result<Tensor> {
-    ffmpeg::init().map_err(|e| anyhow!(format!("Failed to initialize ffmpeg: {}", e)))?;
-
-    let mut ictx = ffmpeg::format::input(&file)
-        .map_err(|e| anyhow!(format!("Failed to open video file: {}", e)))?;
-    let input = ictx
-        .streams()
-        .best(ffmpeg::media::Type::Video)
-        .ok_or_else(|| anyhow!(format!("No video stream found")))?;
-    let video_stream_index = input.index();
-    let context_decoder = ffmpeg::codec::context::Context::from_parameters(input.parameters())
-        .map_err(|e| anyhow!(format!("Failed to crate decoder context: {}", e)))?;
-    let mut decoder = context_decoder
-        .decoder()
-        .video()
-        .map_err(|e| anyhow!(format!("Failed to decoder video: {}", e)))?;
-
-    let video_h = decoder.height();
-    let video_w = decoder.width();
-    let format = decoder.format();
+    let images = load_frames(frames)?;
+    let (resize_h, resize_w) = smart_resize(
+        images[0].height(),
+        images[0].width(),
+        vision_setting,
+        false,
+        None,
+        overrides,
+    )?;
+    frames_to_tensor(&images, resize_h, resize_w, device)
+}
 
-    let frames = input.frames();
-    let rate = (input.rate().0 as f32 / input.rate().1 as f32).round() as u32;
-    // 1s取两帧
+// 音轨用audio_utils::get_video_audio从同一个VideoSource提取
+pub fn get_video_data(
+    video: &VideoSource,
+    vision_setting: &VisionSetting,
+    overrides: &VisionOverrides,
+    device: &Device,
+) -> Result<Tensor> {
+    let fps = overrides.fps(vision_setting.fps)?;
+    let media_file = match video {
+        VideoSource::File(file) => file,
+        VideoSource::Frames(frames) => {
+            return get_frames_data(frames, vision_setting, overrides, device);
+        }
+    };
+    let mut reader = VideoReader::open(media_file.path())?;
+    let info = reader.info().clone();
+    // 按时间均匀采样, 默认1s取两帧
+    let (start_frame, end_frame) = info.clip(overrides.video_start, overrides.video_end)?;
+    let clip_frames = end_frame - start_frame + 1;
     let min_frames = ceil_by_factor(
         vision_setting.fps_min_frames as f32,
         vision_setting.frame_factor,
@@ -395,70 +521,21 @@ pub fn get_video_data(
         vision_setting.fps_max_frames as f32,
         vision_setting.frame_factor,
     );
-    let nframes = (frames as f32 / rate as f32 * vision_setting.fps) as u32;
+    let nframes = (clip_frames as f32 / info.fps * fps) as u32;
     let nframes = std::cmp::min(std::cmp::max(nframes, min_frames), max_frames);
     let nframes = round_by_factor(nframes, vision_setting.frame_factor);
-    let sample_interval = (frames as f32 / nframes as f32).round() as u32;
-    let mut frame_id = 0_u32;
+    let frame_indices = uniform_indices(start_frame, end_frame, nframes);
 
     // 图片帧使用scaler reshape的时候需要保证宽高是16的倍数,不然reshape出来的是损坏的图片
     // 所以计算resize的目标宽高时,需要用16和image_factor的最小公倍数
-    let (resize_h, resize_w) = smart_resize(video_h, video_w, vision_setting, false, Some(16))?;
-    let mut scaler = ffmpeg::software::scaling::context::Context::get(
-        format,
-        video_w,
-        video_h,
-        ffmpeg::format::Pixel::RGB24,
-        resize_w,
-        resize_h,
-        ffmpeg::software::scaling::flag::Flags::BILINEAR
-            | ffmpeg::software::scaling::flag::Flags::ACCURATE_RND,
-    )
-    .map_err(|e| anyhow!(format!("Failed to crate scaler: {}", e)))?;
-
-    let mut frames_vec = Vec::new();
-    let mut receive_and_process_decoded_frames =
-        |decoder: &mut ffmpeg::decoder::Video| -> Result<()> {
-            let mut decoded = ffmpeg::frame::Video::empty();
-            while decoder.receive_frame(&mut decoded).is_ok() {
-                if frame_id.is_multiple_of(sample_interval) {
-                    let mut rgb_frame = ffmpeg::frame::Video::empty();
-                    scaler
-                        .run(&decoded, &mut rgb_frame)
-                        .map_err(|e| anyhow!(format!("Failed to scaler run decoded: {}", e)))?;
-
-                    // save_file(&rgb_frame, frame_id as usize);
-                    let frame_data = rgb_frame.data(0);
-                    let frame_tensor = Tensor::from_slice(
-                        frame_data,
-                        (resize_h as usize, resize_w as usize, 3),
-                        device,
-                    )?
-                    .permute((2, 0, 1))?;
-                    frames_vec.push(frame_tensor);
-                }
-                frame_id += 1;
-            }
-            Ok(())
-        };
-
-    for (stream, packet) in ictx.packets() {
-        if stream.index() == video_stream_index {
-            decoder
-                .send_packet(&packet)
-                .map_err(|e| anyhow!(format!("Failed to send packet: {}", e)))?;
-            receive_and_process_decoded_frames(&mut decoder)?;
-        }
-    }
-    decoder
-        .send_eof()
-        .map_err(|e| anyhow!(format!("Failed to decoder.send_eof(): {}", e)))?;
-    receive_and_process_decoded_frames(&mut decoder)?;
-
-    if frames_vec.is_empty() {
-        return Err(anyhow!("No frames extracted from video".to_string()));
-    }
+    let (resize_h, resize_w) = smart_resize(
+        info.height,
+        info.width,
+        vision_setting,
+        false,
+        Some(16),
+        overrides,
+    )?;
     // (t, c, h, w)
-    let frames_tensor = Tensor::stack(&frames_vec, 0)?.contiguous()?;
-    Ok(frames_tensor)
+    reader.read_frames(&frame_indices, resize_h, resize_w, device)
 }
@@ -1,5 +1,10 @@
 use candle_nn::Activation;
 
+use crate::models::common::{
+    manifest::{check_attention, check_positive},
+    quant::QuantSource,
+};
+
 #[derive(Debug, Clone, PartialEq, serde::Deserialize)]
 pub struct Size {
     pub longest_edge: usize,
@@ -14,6 +19,13 @@ pub struct PreprocessorConfig {
     pub merge_size: usize,
     pub image_mean: Vec<f32>,
     pub image_std: Vec<f32>,
+    // 只有video_preprocessor_config.json中有
+    #[serde(default)]
+    pub fps: Option<f32>,
+    #[serde(default)]
+    pub min_frames: Option<u32>,
+    #[serde(default)]
+    pub max_frames: Option<u32>,
 }
 
 #[derive(Debug, Clone, PartialEq, serde::Deserialize)]
@@ -45,6 +57,9 @@ pub struct Qwen3VLTextConfig {
     pub tie_word_embeddings: bool,
     pub use_cache: bool,
     pub vocab_size: usize,
+    // 不从config.json读取, 加载模型时设置
+    #[serde(skip)]
+    pub quant: QuantSource,
 }
 
 #[derive(Debug, Clone, PartialEq, serde::Deserialize)]
@@ -75,6 +90,60 @@ pub struct Qwen3VLConfig {
     pub vision_start_token_id: usize,
 }
 
+impl Qwen3VLConfig {
+    // 配置字段的合理性检查, 返回所有问题
+    pub fn check(&self) -> Vec<String> {
+        let text = &self.text_config;
+        let mut errors = check_attention(
+            "text_config",
+            text.hidden_size,
+            text.num_attention_heads,
+            text.num_key_value_heads,
+            Some(text.head_dim),
+        );
+        check_positive(&mut errors, "text_config.head_dim", text.head_dim);
+        check_positive(
+            &mut errors,
+            "text_config.num_hidden_layers",
+            text.num_hidden_layers,
+        );
+        check_positive(&mut errors, "text_config.vocab_size", text.vocab_size);
+        let section: usize = text.rope_scaling.mrope_section.iter().sum();
+        if section != text.head_dim / 2 {
+            errors.push(format!(
+                "sum of text_config.rope_scaling.mrope_section {:?} != head_dim / 2 ({})",
+                text.rope_scaling.mrope_section,
+                text.head_dim / 2
+            ));
+        }
+        let vision = &self.vision_config;
+        errors.extend(check_attention(
+            "vision_config",
+            vision.hidden_size,
+            vision.num_heads,
+            vision.num_heads,
+            None,
+        ));
+        check_positive(&mut errors, "vision_config.patch_size", vision.patch_size);
+        check_positive(
+            &mut errors,
+            "vision_config.spatial_merge_size",
+            vision.spatial_merge_size,
+        );
+        if let Some(idx) = vision
+            .deepstack_visual_indexes
+            .iter()
+            .find(|i| **i >= vision.depth)
+        {
+            errors.push(format!(
+                "vision_config.deepstack_visual_indexes contains {} >= depth {}",
+                idx, vision.depth
+            ));
+        }
+        errors
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, serde::Deserialize)]
 pub struct Qwen3VLGenerationConfig {
     pub bos_token_id: usize,
@@ -2,25 +2,39 @@ use aha_openai_dive::v1::resources::chat::{
     ChatCompletionChunkResponse, ChatCompletionParameters, ChatCompletionResponse,
 };
 use anyhow::{Result, anyhow};
-use candle_core::{DType, Device, Tensor};
-use candle_nn::VarBuilder;
+use candle_core::{DType, Device, IndexOp, Tensor};
 use rocket::async_stream::stream;
 use rocket::futures::Stream;
 
 use crate::{
     chat_template::ChatTemplate,
     models::{
-        GenerateModel,
+        EmbeddingModel, GenerateModel, ParityModel, ScoringModel,
+        common::{
+            embedding::{Pooling, pad_batch, pool_batch, pool_hidden_states},
+            gguf::GgufFile,
+            manifest::{WeightReport, checked_var_builder},
+            parity::GoldenData,
+            quant::{QuantSource, QuantType},
+            scoring::{ScoreOutput, score_tokens, token_logprobs},
+            search::{BeamSearchConfig, Hypothesis, beam_search, best_of_n},
+            vision_cache::{
+                CachedVision, SharedVisionCache, VisionCacheKey, cached_vision, concat_features,
+                grid_thw_tensor, vision_cache_key,
+            },
+        },
         qwen3vl::{
             config::{Qwen3VLConfig, Qwen3VLGenerationConfig},
             model::Qwen3VLModel,
             processor::Qwen3VLProcessor,
+            session::{ChatSession, MediaItem},
         },
     },
-    tokenizer::TokenizerModel,
+    tokenizer::{StreamDecoder, TokenizerModel},
     utils::{
-        build_completion_chunk_response, build_completion_response, find_type_files, get_device,
+        build_completion_chunk_response, build_completion_response, completion_usage, get_device,
         get_dtype, get_logit_processor,
+        vision_utils::{MediaRequest, RequestVisionOverrides},
     },
 };
 
@@ -33,22 +47,73 @@ pub struct Qwen3VLGenerateModel<'a> {
     eos_token_id1: u32,
     eos_token_id2: u32,
     generation_config: Qwen3VLGenerationConfig,
+    model_path: String,
+    vision_cache: Option<SharedVisionCache>,
+    request_overrides: Option<RequestVisionOverrides>,
 }
 
 impl<'a> Qwen3VLGenerateModel<'a> {
     pub fn init(path: &str, device: Option<&Device>, dtype: Option<DType>) -> Result<Self> {
+        Self::init_with_quant(path, device, dtype, None)
+    }
+
+    // 不读取权重, 检查配置和目录下的权重名/shape是否与模型一致
+    pub fn check_model(path: &str) -> Result<WeightReport> {
+        let config_path = path.to_string() + "/config.json";
+        let mut cfg: Qwen3VLConfig = serde_json::from_slice(&std::fs::read(config_path)?)?;
+        let config_errors = cfg.check();
+        if !config_errors.is_empty() {
+            return Ok(WeightReport::from_config_errors(config_errors));
+        }
+        cfg.text_config.quant = QuantType::from_model_path(path)?.into();
+        let dtype = get_dtype(None, cfg.text_config.dtype.as_str());
+        let gguf = GgufFile::find(path)?;
+        let (vb, checker) = checked_var_builder(
+            path,
+            gguf,
+            "model.language_model.",
+            "model.lm_head",
+            dtype,
+            &Device::Cpu,
+            true,
+        )?;
+        Ok(checker.finish_report(Qwen3VLModel::new(cfg, vb.pp("model"))))
+    }
+
+    // quant为解码层Linear的weight-only量化方式, 目录下有quantization.json时以其为准
+    pub fn init_with_quant(
+        path: &str,
+        device: Option<&Device>,
+        dtype: Option<DType>,
+        quant: Option<QuantType>,
+    ) -> Result<Self> {
         let chat_template = ChatTemplate::init(path)?;
         let tokenizer = TokenizerModel::init(path)?;
         let config_path = path.to_string() + "/config.json";
-        let cfg: Qwen3VLConfig = serde_json::from_slice(&std::fs::read(config_path)?)?;
+        let mut cfg: Qwen3VLConfig = serde_json::from_slice(&std::fs::read(config_path)?)?;
+        WeightReport::from_config_errors(cfg.check()).ensure_ok()?;
+        // gguf中只有文本解码器, 视觉部分仍从safetensors读取
+        let gguf = GgufFile::find(path)?;
+        cfg.text_config.quant = QuantSource::new(
+            QuantType::from_model_path(path)?.or(quant),
+            gguf.as_ref(),
+            "model.language_model.",
+            "model.lm_head",
+        )?;
         let device = get_device(device);
         let cfg_dtype = cfg.text_config.dtype.as_str();
         let dtype = get_dtype(dtype, cfg_dtype);
         let pre_processor = Qwen3VLProcessor::new(path, &device, dtype)?;
-        let model_list = find_type_files(path, "safetensors")?;
-        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&model_list, dtype, &device)? };
-        let vb = vb.pp("model");
-        let qwen3_vl = Qwen3VLModel::new(cfg, vb)?;
+        let (vb, checker) = checked_var_builder(
+            path,
+            gguf,
+            "model.language_model.",
+            "model.lm_head",
+            dtype,
+            &device,
+            false,
+        )?;
+        let qwen3_vl = checker.finish(Qwen3VLModel::new(cfg, vb.pp("model")))?;
         let generation_config_path = path.to_string() + "/generation_config.json";
         let generation_config: Qwen3VLGenerationConfig =
             serde_json::from_slice(&std::fs::read(generation_config_path)?)?;
@@ -61,8 +126,351 @@ impl<'a> Qwen3VLGenerateModel<'a> {
             eos_token_id1: generation_config.eos_token_id[0] as u32,
             eos_token_id2: generation_config.eos_token_id[1] as u32,
             generation_config,
+            model_path: path.to_string(),
+            vision_cache: None,
+            request_overrides: None,
+        })
+    }
+
+    // 多个请求/多个同权重的模型实例共用视觉特征缓存, 以模型路径区分不同权重, None关闭缓存
+    pub fn set_vision_cache(&mut self, cache: Option<SharedVisionCache>) {
+        self.vision_cache = cache;
+    }
+
+    // 下一次请求使用的图片/视频设置, 代替从ChatCompletionParameters中读取, 用完即清除
+    pub fn set_request_overrides(&mut self, overrides: RequestVisionOverrides) {
+        self.request_overrides = Some(overrides);
+    }
+
+    fn take_request_overrides(
+        &mut self,
+        mes: &ChatCompletionParameters,
+    ) -> Result<RequestVisionOverrides> {
+        match self.request_overrides.take() {
+            Some(overrides) => Ok(overrides),
+            None => RequestVisionOverrides::from_request(mes),
+        }
+    }
+
+    // 请求中按顺序的图片和视频的视觉特征, 按来源和预处理设置查缓存, 只预处理未命中的
+    // 有session时使用会话的缓存, 返回每项的key和特征, 以及其中图片的数量
+    fn media_vision(
+        &self,
+        mes: &ChatCompletionParameters,
+        overrides: &RequestVisionOverrides,
+        session: Option<&mut ChatSession>,
+    ) -> Result<(Vec<(VisionCacheKey, CachedVision)>, usize)> {
+        let (image_requests, video_requests) = self.pre_processor.media_requests(mes, overrides)?;
+        let num_images = image_requests.len();
+        let requests: Vec<MediaRequest> =
+            image_requests.into_iter().chain(video_requests).collect();
+        let keys = requests
+            .iter()
+            .map(|request| vision_cache_key(&self.model_path, request))
+            .collect::<Result<Vec<_>>>()?;
+        let compute = |missing: &[usize]| self.compute_vision(&requests, missing);
+        let values = match session {
+            Some(session) => session.cached_vision(&keys, compute)?,
+            None => cached_vision(self.vision_cache.as_ref(), &keys, compute)?.0,
+        };
+        Ok((keys.into_iter().zip(values).collect(), num_images))
+    }
+
+    // 预处理requests中下标为indices的图片/视频, 一起计算视觉特征
+    fn compute_vision(
+        &self,
+        requests: &[MediaRequest],
+        indices: &[usize],
+    ) -> Result<Vec<CachedVision>> {
+        let inputs = indices
+            .iter()
+            .map(|&i| self.pre_processor.process_media(&requests[i]))
+            .collect::<Result<Vec<_>>>()?;
+        let pixel_values: Vec<&Tensor> = inputs.iter().map(|m| &m.pixel_values).collect();
+        let grid_thw: Vec<&Tensor> = inputs.iter().map(|m| &m.grid_thw).collect();
+        let features = self
+            .qwen3_vl
+            .vision_features(&Tensor::cat(&pixel_values, 0)?, &Tensor::cat(&grid_thw, 0)?)?;
+        inputs
+            .into_iter()
+            .zip(features)
+            .map(|(input, feature)| {
+                Ok(CachedVision {
+                    feature,
+                    grid_thw: input.grid_thw.i(0)?.to_vec1::<u32>()?,
+                    timestamps: input.timestamps,
+                })
+            })
+            .collect()
+    }
+
+    // 输入完整prompt, 返回最后位置的logits和prompt长度
+    fn prefill_logits(&mut self, mes: &ChatCompletionParameters) -> Result<(Tensor, usize)> {
+        let mes_render = self.chat_template.apply_chat_template(mes)?;
+        let overrides = self.take_request_overrides(mes)?;
+        let (media, num_images) = self.media_vision(mes, &overrides, None)?;
+        let (images, videos) = media.split_at(num_images);
+        let images: Vec<&CachedVision> = images.iter().map(|(_, v)| v).collect();
+        let videos: Vec<&CachedVision> = videos.iter().map(|(_, v)| v).collect();
+        let text = self
+            .pre_processor
+            .replace_text(&mes_render, &images, &videos)?;
+        let input_ids = self.tokenizer.text_encode(text, &self.device)?;
+        let image_features =
+            concat_features(&images.iter().map(|v| &v.feature).collect::<Vec<_>>())?;
+        let video_features =
+            concat_features(&videos.iter().map(|v| &v.feature).collect::<Vec<_>>())?;
+        let image_grid_thw = grid_thw_tensor(&images, &self.device)?;
+        let video_grid_thw = grid_thw_tensor(&videos, &self.device)?;
+        self.qwen3_vl.clear_kv_cache();
+        let logits = self.qwen3_vl.forward_delta(
+            &input_ids,
+            0,
+            image_grid_thw.as_ref(),
+            video_grid_thw.as_ref(),
+            image_features,
+            video_features,
+        )?;
+        Ok((logits, input_ids.dim(1)?))
+    }
+
+    fn prefill(&mut self, mes: &ChatCompletionParameters) -> Result<(Vec<f32>, usize)> {
+        let (logits, seq_len) = self.prefill_logits(mes)?;
+        let logits = logits
+            .flatten_all()?
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?;
+        Ok((logits, seq_len))
+    }
+
+    fn decode_hypotheses(&self, mut hyps: Vec<Hypothesis>) -> Result<Vec<Hypothesis>> {
+        for hyp in hyps.iter_mut() {
+            hyp.text = self.tokenizer.token_decode(hyp.tokens.clone())?;
+        }
+        Ok(hyps)
+    }
+
+    // 确定性的束搜索, 返回得分最高的cfg.num_return_sequences个结果
+    pub fn beam_search(
+        &mut self,
+        mes: ChatCompletionParameters,
+        cfg: &BeamSearchConfig,
+    ) -> Result<Vec<Hypothesis>> {
+        let (logits, seq_len) = self.prefill(&mes)?;
+        let eos_token_ids = [self.eos_token_id1, self.eos_token_id2];
+        let hyps = beam_search(&mut self.qwen3_vl, logits, seq_len, &eos_token_ids, cfg);
+        self.qwen3_vl.clear_kv_cache();
+        self.decode_hypotheses(hyps?)
+    }
+
+    // 采样n次, 按累计logprob从高到低返回
+    pub fn best_of_n(
+        &mut self,
+        mes: ChatCompletionParameters,
+        n: usize,
+    ) -> Result<Vec<Hypothesis>> {
+        let temperature = match mes.temperature {
+            None => self.generation_config.temperature,
+            Some(tem) => tem,
+        };
+        let top_p = match mes.top_p {
+            None => self.generation_config.top_p,
+            Some(top_p) => top_p,
+        };
+        let top_k = self.generation_config.top_k;
+        let mut logit_processor = get_logit_processor(Some(temperature), Some(top_p), Some(top_k));
+        let (logits, seq_len) = self.prefill(&mes)?;
+        let eos_token_ids = [self.eos_token_id1, self.eos_token_id2];
+        let sample_len = mes.max_tokens.unwrap_or(1024) as usize;
+        let hyps = best_of_n(
+            &mut self.qwen3_vl,
+            logits,
+            seq_len,
+            &eos_token_ids,
+            n,
+            sample_len,
+            &mut logit_processor,
+        );
+        self.qwen3_vl.clear_kv_cache();
+        self.decode_hypotheses(hyps?)
+    }
+
+    // 渲染完整对话, 复用session中相同前缀的kv cache, 只prefill之后的token, 返回最后位置的logits和完整输入
+    fn session_prefill(
+        &mut self,
+        session: &mut ChatSession,
+        mes: &ChatCompletionParameters,
+    ) -> Result<(Tensor, Vec<u32>)> {
+        let mes_render = self.chat_template.apply_chat_template(mes)?;
+        let overrides = self.take_request_overrides(mes)?;
+        let (media, num_images) = self.media_vision(mes, &overrides, Some(session))?;
+        let (image_media, video_media) = media.split_at(num_images);
+        let images: Vec<&CachedVision> = image_media.iter().map(|(_, v)| v).collect();
+        let videos: Vec<&CachedVision> = video_media.iter().map(|(_, v)| v).collect();
+        let text = self
+            .pre_processor
+            .replace_text(&mes_render, &images, &videos)?;
+        let input_ids = self.tokenizer.text_encode(text, &self.device)?;
+        let image_grid_thw = grid_thw_tensor(&images, &self.device)?;
+        let video_grid_thw = grid_thw_tensor(&videos, &self.device)?;
+        let merge_size = self.qwen3_vl.config().vision_config.spatial_merge_size;
+        let to_items = |media: &[(VisionCacheKey, CachedVision)]| -> Result<Vec<MediaItem>> {
+            media
+                .iter()
+                .map(|(key, v)| {
+                    MediaItem::new(key.clone(), &v.grid_thw, v.feature.clone(), merge_size)
+                })
+                .collect()
+        };
+        let images = to_items(image_media)?;
+        let videos = to_items(video_media)?;
+        let ids = input_ids.squeeze(0)?.to_vec1::<u32>()?;
+        let (start, image_features, video_features) =
+            session.prepare(&mut self.qwen3_vl, &ids, &images, &videos)?;
+        let logits = self.qwen3_vl.forward_delta(
+            &input_ids,
+            start,
+            image_grid_thw.as_ref(),
+            video_grid_thw.as_ref(),
+            image_features,
+            video_features,
+        )?;
+        Ok((logits, ids))
+    }
+
+    fn forward_next(&mut self, token: u32, seqlen_offset: usize) -> Result<Tensor> {
+        let input_ids = Tensor::from_vec(vec![token], (1, 1), &self.device)?;
+        let cache_position = Tensor::from_vec(vec![seqlen_offset as u32], 1, &self.device)?;
+        self.qwen3_vl.forward(
+            &input_ids,
+            None,
+            None,
+            None,
+            None,
+            Some(&cache_position),
+            seqlen_offset,
+        )
+    }
+
+    // 图片/视频的占位token不计入困惑度
+    fn score_rendered(
+        &mut self,
+        mes: &ChatCompletionParameters,
+        mes_render: &str,
+    ) -> Result<ScoreOutput> {
+        let overrides = self.take_request_overrides(mes)?;
+        let input = self
+            .pre_processor
+            .process_info(mes, mes_render, &overrides)?;
+        let input_ids = self
+            .tokenizer
+            .text_encode(input.replace_text.clone(), &self.device)?;
+        let hidden_states = self
+            .qwen3_vl
+            .forward_hidden(
+                &input_ids,
+                input.pixel_values.as_ref(),
+                input.image_grid_thw.as_ref(),
+                input.pixel_values_video.as_ref(),
+                input.video_grid_thw.as_ref(),
+            )?
+            .squeeze(0)?;
+        let ids = input_ids.squeeze(0)?.to_vec1::<u32>()?;
+        let logprobs = token_logprobs(&hidden_states, &ids, |h| self.qwen3_vl.hidden_logits(h))?;
+        let config = self.qwen3_vl.config();
+        let skip_token_ids = [config.image_token_id as u32, config.video_token_id as u32];
+        score_tokens(&ids, &logprobs, &skip_token_ids, |id| {
+            self.tokenizer.token_decode(vec![id])
         })
     }
+
+    // 多轮对话, mes为包含历史消息的完整对话, session在轮次之间保存kv cache和视觉特征
+    pub fn chat(
+        &mut self,
+        session: &mut ChatSession,
+        mes: ChatCompletionParameters,
+    ) -> Result<ChatCompletionResponse> {
+        let temperature = match mes.temperature {
+            None => self.generation_config.temperature,
+            Some(tem) => tem,
+        };
+        let top_p = match mes.top_p {
+            None => self.generation_config.top_p,
+            Some(top_p) => top_p,
+        };
+        let top_k = self.generation_config.top_k;
+        let mut logit_processor = get_logit_processor(Some(temperature), Some(top_p), Some(top_k));
+        let (mut logits, mut tokens) = self.session_prefill(session, &mes)?;
+        let prompt_tokens = tokens.len();
+        let mut generate = Vec::new();
+        let sample_len = mes.max_tokens.unwrap_or(1024) as usize;
+        loop {
+            let next_token =
+                logit_processor.sample(&logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?)?;
+            generate.push(next_token);
+            if next_token == self.eos_token_id1
+                || next_token == self.eos_token_id2
+                || generate.len() >= sample_len
+            {
+                break;
+            }
+            logits = self.forward_next(next_token, tokens.len())?;
+            tokens.push(next_token);
+        }
+        session.update(&self.qwen3_vl, tokens)?;
+        self.qwen3_vl.clear_kv_cache();
+        let completion_tokens = generate.len();
+        let res = self.tokenizer.token_decode(generate)?;
+        let mut response = build_completion_response(res, "qwen3vl");
+        response.usage = Some(completion_usage(prompt_tokens, completion_tokens)?);
+        Ok(response)
+    }
+
+    pub fn chat_stream<'s>(
+        &'s mut self,
+        session: &'s mut ChatSession,
+        mes: ChatCompletionParameters,
+    ) -> Result<impl Stream<Item = Result<ChatCompletionChunkResponse, anyhow::Error>> + 's> {
+        let temperature = match mes.temperature {
+            None => self.generation_config.temperature,
+            Some(tem) => tem,
+        };
+        let top_p = match mes.top_p {
+            None => self.generation_config.top_p,
+            Some(top_p) => top_p,
+        };
+        let top_k = self.generation_config.top_k;
+        let mut logit_processor = get_logit_processor(Some(temperature), Some(top_p), Some(top_k));
+        let (mut logits, mut tokens) = self.session_prefill(session, &mes)?;
+        let sample_len = mes.max_tokens.unwrap_or(1024) as usize;
+        let stream = stream! {
+            let mut decoder = StreamDecoder::default();
+            let mut generated = 0;
+            loop {
+                let logits_ = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+                let next_token = logit_processor.sample(&logits_)?;
+                generated += 1;
+                if let Some(text) = decoder.step(&self.tokenizer, next_token)? {
+                    let chunk = build_completion_chunk_response(text, "qwen3vl", None, None);
+                    yield Ok(chunk);
+                }
+                if next_token == self.eos_token_id1
+                    || next_token == self.eos_token_id2
+                    || generated >= sample_len
+                {
+                    break;
+                }
+                logits = self.forward_next(next_token, tokens.len())?;
+                tokens.push(next_token);
+            }
+            if let Some(text) = decoder.flush(&self.tokenizer)? {
+                yield Ok(build_completion_chunk_response(text, "qwen3vl", None, None));
+            }
+            session.update(&self.qwen3_vl, tokens)?;
+            self.qwen3_vl.clear_kv_cache();
+        };
+        Ok(stream)
+    }
 }
 
 impl<'a> GenerateModel for Qwen3VLGenerateModel<'a> {
@@ -77,46 +485,28 @@ impl<'a> GenerateModel for Qwen3VLGenerateModel<'a> {
         };
         let top_k = self.generation_config.top_k;
         let mut logit_processor = get_logit_processor(Some(temperature), Some(top_p), Some(top_k));
-        let mes_render = self.chat_template.apply_chat_template(&mes)?;
-        let input = self.pre_processor.process_info(&mes, &mes_render)?;
-        let mut input_ids = self
-            .tokenizer
-            .text_encode(input.replace_text.clone(), &self.device)?;
-        let mut seq_len = input_ids.dim(1)?;
-        let mut seqlen_offset = 0;
-        let mut pixel_values = input.pixel_values.as_ref();
-        let image_grid_thw = input.image_grid_thw.as_ref();
-        let mut pixel_values_video = input.pixel_values_video.as_ref();
-        let video_grid_thw = input.video_grid_thw.as_ref();
-        let mut cache_position = Tensor::arange(0u32, seq_len as u32, &self.device)?;
+        let (mut logits, mut seqlen_offset) = self.prefill_logits(&mes)?;
+        let prompt_tokens = seqlen_offset;
         let mut generate = Vec::new();
-        let sample_len = mes.max_tokens.unwrap_or(1024);
-        for _ in 0..sample_len {
-            let logits = self.qwen3_vl.forward(
-                &input_ids,
-                pixel_values,
-                image_grid_thw,
-                pixel_values_video,
-                video_grid_thw,
-                Some(&cache_position),
-                seqlen_offset,
-            )?;
-            let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
-            let next_token = logit_processor.sample(&logits)?;
+        let sample_len = mes.max_tokens.unwrap_or(1024) as usize;
+        loop {
+            let next_token =
+                logit_processor.sample(&logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?)?;
             generate.push(next_token);
-            if next_token == self.eos_token_id1 || next_token == self.eos_token_id2 {
+            if next_token == self.eos_token_id1
+                || next_token == self.eos_token_id2
+                || generate.len() >= sample_len
+            {
                 break;
             }
-            seqlen_offset += seq_len;
-            seq_len = 1;
-            input_ids = Tensor::from_vec(vec![next_token], (1, 1), &self.device)?;
-            cache_position = Tensor::from_vec(vec![seqlen_offset as u32], 1, &self.device)?;
-            pixel_values = None;
-            pixel_values_video = None;
+            logits = self.forward_next(next_token, seqlen_offset)?;
+            seqlen_offset += 1;
         }
+        let completion_tokens = generate.len();
         let res = self.tokenizer.token_decode(generate)?;
         self.qwen3_vl.clear_kv_cache();
-        let response = build_completion_response(res, "qwen3vl");
+        let mut response = build_completion_response(res, "qwen3vl");
+        response.usage = Some(completion_usage(prompt_tokens, completion_tokens)?);
         Ok(response)
     }
 
@@ -134,71 +524,184 @@ impl<'a> GenerateModel for Qwen3VLGenerateModel<'a> {
         };
         let top_k = self.generation_config.top_k;
         let mut logit_processor = get_logit_processor(Some(temperature), Some(top_p), Some(top_k));
-        let mes_render = self.chat_template.apply_chat_template(&mes)?;
-        let input = self.pre_processor.process_info(&mes, &mes_render)?;
-        let mut input_ids = self
-            .tokenizer
-            .text_encode(input.replace_text.clone(), &self.device)?;
-        let mut seq_len = input_ids.dim(1)?;
-        let mut seqlen_offset = 0;
-        let pixel_values = input.pixel_values.clone();
-        let image_grid_thw = input.image_grid_thw.clone();
-        let pixel_values_video = input.pixel_values_video.clone();
-        let video_grid_thw = input.video_grid_thw.clone();
-        let mut cache_position = Tensor::arange(0u32, seq_len as u32, &self.device)?;
-        let sample_len = mes.max_tokens.unwrap_or(1024);
+        let (mut logits, mut seqlen_offset) = self.prefill_logits(&mes)?;
+        let sample_len = mes.max_tokens.unwrap_or(1024) as usize;
         let stream = stream! {
-            let mut error_tokens = Vec::new();
-            let mut pixel_values = pixel_values.as_ref();
-            let image_grid_thw = image_grid_thw.as_ref();
-            let mut pixel_values_video = pixel_values_video.as_ref();
-            let video_grid_thw = video_grid_thw.as_ref();
-            for _ in 0..sample_len {
-            let logits = self.qwen3_vl.forward(
-                &input_ids,
-                pixel_values,
-                image_grid_thw,
-                pixel_values_video,
-                video_grid_thw,
-                Some(&cache_position),
-                seqlen_offset,
-            )?;
-            let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
-            let next_token = logit_processor.sample(&logits)?;
-            let mut decode_ids = Vec::new();
-                if !error_tokens.is_empty() {
-                    decode_ids.extend_from_slice(&error_tokens);
-                }
-                decode_ids.push(next_token);
-                let decoded_token = self.tokenizer.token_decode(decode_ids).map_err(|e| anyhow!(format!("stream decode error{}", e)))?;
-                if decoded_token.contains("�") {
-                    error_tokens.push(next_token);
-                    if error_tokens.len() > 3 {
-                        error_tokens.clear();
-                    }
-                    seqlen_offset += seq_len;
-                    seq_len = 1;
-                    input_ids = Tensor::from_vec(vec![next_token], (1, 1), &self.device)?;
-                    cache_position = Tensor::from_vec(vec![seqlen_offset as u32], 1, &self.device)?;
-                    pixel_values = None;
-                    pixel_values_video = None;
-                    continue;
+            let mut decoder = StreamDecoder::default();
+            let mut generated = 0;
+            loop {
+                let logits_ = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+                let next_token = logit_processor.sample(&logits_)?;
+                generated += 1;
+                if let Some(text) = decoder.step(&self.tokenizer, next_token)? {
+                    let chunk = build_completion_chunk_response(text, "qwen3vl", None, None);
+                    yield Ok(chunk);
                 }
-                error_tokens.clear();
-                let chunk = build_completion_chunk_response(decoded_token, "qwen3vl", None, None);
-                yield Ok(chunk);
-                if next_token == self.eos_token_id1 || next_token == self.eos_token_id2 {
+                if next_token == self.eos_token_id1
+                    || next_token == self.eos_token_id2
+                    || generated >= sample_len
+                {
                     break;
                 }
-                seqlen_offset += seq_len;
-                seq_len = 1;
-                input_ids = Tensor::from_vec(vec![next_token], (1, 1), &self.device)?;
-                cache_position = Tensor::from_vec(vec![seqlen_offset as u32], 1, &self.device)?;
-                pixel_values = None;
-                pixel_values_video = None;
+                logits = self.forward_next(next_token, seqlen_offset)?;
+                seqlen_offset += 1;
+            }
+            if let Some(text) = decoder.flush(&self.tokenizer)? {
+                yield Ok(build_completion_chunk_response(text, "qwen3vl", None, None));
             }
             self.qwen3_vl.clear_kv_cache();
         };
         Ok(stream)
     }
 }
+
+impl<'a> EmbeddingModel for Qwen3VLGenerateModel<'a> {
+    fn hidden_size(&self) -> usize {
+        self.qwen3_vl.config().text_config.hidden_size
+    }
+
+    fn embed_text(&mut self, text: &str, pooling: Pooling) -> Result<(Vec<f32>, usize)> {
+        let input_ids = self.tokenizer.text_encode(text.to_string(), &self.device)?;
+        let hidden_states = self
+            .qwen3_vl
+            .forward_hidden(&input_ids, None, None, None, None)?;
+        let embedding = pool_hidden_states(&hidden_states.squeeze(0)?, pooling)?;
+        Ok((embedding, input_ids.dim(1)?))
+    }
+
+    fn embed_texts(&mut self, texts: &[&str], pooling: Pooling) -> Result<Vec<(Vec<f32>, usize)>> {
+        let ids = texts
+            .iter()
+            .map(|text| {
+                Ok(self
+                    .tokenizer
+                    .text_encode(text.to_string(), &self.device)?
+                    .squeeze(0)?
+                    .to_vec1::<u32>()?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let input_ids = pad_batch(&ids, &self.device)?;
+        let hidden_states = self
+            .qwen3_vl
+            .forward_hidden(&input_ids, None, None, None, None)?;
+        let lens: Vec<usize> = ids.iter().map(|i| i.len()).collect();
+        pool_batch(&hidden_states, &lens, pooling)
+    }
+
+    // 只输入图片的占位token, 不使用对话模板
+    fn embed_image(&mut self, url: &str, pooling: Pooling) -> Result<(Vec<f32>, usize)> {
+        let mes: ChatCompletionParameters = serde_json::from_value(serde_json::json!({
+            "model": "qwen3vl",
+            "messages": [{
+                "role": "user",
+                "content": [{"type": "image", "image_url": {"url": url}}]
+            }]
+        }))?;
+        let input = self.pre_processor.process_info(
+            &mes,
+            "<|vision_start|><|image_pad|><|vision_end|>",
+            &RequestVisionOverrides::default(),
+        )?;
+        if input.pixel_values.is_none() {
+            return Err(anyhow!(format!("load image {} failed", url)));
+        }
+        let input_ids = self
+            .tokenizer
+            .text_encode(input.replace_text.clone(), &self.device)?;
+        let hidden_states = self.qwen3_vl.forward_hidden(
+            &input_ids,
+            input.pixel_values.as_ref(),
+            input.image_grid_thw.as_ref(),
+            None,
+            None,
+        )?;
+        let embedding = pool_hidden_states(&hidden_states.squeeze(0)?, pooling)?;
+        Ok((embedding, input_ids.dim(1)?))
+    }
+}
+
+impl<'a> ScoringModel for Qwen3VLGenerateModel<'a> {
+    fn score_text(&mut self, text: &str) -> Result<ScoreOutput> {
+        let input_ids = self.tokenizer.text_encode(text.to_string(), &self.device)?;
+        let hidden_states = self
+            .qwen3_vl
+            .forward_hidden(&input_ids, None, None, None, None)?
+            .squeeze(0)?;
+        let ids = input_ids.squeeze(0)?.to_vec1::<u32>()?;
+        let logprobs = token_logprobs(&hidden_states, &ids, |h| self.qwen3_vl.hidden_logits(h))?;
+        score_tokens(&ids, &logprobs, &[], |id| {
+            self.tokenizer.token_decode(vec![id])
+        })
+    }
+
+    fn score(&mut self, mes: ChatCompletionParameters) -> Result<ScoreOutput> {
+        let mes_render = self.chat_template.render(&mes, false)?;
+        self.score_rendered(&mes, &mes_render)
+    }
+}
+
+impl<'a> ParityModel for Qwen3VLGenerateModel<'a> {
+    fn parity_outputs(&mut self, golden: &GoldenData) -> Result<Vec<(String, Tensor)>> {
+        let dtype = self.qwen3_vl.dtype();
+        let input_ids = golden.input_ids("input_ids", &self.device)?;
+        let pixel_values = golden.input("pixel_values", dtype, &self.device)?;
+        let image_grid_thw = golden.input("image_grid_thw", DType::U32, &self.device)?;
+        let pixel_values_video = golden.input("pixel_values_videos", dtype, &self.device)?;
+        let video_grid_thw = golden.input("video_grid_thw", DType::U32, &self.device)?;
+        let mut outputs = vec![];
+        let vision_inputs = [
+            ("image", &pixel_values, &image_grid_thw),
+            ("video", &pixel_values_video, &video_grid_thw),
+        ];
+        for (kind, pixel_values, grid_thw) in vision_inputs {
+            let (Some(pixel_values), Some(grid_thw)) = (pixel_values, grid_thw) else {
+                continue;
+            };
+            let features = self.qwen3_vl.vision_features(pixel_values, grid_thw)?;
+            let embeds: Vec<&Tensor> = features.iter().map(|f| &f.0).collect();
+            outputs.push((format!("{}_embeds", kind), Tensor::cat(&embeds, 0)?));
+            let num_deepstack = features.first().map(|f| f.1.len()).unwrap_or(0);
+            for layer in 0..num_deepstack {
+                let layer_embeds: Vec<&Tensor> = features.iter().map(|f| &f.1[layer]).collect();
+                outputs.push((
+                    format!("deepstack_{}_embeds.{}", kind, layer),
+                    Tensor::cat(&layer_embeds, 0)?,
+                ));
+            }
+        }
+        let hidden_states = self.qwen3_vl.forward_hidden_states(
+            &input_ids,
+            pixel_values.as_ref(),
+            image_grid_thw.as_ref(),
+            pixel_values_video.as_ref(),
+            video_grid_thw.as_ref(),
+        )?;
+        for (i, hidden) in hidden_states.into_iter().enumerate() {
+            outputs.push((format!("hidden_states.{}", i), hidden));
+        }
+        let logits = self.qwen3_vl.forward_logits(
+            &input_ids,
+            pixel_values.as_ref(),
+            image_grid_thw.as_ref(),
+            pixel_values_video.as_ref(),
+            video_grid_thw.as_ref(),
+        )?;
+        outputs.push(("logits".to_string(), logits));
+        // 生成时走的kv cache路径
+        let seq_len = input_ids.dim(1)?;
+        let cache_position = Tensor::arange(0u32, seq_len as u32, &self.device)?;
+        self.qwen3_vl.clear_kv_cache();
+        let logits = self.qwen3_vl.forward(
+            &input_ids,
+            pixel_values.as_ref(),
+            image_grid_thw.as_ref(),
+            pixel_values_video.as_ref(),
+            video_grid_thw.as_ref(),
+            Some(&cache_position),
+            0,
+        );
+        self.qwen3_vl.clear_kv_cache();
+        outputs.push(("logits.last_with_cache".to_string(), logits?));
+        Ok(outputs)
+    }
+}
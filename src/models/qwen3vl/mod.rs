@@ -2,3 +2,4 @@ pub mod config;
 pub mod generate;
 pub mod model;
 pub mod processor;
+pub mod session;
@@ -2,12 +2,19 @@ use anyhow::{Result, anyhow};
 use candle_core::{D, DType, IndexOp, Shape, Tensor};
 use candle_nn::{
     Activation, Embedding, Init, LayerNorm, LayerNormConfig, Linear, Module, RmsNorm, VarBuilder,
-    embedding, layer_norm, linear, linear_no_bias, rms_norm,
+    embedding, layer_norm, linear, rms_norm,
 };
 
 use crate::{
     models::{
-        common::{MLPNoBias, eager_attention_forward},
+        common::{
+            KvCacheLayer, KvCacheSnapshot, MLPNoBias, eager_attention_forward, layers_kv_cache_len,
+            layers_kv_caches,
+            quant::{QLinear, qlinear, qlinear_no_bias},
+            search::SearchLM,
+            set_layers_kv_caches, truncate_kv_cache,
+            vision_cache::VisionFeature,
+        },
         qwen3vl::config::{Qwen3VLConfig, Qwen3VLTextConfig, Qwen3VLVisionConfig},
     },
     position_embed::rope::{
@@ -551,10 +558,10 @@ impl Qwen3VLVisionModel {
 }
 
 pub struct Qwen3VLTextAttention {
-    q_proj: Linear,
-    k_proj: Linear,
-    v_proj: Linear,
-    o_proj: Linear,
+    q_proj: QLinear,
+    k_proj: QLinear,
+    v_proj: QLinear,
+    o_proj: QLinear,
     q_norm: RmsNorm,
     k_norm: RmsNorm,
     num_attention_heads: usize,
@@ -574,20 +581,48 @@ impl Qwen3VLTextAttention {
         let num_key_value_heads = config.num_key_value_heads;
         let num_kv_groups = num_attention_heads / num_key_value_heads;
         let scaling = 1f64 / f64::sqrt(head_dim as f64);
+        let quant = &config.quant;
         let (q_proj, k_proj, v_proj, o_proj) = if config.attention_bias {
-            let q_proj = linear(hidden_size, num_attention_heads * head_dim, vb.pp("q_proj"))?;
-            let k_proj = linear(hidden_size, num_key_value_heads * head_dim, vb.pp("k_proj"))?;
-            let v_proj = linear(hidden_size, num_key_value_heads * head_dim, vb.pp("v_proj"))?;
-            let o_proj = linear(hidden_size, hidden_size, vb.pp("o_proj"))?;
+            let q_proj = qlinear(
+                hidden_size,
+                num_attention_heads * head_dim,
+                vb.pp("q_proj"),
+                quant,
+            )?;
+            let k_proj = qlinear(
+                hidden_size,
+                num_key_value_heads * head_dim,
+                vb.pp("k_proj"),
+                quant,
+            )?;
+            let v_proj = qlinear(
+                hidden_size,
+                num_key_value_heads * head_dim,
+                vb.pp("v_proj"),
+                quant,
+            )?;
+            let o_proj = qlinear(hidden_size, hidden_size, vb.pp("o_proj"), quant)?;
             (q_proj, k_proj, v_proj, o_proj)
         } else {
-            let q_proj =
-                linear_no_bias(hidden_size, num_attention_heads * head_dim, vb.pp("q_proj"))?;
-            let k_proj =
-                linear_no_bias(hidden_size, num_key_value_heads * head_dim, vb.pp("k_proj"))?;
-            let v_proj =
-                linear_no_bias(hidden_size, num_key_value_heads * head_dim, vb.pp("v_proj"))?;
-            let o_proj = linear_no_bias(hidden_size, hidden_size, vb.pp("o_proj"))?;
+            let q_proj = qlinear_no_bias(
+                hidden_size,
+                num_attention_heads * head_dim,
+                vb.pp("q_proj"),
+                quant,
+            )?;
+            let k_proj = qlinear_no_bias(
+                hidden_size,
+                num_key_value_heads * head_dim,
+                vb.pp("k_proj"),
+                quant,
+            )?;
+            let v_proj = qlinear_no_bias(
+                hidden_size,
+                num_key_value_heads * head_dim,
+                vb.pp("v_proj"),
+                quant,
+            )?;
+            let o_proj = qlinear_no_bias(hidden_size, hidden_size, vb.pp("o_proj"), quant)?;
             (q_proj, k_proj, v_proj, o_proj)
         };
         let q_norm = rms_norm(head_dim, config.rms_norm_eps, vb.pp("q_norm"))?;
@@ -662,6 +697,18 @@ impl Qwen3VLTextAttention {
     pub fn clear_kv_cache(&mut self) {
         self.kv_cache = None
     }
+
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        truncate_kv_cache(&mut self.kv_cache, len)
+    }
+
+    pub fn kv_cache(&self) -> Option<(Tensor, Tensor)> {
+        self.kv_cache.clone()
+    }
+
+    pub fn set_kv_cache(&mut self, kv_cache: Option<(Tensor, Tensor)>) {
+        self.kv_cache = kv_cache;
+    }
 }
 
 pub struct Qwen3VLTextDecoderLayer {
@@ -679,6 +726,7 @@ impl Qwen3VLTextDecoderLayer {
             config.hidden_size,
             config.intermediate_size,
             config.hidden_act,
+            &config.quant,
         )?;
         let input_layernorm = rms_norm(
             config.hidden_size,
@@ -719,6 +767,20 @@ impl Qwen3VLTextDecoderLayer {
     pub fn clear_kv_cache(&mut self) {
         self.self_attn.clear_kv_cache();
     }
+
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.self_attn.truncate_kv_cache(len)
+    }
+}
+
+impl KvCacheLayer for Qwen3VLTextDecoderLayer {
+    fn kv_cache(&self) -> Option<(Tensor, Tensor)> {
+        self.self_attn.kv_cache()
+    }
+
+    fn set_kv_cache(&mut self, kv_cache: Option<(Tensor, Tensor)>) {
+        self.self_attn.set_kv_cache(kv_cache);
+    }
 }
 
 pub struct Qwen3VLTextModel {
@@ -770,6 +832,26 @@ impl Qwen3VLTextModel {
         position_ids: Option<&Tensor>,
         visual_pos_masks: Option<&Tensor>,
         deepstack_visual_embeds: Option<Vec<Tensor>>,
+    ) -> Result<Tensor> {
+        self.forward_impl(
+            inputs_embeds,
+            seqlen_offset,
+            position_ids,
+            visual_pos_masks,
+            deepstack_visual_embeds,
+            None,
+        )
+    }
+
+    // all_hidden_states不为None时记录每层的输入(deepstack加在上一层输出之后)和最后norm之后的输出
+    fn forward_impl(
+        &mut self,
+        inputs_embeds: &Tensor,
+        seqlen_offset: usize,
+        position_ids: Option<&Tensor>,
+        visual_pos_masks: Option<&Tensor>,
+        deepstack_visual_embeds: Option<Vec<Tensor>>,
+        mut all_hidden_states: Option<&mut Vec<Tensor>>,
     ) -> Result<Tensor> {
         let (b_size, seq_len, _) = inputs_embeds.dims3()?;
 
@@ -797,12 +879,15 @@ impl Qwen3VLTextModel {
                 Some(&prepare_causal_attention_mask(
                     b_size,
                     seq_len,
-                    0,
+                    seqlen_offset,
                     inputs_embeds.device(),
                 )?)
             }
         };
         for (layer_idx, layer) in self.layers.iter_mut().enumerate() {
+            if let Some(all) = all_hidden_states.as_mut() {
+                all.push(xs.clone());
+            }
             xs = layer.forward(&xs, &cos, &sin, attention_mask)?;
             if let Some(deepstack_embeds) = deepstack_visual_embeds.as_ref()
                 && layer_idx < deepstack_embeds.len()
@@ -816,6 +901,9 @@ impl Qwen3VLTextModel {
             }
         }
         let xs = xs.apply(&self.norm)?;
+        if let Some(all) = all_hidden_states {
+            all.push(xs.clone());
+        }
         Ok(xs)
     }
 
@@ -824,13 +912,32 @@ impl Qwen3VLTextModel {
             layer.clear_kv_cache()
         }
     }
+
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            layer.truncate_kv_cache(len)?;
+        }
+        Ok(())
+    }
+
+    pub fn kv_cache_len(&self) -> usize {
+        layers_kv_cache_len(&self.layers)
+    }
+
+    pub fn kv_caches(&self) -> Vec<Option<(Tensor, Tensor)>> {
+        layers_kv_caches(&self.layers)
+    }
+
+    pub fn set_kv_caches(&mut self, kv_caches: &[Option<(Tensor, Tensor)>]) -> Result<()> {
+        set_layers_kv_caches(&mut self.layers, kv_caches)
+    }
 }
 
 pub struct Qwen3VLModel {
     config: Qwen3VLConfig,
     visual: Qwen3VLVisionModel,
     language_model: Qwen3VLTextModel,
-    lm_head: Linear,
+    lm_head: QLinear,
     rope_deltas: Option<Tensor>,
 }
 
@@ -841,12 +948,16 @@ impl Qwen3VLModel {
         let language_model =
             Qwen3VLTextModel::new(config.text_config.clone(), vb.pp("language_model"))?;
         let lm_head = if config.tie_word_embeddings {
-            Linear::new(language_model.embed_tokens.embeddings().clone(), None)
+            QLinear::Linear(Linear::new(
+                language_model.embed_tokens.embeddings().clone(),
+                None,
+            ))
         } else {
-            linear_no_bias(
+            qlinear_no_bias(
                 config.text_config.hidden_size,
                 config.text_config.vocab_size,
                 vb.pp("lm_head"),
+                &config.text_config.quant.gguf_only(),
             )?
         };
         Ok(Self {
@@ -858,6 +969,7 @@ impl Qwen3VLModel {
         })
     }
 
+    // 返回每项的embeds和按层拼接的deepstack embeds
     fn get_vision_features(
         &self,
         pixel_values: &Tensor,
@@ -1115,6 +1227,29 @@ impl Qwen3VLModel {
         }
     }
 
+    // 按图片/视频切分视觉特征, 每项为(embeds, 每个deepstack层的embeds)
+    pub fn vision_features(
+        &self,
+        pixel_values: &Tensor,
+        grid_thw: &Tensor,
+    ) -> Result<Vec<VisionFeature>> {
+        let (embeds, deepstack_embeds) = self.get_vision_features(pixel_values, grid_thw)?;
+        let split_sizes: Vec<usize> = embeds
+            .iter()
+            .map(|e| e.dim(0))
+            .collect::<candle_core::Result<_>>()?;
+        let deepstack_embeds = deepstack_embeds
+            .iter()
+            .map(|e| split_tensor(e, &split_sizes, 0))
+            .collect::<Result<Vec<_>>>()?;
+        let features = embeds
+            .into_iter()
+            .enumerate()
+            .map(|(i, e)| (e, deepstack_embeds.iter().map(|d| d[i].clone()).collect()))
+            .collect();
+        Ok(features)
+    }
+
     pub fn forward(
         &mut self,
         input_ids: &Tensor,
@@ -1124,18 +1259,237 @@ impl Qwen3VLModel {
         video_grid_thw: Option<&Tensor>,
         cache_position: Option<&Tensor>,
         seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let image_features = match (pixel_values, image_grid_thw) {
+            (Some(pixel_values), Some(image_grid_thw)) => {
+                let (image_embeds, deepstack_img_embed) =
+                    self.get_vision_features(pixel_values, image_grid_thw)?;
+                Some((Tensor::cat(&image_embeds, 0)?, deepstack_img_embed))
+            }
+            _ => None,
+        };
+        let video_features = match (pixel_values_video, video_grid_thw) {
+            (Some(pixel_values_video), Some(video_grid_thw)) => {
+                let (video_embeds, deepstack_video_embed) =
+                    self.get_vision_features(pixel_values_video, video_grid_thw)?;
+                Some((Tensor::cat(&video_embeds, 0)?, deepstack_video_embed))
+            }
+            _ => None,
+        };
+        let position_ids;
+        let rope_deltas;
+        if (cache_position.is_some() && cache_position.unwrap().i(0)?.to_scalar::<u32>()? == 0)
+            || self.rope_deltas.is_none()
+        {
+            (position_ids, rope_deltas) =
+                self.get_rope_index(input_ids, image_grid_thw, video_grid_thw, None)?;
+            self.rope_deltas = Some(rope_deltas);
+        } else {
+            let (bs, seq_len) = input_ids.dims2()?;
+            let delta = if let Some(cache_position) = cache_position {
+                cache_position
+                    .i(0)?
+                    .to_dtype(self.rope_deltas.as_ref().unwrap().dtype())?
+                    .broadcast_add(self.rope_deltas.as_ref().unwrap())?
+                    .contiguous()?
+                    .to_dtype(candle_core::DType::U32)?
+            } else {
+                Tensor::zeros(1, candle_core::DType::U32, input_ids.device())?
+            };
+            position_ids = Tensor::arange(0u32, seq_len as u32, input_ids.device())?
+                .unsqueeze(0)?
+                .broadcast_as((bs, seq_len))?
+                .broadcast_add(&delta)?
+                .unsqueeze(0)?
+                .broadcast_as((3, bs, seq_len))?
+                .contiguous()?;
+        }
+        self.forward_embeds(
+            input_ids,
+            image_features,
+            video_features,
+            &position_ids,
+            seqlen_offset,
+        )
+    }
+
+    // 多轮会话的增量prefill, input_ids为完整对话, kv cache中已有前start个token
+    // image_features/video_features只包含start之后出现的图片/视频, grid_thw为完整对话中所有的图片/视频
+    pub fn forward_delta(
+        &mut self,
+        input_ids: &Tensor,
+        start: usize,
+        image_grid_thw: Option<&Tensor>,
+        video_grid_thw: Option<&Tensor>,
+        image_features: Option<(Tensor, Vec<Tensor>)>,
+        video_features: Option<(Tensor, Vec<Tensor>)>,
+    ) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        if start >= seq_len {
+            return Err(anyhow!(format!(
+                "delta prefill start {} must be less than input len {}",
+                start, seq_len
+            )));
+        }
+        // mrope位置依赖之前所有图片的grid, 按完整序列计算后截取
+        let (position_ids, rope_deltas) =
+            self.get_rope_index(input_ids, image_grid_thw, video_grid_thw, None)?;
+        self.rope_deltas = Some(rope_deltas);
+        let position_ids = position_ids
+            .narrow(2, start, seq_len - start)?
+            .contiguous()?;
+        let input_ids = input_ids.narrow(1, start, seq_len - start)?;
+        self.forward_embeds(
+            &input_ids,
+            image_features,
+            video_features,
+            &position_ids,
+            start,
+        )
+    }
+
+    // 不使用kv cache, 返回最后一层norm之后所有位置的hidden states: (1, seq_len, hidden_size)
+    pub fn forward_hidden(
+        &mut self,
+        input_ids: &Tensor,
+        pixel_values: Option<&Tensor>,
+        image_grid_thw: Option<&Tensor>,
+        pixel_values_video: Option<&Tensor>,
+        video_grid_thw: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        self.forward_hidden_impl(
+            input_ids,
+            pixel_values,
+            image_grid_thw,
+            pixel_values_video,
+            video_grid_thw,
+            None,
+        )
+    }
+
+    // 与transformers的output_hidden_states一致, 返回num_hidden_layers + 1个hidden states:
+    // 第0个为填入视觉特征后的embedding, 第i个为第i层的输入, 最后一个为最后一层norm之后的输出
+    pub fn forward_hidden_states(
+        &mut self,
+        input_ids: &Tensor,
+        pixel_values: Option<&Tensor>,
+        image_grid_thw: Option<&Tensor>,
+        pixel_values_video: Option<&Tensor>,
+        video_grid_thw: Option<&Tensor>,
+    ) -> Result<Vec<Tensor>> {
+        let mut all_hidden_states = vec![];
+        self.forward_hidden_impl(
+            input_ids,
+            pixel_values,
+            image_grid_thw,
+            pixel_values_video,
+            video_grid_thw,
+            Some(&mut all_hidden_states),
+        )?;
+        Ok(all_hidden_states)
+    }
+
+    fn forward_hidden_impl(
+        &mut self,
+        input_ids: &Tensor,
+        pixel_values: Option<&Tensor>,
+        image_grid_thw: Option<&Tensor>,
+        pixel_values_video: Option<&Tensor>,
+        video_grid_thw: Option<&Tensor>,
+        all_hidden_states: Option<&mut Vec<Tensor>>,
+    ) -> Result<Tensor> {
+        let image_features = match (pixel_values, image_grid_thw) {
+            (Some(pixel_values), Some(image_grid_thw)) => {
+                let (image_embeds, deepstack_img_embed) =
+                    self.get_vision_features(pixel_values, image_grid_thw)?;
+                Some((Tensor::cat(&image_embeds, 0)?, deepstack_img_embed))
+            }
+            _ => None,
+        };
+        let video_features = match (pixel_values_video, video_grid_thw) {
+            (Some(pixel_values_video), Some(video_grid_thw)) => {
+                let (video_embeds, deepstack_video_embed) =
+                    self.get_vision_features(pixel_values_video, video_grid_thw)?;
+                Some((Tensor::cat(&video_embeds, 0)?, deepstack_video_embed))
+            }
+            _ => None,
+        };
+        let (position_ids, _) =
+            self.get_rope_index(input_ids, image_grid_thw, video_grid_thw, None)?;
+        self.clear_kv_cache();
+        let hidden_states = self.hidden_states(
+            input_ids,
+            image_features,
+            video_features,
+            &position_ids,
+            0,
+            all_hidden_states,
+        );
+        self.clear_kv_cache();
+        hidden_states
+    }
+
+    // 不使用kv cache, 返回所有位置的logits: (1, seq_len, vocab_size)
+    pub fn forward_logits(
+        &mut self,
+        input_ids: &Tensor,
+        pixel_values: Option<&Tensor>,
+        image_grid_thw: Option<&Tensor>,
+        pixel_values_video: Option<&Tensor>,
+        video_grid_thw: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let hidden_states = self.forward_hidden(
+            input_ids,
+            pixel_values,
+            image_grid_thw,
+            pixel_values_video,
+            video_grid_thw,
+        )?;
+        self.hidden_logits(&hidden_states)
+    }
+
+    // forward_hidden的输出转为logits
+    pub fn hidden_logits(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        Ok(self.lm_head.forward(hidden_states)?)
+    }
+
+    fn forward_embeds(
+        &mut self,
+        input_ids: &Tensor,
+        image_features: Option<(Tensor, Vec<Tensor>)>,
+        video_features: Option<(Tensor, Vec<Tensor>)>,
+        position_ids: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let outputs = self.hidden_states(
+            input_ids,
+            image_features,
+            video_features,
+            position_ids,
+            seqlen_offset,
+            None,
+        )?;
+        let seq_len = outputs.dim(1)?;
+        let hidden_state = outputs.narrow(1, seq_len - 1, 1)?;
+        let logits = self.lm_head.forward(&hidden_state)?;
+        Ok(logits)
+    }
+
+    fn hidden_states(
+        &mut self,
+        input_ids: &Tensor,
+        image_features: Option<(Tensor, Vec<Tensor>)>,
+        video_features: Option<(Tensor, Vec<Tensor>)>,
+        position_ids: &Tensor,
+        seqlen_offset: usize,
+        all_hidden_states: Option<&mut Vec<Tensor>>,
     ) -> Result<Tensor> {
         let mut inputs_embeds = self.language_model.embed_tokens.forward(input_ids)?;
         let mut image_mask = None;
         let mut video_mask = None;
         let mut deepstack_image_embeds = None;
         let mut deepstack_video_embeds = None;
-        if let Some(pixel_values) = pixel_values
-            && let Some(image_grid_thw) = image_grid_thw
-        {
-            let (image_embeds, deepstack_img_embed) =
-                self.get_vision_features(pixel_values, image_grid_thw)?;
-            let image_embeds = Tensor::cat(&image_embeds, 0)?;
+        if let Some((image_embeds, deepstack_img_embed)) = image_features {
             let vision_mask = self.get_placeholder_mask(input_ids, true)?;
             let n_image_tokens = vision_mask.sum_all()?.to_scalar::<u32>()?;
             if n_image_tokens as usize != image_embeds.dim(0)? {
@@ -1149,12 +1503,7 @@ impl Qwen3VLModel {
             image_mask = Some(vision_mask);
             deepstack_image_embeds = Some(deepstack_img_embed);
         }
-        if let Some(pixel_values_video) = pixel_values_video
-            && let Some(video_grid_thw) = video_grid_thw
-        {
-            let (video_embeds, deepstack_video_embed) =
-                self.get_vision_features(pixel_values_video, video_grid_thw)?;
-            let video_embeds = Tensor::cat(&video_embeds, 0)?;
+        if let Some((video_embeds, deepstack_video_embed)) = video_features {
             let vision_mask = self.get_placeholder_mask(input_ids, false)?;
             let n_video_tokens = vision_mask.sum_all()?.to_scalar::<u32>()?;
             if n_video_tokens as usize != video_embeds.dim(0)? {
@@ -1208,48 +1557,79 @@ impl Qwen3VLModel {
             deepstack_visual_embeds = deepstack_video_embeds;
         }
 
-        let position_ids;
-        let rope_deltas;
-        if (cache_position.is_some() && cache_position.unwrap().i(0)?.to_scalar::<u32>()? == 0)
-            || self.rope_deltas.is_none()
-        {
-            (position_ids, rope_deltas) =
-                self.get_rope_index(input_ids, image_grid_thw, video_grid_thw, None)?;
-            self.rope_deltas = Some(rope_deltas);
-        } else {
-            let (bs, seq_len, _) = inputs_embeds.dims3()?;
-            let delta = if let Some(cache_position) = cache_position {
-                cache_position
-                    .i(0)?
-                    .to_dtype(self.rope_deltas.as_ref().unwrap().dtype())?
-                    .broadcast_add(self.rope_deltas.as_ref().unwrap())?
-                    .contiguous()?
-                    .to_dtype(candle_core::DType::U32)?
-            } else {
-                Tensor::zeros(1, inputs_embeds.dtype(), inputs_embeds.device())?
-            };
-            position_ids = Tensor::arange(0u32, seq_len as u32, input_ids.device())?
-                .unsqueeze(0)?
-                .broadcast_as((bs, seq_len))?
-                .broadcast_add(&delta)?
-                .unsqueeze(0)?
-                .broadcast_as((3, bs, seq_len))?
-                .contiguous()?;
-        }
-        let outputs = self.language_model.forward(
+        self.language_model.forward_impl(
             &inputs_embeds,
             seqlen_offset,
-            Some(&position_ids),
+            Some(position_ids),
             visual_pos_mask.as_ref(),
             deepstack_visual_embeds,
-        )?;
-        let seq_len = outputs.dim(1)?;
-        let hidden_state = outputs.narrow(1, seq_len - 1, 1)?;
-        let logits = self.lm_head.forward(&hidden_state)?;
-        Ok(logits)
+            all_hidden_states,
+        )
+    }
+
+    pub fn config(&self) -> &Qwen3VLConfig {
+        &self.config
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.language_model.embed_tokens.embeddings().dtype()
     }
 
     pub fn clear_kv_cache(&mut self) {
         self.language_model.clear_kv_cache();
     }
+
+    // 截断后继续生成时rope_deltas不变
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.language_model.truncate_kv_cache(len)
+    }
+
+    pub fn kv_cache_len(&self) -> usize {
+        self.language_model.kv_cache_len()
+    }
+
+    pub fn snapshot(&self) -> KvCacheSnapshot {
+        KvCacheSnapshot {
+            layers: self.language_model.kv_caches(),
+            rope_deltas: self.rope_deltas.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &KvCacheSnapshot) -> Result<()> {
+        self.language_model.set_kv_caches(&snapshot.layers)?;
+        self.rope_deltas = snapshot.rope_deltas.clone();
+        Ok(())
+    }
+}
+
+impl SearchLM for Qwen3VLModel {
+    fn decode(&mut self, token: u32, position_id: usize) -> Result<Vec<f32>> {
+        let device = self
+            .language_model
+            .embed_tokens
+            .embeddings()
+            .device()
+            .clone();
+        let input_ids = Tensor::from_vec(vec![token], (1, 1), &device)?;
+        let cache_position = Tensor::from_vec(vec![position_id as u32], 1, &device)?;
+        let logits = self.forward(
+            &input_ids,
+            None,
+            None,
+            None,
+            None,
+            Some(&cache_position),
+            position_id,
+        )?;
+        let logits = logits.flatten_all()?.to_dtype(DType::F32)?;
+        Ok(logits.to_vec1::<f32>()?)
+    }
+
+    fn snapshot(&self) -> KvCacheSnapshot {
+        Qwen3VLModel::snapshot(self)
+    }
+
+    fn restore(&mut self, snapshot: &KvCacheSnapshot) -> Result<()> {
+        Qwen3VLModel::restore(self, snapshot)
+    }
 }
@@ -5,15 +5,26 @@ use aha_openai_dive::v1::resources::chat::{
 };
 use anyhow::{Result, anyhow};
 use candle_core::{DType, Device, IndexOp, Shape, Tensor};
-use ffmpeg_next as ffmpeg;
 use image::DynamicImage;
 use num::integer::lcm;
 
 use crate::{
-    models::qwen3vl::config::PreprocessorConfig,
-    utils::{ceil_by_factor, floor_by_factor, img_utils::get_image, round_by_factor},
+    models::{common::vision_cache::CachedVision, qwen3vl::config::PreprocessorConfig},
+    utils::{
+        ceil_by_factor, floor_by_factor,
+        img_utils::resize_bicubic,
+        media_utils::{MediaSource, VideoSource, frames_to_tensor, load_frames},
+        round_by_factor,
+        video_utils::{VideoReader, uniform_indices},
+        vision_utils::{MediaRequest, RequestVisionOverrides, VisionOverrides},
+    },
 };
 
+// video_preprocessor_config.json中没有时的默认采样设置
+const DEFAULT_FPS: f32 = 2.0;
+const DEFAULT_MIN_FRAMES: u32 = 4;
+const DEFAULT_MAX_FRAMES: u32 = 768;
+
 #[derive(Clone)]
 pub struct VisionInput {
     pub data: Tensor,
@@ -29,15 +40,24 @@ pub struct GeneralInput {
     pub video_grid_thw: Option<Tensor>,
 }
 
-#[allow(unused)]
+// 一张图片或一个视频预处理后的输入
+#[derive(Debug, Clone)]
+pub struct MediaInput {
+    pub pixel_values: Tensor,
+    // (1, 3)
+    pub grid_thw: Tensor,
+    // 视频每个时间块的时间戳(秒), 图片为空
+    pub timestamps: Vec<f32>,
+}
+
 #[derive(Debug, Clone)]
 pub struct VideoMetadata {
-    total_num_frames: u32,
-    fps: f32,
-    width: u32,
-    height: u32,
-    duration: f32,
-    frame_indices: Vec<u32>,
+    pub total_num_frames: u32,
+    pub fps: f32,
+    pub width: u32,
+    pub height: u32,
+    pub duration: f32,
+    pub frame_indices: Vec<u32>,
 }
 
 pub struct Qwen3VLProcessor {
@@ -49,7 +69,7 @@ pub struct Qwen3VLProcessor {
     video_token: String,
     vision_start_token: String,
     vision_end_token: String,
-    fps: u32,
+    fps: f32,
     min_frames: u32,
     max_frames: u32,
 }
@@ -81,6 +101,9 @@ impl Qwen3VLProcessor {
         let video_token = "<|video_pad|>".to_string();
         let vision_start_token = "<|vision_start|>".to_string();
         let vision_end_token = "<|vision_end|>".to_string();
+        let fps = video_process_cfg.fps.unwrap_or(DEFAULT_FPS);
+        let min_frames = video_process_cfg.min_frames.unwrap_or(DEFAULT_MIN_FRAMES);
+        let max_frames = video_process_cfg.max_frames.unwrap_or(DEFAULT_MAX_FRAMES);
         Ok(Self {
             img_process_cfg,
             video_process_cfg,
@@ -90,9 +113,9 @@ impl Qwen3VLProcessor {
             video_token,
             vision_start_token,
             vision_end_token,
-            fps: 2,
-            min_frames: 4,
-            max_frames: 768,
+            fps,
+            min_frames,
+            max_frames,
         })
     }
 
@@ -126,20 +149,24 @@ impl Qwen3VLProcessor {
         img: &DynamicImage,
         img_mean: &Tensor,
         img_std: &Tensor,
+        overrides: &VisionOverrides,
     ) -> Result<Tensor> {
-        let img_h = img.height();
-        let img_w = img.width();
+        let (img_h, img_w, min_pixels, max_pixels) = overrides.resize_args(
+            img.height(),
+            img.width(),
+            self.img_process_cfg.size.shortest_edge as u32,
+            self.img_process_cfg.size.longest_edge as u32,
+        )?;
         //  h,w resize成 28的倍数
         let (resize_h, resize_w) = img_smart_resize(
             img_h,
             img_w,
             (self.img_process_cfg.patch_size * self.img_process_cfg.merge_size) as u32,
-            self.img_process_cfg.size.shortest_edge as u32,
-            self.img_process_cfg.size.longest_edge as u32,
+            min_pixels,
+            max_pixels,
             None,
         )?;
-        let img = img.resize_exact(resize_w, resize_h, image::imageops::FilterType::CatmullRom);
-        let img_vec = img.to_rgb8().into_raw();
+        let img_vec = resize_bicubic(img, resize_w, resize_h).into_raw();
         // (h, w, c) => (c, h, w)
         let img_tensor = Tensor::from_slice(
             &img_vec,
@@ -214,15 +241,15 @@ impl Qwen3VLProcessor {
 
     pub fn process_images(
         &self,
-        imgs: Vec<DynamicImage>,
+        imgs: Vec<(DynamicImage, VisionOverrides)>,
         img_mean: &Tensor,
         img_std: &Tensor,
     ) -> Result<VisionInput> {
         let mut pixel_values_vec = Vec::new();
         let mut vision_grid_thws_vec = Vec::new();
 
-        for img in imgs {
-            let img_tensor = self.process_img(&img, img_mean, img_std)?;
+        for (img, overrides) in imgs {
+            let img_tensor = self.process_img(&img, img_mean, img_std, &overrides)?;
             let img_tensor = Tensor::cat(&[&img_tensor, &img_tensor], 0)?.contiguous()?;
             let (img_tensor, grid_thw) = self.process_vision_tensor(&img_tensor)?;
             pixel_values_vec.push(img_tensor);
@@ -291,112 +318,221 @@ impl Qwen3VLProcessor {
         Ok(stamps)
     }
 
-    pub fn process_info(
+    // 请求中按出现顺序的图片和视频, 以及各自的预处理设置
+    pub fn media_requests(
         &self,
         messages: &ChatCompletionParameters,
-        text: &str,
-    ) -> Result<GeneralInput> {
-        let mut pixel_values = None;
-        let mut image_grid_thw = None;
-        let mut pixel_values_video = None;
-        let mut video_grid_thw: Option<Tensor> = None;
-        let mut video_metadata = None;
-        let vision_map = self.extract_vision_info(messages)?;
+        overrides: &RequestVisionOverrides,
+    ) -> Result<(Vec<MediaRequest>, Vec<MediaRequest>)> {
+        let mut vision_map = self.extract_vision_info(messages)?;
+        let images = vision_map
+            .remove("image")
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+            .map(|(i, source)| {
+                Ok(MediaRequest {
+                    source: MediaSource::parse(&source)?,
+                    video: false,
+                    overrides: overrides.image(i),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let default = VisionOverrides {
+            fps: Some(self.fps),
+            ..Default::default()
+        };
+        let videos = vision_map
+            .remove("video")
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+            .map(|(i, source)| {
+                Ok(MediaRequest {
+                    source: MediaSource::parse(&source)?,
+                    video: true,
+                    overrides: overrides.video(i).or(&default),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok((images, videos))
+    }
+
+    // 单张图片或单个视频的预处理
+    pub fn process_media(&self, request: &MediaRequest) -> Result<MediaInput> {
         let img_mean =
             Tensor::from_slice(&self.img_process_cfg.image_mean, (3, 1, 1), &self.device)?
                 .to_dtype(self.dtype)?;
         let img_std = Tensor::from_slice(&self.img_process_cfg.image_std, (3, 1, 1), &self.device)?
             .to_dtype(self.dtype)?;
-        for (key, vec) in vision_map {
-            // println!("key: {}, \nvalue: {:?}", key, vec);
-            if key.eq("image") {
-                let mut file_vec = Vec::new();
-                for file in &vec {
-                    let image = get_image(file);
-                    match image {
-                        Ok(img) => file_vec.push(img),
-                        Err(e) => println!("get_image err: {:?}", e),
-                    };
-                }
-                if !file_vec.is_empty() {
-                    let vision_input = self.process_images(file_vec, &img_mean, &img_std);
-                    match vision_input {
-                        Ok(img_input) => {
-                            pixel_values = Some(img_input.data);
-                            image_grid_thw = Some(img_input.grid_thw);
-                        }
-                        Err(e) => println!("img process_images err: {:?}", e),
-                    };
-                }
-            }
-            if key.eq("video") {
-                let mut file_vec = Vec::new();
-                let mut video_infos = Vec::new();
-                for file in &vec {
-                    let video_data = get_video_data(
-                        file,
-                        self.video_process_cfg.patch_size as u32,
-                        self.video_process_cfg.temporal_patch_size as u32,
-                        self.video_process_cfg.merge_size as u32,
-                        self.fps,
-                        self.min_frames,
-                        self.max_frames,
-                        self.video_process_cfg.size.shortest_edge as u32,
-                        self.video_process_cfg.size.longest_edge as u32,
-                        &self.device,
-                    );
-                    match video_data {
-                        Ok((tensor, video_info)) => {
-                            file_vec.push(tensor);
-                            video_infos.push(video_info);
-                        }
-                        Err(e) => println!("get_video_data err: {:?}", e),
-                    };
-                }
-                if !file_vec.is_empty() {
-                    let vision_input = self.process_videos(file_vec, &img_mean, &img_std);
-                    match vision_input {
-                        Ok(video_input) => {
-                            pixel_values_video = Some(video_input.data);
-                            video_grid_thw = Some(video_input.grid_thw);
-                            video_metadata = Some(video_infos);
-                        }
-                        Err(e) => println!("video process_videos err: {:?}", e),
-                    };
+        if !request.video {
+            let img = request.source.load_image()?;
+            let input =
+                self.process_images(vec![(img, request.overrides.clone())], &img_mean, &img_std)?;
+            return Ok(MediaInput {
+                pixel_values: input.data,
+                grid_thw: input.grid_thw,
+                timestamps: vec![],
+            });
+        }
+        let (tensor, video_info) = get_video_data(
+            &request.source.open_video()?,
+            self.video_process_cfg.patch_size as u32,
+            self.video_process_cfg.temporal_patch_size as u32,
+            self.video_process_cfg.merge_size as u32,
+            self.min_frames,
+            self.max_frames,
+            self.video_process_cfg.size.shortest_edge as u32,
+            self.video_process_cfg.size.longest_edge as u32,
+            &request.overrides,
+            &self.device,
+        )?;
+        let input = self.process_videos(vec![tensor], &img_mean, &img_std)?;
+        let timestamps = self.calculate_timestamps(
+            video_info.frame_indices.clone(),
+            video_info.fps,
+            self.img_process_cfg.merge_size,
+        )?;
+        Ok(MediaInput {
+            pixel_values: input.data,
+            grid_thw: input.grid_thw,
+            timestamps,
+        })
+    }
+
+    // 预处理失败的图片/视频跳过
+    fn process_all(&self, requests: &[MediaRequest]) -> Vec<MediaInput> {
+        requests
+            .iter()
+            .filter_map(|request| match self.process_media(request) {
+                Ok(input) => Some(input),
+                Err(e) => {
+                    println!("process media err: {:?}", e);
+                    None
                 }
-            }
+            })
+            .collect()
+    }
+
+    // overrides为本次请求的图片/视频设置, 覆盖preprocessor_config中的min_pixels/max_pixels/fps
+    pub fn process_info(
+        &self,
+        messages: &ChatCompletionParameters,
+        text: &str,
+        overrides: &RequestVisionOverrides,
+    ) -> Result<GeneralInput> {
+        let (image_requests, video_requests) = self.media_requests(messages, overrides)?;
+        let images = self.process_all(&image_requests);
+        let videos = self.process_all(&video_requests);
+        self.general_input(
+            text,
+            &images.iter().collect::<Vec<_>>(),
+            &videos.iter().collect::<Vec<_>>(),
+        )
+    }
+
+    // 拼接预处理结果, 按每张图片/每个视频的grid展开文本中的占位token
+    pub fn general_input(
+        &self,
+        text: &str,
+        images: &[&MediaInput],
+        videos: &[&MediaInput],
+    ) -> Result<GeneralInput> {
+        let image_grids = images
+            .iter()
+            .map(|m| Ok(m.grid_thw.i(0)?.to_vec1::<u32>()?))
+            .collect::<Result<Vec<_>>>()?;
+        let video_grids = videos
+            .iter()
+            .map(|m| Ok(m.grid_thw.i(0)?.to_vec1::<u32>()?))
+            .collect::<Result<Vec<_>>>()?;
+        let text = self.expand_text(
+            text,
+            &image_grids.iter().map(|g| g.as_slice()).collect::<Vec<_>>(),
+            &video_grids
+                .iter()
+                .zip(videos.iter())
+                .map(|(g, m)| (g.as_slice(), m.timestamps.as_slice()))
+                .collect::<Vec<_>>(),
+        )?;
+        let (mut pixel_values, mut image_grid_thw) = (None, None);
+        if !images.is_empty() {
+            let data: Vec<&Tensor> = images.iter().map(|m| &m.pixel_values).collect();
+            let grid: Vec<&Tensor> = images.iter().map(|m| &m.grid_thw).collect();
+            pixel_values = Some(Tensor::cat(&data, 0)?);
+            image_grid_thw = Some(Tensor::cat(&grid, 0)?);
         }
+        let (mut pixel_values_video, mut video_grid_thw) = (None, None);
+        if !videos.is_empty() {
+            let data: Vec<&Tensor> = videos.iter().map(|m| &m.pixel_values).collect();
+            let grid: Vec<&Tensor> = videos.iter().map(|m| &m.grid_thw).collect();
+            pixel_values_video = Some(Tensor::cat(&data, 0)?);
+            video_grid_thw = Some(Tensor::cat(&grid, 0)?);
+        }
+        Ok(GeneralInput {
+            replace_text: text,
+            pixel_values,
+            image_grid_thw,
+            pixel_values_video,
+            video_grid_thw,
+        })
+    }
+
+    // 命中视觉特征缓存时不再预处理, 按缓存的grid_thw和时间戳展开占位token
+    pub fn replace_text(
+        &self,
+        text: &str,
+        images: &[&CachedVision],
+        videos: &[&CachedVision],
+    ) -> Result<String> {
+        self.expand_text(
+            text,
+            &images
+                .iter()
+                .map(|v| v.grid_thw.as_slice())
+                .collect::<Vec<_>>(),
+            &videos
+                .iter()
+                .map(|v| (v.grid_thw.as_slice(), v.timestamps.as_slice()))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    // images为每张图片的grid_thw, videos为每个视频的grid_thw和各时间块的时间戳
+    fn expand_text(
+        &self,
+        text: &str,
+        images: &[&[u32]],
+        videos: &[(&[u32], &[f32])],
+    ) -> Result<String> {
         let merge_length = self.img_process_cfg.merge_size.pow(2);
         let mut text = text.to_string();
-        if let Some(ref image_grid_thw) = image_grid_thw {
-            let mut index = 0;
-            while text.contains(&self.image_token) {
-                let grid_i = image_grid_thw.i(index)?;
-                let repeat_num =
-                    grid_i.to_vec1::<u32>()?.iter().product::<u32>() as usize / merge_length;
+        if !images.is_empty() {
+            for grid_thw in images {
+                let repeat_num = grid_thw.iter().product::<u32>() as usize / merge_length;
                 let replace = "<|placeholder|>".repeat(repeat_num);
                 text = text.replacen(&self.image_token, &replace, 1);
-                index += 1;
+            }
+            if text.contains(&self.image_token) {
+                return Err(anyhow!("image token num more than images"));
             }
             text = text.replace("<|placeholder|>", &self.image_token);
         }
-        if let Some(ref video_grid_thw) = video_grid_thw {
-            let mut index = 0;
-            while text.contains(&self.video_token) {
-                let grid_i = video_grid_thw.i(index)?;
-                let video_info = &video_metadata.as_ref().unwrap()[index];
-                let curr_timestamp = self.calculate_timestamps(
-                    video_info.frame_indices.clone(),
-                    video_info.fps,
-                    self.img_process_cfg.merge_size,
-                )?;
-                let mut video_placeholder = "".to_string();
-                let [t, h, w] = grid_i.to_vec1::<u32>()?[..] else {
+        if !videos.is_empty() {
+            for (grid_thw, timestamps) in videos {
+                let [t, h, w] = grid_thw[..] else {
                     return Err(anyhow!(format!("grid_thw Expected exactly 3 elements")));
                 };
+                if timestamps.len() < t as usize {
+                    return Err(anyhow!(format!(
+                        "video timestamps len {} less than grid t {}",
+                        timestamps.len(),
+                        t
+                    )));
+                }
                 let frame_seqlen = h * w / merge_length as u32;
-                for frame_idx in 0..t {
-                    let curr_time = curr_timestamp[frame_idx as usize];
+                let mut video_placeholder = "".to_string();
+                for curr_time in timestamps.iter().take(t as usize) {
                     video_placeholder += format!("<{:.1} seconds>", curr_time).as_str();
                     video_placeholder += self.vision_start_token.as_str();
                     video_placeholder += "<|placeholder|>".repeat(frame_seqlen as usize).as_str();
@@ -411,18 +547,13 @@ impl Qwen3VLProcessor {
                 } else {
                     text = text.replacen(&self.video_token, &video_placeholder, 1);
                 }
-                index += 1;
+            }
+            if text.contains(&self.video_token) {
+                return Err(anyhow!("video token num more than videos"));
             }
             text = text.replace("<|placeholder|>", &self.video_token);
         }
-        let input = GeneralInput {
-            replace_text: text,
-            pixel_values,
-            image_grid_thw,
-            pixel_values_video,
-            video_grid_thw,
-        };
-        Ok(input)
+        Ok(text)
     }
 }
 
@@ -514,126 +645,126 @@ pub fn video_smart_resize(
     Ok((h_bar, w_bar))
 }
 
+// 每帧像素数的覆盖值乘以帧数
+fn video_overrides(overrides: &VisionOverrides, t_bar: u32) -> VisionOverrides {
+    VisionOverrides {
+        min_pixels: overrides.min_pixels.map(|p| p.saturating_mul(t_bar)),
+        max_pixels: overrides.max_pixels.map(|p| p.saturating_mul(t_bar)),
+        ..overrides.clone()
+    }
+}
+
+// 图片帧列表组成的视频, 不做抽帧, 按fps计算时间戳
+pub fn get_frames_data(
+    frames: &[MediaSource],
+    patch_size: u32,
+    temporal_patch_size: u32,
+    merge_size: u32,
+    min_pixels: u32,
+    max_pixels: u32,
+    fps: f32,
+    overrides: &VisionOverrides,
+    device: &Device,
+) -> Result<(Tensor, VideoMetadata)> {
+    let images = load_frames(frames)?;
+    let nframes = images.len() as u32;
+    let (video_h, video_w) = (images[0].height(), images[0].width());
+    // 帧数不足temporal_patch_size时在process_vision_tensor中补齐
+    let t_bar = ceil_by_factor(nframes as f32, temporal_patch_size);
+    let (src_h, src_w, min_pixels, max_pixels) =
+        video_overrides(overrides, t_bar).resize_args(video_h, video_w, min_pixels, max_pixels)?;
+    let (resize_h, resize_w) = video_smart_resize(
+        t_bar,
+        src_h,
+        src_w,
+        temporal_patch_size,
+        patch_size * merge_size,
+        min_pixels,
+        max_pixels,
+        None,
+    )?;
+    let frames_tensor = frames_to_tensor(&images, resize_h, resize_w, device)?;
+    let video_info = VideoMetadata {
+        total_num_frames: nframes,
+        fps,
+        width: video_w,
+        height: video_h,
+        duration: nframes as f32 / fps,
+        frame_indices: (0..nframes).collect(),
+    };
+    Ok((frames_tensor, video_info))
+}
+
+// 音轨用audio_utils::get_video_audio从同一个VideoSource提取
 pub fn get_video_data(
-    file: &String,
+    video: &VideoSource,
     patch_size: u32,
     temporal_patch_size: u32,
     merge_size: u32,
-    fps: u32,
     min_frames: u32,
     max_frames: u32,
     min_pixels: u32,
     max_pixels: u32,
+    overrides: &VisionOverrides,
     device: &Device,
 ) -> Result<(Tensor, VideoMetadata)> {
-    ffmpeg::init().map_err(|e| anyhow!(format!("Failed to initialize ffmpeg: {}", e)))?;
-
-    let mut ictx = ffmpeg::format::input(&file)
-        .map_err(|e| anyhow!(format!("Failed to open video file: {}", e)))?;
-    let input = ictx
-        .streams()
-        .best(ffmpeg::media::Type::Video)
-        .ok_or_else(|| anyhow!(format!("No video stream found")))?;
-    let video_stream_index = input.index();
-    let context_decoder = ffmpeg::codec::context::Context::from_parameters(input.parameters())
-        .map_err(|e| anyhow!(format!("Failed to crate decoder context: {}", e)))?;
-    let mut decoder = context_decoder
-        .decoder()
-        .video()
-        .map_err(|e| anyhow!(format!("Failed to decoder video: {}", e)))?;
-
-    let video_h = decoder.height();
-    let video_w = decoder.width();
-    let format = decoder.format();
-
-    let frames = input.frames();
-    let rate = input.rate().0 as f32 / input.rate().1 as f32;
-    let duration = frames as f32 * 1.0 / rate;
-    // 1s取两帧
-    let nframes = (frames as f32 / rate * fps as f32).round() as u32;
+    let fps = overrides.fps(DEFAULT_FPS)?;
+    let media_file = match video {
+        VideoSource::File(file) => file,
+        VideoSource::Frames(frames) => {
+            return get_frames_data(
+                frames,
+                patch_size,
+                temporal_patch_size,
+                merge_size,
+                min_pixels,
+                max_pixels,
+                fps,
+                overrides,
+                device,
+            );
+        }
+    };
+    let mut reader = VideoReader::open(media_file.path())?;
+    let info = reader.info().clone();
+    // 按时间均匀采样, 默认1s取两帧
+    let (start_frame, end_frame) = info.clip(overrides.video_start, overrides.video_end)?;
+    let clip_frames = end_frame - start_frame + 1;
+    let nframes = (clip_frames as f32 / info.fps * fps).round() as u32;
     let nframes = std::cmp::min(
         std::cmp::min(std::cmp::max(nframes, min_frames), max_frames),
-        frames as u32,
+        clip_frames,
     );
-    let sample_interval = (frames as f32 / nframes as f32).round() as u32;
-    let mut frame_indices = Vec::new();
-    let mut frame_id = 0_u32;
+    let frame_indices = uniform_indices(start_frame, end_frame, nframes);
 
     // 图片帧使用scaler reshape的时候需要保证宽高是16的倍数,不然reshape出来的是损坏的图片
     // 所以计算resize的目标宽高时,需要用16和image_factor的最小公倍数
+    // 默认值为所有帧的像素数, 覆盖值为每帧的像素数
+    let t_bar = round_by_factor(nframes, temporal_patch_size);
+    let (src_h, src_w, min_pixels, max_pixels) = video_overrides(overrides, t_bar).resize_args(
+        info.height,
+        info.width,
+        min_pixels,
+        max_pixels,
+    )?;
     let (resize_h, resize_w) = video_smart_resize(
         nframes,
-        video_h,
-        video_w,
+        src_h,
+        src_w,
         temporal_patch_size,
         patch_size * merge_size,
         min_pixels,
         max_pixels,
         Some(16),
     )?;
-    let mut scaler = ffmpeg::software::scaling::context::Context::get(
-        format,
-        video_w,
-        video_h,
-        ffmpeg::format::Pixel::RGB24,
-        resize_w,
-        resize_h,
-        ffmpeg::software::scaling::flag::Flags::BILINEAR
-            | ffmpeg::software::scaling::flag::Flags::ACCURATE_RND,
-    )
-    .map_err(|e| anyhow!(format!("Failed to crate scaler: {}", e)))?;
-
-    let mut frames_vec = Vec::new();
-    let mut receive_and_process_decoded_frames =
-        |decoder: &mut ffmpeg::decoder::Video| -> Result<()> {
-            let mut decoded = ffmpeg::frame::Video::empty();
-            while decoder.receive_frame(&mut decoded).is_ok() {
-                if frame_id.is_multiple_of(sample_interval) {
-                    frame_indices.push(frame_id);
-                    let mut rgb_frame = ffmpeg::frame::Video::empty();
-                    scaler
-                        .run(&decoded, &mut rgb_frame)
-                        .map_err(|e| anyhow!(format!("Failed to scaler run decoded: {}", e)))?;
-
-                    // save_file(&rgb_frame, frame_id as usize);
-                    let frame_data = rgb_frame.data(0);
-                    let frame_tensor = Tensor::from_slice(
-                        frame_data,
-                        (resize_h as usize, resize_w as usize, 3),
-                        device,
-                    )?
-                    .permute((2, 0, 1))?;
-                    frames_vec.push(frame_tensor);
-                }
-                frame_id += 1;
-            }
-            Ok(())
-        };
-
-    for (stream, packet) in ictx.packets() {
-        if stream.index() == video_stream_index {
-            decoder
-                .send_packet(&packet)
-                .map_err(|e| anyhow!(format!("Failed to send packet: {}", e)))?;
-            receive_and_process_decoded_frames(&mut decoder)?;
-        }
-    }
-    decoder
-        .send_eof()
-        .map_err(|e| anyhow!(format!("Failed to decoder.send_eof(): {}", e)))?;
-    receive_and_process_decoded_frames(&mut decoder)?;
-
-    if frames_vec.is_empty() {
-        return Err(anyhow!("No frames extracted from video".to_string()));
-    }
     // (t, c, h, w)
-    let frames_tensor = Tensor::stack(&frames_vec, 0)?.contiguous()?;
+    let frames_tensor = reader.read_frames(&frame_indices, resize_h, resize_w, device)?;
     let video_info = VideoMetadata {
-        total_num_frames: frames as u32,
-        fps: rate,
-        width: video_w,
-        height: video_h,
-        duration,
+        total_num_frames: info.total_frames,
+        fps: info.fps,
+        width: info.width,
+        height: info.height,
+        duration: info.duration,
         frame_indices,
     };
     Ok((frames_tensor, video_info))
@@ -0,0 +1,220 @@
+use anyhow::{Result, anyhow};
+
+use crate::models::{
+    common::{
+        KvCacheSnapshot,
+        vision_cache::{
+            CachedVision, SharedVisionCache, VisionCacheKey, VisionFeature, cached_vision,
+            concat_features,
+        },
+    },
+    qwen3vl::model::Qwen3VLModel,
+};
+
+// 一张图片或一个视频的视觉特征
+pub struct MediaItem {
+    // 来源和预处理设置, 占位token相同时用于判断图片/视频是否变化
+    key: VisionCacheKey,
+    // 在文本中展开后的占位token数
+    num_tokens: usize,
+    feature: VisionFeature,
+}
+
+impl MediaItem {
+    pub fn new(
+        key: VisionCacheKey,
+        grid_thw: &[u32],
+        feature: VisionFeature,
+        merge_size: usize,
+    ) -> Result<Self> {
+        let num_tokens = grid_thw.iter().product::<u32>() as usize / merge_size.pow(2);
+        if num_tokens != feature.0.dim(0)? {
+            return Err(anyhow!(format!(
+                "vision feature len {} not equal to grid_thw tokens {}",
+                feature.0.dim(0)?,
+                num_tokens
+            )));
+        }
+        Ok(Self {
+            key,
+            num_tokens,
+            feature,
+        })
+    }
+}
+
+// 每个图片/视频的占位token在input_ids中的范围[start, end)
+// 视频的各帧之间有时间戳文本, 范围包含这些文本
+fn media_spans(
+    input_ids: &[u32],
+    token_id: u32,
+    items: &[MediaItem],
+) -> Result<Vec<(usize, usize)>> {
+    let positions: Vec<usize> = input_ids
+        .iter()
+        .enumerate()
+        .filter(|(_, id)| **id == token_id)
+        .map(|(i, _)| i)
+        .collect();
+    let total: usize = items.iter().map(|item| item.num_tokens).sum();
+    if positions.len() != total {
+        return Err(anyhow!(format!(
+            "placeholder token {} num: {} not equal to vision token num: {}",
+            token_id,
+            positions.len(),
+            total
+        )));
+    }
+    let mut spans = vec![];
+    let mut index = 0;
+    for item in items {
+        if item.num_tokens == 0 {
+            return Err(anyhow!("vision input has no tokens"));
+        }
+        spans.push((positions[index], positions[index + item.num_tokens - 1] + 1));
+        index += item.num_tokens;
+    }
+    Ok(spans)
+}
+
+// 多轮对话的会话状态, 保存已输入模型的token和对应的kv cache
+// 每轮重新渲染完整对话, 与上一轮相同的前缀直接复用kv cache, 只prefill之后的token
+// 图片/视频的视觉特征保存在vision_cache中, 默认每个会话单独一个, 可以与模型或其他会话共用
+#[derive(Debug, Clone, Default)]
+pub struct ChatSession {
+    // 与kv_cache一一对应, 包括上一轮生成的回复
+    tokens: Vec<u32>,
+    kv_cache: KvCacheSnapshot,
+    // tokens中每个图片/视频的起始位置和key, 占位token相同时还需要比较来源和设置
+    media: Vec<(usize, VisionCacheKey)>,
+    vision_cache: SharedVisionCache,
+    processed_media: usize,
+    reused_tokens: usize,
+}
+
+impl ChatSession {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_vision_cache(cache: SharedVisionCache) -> Self {
+        Self {
+            vision_cache: cache,
+            ..Self::default()
+        }
+    }
+
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    // 最近一轮从kv cache复用的token数
+    pub fn reused_tokens(&self) -> usize {
+        self.reused_tokens
+    }
+
+    pub fn cached_vision_features(&self) -> usize {
+        self.vision_cache
+            .lock()
+            .map(|cache| cache.stats().entries)
+            .unwrap_or(0)
+    }
+
+    // 最近一轮重新预处理并计算视觉特征的图片/视频数
+    pub fn processed_media(&self) -> usize {
+        self.processed_media
+    }
+
+    // 对话中的图片/视频按key查会话的视觉特征缓存, 只有未命中的交给compute
+    pub fn cached_vision(
+        &mut self,
+        keys: &[VisionCacheKey],
+        compute: impl FnOnce(&[usize]) -> Result<Vec<CachedVision>>,
+    ) -> Result<Vec<CachedVision>> {
+        let (values, computed) = cached_vision(Some(&self.vision_cache), keys, compute)?;
+        self.processed_media = computed;
+        Ok(values)
+    }
+
+    // 只清空对话状态, 共用的视觉特征缓存保留
+    pub fn clear(&mut self) {
+        *self = Self::with_vision_cache(self.vision_cache.clone());
+    }
+
+    // 把模型的kv cache恢复到可复用的前缀, 返回前缀长度和之后出现的图片/视频的视觉特征
+    pub fn prepare(
+        &mut self,
+        model: &mut Qwen3VLModel,
+        input_ids: &[u32],
+        images: &[MediaItem],
+        videos: &[MediaItem],
+    ) -> Result<(usize, Option<VisionFeature>, Option<VisionFeature>)> {
+        let config = model.config();
+        let image_spans = media_spans(input_ids, config.image_token_id as u32, images)?;
+        let video_spans = media_spans(input_ids, config.video_token_id as u32, videos)?;
+        let mut start = self
+            .tokens
+            .iter()
+            .zip(input_ids.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        // 至少输入一个token才能得到logits
+        start = start.min(input_ids.len().saturating_sub(1));
+        // 不能从图片/视频的中间开始prefill, 内容变化的图片/视频需要重新输入
+        let media: Vec<(usize, VisionCacheKey)> = image_spans
+            .iter()
+            .zip(images.iter())
+            .chain(video_spans.iter().zip(videos.iter()))
+            .map(|(span, item)| (span.0, item.key.clone()))
+            .collect();
+        for (&(span_start, span_end), item) in image_spans
+            .iter()
+            .chain(video_spans.iter())
+            .zip(media.iter())
+        {
+            if span_start < start && (start < span_end || !self.media.contains(item)) {
+                start = span_start;
+            }
+        }
+
+        let new_features = |items: &[MediaItem], spans: &[(usize, usize)]| {
+            let features: Vec<&VisionFeature> = items
+                .iter()
+                .zip(spans.iter())
+                .filter(|(_, span)| span.0 >= start)
+                .map(|(item, _)| &item.feature)
+                .collect();
+            concat_features(&features)
+        };
+        let image_features = new_features(images, &image_spans)?;
+        let video_features = new_features(videos, &video_spans)?;
+
+        if start == 0 {
+            model.clear_kv_cache();
+        } else {
+            model.restore(&self.kv_cache)?;
+            model.truncate_kv_cache(start)?;
+        }
+        // 本轮生成失败时session仍然保持有效的前缀
+        self.tokens.truncate(start);
+        self.kv_cache = model.snapshot();
+        self.media = media;
+        self.reused_tokens = start;
+        Ok((start, image_features, video_features))
+    }
+
+    // 本轮结束, tokens为kv cache中的所有token
+    pub fn update(&mut self, model: &Qwen3VLModel, tokens: Vec<u32>) -> Result<()> {
+        let kv_cache = model.snapshot();
+        if kv_cache.seq_len() != tokens.len() {
+            return Err(anyhow!(format!(
+                "session tokens len {} not equal to kv cache len {}",
+                tokens.len(),
+                kv_cache.seq_len()
+            )));
+        }
+        self.tokens = tokens;
+        self.kv_cache = kv_cache;
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
-use anyhow::{Ok, Result};
+use anyhow::{Ok, Result, anyhow};
 use candle_core::{D, Tensor};
 use candle_nn::{Conv1d, Conv1dConfig, ConvTranspose1d, ConvTranspose1dConfig, Module, VarBuilder};
 
+use crate::models::voxcpm::config::AudioVAEConfig;
+
 pub struct CausalConv1d {
     conv1d: Conv1d,
     padding: usize,
@@ -488,6 +490,22 @@ impl AudioVAE {
         decoder_rates: Vec<usize>,
         sample_rate: usize,
     ) -> Result<Self> {
+        // 配置不合法时返回错误, 避免构建时除0
+        if encoder_dim == 0 {
+            return Err(anyhow!("audio vae encoder_dim must be > 0"));
+        }
+        // 解码器每层通道数减半
+        if decoder_dim
+            .checked_shr(decoder_rates.len() as u32)
+            .unwrap_or(0)
+            == 0
+        {
+            return Err(anyhow!(format!(
+                "audio vae decoder_dim {} is too small for {} decoder_rates",
+                decoder_dim,
+                decoder_rates.len()
+            )));
+        }
         let latent_dim = match laten_dim {
             Some(d) => d,
             None => encoder_dim * (2_usize.pow(encoder_rates.len() as u32)),
@@ -522,6 +540,22 @@ impl AudioVAE {
         })
     }
 
+    pub fn from_config(vb: VarBuilder, config: &AudioVAEConfig) -> Result<Self> {
+        Self::new(
+            vb,
+            config.encoder_dim,
+            config.encoder_rates.clone(),
+            config.latent_dim,
+            config.decoder_dim,
+            config.decoder_rates.clone(),
+            config.sample_rate,
+        )
+    }
+
+    pub fn hop_length(&self) -> usize {
+        self.hop_length
+    }
+
     pub fn preprocess(&self, audio_data: &Tensor, sample_rate: Option<usize>) -> Result<Tensor> {
         let sample_rate = match sample_rate {
             Some(r) => r,
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use anyhow::{Ok, Result, anyhow};
+use candle_core::{DType, Device, Tensor, pickle::read_all_with_key};
+use candle_nn::VarBuilder;
+
+use crate::{
+    models::voxcpm::{audio_vae::AudioVAE, config::AudioVAEConfig},
+    utils::{
+        audio_utils::{load_audio_with_resample, resample_simple},
+        find_type_files, get_device,
+    },
+};
+
+// 单独使用AudioVAE做音频编解码, 不需要加载VoxCPM的语言模型部分
+pub struct AudioVAECodec {
+    vae: AudioVAE,
+    device: Device,
+    dtype: DType,
+}
+
+impl AudioVAECodec {
+    // 配置读取顺序: audio_vae_config.json -> config.json中的audio_vae_config -> 默认配置
+    pub fn load_config(path: &str) -> Result<AudioVAEConfig> {
+        let vae_config_path = path.to_string() + "/audio_vae_config.json";
+        if std::path::Path::new(&vae_config_path).exists() {
+            let config: AudioVAEConfig = serde_json::from_slice(&std::fs::read(vae_config_path)?)?;
+            return Ok(config);
+        }
+        let config_path = path.to_string() + "/config.json";
+        if std::path::Path::new(&config_path).exists() {
+            let config: serde_json::Value = serde_json::from_slice(&std::fs::read(config_path)?)?;
+            if let Some(vae_config) = config.get("audio_vae_config") {
+                let config: AudioVAEConfig = serde_json::from_value(vae_config.clone())?;
+                return Ok(config);
+            }
+        }
+        Ok(AudioVAEConfig::default())
+    }
+
+    // 只读取audiovae开头的权重文件, 目录下语言模型的权重不加载
+    fn find_vae_files(path: &str, file_type: &str) -> Result<Vec<String>> {
+        let files = find_type_files(path, file_type)?
+            .into_iter()
+            .filter(|f| {
+                std::path::Path::new(f)
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .starts_with("audiovae")
+            })
+            .collect();
+        Ok(files)
+    }
+
+    // 权重优先使用safetensors, 没有则读取pth(如VoxCPM目录下的audiovae.pth)
+    pub fn init(path: &str, device: Option<&Device>, dtype: Option<DType>) -> Result<Self> {
+        let device = get_device(device);
+        let dtype = dtype.unwrap_or(DType::F32);
+        let config = Self::load_config(path)?;
+        let model_list = Self::find_vae_files(path, "safetensors")?;
+        let vb = if !model_list.is_empty() {
+            unsafe { VarBuilder::from_mmaped_safetensors(&model_list, dtype, &device)? }
+        } else {
+            let model_list = Self::find_vae_files(path, "pth")?;
+            if model_list.is_empty() {
+                return Err(anyhow!(format!("no audio vae weights found in {}", path)));
+            }
+            let mut dict_to_hashmap = HashMap::new();
+            for m in model_list {
+                let dict = read_all_with_key(m, Some("state_dict"))?;
+                for (k, v) in dict {
+                    dict_to_hashmap.insert(k, v);
+                }
+            }
+            VarBuilder::from_tensors(dict_to_hashmap, dtype, &device)
+        };
+        let vae = AudioVAE::from_config(vb, &config)?;
+        Ok(Self { vae, device, dtype })
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        self.vae.sample_rate
+    }
+
+    pub fn latent_dim(&self) -> usize {
+        self.vae.latent_dim
+    }
+
+    // 每帧latent对应的采样点数
+    pub fn hop_length(&self) -> usize {
+        self.vae.hop_length()
+    }
+
+    // audio: (T) 或 (1, T), 采样率与vae不一致时先重采样
+    // 返回 (1, latent_dim, frames), frames = ceil(T / hop_length)
+    pub fn encode(&self, audio: &Tensor, sample_rate: usize) -> Result<Tensor> {
+        let mut audio = match audio.rank() {
+            1 => audio.unsqueeze(0)?,
+            _ => audio.clone(),
+        };
+        if sample_rate != self.sample_rate() {
+            audio = resample_simple(&audio, sample_rate as i64, self.sample_rate() as i64)?;
+        }
+        let audio = audio.to_device(&self.device)?.to_dtype(self.dtype)?;
+        let latents = self.vae.encode(&audio, Some(self.sample_rate()))?;
+        Ok(latents)
+    }
+
+    pub fn encode_file(&self, path: &str) -> Result<Tensor> {
+        let audio = load_audio_with_resample(path, self.device.clone(), Some(self.sample_rate()))?;
+        self.encode(&audio, self.sample_rate())
+    }
+
+    // latents: (latent_dim, frames) 或 (B, latent_dim, frames), 返回 (B, T) 的f32音频
+    pub fn decode(&self, latents: &Tensor) -> Result<Tensor> {
+        let latents = match latents.rank() {
+            2 => latents.unsqueeze(0)?,
+            _ => latents.clone(),
+        };
+        let latents = latents.to_device(&self.device)?.to_dtype(self.dtype)?;
+        let audio = self
+            .vae
+            .decode(&latents)?
+            .squeeze(1)?
+            .to_dtype(DType::F32)?;
+        Ok(audio)
+    }
+
+    // latents和采样率一起保存为safetensors
+    pub fn save_latents(&self, latents: &Tensor, path: &str) -> Result<()> {
+        let mut tensors = HashMap::new();
+        tensors.insert("latents".to_string(), latents.to_dtype(DType::F32)?);
+        tensors.insert(
+            "sample_rate".to_string(),
+            Tensor::new(&[self.sample_rate() as u32], &Device::Cpu)?,
+        );
+        tensors.insert(
+            "hop_length".to_string(),
+            Tensor::new(&[self.hop_length() as u32], &Device::Cpu)?,
+        );
+        candle_core::safetensors::save(&tensors, path)?;
+        Ok(())
+    }
+
+    // 保存时的sample_rate和hop_length与当前vae不一致时latents无法正确解码, 直接报错
+    pub fn load_latents(&self, path: &str) -> Result<Tensor> {
+        let tensors = candle_core::safetensors::load(path, &self.device)?;
+        let latents = tensors
+            .get("latents")
+            .ok_or(anyhow!(format!("latents not found in {}", path)))?
+            .clone();
+        let sample_rate = match tensors.get("sample_rate") {
+            Some(sr) => sr.to_vec1::<u32>()?[0] as usize,
+            None => AudioVAEConfig::default().sample_rate,
+        };
+        if sample_rate != self.sample_rate() {
+            return Err(anyhow!(format!(
+                "latents sample_rate {} not match audio vae sample_rate {}",
+                sample_rate,
+                self.sample_rate()
+            )));
+        }
+        if let Some(hop_length) = tensors.get("hop_length") {
+            let hop_length = hop_length.to_vec1::<u32>()?[0] as usize;
+            if hop_length != self.hop_length() {
+                return Err(anyhow!(format!(
+                    "latents hop_length {} not match audio vae hop_length {}",
+                    hop_length,
+                    self.hop_length()
+                )));
+            }
+        }
+        if latents.dim(latents.rank() - 2)? != self.latent_dim() {
+            return Err(anyhow!(format!(
+                "latents dim {} not match audio vae latent_dim {}",
+                latents.dim(latents.rank() - 2)?,
+                self.latent_dim()
+            )));
+        }
+        Ok(latents)
+    }
+}
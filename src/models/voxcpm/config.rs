@@ -1,3 +1,5 @@
+use crate::models::common::manifest::{check_attention, check_positive};
+
 #[derive(Debug, Clone, PartialEq, serde::Deserialize)]
 pub struct VoxRopeScalingConfig {
     pub r#type: String,
@@ -63,4 +65,84 @@ pub struct VoxCPMConfig {
     pub dit_config: VoxCPMDitConfig,
     pub max_length: usize,
     pub dtype: String,
+    // 旧版本config.json中没有这一项, 使用AudioVAEConfig::default()
+    pub audio_vae_config: Option<AudioVAEConfig>,
+}
+
+impl VoxCPMConfig {
+    // 配置字段的合理性检查, 返回所有问题
+    pub fn check(&self) -> Vec<String> {
+        let lm = &self.lm_config;
+        let mut errors = check_attention(
+            "lm_config",
+            lm.hidden_size,
+            lm.num_attention_heads,
+            lm.num_key_value_heads,
+            None,
+        );
+        check_positive(
+            &mut errors,
+            "lm_config.num_hidden_layers",
+            lm.num_hidden_layers,
+        );
+        check_positive(&mut errors, "lm_config.vocab_size", lm.vocab_size);
+        errors.extend(check_attention(
+            "encoder_config",
+            self.encoder_config.hidden_dim,
+            self.encoder_config.num_heads,
+            self.encoder_config.num_heads,
+            None,
+        ));
+        errors.extend(check_attention(
+            "dit_config",
+            self.dit_config.hidden_dim,
+            self.dit_config.num_heads,
+            self.dit_config.num_heads,
+            None,
+        ));
+        check_positive(&mut errors, "patch_size", self.patch_size);
+        check_positive(&mut errors, "feat_dim", self.feat_dim);
+        if let Some(vae) = &self.audio_vae_config {
+            let encoder_hop: usize = vae.encoder_rates.iter().product();
+            let decoder_hop: usize = vae.decoder_rates.iter().product();
+            if encoder_hop != decoder_hop {
+                errors.push(format!(
+                    "audio_vae_config encoder_rates {:?} and decoder_rates {:?} have different hop length",
+                    vae.encoder_rates, vae.decoder_rates
+                ));
+            }
+            if let Some(latent_dim) = vae.latent_dim
+                && latent_dim != self.feat_dim
+            {
+                errors.push(format!(
+                    "audio_vae_config.latent_dim {} != feat_dim {}",
+                    latent_dim, self.feat_dim
+                ));
+            }
+        }
+        errors
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct AudioVAEConfig {
+    pub encoder_dim: usize,
+    pub encoder_rates: Vec<usize>,
+    pub latent_dim: Option<usize>,
+    pub decoder_dim: usize,
+    pub decoder_rates: Vec<usize>,
+    pub sample_rate: usize,
+}
+
+impl Default for AudioVAEConfig {
+    fn default() -> Self {
+        Self {
+            encoder_dim: 128,
+            encoder_rates: vec![2, 5, 8, 8],
+            latent_dim: Some(64),
+            decoder_dim: 1536,
+            decoder_rates: vec![8, 8, 5, 2],
+            sample_rate: 16000,
+        }
+    }
 }
@@ -1,31 +1,177 @@
 use std::collections::HashMap;
 
-use anyhow::{Ok, Result};
-use candle_core::{DType, Device, Tensor, pickle::read_all_with_key};
-use candle_nn::VarBuilder;
+use anyhow::{Ok, Result, anyhow};
+use candle_core::{
+    D, DType, Device, Tensor,
+    pickle::{read_all_with_key, read_pth_tensor_info},
+    safetensors::MmapedSafetensors,
+};
+use candle_nn::var_builder::SimpleBackend;
 
 use crate::{
+    models::ParityModel,
+    models::common::{
+        manifest::{WeightChecker, WeightIndex, WeightReport},
+        parity::GoldenData,
+    },
     models::voxcpm::{
-        audio_vae::AudioVAE, config::VoxCPMConfig, model::VoxCPMModel,
+        audio_vae::AudioVAE,
+        config::{AudioVAEConfig, VoxCPMConfig},
+        model::VoxCPMModel,
         tokenizer::SingleChineseTokenizer,
     },
-    utils::{find_type_files, get_device, get_dtype},
+    utils::{
+        audio_utils::{
+            AudioJoinMode, AudioPostProcess, AudioPreprocessConfig, AudioPreprocessReport,
+            concat_audio_segments, post_process_audio,
+        },
+        find_type_files, get_device, get_dtype,
+        text_utils::split_text_by_punctuation,
+    },
 };
 
+// 长文本合成参数
+#[derive(Debug, Clone)]
+pub struct LongTextConfig {
+    // 每段最多字符数
+    pub max_segment_chars: usize,
+    pub join_mode: AudioJoinMode,
+    pub min_len: usize,
+    pub max_len: usize,
+    pub inference_timesteps: usize,
+    pub cfg_value: f64,
+    pub retry_badcase: bool,
+    pub retry_badcase_ratio_threshold: f64,
+}
+
+impl Default for LongTextConfig {
+    fn default() -> Self {
+        Self {
+            max_segment_chars: 60,
+            join_mode: AudioJoinMode::Crossfade(50),
+            min_len: 2,
+            max_len: 1000,
+            inference_timesteps: 10,
+            cfg_value: 2.0,
+            retry_badcase: false,
+            retry_badcase_ratio_threshold: 6.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SegmentTimestamp {
+    pub text: String,
+    // 单位秒
+    pub start: f32,
+    pub end: f32,
+}
+
+pub struct LongTextAudio {
+    // (1, T)
+    pub audio: Tensor,
+    pub sample_rate: usize,
+    pub segments: Vec<SegmentTimestamp>,
+}
+
+struct VoxCPMWeights {
+    vae: Option<Box<dyn SimpleBackend>>,
+    vae_index: WeightIndex,
+    vae_dtype: DType,
+    model: Option<Box<dyn SimpleBackend>>,
+    model_index: WeightIndex,
+}
+
 pub struct VoxCPMGenerate {
     voxcpm: VoxCPMModel,
     prompt_cache: Option<HashMap<String, Tensor>>,
+    post_process: AudioPostProcess,
 }
 
 impl VoxCPMGenerate {
     pub fn init(path: &str, device: Option<&Device>, dtype: Option<DType>) -> Result<Self> {
         let device = &get_device(device);
 
-        let model_list = find_type_files(path, "pth")?;
-        // println!(" pth model_list: {:?}", model_list);
+        let config_path = path.to_string() + "/config.json";
+        let config: VoxCPMConfig = serde_json::from_slice(&std::fs::read(config_path)?)?;
+        let cfg_dtype = config.dtype.as_str();
+        let m_dtype = get_dtype(dtype, cfg_dtype);
+        WeightReport::from_config_errors(config.check()).ensure_ok()?;
+        let weights = Self::load_weights(path, false)?;
+        let (vb_vae, vae_checker) =
+            WeightChecker::var_builder(weights.vae, weights.vae_index, weights.vae_dtype, device);
+        let audio_vae = vae_checker.finish(AudioVAE::from_config(
+            vb_vae,
+            &config.audio_vae_config.clone().unwrap_or_default(),
+        ))?;
+        // println!("model dtype: {:?}", m_dtype);
+        let (vb_voxcpm, checker) =
+            WeightChecker::var_builder(weights.model, weights.model_index, m_dtype, device);
+        let tokenizer = SingleChineseTokenizer::new(path)?;
+        let voxcpm = checker.finish(VoxCPMModel::new(vb_voxcpm, config, tokenizer, audio_vae))?;
+
+        Ok(Self {
+            voxcpm,
+            prompt_cache: None,
+            post_process: AudioPostProcess::default(),
+        })
+    }
+
+    // 有safetensors(convert_checkpoint转换得到)时mmap加载, 否则读取pth/bin,
+    // dry_run时只读取权重名和shape
+    fn load_weights(path: &str, dry_run: bool) -> Result<VoxCPMWeights> {
+        let st_list = find_type_files(path, "safetensors")?;
+        if !st_list.is_empty() {
+            let (vae_list, model_list): (Vec<String>, Vec<String>) =
+                st_list.into_iter().partition(|f| {
+                    std::path::Path::new(f)
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .starts_with("audiovae")
+                });
+            let vae_st = unsafe { MmapedSafetensors::multi(&vae_list)? };
+            let vae_dtype = match vae_st.tensors().first() {
+                Some((_, view)) => DType::try_from(view.dtype())?,
+                None => return Err(anyhow!(format!("no audiovae weights found in {}", path))),
+            };
+            let vae_index = WeightIndex::from_safetensors(&vae_list)?;
+            let model_index = WeightIndex::from_safetensors(&model_list)?;
+            let mut weights = VoxCPMWeights {
+                vae: None,
+                vae_index,
+                vae_dtype,
+                model: None,
+                model_index,
+            };
+            if !dry_run {
+                weights.vae = Some(Box::new(vae_st));
+                weights.model = Some(Box::new(unsafe { MmapedSafetensors::multi(&model_list)? }));
+            }
+            return Ok(weights);
+        }
+        let vae_list = find_type_files(path, "pth")?;
+        let model_list = find_type_files(path, "bin")?;
+        if dry_run {
+            let vae_dtype = match vae_list.first() {
+                Some(f) => match read_pth_tensor_info(f, false, Some("state_dict"))?.first() {
+                    Some(info) => info.dtype,
+                    None => DType::F32,
+                },
+                None => DType::F32,
+            };
+            return Ok(VoxCPMWeights {
+                vae: None,
+                vae_index: WeightIndex::from_pth(&vae_list, Some("state_dict"))?,
+                vae_dtype,
+                model: None,
+                model_index: WeightIndex::from_pth(&model_list, Some("state_dict"))?,
+            });
+        }
+        // println!(" pth model_list: {:?}", vae_list);
         let mut dict_to_hashmap = HashMap::new();
         let mut vae_dtype = candle_core::DType::F32;
-        for m in model_list {
+        for m in vae_list {
             let dict = read_all_with_key(m, Some("state_dict"))?;
             vae_dtype = dict[0].1.dtype();
             for (k, v) in dict {
@@ -33,24 +179,11 @@ impl VoxCPMGenerate {
                 dict_to_hashmap.insert(k, v);
             }
         }
-        let vb_vae = VarBuilder::from_tensors(dict_to_hashmap, vae_dtype, device);
-        let audio_vae = AudioVAE::new(
-            vb_vae,
-            128,
-            vec![2, 5, 8, 8],
-            Some(64),
-            1536,
-            vec![8, 8, 5, 2],
-            16000,
-        )?;
+        let vae_index = WeightIndex::from_tensors(&dict_to_hashmap);
+        let vae: Box<dyn SimpleBackend> = Box::new(dict_to_hashmap);
 
-        let model_list = find_type_files(path, "bin")?;
         // println!(" bin model_list: {:?}", model_list);
-        dict_to_hashmap = HashMap::new();
-        let config_path = path.to_string() + "/config.json";
-        let config: VoxCPMConfig = serde_json::from_slice(&std::fs::read(config_path)?)?;
-        let cfg_dtype = config.dtype.as_str();
-        let m_dtype = get_dtype(dtype, cfg_dtype);
+        let mut dict_to_hashmap = HashMap::new();
         for m in model_list {
             let dict = read_all_with_key(m, Some("state_dict"))?;
             for (k, v) in dict {
@@ -58,15 +191,88 @@ impl VoxCPMGenerate {
                 dict_to_hashmap.insert(k, v);
             }
         }
-        // println!("model dtype: {:?}", m_dtype);
-        let vb_voxcpm = VarBuilder::from_tensors(dict_to_hashmap, m_dtype, device);
+        let model_index = WeightIndex::from_tensors(&dict_to_hashmap);
+        let model: Box<dyn SimpleBackend> = Box::new(dict_to_hashmap);
+        Ok(VoxCPMWeights {
+            vae: Some(vae),
+            vae_index,
+            vae_dtype,
+            model: Some(model),
+            model_index,
+        })
+    }
+
+    // 不读取权重, 检查配置和目录下的权重名/shape是否与模型一致
+    pub fn check_model(path: &str) -> Result<WeightReport> {
+        let config_path = path.to_string() + "/config.json";
+        let config: VoxCPMConfig = serde_json::from_slice(&std::fs::read(config_path)?)?;
+        let config_errors = config.check();
+        if !config_errors.is_empty() {
+            return Ok(WeightReport::from_config_errors(config_errors));
+        }
+        let device = Device::Cpu;
+        let dtype = get_dtype(None, config.dtype.as_str());
+        let weights = Self::load_weights(path, true)?;
+        let (vb_vae, vae_checker) =
+            WeightChecker::var_builder(None, weights.vae_index, weights.vae_dtype, &device);
+        let vae_config = config.audio_vae_config.clone().unwrap_or_default();
+        // audio vae构建失败时用默认配置的占位vae继续检查语言模型, 两部分的错误分别记录
+        let mut vae_error = None;
+        let audio_vae = AudioVAE::from_config(vb_vae, &vae_config).or_else(|e| {
+            vae_error = Some(format!("build audio vae error: {}", e));
+            let (vb_placeholder, _) = WeightChecker::var_builder(
+                None,
+                WeightIndex::default(),
+                weights.vae_dtype,
+                &device,
+            );
+            // 语言模型只用到采样率, 占位vae用最小的配置
+            let placeholder_config = AudioVAEConfig {
+                encoder_dim: 1,
+                encoder_rates: vec![1],
+                latent_dim: Some(1),
+                decoder_dim: 2,
+                decoder_rates: vec![1],
+                sample_rate: vae_config.sample_rate,
+            };
+            AudioVAE::from_config(vb_placeholder, &placeholder_config)
+        })?;
+        let mut vae_report = vae_checker.report();
+        vae_report.build_error = vae_error;
+        let (vb_voxcpm, checker) =
+            WeightChecker::var_builder(None, weights.model_index, dtype, &device);
         let tokenizer = SingleChineseTokenizer::new(path)?;
-        let voxcpm = VoxCPMModel::new(vb_voxcpm, config, tokenizer, audio_vae)?;
+        let built = VoxCPMModel::new(vb_voxcpm, config, tokenizer, audio_vae);
+        let mut report = checker.finish_report(built);
+        report.merge(vae_report);
+        Ok(report)
+    }
 
-        Ok(Self {
-            voxcpm,
-            prompt_cache: None,
-        })
+    // 生成音频的采样率
+    pub fn sample_rate(&self) -> usize {
+        self.voxcpm.sample_rate()
+    }
+
+    // 设置输出音频的语速和响度归一化, 对之后所有的生成接口生效
+    pub fn set_post_process(&mut self, post_process: AudioPostProcess) {
+        self.post_process = post_process;
+    }
+
+    // 设置参考音频预处理(去静音/截断/去直流/归一化), None表示直接使用原始音频
+    pub fn set_prompt_preprocess(&mut self, cfg: Option<AudioPreprocessConfig>) {
+        self.voxcpm.set_prompt_preprocess(cfg);
+    }
+
+    // 最近一次参考音频的预处理报告
+    pub fn last_prompt_report(&self) -> Option<&AudioPreprocessReport> {
+        self.voxcpm.last_prompt_report()
+    }
+
+    fn post_process(&self, audio: Tensor) -> Result<Tensor> {
+        if self.post_process == AudioPostProcess::default() {
+            return Ok(audio);
+        }
+        post_process_audio(&audio, self.voxcpm.sample_rate(), &self.post_process)
     }
 
     pub fn build_prompt_cache(
@@ -94,7 +300,7 @@ impl VoxCPMGenerate {
         let audio = match &self.prompt_cache {
             Some(cache) => {
                 let prompt_cache = cache.clone();
-                self.voxcpm.generate_with_prompt_cache(
+                let audio = self.voxcpm.generate_with_prompt_cache(
                     target_text,
                     prompt_cache,
                     min_len,
@@ -103,7 +309,8 @@ impl VoxCPMGenerate {
                     cfg_value,
                     retry_badcase,
                     retry_badcase_ratio_threshold,
-                )?
+                )?;
+                self.post_process(audio)?
             }
             None => self.generate_simple(target_text)?,
         };
@@ -156,6 +363,100 @@ impl VoxCPMGenerate {
             retry_badcase,
             retry_badcase_ratio_threshold,
         )?;
-        Ok(audio)
+        self.post_process(audio)
+    }
+
+    // 长文本合成: 按标点切分后逐段生成, 每段使用同一个音色prompt, 最后拼接成一条音频
+    // 传入prompt_wav_path时只编码一次prompt音频; 未传入时使用build_prompt_cache建立的缓存
+    // 都没有时第一段自由生成, 之后各段以第一段的文本和音频作为prompt
+    // 语速和响度在拼接后的整条音频上处理, 避免各段响度不一致
+    pub fn generate_long(
+        &mut self,
+        target_text: String,
+        prompt_text: Option<String>,
+        prompt_wav_path: Option<String>,
+        long_cfg: &LongTextConfig,
+    ) -> Result<LongTextAudio> {
+        let texts = split_text_by_punctuation(&target_text, long_cfg.max_segment_chars);
+        if texts.is_empty() {
+            return Err(anyhow!("target text is empty"));
+        }
+        let mut prompt_cache = match prompt_wav_path {
+            Some(path) => Some(
+                self.voxcpm
+                    .build_prompt_cache(prompt_text.unwrap_or_default(), path)?,
+            ),
+            None => self.prompt_cache.clone(),
+        };
+        let mut audios = Vec::new();
+        for text in &texts {
+            let audio = match &prompt_cache {
+                Some(cache) => self.voxcpm.generate_with_prompt_cache(
+                    text.clone(),
+                    cache.clone(),
+                    long_cfg.min_len,
+                    long_cfg.max_len,
+                    long_cfg.inference_timesteps,
+                    long_cfg.cfg_value,
+                    long_cfg.retry_badcase,
+                    long_cfg.retry_badcase_ratio_threshold,
+                )?,
+                None => {
+                    let audio = self.voxcpm.generate(
+                        text.clone(),
+                        None,
+                        None,
+                        long_cfg.min_len,
+                        long_cfg.max_len,
+                        long_cfg.inference_timesteps,
+                        long_cfg.cfg_value,
+                        long_cfg.retry_badcase,
+                        long_cfg.retry_badcase_ratio_threshold,
+                    )?;
+                    prompt_cache = Some(
+                        self.voxcpm
+                            .build_prompt_cache_from_audio(text.clone(), &audio)?,
+                    );
+                    audio
+                }
+            };
+            audios.push(audio);
+        }
+        let sample_rate = self.voxcpm.sample_rate();
+        let (audio, spans) = concat_audio_segments(&audios, long_cfg.join_mode, sample_rate)?;
+        // 变速后按比例换算每段的起止位置
+        let joined_len = audio.dim(D::Minus1)?;
+        let audio = self.post_process(audio)?;
+        let scale = audio.dim(D::Minus1)? as f32 / joined_len.max(1) as f32;
+        let spans: Vec<(f32, f32)> = spans
+            .into_iter()
+            .map(|(start, end)| (start as f32 * scale, end as f32 * scale))
+            .collect();
+        let segments = texts
+            .into_iter()
+            .zip(spans)
+            .map(|(text, (start, end))| SegmentTimestamp {
+                text,
+                start: start / sample_rate as f32,
+                end: end / sample_rate as f32,
+            })
+            .collect();
+        Ok(LongTextAudio {
+            audio,
+            sample_rate,
+            segments,
+        })
+    }
+}
+
+impl ParityModel for VoxCPMGenerate {
+    fn parity_outputs(&mut self, golden: &GoldenData) -> Result<Vec<(String, Tensor)>> {
+        let device = self.voxcpm.device().clone();
+        let text_token = golden.require("text_token", DType::U32, &device)?;
+        let text_mask = golden.require("text_mask", DType::F32, &device)?;
+        let audio_feat = golden.require("audio_feat", DType::F32, &device)?;
+        let audio_mask = golden.require("audio_mask", DType::F32, &device)?;
+        self.voxcpm
+            .prefill_outputs(&text_token, &text_mask, &audio_feat, &audio_mask)
     }
 }
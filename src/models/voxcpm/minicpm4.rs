@@ -4,7 +4,7 @@ use candle_nn::{Embedding, Module, RmsNorm, VarBuilder, embedding, rms_norm};
 
 use crate::{
     models::{
-        common::{AttentionNobias, MLPNoBias},
+        common::{AttentionNobias, KvCacheSnapshot, MLPNoBias, kv_cache_len, quant::QuantSource},
         voxcpm::config::VoxMiniCPM4Config,
     },
     position_embed::rope::compute_default_rope_parameters,
@@ -28,7 +28,7 @@ impl MiniCPMLongRoPE {
         let head_dim = cfg.hidden_size / cfg.num_attention_heads;
         let rope_theta = cfg.rope_theta;
         let short_factor = cfg.rope_scaling.short_factor.clone();
-        let long_factor = cfg.rope_scaling.short_factor.clone();
+        let long_factor = cfg.rope_scaling.long_factor.clone();
         let original_max_position_embeddings = cfg.rope_scaling.original_max_position_embeddings;
         let max_position_embeddings = cfg.max_position_embeddings;
         let scale = max_position_embeddings as f64 / original_max_position_embeddings as f64;
@@ -117,12 +117,14 @@ impl MiniCPMDecoderLayer {
             cfg.hidden_size,
             cfg.num_attention_heads,
             cfg.num_key_value_heads,
+            &QuantSource::None,
         )?;
         let mlp = MLPNoBias::new(
             vb.pp("mlp"),
             cfg.hidden_size,
             cfg.intermediate_size,
             candle_nn::Activation::Silu,
+            &QuantSource::None,
         )?;
         let input_layernorm =
             rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
@@ -215,6 +217,18 @@ impl MiniCPMDecoderLayer {
     pub fn clear_kv_cache(&mut self) {
         self.self_attn.clear_kv_cache();
     }
+
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.self_attn.truncate_kv_cache(len)
+    }
+
+    pub fn kv_cache(&self) -> Option<(Tensor, Tensor)> {
+        self.self_attn.kv_cache()
+    }
+
+    pub fn set_kv_cache(&mut self, kv_cache: Option<(Tensor, Tensor)>) {
+        self.self_attn.set_kv_cache(kv_cache);
+    }
 }
 
 pub struct MiniCPMModel {
@@ -287,6 +301,26 @@ impl MiniCPMModel {
         &mut self,
         input_embeds: &Tensor,
         position_id: usize,
+    ) -> Result<Tensor> {
+        self.forward_with_cache_impl(input_embeds, position_id, None)
+    }
+
+    // 同forward_with_cache, 并按transformers的output_hidden_states记录num_hidden_layers + 1个hidden states:
+    // 第0个为输入, 第i个为第i层的输入, 最后一个为最后一层norm之后的输出
+    pub fn forward_with_cache_hidden_states(
+        &mut self,
+        input_embeds: &Tensor,
+        position_id: usize,
+        all_hidden_states: &mut Vec<Tensor>,
+    ) -> Result<Tensor> {
+        self.forward_with_cache_impl(input_embeds, position_id, Some(all_hidden_states))
+    }
+
+    fn forward_with_cache_impl(
+        &mut self,
+        input_embeds: &Tensor,
+        position_id: usize,
+        mut all_hidden_states: Option<&mut Vec<Tensor>>,
     ) -> Result<Tensor> {
         let input_embeds = match input_embeds.rank() {
             2 => input_embeds.unsqueeze(1)?,
@@ -309,10 +343,16 @@ impl MiniCPMModel {
         let (cos, sin) = self.rope_emb.forward(position_id, seq_len)?;
         let mut hidden_states = input_embeds.clone();
         for decode_layer in &mut self.layers {
+            if let Some(all) = all_hidden_states.as_mut() {
+                all.push(hidden_states.clone());
+            }
             hidden_states =
                 decode_layer.forward_with_cache(&hidden_states, &cos, &sin, attention_mask)?;
         }
         hidden_states = self.norm.forward(&hidden_states)?;
+        if let Some(all) = all_hidden_states {
+            all.push(hidden_states.clone());
+        }
 
         Ok(hidden_states)
     }
@@ -322,4 +362,33 @@ impl MiniCPMModel {
             layer.clear_kv_cache()
         }
     }
+
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            layer.truncate_kv_cache(len)?;
+        }
+        Ok(())
+    }
+
+    pub fn kv_cache_len(&self) -> usize {
+        self.layers
+            .first()
+            .map(|layer| kv_cache_len(&layer.kv_cache()))
+            .unwrap_or(0)
+    }
+
+    pub fn snapshot(&self) -> KvCacheSnapshot {
+        KvCacheSnapshot {
+            layers: self.layers.iter().map(|layer| layer.kv_cache()).collect(),
+            rope_deltas: None,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &KvCacheSnapshot) -> Result<()> {
+        snapshot.check_num_layers(self.layers.len())?;
+        for (layer, kv_cache) in self.layers.iter_mut().zip(snapshot.layers.iter()) {
+            layer.set_kv_cache(kv_cache.clone());
+        }
+        Ok(())
+    }
 }
@@ -1,4 +1,5 @@
 pub mod audio_vae;
+pub mod codec;
 pub mod config;
 pub mod generate;
 pub mod minicpm4;
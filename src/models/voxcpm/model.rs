@@ -12,7 +12,13 @@ use crate::{
         minicpm4::MiniCPMModel,
         tokenizer::SingleChineseTokenizer,
     },
-    utils::{audio_utils::load_audio_with_resample, tensor_utils::linspace},
+    utils::{
+        audio_utils::{
+            AudioPreprocessConfig, AudioPreprocessReport, load_audio_with_resample,
+            preprocess_audio,
+        },
+        tensor_utils::linspace,
+    },
 };
 
 pub struct ScalarQuantizationLayer {
@@ -379,6 +385,8 @@ pub struct VoxCPMModel {
     stop_head: Linear,
     device: Device,
     dtype: DType,
+    prompt_preprocess: Option<AudioPreprocessConfig>,
+    last_prompt_report: Option<AudioPreprocessReport>,
 }
 
 impl VoxCPMModel {
@@ -473,9 +481,51 @@ impl VoxCPMModel {
             stop_head,
             device: vb.device().clone(),
             dtype: vb.dtype(),
+            prompt_preprocess: None,
+            last_prompt_report: None,
         })
     }
 
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    pub fn set_prompt_preprocess(&mut self, cfg: Option<AudioPreprocessConfig>) {
+        self.prompt_preprocess = cfg;
+    }
+
+    pub fn last_prompt_report(&self) -> Option<&AudioPreprocessReport> {
+        self.last_prompt_report.as_ref()
+    }
+
+    // 读取参考音频, 预处理后补齐到patch_len的整数倍, 编码为 (T, patch_size, latent_dim)
+    fn encode_prompt_audio(&mut self, path: &str) -> Result<Tensor> {
+        let mut audio =
+            load_audio_with_resample(path, self.device.clone(), Some(self.sample_rate))?;
+        self.last_prompt_report = None;
+        if let Some(cfg) = &self.prompt_preprocess {
+            let (processed, report) = preprocess_audio(&audio, self.sample_rate, cfg)?;
+            audio = processed;
+            self.last_prompt_report = Some(report);
+        }
+        self.encode_audio_feat(audio)
+    }
+
+    // (1, samples)的音频编码为prompt的audio_feat
+    fn encode_audio_feat(&self, mut audio: Tensor) -> Result<Tensor> {
+        let patch_len = self.patch_size * self.chunk_size;
+        if audio.dim(1)? % patch_len != 0 {
+            audio = audio.pad_with_zeros(D::Minus1, 0, patch_len - audio.dim(1)? % patch_len)?;
+        }
+        let audio_feat = self.audio_vae.encode(&audio, Some(self.sample_rate))?;
+        let audio_feat = audio_feat
+            .reshape((self.audio_vae.latent_dim, (), self.patch_size))?
+            .permute((1, 2, 0))?;
+        let dim0 = audio_feat.dim(0)? - 1;
+        let audio_feat = audio_feat.i(..dim0)?;
+        Ok(audio_feat)
+    }
+
     pub fn generate(
         &mut self,
         target_text: String,
@@ -511,22 +561,7 @@ impl VoxCPMModel {
                 let audio_start = Tensor::new(vec![self.audio_start_token as u32], &self.device)?;
                 let text_token = Tensor::cat(&[text_token, audio_start], D::Minus1)?;
                 let text_length = text_token.dim(0)?;
-                let mut audio =
-                    load_audio_with_resample(path, self.device.clone(), Some(self.sample_rate))?;
-                let patch_len = self.patch_size * self.chunk_size;
-                if audio.dim(1)? % patch_len != 0 {
-                    audio = audio.pad_with_zeros(
-                        D::Minus1,
-                        0,
-                        patch_len - audio.dim(1)? % patch_len,
-                    )?;
-                }
-                let audio_feat = self.audio_vae.encode(&audio, Some(self.sample_rate))?;
-                let audio_feat = audio_feat
-                    .reshape((self.audio_vae.latent_dim, (), self.patch_size))?
-                    .permute((1, 2, 0))?;
-                let dim0 = audio_feat.dim(0)? - 1;
-                let audio_feat = audio_feat.i(..dim0)?;
+                let audio_feat = self.encode_prompt_audio(&path)?;
                 let audio_length = audio_feat.dim(0)?;
                 let text_pad_token = Tensor::zeros(audio_length, DType::U32, &self.device)?;
                 let text_token = Tensor::cat(&[text_token, text_pad_token], D::Minus1)?;
@@ -602,8 +637,10 @@ impl VoxCPMModel {
             .audio_vae
             .decode(&latent_pred.to_dtype(DType::F32)?)?
             .squeeze(1)?;
-        let decode_audio_len = decode_audio.dim(D::Minus1)? - 640 - 640;
-        let decode_audio = decode_audio.narrow(D::Minus1, 640, decode_audio_len)?;
+        // 去掉首尾各一帧latent对应的采样点
+        let hop_length = self.audio_vae.hop_length();
+        let decode_audio_len = decode_audio.dim(D::Minus1)? - 2 * hop_length;
+        let decode_audio = decode_audio.narrow(D::Minus1, hop_length, decode_audio_len)?;
         Ok(decode_audio)
     }
 
@@ -619,46 +656,12 @@ impl VoxCPMModel {
         cfg_value: f64,
     ) -> Result<Tensor> {
         let (_, t, _, _) = feat.dims4()?;
-        let feat_embed = self.feat_encoder.forward(feat)?; // [b, t, h_feat]
-        let feat_embed = self.enc_to_lm_proj.forward(&feat_embed)?;
-        let scale_emb = if self.config.lm_config.use_mup {
-            self.config.lm_config.scale_emb
-        } else {
-            1.0
-        };
-
-        let text_embed = self
-            .base_lm
-            .embed_tokens
-            .as_ref()
-            .unwrap()
-            .forward(text)?
-            .affine(scale_emb as f64, 0.0)?;
-        let combined_embed = text_mask
-            .unsqueeze(D::Minus1)?
-            .broadcast_mul(&text_embed)?
-            .add(&feat_mask.unsqueeze(D::Minus1)?.broadcast_mul(&feat_embed)?)?;
         let mut prefix_feat_cond = feat.i((.., t - 1, ..))?;
         let mut pred_feat_seq = Vec::new();
         let mut position_id = 0;
         let mut seq_len = t;
-        let enc_outputs = self
-            .base_lm
-            .forward_with_cache(&combined_embed, position_id)?;
-        let enc_outputs = self
-            .fsq_layer
-            .forward(&enc_outputs)?
-            .broadcast_mul(&feat_mask.unsqueeze(D::Minus1)?)?
-            .add(&enc_outputs.broadcast_mul(&text_mask.unsqueeze(D::Minus1)?)?)?;
-
-        let mut lm_hidden = enc_outputs.i((.., t - 1, ..))?;
-
-        let input_embeds =
-            enc_outputs.add(&feat_mask.unsqueeze(D::Minus1)?.broadcast_mul(&feat_embed)?)?;
-        let residual_enc_outputs = self
-            .residual_lm
-            .forward_with_cache(&input_embeds, position_id)?;
-        let mut residual_hidden = residual_enc_outputs.i((.., t - 1, ..))?;
+        let (mut lm_hidden, mut residual_hidden) =
+            self.prefill(text, text_mask, feat, feat_mask, None)?;
 
         for i in 0..max_len {
             let dit_hidden_1 = self.lm_to_dit_proj.forward(&lm_hidden)?; // [b, h_dit]
@@ -716,6 +719,122 @@ impl VoxCPMModel {
         Ok(feat_pred)
     }
 
+    // 文本和参考音频特征一起prefill, 返回最后位置的(lm_hidden, residual_hidden), kv cache保留用于后续生成
+    // outputs不为None时按名字记录中间结果, 用于和参考实现对比
+    fn prefill(
+        &mut self,
+        text: &Tensor,
+        text_mask: &Tensor,
+        feat: &Tensor,
+        feat_mask: &Tensor,
+        mut outputs: Option<&mut Vec<(String, Tensor)>>,
+    ) -> Result<(Tensor, Tensor)> {
+        let (_, t, _, _) = feat.dims4()?;
+        let feat_embed = self.feat_encoder.forward(feat)?; // [b, t, h_feat]
+        let feat_embed = self.enc_to_lm_proj.forward(&feat_embed)?;
+        let scale_emb = if self.config.lm_config.use_mup {
+            self.config.lm_config.scale_emb
+        } else {
+            1.0
+        };
+
+        let text_embed = self
+            .base_lm
+            .embed_tokens
+            .as_ref()
+            .unwrap()
+            .forward(text)?
+            .affine(scale_emb as f64, 0.0)?;
+        let combined_embed = text_mask
+            .unsqueeze(D::Minus1)?
+            .broadcast_mul(&text_embed)?
+            .add(&feat_mask.unsqueeze(D::Minus1)?.broadcast_mul(&feat_embed)?)?;
+        let mut base_hidden_states = vec![];
+        let enc_outputs = match outputs {
+            Some(_) => self.base_lm.forward_with_cache_hidden_states(
+                &combined_embed,
+                0,
+                &mut base_hidden_states,
+            )?,
+            None => self.base_lm.forward_with_cache(&combined_embed, 0)?,
+        };
+        let enc_outputs = self
+            .fsq_layer
+            .forward(&enc_outputs)?
+            .broadcast_mul(&feat_mask.unsqueeze(D::Minus1)?)?
+            .add(&enc_outputs.broadcast_mul(&text_mask.unsqueeze(D::Minus1)?)?)?;
+
+        let lm_hidden = enc_outputs.i((.., t - 1, ..))?;
+
+        let input_embeds =
+            enc_outputs.add(&feat_mask.unsqueeze(D::Minus1)?.broadcast_mul(&feat_embed)?)?;
+        let mut residual_hidden_states = vec![];
+        let residual_enc_outputs = match outputs {
+            Some(_) => self.residual_lm.forward_with_cache_hidden_states(
+                &input_embeds,
+                0,
+                &mut residual_hidden_states,
+            )?,
+            None => self.residual_lm.forward_with_cache(&input_embeds, 0)?,
+        };
+        let residual_hidden = residual_enc_outputs.i((.., t - 1, ..))?;
+
+        if let Some(outputs) = outputs.as_mut() {
+            outputs.push(("feat_embed".to_string(), feat_embed));
+            for (i, hidden) in base_hidden_states.into_iter().enumerate() {
+                outputs.push((format!("base_lm.hidden_states.{}", i), hidden));
+            }
+            outputs.push(("enc_outputs".to_string(), enc_outputs));
+            for (i, hidden) in residual_hidden_states.into_iter().enumerate() {
+                outputs.push((format!("residual_lm.hidden_states.{}", i), hidden));
+            }
+            outputs.push(("lm_hidden".to_string(), lm_hidden.clone()));
+            outputs.push(("residual_hidden".to_string(), residual_hidden.clone()));
+            let dit_hidden = self
+                .lm_to_dit_proj
+                .forward(&lm_hidden)?
+                .add(&self.res_to_dit_proj.forward(&residual_hidden)?)?;
+            outputs.push(("dit_hidden".to_string(), dit_hidden));
+            let stop_logits = self
+                .stop_head
+                .forward(&self.stop_proj.forward(&lm_hidden)?.silu()?)?;
+            outputs.push(("stop_logits".to_string(), stop_logits));
+        }
+        Ok((lm_hidden, residual_hidden))
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    // 只做prefill并返回各模块的中间结果, 输入与_generate相同(不含batch维), 用于数值一致性检查
+    pub fn prefill_outputs(
+        &mut self,
+        text_token: &Tensor,
+        text_mask: &Tensor,
+        audio_feat: &Tensor,
+        audio_mask: &Tensor,
+    ) -> Result<Vec<(String, Tensor)>> {
+        let text_token = text_token.unsqueeze(0)?;
+        let text_mask = text_mask.unsqueeze(0)?.to_dtype(self.dtype)?;
+        let audio_feat = audio_feat.unsqueeze(0)?.to_dtype(self.dtype)?;
+        let audio_mask = audio_mask.unsqueeze(0)?.to_dtype(self.dtype)?;
+        self.base_lm.clear_kv_cache();
+        self.residual_lm.clear_kv_cache();
+        let mut outputs = vec![];
+        let result = self.prefill(
+            &text_token,
+            &text_mask,
+            &audio_feat,
+            &audio_mask,
+            Some(&mut outputs),
+        );
+        self.base_lm.clear_kv_cache();
+        self.residual_lm.clear_kv_cache();
+        result?;
+        Ok(outputs)
+    }
+
     pub fn build_prompt_cache(
         &mut self,
         prompt_text: String,
@@ -723,18 +842,22 @@ impl VoxCPMModel {
     ) -> Result<HashMap<String, Tensor>> {
         let text_token = self.tokenizer.encode(prompt_text)?;
         let text_token = Tensor::from_slice(&text_token, text_token.len(), &self.device)?;
-        let mut audio =
-            load_audio_with_resample(prompt_wav_path, self.device.clone(), Some(self.sample_rate))?;
-        let patch_len = self.patch_size * self.chunk_size;
-        if audio.dim(1)? % patch_len != 0 {
-            audio = audio.pad_with_zeros(D::Minus1, 0, patch_len - audio.dim(1)? % patch_len)?;
-        }
-        let audio_feat = self.audio_vae.encode(&audio, Some(self.sample_rate))?;
-        let audio_feat = audio_feat
-            .reshape((self.audio_vae.latent_dim, (), self.patch_size))?
-            .permute((1, 2, 0))?;
-        let dim0 = audio_feat.dim(0)? - 1;
-        let audio_feat = audio_feat.i(..dim0)?;
+        let audio_feat = self.encode_prompt_audio(&prompt_wav_path)?;
+        let mut hashmap = HashMap::new();
+        hashmap.insert("text_token".to_string(), text_token);
+        hashmap.insert("audio_feat".to_string(), audio_feat);
+        Ok(hashmap)
+    }
+
+    // 用已经生成的音频作为prompt, 不经过参考音频预处理
+    pub fn build_prompt_cache_from_audio(
+        &mut self,
+        prompt_text: String,
+        audio: &Tensor,
+    ) -> Result<HashMap<String, Tensor>> {
+        let text_token = self.tokenizer.encode(prompt_text)?;
+        let text_token = Tensor::from_slice(&text_token, text_token.len(), &self.device)?;
+        let audio_feat = self.encode_audio_feat(audio.to_dtype(DType::F32)?)?;
         let mut hashmap = HashMap::new();
         hashmap.insert("text_token".to_string(), text_token);
         hashmap.insert("audio_feat".to_string(), audio_feat);
@@ -1,12 +1,26 @@
+use std::collections::HashMap;
+
 use anyhow::{Result, anyhow};
 use candle_core::{Device, Tensor};
+use serde_json::{Value, json};
 use tokenizers::Tokenizer;
 
+use crate::models::common::gguf::GgufFile;
+
+// Qwen2系列的预分词正则
+const QWEN2_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+// gguf中的token_type
+const TOKEN_TYPE_CONTROL: i64 = 3;
+const TOKEN_TYPE_USER_DEFINED: i64 = 4;
+const TOKEN_TYPE_BYTE: i64 = 6;
+
 pub struct TokenizerModel {
     tokenizer: Tokenizer,
 }
 
 impl TokenizerModel {
+    // 优先读取tokenizer.json, 没有时从目录下gguf的元数据构建
     pub fn init(path: &str) -> Result<Self> {
         let path = path.to_string();
         assert!(
@@ -14,6 +28,11 @@ impl TokenizerModel {
             "model path file not exists"
         );
         let tokenizer_file = path.clone() + "/tokenizer.json";
+        if !std::path::Path::new(&tokenizer_file).exists()
+            && let Some(gguf) = GgufFile::find(&path)?
+        {
+            return Self::from_gguf(&gguf);
+        }
         assert!(
             std::path::Path::new(&tokenizer_file).exists(),
             "tokenizer.json not exists in model path"
@@ -23,6 +42,13 @@ impl TokenizerModel {
         Ok(Self { tokenizer })
     }
 
+    pub fn from_gguf(gguf: &GgufFile) -> Result<Self> {
+        let tokenizer_json = gguf_tokenizer_json(gguf)?;
+        let tokenizer = Tokenizer::from_bytes(serde_json::to_vec(&tokenizer_json)?)
+            .map_err(|e| anyhow!(format!("tokenizer from gguf error{}", e)))?;
+        Ok(Self { tokenizer })
+    }
+
     pub fn text_encode(&self, text: String, device: &Device) -> Result<Tensor> {
         let token_id = self
             .tokenizer
@@ -42,3 +68,335 @@ impl TokenizerModel {
         Ok(decode)
     }
 }
+
+// 流式输出的增量解码, 拼接所有输出与一次性解码全部token的结果一致
+// 从上次输出的位置往前多解码一段, 与这一段单独解码的结果相减得到新增文本
+// 结尾是不完整的utf-8字节(�)时先不输出, 等后续token补全, 结束时flush
+#[derive(Debug, Default)]
+pub struct StreamDecoder {
+    tokens: Vec<u32>,
+    prefix_offset: usize,
+    read_offset: usize,
+}
+
+impl StreamDecoder {
+    pub fn step(&mut self, tokenizer: &TokenizerModel, token: u32) -> Result<Option<String>> {
+        self.tokens.push(token);
+        self.decode(tokenizer, false)
+    }
+
+    pub fn flush(&mut self, tokenizer: &TokenizerModel) -> Result<Option<String>> {
+        self.decode(tokenizer, true)
+    }
+
+    fn decode(&mut self, tokenizer: &TokenizerModel, flush: bool) -> Result<Option<String>> {
+        if self.read_offset == self.tokens.len() {
+            return Ok(None);
+        }
+        let prefix =
+            tokenizer.token_decode(self.tokens[self.prefix_offset..self.read_offset].to_vec())?;
+        let text = tokenizer.token_decode(self.tokens[self.prefix_offset..].to_vec())?;
+        if (text.ends_with('�') && !flush) || text.len() <= prefix.len() {
+            return Ok(None);
+        }
+        let delta = text.get(prefix.len()..).map(|t| t.to_string());
+        if delta.is_some() {
+            self.prefix_offset = self.read_offset;
+            self.read_offset = self.tokens.len();
+        }
+        Ok(delta)
+    }
+}
+
+fn byte_level() -> Value {
+    json!({
+        "type": "ByteLevel",
+        "add_prefix_space": false,
+        "trim_offsets": false,
+        "use_regex": false
+    })
+}
+
+// GPT-2 byte-level的字节到可见字符映射
+fn bytes_to_unicode() -> Vec<char> {
+    let mut chars = vec![' '; 256];
+    let mut n = 0;
+    for b in 0..256u32 {
+        let visible =
+            (33..=126).contains(&b) || (161..=172).contains(&b) || (174..=255).contains(&b);
+        chars[b as usize] = if visible {
+            char::from_u32(b).unwrap_or(' ')
+        } else {
+            n += 1;
+            char::from_u32(255 + n).unwrap_or(' ')
+        };
+    }
+    chars
+}
+
+// 测试用的最小byte-level BPE分词器: 256个字节token(id与字节值相同), 没有merges,
+// special_tokens依次排在256之后
+pub fn tiny_tokenizer_json(special_tokens: &[&str]) -> Value {
+    let mut vocab: serde_json::Map<String, Value> = bytes_to_unicode()
+        .into_iter()
+        .enumerate()
+        .map(|(i, c)| (c.to_string(), json!(i)))
+        .collect();
+    let added_tokens: Vec<Value> = special_tokens
+        .iter()
+        .enumerate()
+        .map(|(i, t)| {
+            vocab.insert(t.to_string(), json!(256 + i));
+            json!({
+                "id": 256 + i,
+                "content": t,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true
+            })
+        })
+        .collect();
+    json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": null,
+        "pre_tokenizer": {
+            "type": "ByteLevel",
+            "add_prefix_space": false,
+            "trim_offsets": false,
+            "use_regex": true
+        },
+        "post_processor": byte_level(),
+        "decoder": byte_level(),
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": null,
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": false,
+            "byte_fallback": false,
+            "ignore_merges": false,
+            "vocab": vocab,
+            "merges": []
+        }
+    })
+}
+
+// sentencepiece词表没有merges, 按transformers的做法由词表和分数生成:
+// 能拆成两个词表内token的词都生成一条merge, 按合并结果的分数从高到低排序
+fn spm_merges(tokens: &[&str], scores: &[f64], token_types: &[i64]) -> Vec<(String, String)> {
+    let vocab: HashMap<&str, usize> = tokens.iter().enumerate().map(|(i, t)| (*t, i)).collect();
+    let mut merges = Vec::new();
+    for (id, token) in tokens.iter().enumerate() {
+        if matches!(
+            token_types.get(id),
+            Some(&TOKEN_TYPE_CONTROL) | Some(&TOKEN_TYPE_BYTE)
+        ) {
+            continue;
+        }
+        let chars: Vec<(usize, char)> = token.char_indices().collect();
+        let mut local = Vec::new();
+        for &(idx, _) in chars.iter().skip(1) {
+            let (left, right) = token.split_at(idx);
+            if let (Some(&l), Some(&r)) = (vocab.get(left), vocab.get(right)) {
+                local.push((l, r, scores.get(id).cloned().unwrap_or(0.0)));
+            }
+        }
+        local.sort_by_key(|(l, r, _)| (*l, *r));
+        merges.extend(local);
+    }
+    merges.sort_by(|a, b| b.2.total_cmp(&a.2));
+    merges
+        .into_iter()
+        .map(|(l, r, _)| (tokens[l].to_string(), tokens[r].to_string()))
+        .collect()
+}
+
+// 由gguf元数据拼出tokenizer.json, 支持gpt2(byte-level BPE)和llama(sentencepiece BPE)两种
+pub fn gguf_tokenizer_json(gguf: &GgufFile) -> Result<Value> {
+    let model_type = gguf
+        .get_str("tokenizer.ggml.model")
+        .ok_or(anyhow!("tokenizer.ggml.model not found in gguf"))?;
+    let tokens = gguf
+        .get_str_array("tokenizer.ggml.tokens")
+        .ok_or(anyhow!("tokenizer.ggml.tokens not found in gguf"))?;
+    let token_types: Vec<i64> = gguf
+        .get_f64_array("tokenizer.ggml.token_type")
+        .unwrap_or_default()
+        .into_iter()
+        .map(|t| t as i64)
+        .collect();
+    let vocab: serde_json::Map<String, Value> = tokens
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (t.to_string(), json!(i)))
+        .collect();
+    let added_tokens: Vec<Value> = token_types
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| **t == TOKEN_TYPE_CONTROL || **t == TOKEN_TYPE_USER_DEFINED)
+        .map(|(i, t)| {
+            json!({
+                "id": i,
+                "content": tokens[i],
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": *t == TOKEN_TYPE_CONTROL
+            })
+        })
+        .collect();
+    let token_of = |key: &str| {
+        gguf.get_usize(key)
+            .and_then(|id| tokens.get(id).map(|t| t.to_string()))
+    };
+
+    let tokenizer = match model_type {
+        "gpt2" => {
+            let merges: Vec<(String, String)> = gguf
+                .get_str_array("tokenizer.ggml.merges")
+                .ok_or(anyhow!("tokenizer.ggml.merges not found in gguf"))?
+                .into_iter()
+                .filter_map(|m| m.split_once(' '))
+                .map(|(l, r)| (l.to_string(), r.to_string()))
+                .collect();
+            let (normalizer, pre_tokenizer) = match gguf.get_str("tokenizer.ggml.pre") {
+                Some("qwen2") => (
+                    json!({"type": "NFC"}),
+                    json!({
+                        "type": "Sequence",
+                        "pretokenizers": [
+                            {
+                                "type": "Split",
+                                "pattern": {"Regex": QWEN2_PATTERN},
+                                "behavior": "Isolated",
+                                "invert": false
+                            },
+                            byte_level()
+                        ]
+                    }),
+                ),
+                _ => (
+                    Value::Null,
+                    json!({
+                        "type": "ByteLevel",
+                        "add_prefix_space": false,
+                        "trim_offsets": true,
+                        "use_regex": true
+                    }),
+                ),
+            };
+            json!({
+                "version": "1.0",
+                "truncation": null,
+                "padding": null,
+                "added_tokens": added_tokens,
+                "normalizer": normalizer,
+                "pre_tokenizer": pre_tokenizer,
+                "post_processor": byte_level(),
+                "decoder": byte_level(),
+                "model": {
+                    "type": "BPE",
+                    "dropout": null,
+                    "unk_token": null,
+                    "continuing_subword_prefix": null,
+                    "end_of_word_suffix": null,
+                    "fuse_unk": false,
+                    "byte_fallback": false,
+                    "ignore_merges": false,
+                    "vocab": vocab,
+                    "merges": merges
+                }
+            })
+        }
+        "llama" => {
+            let scores = gguf
+                .get_f64_array("tokenizer.ggml.scores")
+                .unwrap_or_default();
+            let merges = spm_merges(&tokens, &scores, &token_types);
+            let mut normalizers = vec![json!({
+                "type": "Replace",
+                "pattern": {"String": " "},
+                "content": "▁"
+            })];
+            if gguf
+                .get_bool("tokenizer.ggml.add_space_prefix")
+                .unwrap_or(true)
+            {
+                normalizers.insert(0, json!({"type": "Prepend", "prepend": "▁"}));
+            }
+            let post_processor = match (
+                gguf.get_bool("tokenizer.ggml.add_bos_token")
+                    .unwrap_or(true),
+                token_of("tokenizer.ggml.bos_token_id"),
+                gguf.get_usize("tokenizer.ggml.bos_token_id"),
+            ) {
+                (true, Some(bos), Some(bos_id)) => {
+                    let mut special_tokens = serde_json::Map::new();
+                    special_tokens.insert(
+                        bos.clone(),
+                        json!({"id": bos, "ids": [bos_id], "tokens": [bos]}),
+                    );
+                    json!({
+                        "type": "TemplateProcessing",
+                        "single": [
+                            {"SpecialToken": {"id": bos, "type_id": 0}},
+                            {"Sequence": {"id": "A", "type_id": 0}}
+                        ],
+                        "pair": [
+                            {"SpecialToken": {"id": bos, "type_id": 0}},
+                            {"Sequence": {"id": "A", "type_id": 0}},
+                            {"SpecialToken": {"id": bos, "type_id": 1}},
+                            {"Sequence": {"id": "B", "type_id": 1}}
+                        ],
+                        "special_tokens": special_tokens
+                    })
+                }
+                _ => Value::Null,
+            };
+            json!({
+                "version": "1.0",
+                "truncation": null,
+                "padding": null,
+                "added_tokens": added_tokens,
+                "normalizer": {"type": "Sequence", "normalizers": normalizers},
+                "pre_tokenizer": null,
+                "post_processor": post_processor,
+                "decoder": {
+                    "type": "Sequence",
+                    "decoders": [
+                        {"type": "Replace", "pattern": {"String": "▁"}, "content": " "},
+                        {"type": "ByteFallback"},
+                        {"type": "Fuse"},
+                        {"type": "Strip", "content": " ", "start": 1, "stop": 0}
+                    ]
+                },
+                "model": {
+                    "type": "BPE",
+                    "dropout": null,
+                    "unk_token": token_of("tokenizer.ggml.unknown_token_id"),
+                    "continuing_subword_prefix": null,
+                    "end_of_word_suffix": null,
+                    "fuse_unk": true,
+                    "byte_fallback": true,
+                    "ignore_merges": false,
+                    "vocab": vocab,
+                    "merges": merges
+                }
+            })
+        }
+        _ => {
+            return Err(anyhow!(format!(
+                "unsupported gguf tokenizer model: {}",
+                model_type
+            )));
+        }
+    };
+    Ok(tokenizer)
+}
@@ -1,12 +1,17 @@
 use std::f64::consts::PI;
+use std::io::{BufWriter, Cursor, Read, Seek, Write};
 use std::path::Path;
 
 use anyhow::{Result, anyhow};
-use candle_core::{D, Device, Tensor};
+use base64::{Engine, engine::general_purpose};
+use candle_core::{D, DType, Device, Tensor};
 use candle_nn::{Conv1d, Conv1dConfig, Module};
+use ffmpeg_next as ffmpeg;
 use hound::{SampleFormat, WavReader};
 use num::integer::gcd;
 
+use crate::utils::media_utils::VideoSource;
+
 // 重采样方法枚举
 #[derive(Debug, Clone, Copy)]
 pub enum ResamplingMethod {
@@ -214,8 +219,395 @@ pub fn resample_simple(waveform: &Tensor, orig_freq: i64, new_freq: i64) -> Resu
     )
 }
 
-pub fn load_audio<P: AsRef<Path>>(path: P, device: Device) -> Result<(Tensor, usize)> {
-    let mut reader = WavReader::open(path)?;
+// (1, T) 音频转为cpu上的f32数组, 处理完再用samples_to_audio放回原设备
+fn audio_to_vec(audio: &Tensor) -> Result<Vec<f32>> {
+    if audio.dim(0)? != 1 {
+        return Err(anyhow!("audio channel must be 1"));
+    }
+    Ok(audio.to_dtype(DType::F32)?.squeeze(0)?.to_vec1::<f32>()?)
+}
+
+fn samples_to_audio(samples: &[f32], device: &Device) -> Result<Tensor> {
+    Ok(Tensor::from_slice(samples, (1, samples.len()), device)?)
+}
+
+// WSOLA 变速不变调, speed > 1 加快语速, speed < 1 放慢语速
+// 每帧在理想位置附近搜索与上一帧自然延续最相似的片段, 再做汉宁窗叠加
+pub fn time_stretch(audio: &Tensor, sample_rate: usize, speed: f32) -> Result<Tensor> {
+    if !(0.25..=4.0).contains(&speed) {
+        return Err(anyhow!(format!(
+            "speed must be in [0.25, 4.0], got {}",
+            speed
+        )));
+    }
+    if (speed - 1.0).abs() < 1e-3 {
+        return Ok(audio.clone());
+    }
+    let input = audio_to_vec(audio)?;
+    // 30ms一帧, 50%重叠, 搜索范围为半个hop
+    let frame_len = (sample_rate * 30 / 1000).max(16);
+    let hop_out = frame_len / 2;
+    let hop_in = hop_out as f32 * speed;
+    let tolerance = hop_out / 2;
+    let window: Vec<f32> = (0..frame_len)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI as f32 * i as f32 / frame_len as f32).cos())
+        .collect();
+
+    let out_len = (input.len() as f32 / speed).round() as usize;
+    let mut padded = vec![0.0f32; tolerance];
+    padded.extend_from_slice(&input);
+    padded.extend(std::iter::repeat_n(
+        0.0f32,
+        frame_len + tolerance * 2 + hop_in as usize,
+    ));
+    let mut output = vec![0.0f32; out_len + frame_len];
+    let mut norm = vec![0.0f32; out_len + frame_len];
+
+    let num_frames = out_len / hop_out + 1;
+    let mut prev_pos = tolerance;
+    for k in 0..num_frames {
+        let ideal = tolerance + (k as f32 * hop_in).round() as usize;
+        let pos = if k == 0 {
+            ideal
+        } else {
+            // 上一帧的自然延续, 与候选片段做互相关
+            let natural = prev_pos + hop_out;
+            let target = &padded[natural..natural + hop_out];
+            let mut best_pos = ideal;
+            let mut best_corr = f32::MIN;
+            for cand in ideal - tolerance..=ideal + tolerance {
+                let corr: f32 = padded[cand..cand + hop_out]
+                    .iter()
+                    .zip(target)
+                    .map(|(a, b)| a * b)
+                    .sum();
+                if corr > best_corr {
+                    best_corr = corr;
+                    best_pos = cand;
+                }
+            }
+            best_pos
+        };
+        let out_start = k * hop_out;
+        for i in 0..frame_len {
+            if out_start + i >= output.len() {
+                break;
+            }
+            output[out_start + i] += padded[pos + i] * window[i];
+            norm[out_start + i] += window[i];
+        }
+        prev_pos = pos;
+    }
+    for (o, n) in output.iter_mut().zip(&norm) {
+        if *n > 1e-3 {
+            *o /= n;
+        }
+    }
+    output.truncate(out_len);
+    samples_to_audio(&output, audio.device())
+}
+
+// 响度归一化方式, 目标值单位为 dBFS / LUFS
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoudnessNorm {
+    Peak(f32),
+    Rms(f32),
+    Lufs(f32),
+}
+
+// RBJ biquad, 按 (b0, b1, b2, a1, a2) 归一化后的系数滤波
+fn biquad(samples: &[f64], coef: (f64, f64, f64, f64, f64)) -> Vec<f64> {
+    let (b0, b1, b2, a1, a2) = coef;
+    let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+    samples
+        .iter()
+        .map(|&x| {
+            let y = b0 * x + b1 * x1 + b2 * x2 - a1 * y1 - a2 * y2;
+            x2 = x1;
+            x1 = x;
+            y2 = y1;
+            y1 = y;
+            y
+        })
+        .collect()
+}
+
+// ITU-R BS.1770 K计权: 高架滤波 + 高通滤波, 系数按采样率重新计算
+fn k_weighting(samples: &[f32], sample_rate: usize) -> Vec<f64> {
+    let fs = sample_rate as f64;
+    let samples: Vec<f64> = samples.iter().map(|&s| s as f64).collect();
+
+    let (g, fc, q) = (4.0f64, 1681.974450955533, 0.7071752369554196);
+    let a = 10f64.powf(g / 40.0);
+    let w0 = 2.0 * PI * fc / fs;
+    let alpha = w0.sin() / (2.0 * q);
+    let cos_w0 = w0.cos();
+    let a0 = (a + 1.0) - (a - 1.0) * cos_w0 + 2.0 * a.sqrt() * alpha;
+    let shelf = (
+        a * ((a + 1.0) + (a - 1.0) * cos_w0 + 2.0 * a.sqrt() * alpha) / a0,
+        -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0) / a0,
+        a * ((a + 1.0) + (a - 1.0) * cos_w0 - 2.0 * a.sqrt() * alpha) / a0,
+        2.0 * ((a - 1.0) - (a + 1.0) * cos_w0) / a0,
+        ((a + 1.0) - (a - 1.0) * cos_w0 - 2.0 * a.sqrt() * alpha) / a0,
+    );
+
+    let (fc, q) = (38.13547087602444f64, 0.5003270373238773);
+    let w0 = 2.0 * PI * fc / fs;
+    let alpha = w0.sin() / (2.0 * q);
+    let cos_w0 = w0.cos();
+    let a0 = 1.0 + alpha;
+    let high_pass = (
+        (1.0 + cos_w0) / 2.0 / a0,
+        -(1.0 + cos_w0) / a0,
+        (1.0 + cos_w0) / 2.0 / a0,
+        -2.0 * cos_w0 / a0,
+        (1.0 - alpha) / a0,
+    );
+    biquad(&biquad(&samples, shelf), high_pass)
+}
+
+// BS.1770 积分响度: 400ms块, 75%重叠, -70 LUFS 绝对门限 + -10 LU 相对门限
+pub fn integrated_loudness(audio: &Tensor, sample_rate: usize) -> Result<f32> {
+    let samples = audio_to_vec(audio)?;
+    let weighted = k_weighting(&samples, sample_rate);
+    let block = (sample_rate as f64 * 0.4) as usize;
+    let step = block / 4;
+    if block == 0 || weighted.len() < block {
+        return Err(anyhow!("audio is shorter than 400ms"));
+    }
+    let powers: Vec<f64> = (0..=(weighted.len() - block) / step)
+        .map(|i| {
+            let s = &weighted[i * step..i * step + block];
+            s.iter().map(|x| x * x).sum::<f64>() / block as f64
+        })
+        .collect();
+    let loudness = |p: f64| -0.691 + 10.0 * p.log10();
+    let gated = |powers: Vec<f64>, threshold: f64| -> Vec<f64> {
+        powers
+            .into_iter()
+            .filter(|&p| p > 0.0 && loudness(p) > threshold)
+            .collect()
+    };
+    let abs_gated = gated(powers, -70.0);
+    if abs_gated.is_empty() {
+        return Ok(f32::NEG_INFINITY);
+    }
+    let rel_threshold = loudness(abs_gated.iter().sum::<f64>() / abs_gated.len() as f64) - 10.0;
+    let rel_gated = gated(abs_gated, rel_threshold);
+    if rel_gated.is_empty() {
+        return Ok(f32::NEG_INFINITY);
+    }
+    Ok(loudness(rel_gated.iter().sum::<f64>() / rel_gated.len() as f64) as f32)
+}
+
+// 响度归一化, 增益后峰值超过1时整体压回, 避免削波
+pub fn normalize_loudness(
+    audio: &Tensor,
+    sample_rate: usize,
+    norm: LoudnessNorm,
+) -> Result<Tensor> {
+    let audio = audio.to_dtype(DType::F32)?;
+    let current_db = match norm {
+        LoudnessNorm::Peak(_) => {
+            let peak = audio.abs()?.max_all()?.to_scalar::<f32>()?;
+            20.0 * peak.log10()
+        }
+        LoudnessNorm::Rms(_) => {
+            let rms = audio.sqr()?.mean_all()?.sqrt()?.to_scalar::<f32>()?;
+            20.0 * rms.log10()
+        }
+        // 不足一个400ms块无法计算积分响度, 退回RMS
+        LoudnessNorm::Lufs(_) if audio.dim(D::Minus1)? < sample_rate * 2 / 5 => {
+            let rms = audio.sqr()?.mean_all()?.sqrt()?.to_scalar::<f32>()?;
+            20.0 * rms.log10()
+        }
+        LoudnessNorm::Lufs(_) => integrated_loudness(&audio, sample_rate)?,
+    };
+    // 静音音频不做处理
+    if !current_db.is_finite() {
+        return Ok(audio);
+    }
+    let target_db = match norm {
+        LoudnessNorm::Peak(t) | LoudnessNorm::Rms(t) | LoudnessNorm::Lufs(t) => t,
+    };
+    let gain = 10f32.powf((target_db - current_db) / 20.0);
+    let audio = audio.affine(gain as f64, 0.0)?;
+    let peak = audio.abs()?.max_all()?.to_scalar::<f32>()?;
+    if peak > 1.0 {
+        return Ok(audio.affine(1.0 / peak as f64, 0.0)?);
+    }
+    Ok(audio)
+}
+
+// TTS输出的后处理参数
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioPostProcess {
+    // 语速, 对应openai的speed参数, 1.0为原速
+    pub speed: f32,
+    pub loudness: Option<LoudnessNorm>,
+}
+
+impl Default for AudioPostProcess {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            loudness: None,
+        }
+    }
+}
+
+pub fn post_process_audio(
+    audio: &Tensor,
+    sample_rate: usize,
+    process: &AudioPostProcess,
+) -> Result<Tensor> {
+    let mut audio = time_stretch(audio, sample_rate, process.speed)?;
+    if let Some(norm) = process.loudness {
+        audio = normalize_loudness(&audio, sample_rate, norm)?;
+    }
+    Ok(audio)
+}
+
+// 参考音频预处理参数, 用于音色克隆前清理prompt音频
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioPreprocessConfig {
+    // 基于能量的VAD, 去掉首尾静音
+    pub trim_silence: bool,
+    // 低于 最大帧能量 + vad_threshold_db 的帧视为静音
+    pub vad_threshold_db: f32,
+    pub vad_frame_ms: usize,
+    // 去掉静音后首尾各保留的静音长度
+    pub keep_silence_ms: usize,
+    // 时长限制, 单位秒, 太短报错, 太长在限制附近最安静的位置截断
+    pub min_duration: Option<f32>,
+    pub max_duration: Option<f32>,
+    pub remove_dc: bool,
+    pub normalize: Option<LoudnessNorm>,
+}
+
+impl Default for AudioPreprocessConfig {
+    fn default() -> Self {
+        Self {
+            trim_silence: true,
+            vad_threshold_db: -40.0,
+            vad_frame_ms: 20,
+            keep_silence_ms: 100,
+            min_duration: Some(1.0),
+            max_duration: Some(20.0),
+            remove_dc: true,
+            normalize: Some(LoudnessNorm::Peak(-1.0)),
+        }
+    }
+}
+
+// 预处理结果报告, 时间单位均为秒
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AudioPreprocessReport {
+    pub original_duration: f32,
+    pub trimmed_start: f32,
+    pub trimmed_end: f32,
+    // 超过max_duration被截掉的时长
+    pub truncated: f32,
+    pub dc_offset: f32,
+    // 原始音频中 |x| >= 0.999 的采样点比例, 比例高说明有削波
+    pub clipped_ratio: f32,
+    pub gain_db: f32,
+    pub final_duration: f32,
+}
+
+fn frame_energy_db(samples: &[f32], frame_len: usize) -> Vec<f32> {
+    samples
+        .chunks(frame_len)
+        .map(|frame| {
+            let energy = frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32;
+            10.0 * (energy + 1e-10).log10()
+        })
+        .collect()
+}
+
+pub fn preprocess_audio(
+    audio: &Tensor,
+    sample_rate: usize,
+    cfg: &AudioPreprocessConfig,
+) -> Result<(Tensor, AudioPreprocessReport)> {
+    let mut samples = audio_to_vec(audio)?;
+    if samples.is_empty() {
+        return Err(anyhow!("audio is empty"));
+    }
+    let sr = sample_rate as f32;
+    let mut report = AudioPreprocessReport {
+        original_duration: samples.len() as f32 / sr,
+        clipped_ratio: samples.iter().filter(|s| s.abs() >= 0.999).count() as f32
+            / samples.len() as f32,
+        ..Default::default()
+    };
+
+    if cfg.remove_dc {
+        let dc = samples.iter().sum::<f32>() / samples.len() as f32;
+        samples.iter_mut().for_each(|s| *s -= dc);
+        report.dc_offset = dc;
+    }
+
+    let frame_len = (sample_rate * cfg.vad_frame_ms / 1000).max(1);
+    if cfg.trim_silence {
+        let energy = frame_energy_db(&samples, frame_len);
+        let max_db = energy.iter().cloned().fold(f32::MIN, f32::max);
+        let threshold = max_db + cfg.vad_threshold_db;
+        let first = energy.iter().position(|&e| e > threshold);
+        let last = energy.iter().rposition(|&e| e > threshold);
+        if let (Some(first), Some(last)) = (first, last) {
+            let keep = sample_rate * cfg.keep_silence_ms / 1000;
+            let start = (first * frame_len).saturating_sub(keep);
+            let end = ((last + 1) * frame_len + keep).min(samples.len());
+            report.trimmed_start = start as f32 / sr;
+            report.trimmed_end = (samples.len() - end) as f32 / sr;
+            samples = samples[start..end].to_vec();
+        }
+    }
+
+    if let Some(max_duration) = cfg.max_duration {
+        let max_len = (max_duration * sr) as usize;
+        if samples.len() > max_len {
+            // 在最后1s内找能量最低的帧截断, 尽量不切在字的中间
+            let search_start = max_len.saturating_sub(sample_rate);
+            let energy = frame_energy_db(&samples[search_start..max_len], frame_len);
+            let quietest = energy
+                .iter()
+                .enumerate()
+                .min_by(|a, b| a.1.total_cmp(b.1))
+                .map(|(i, _)| i)
+                .unwrap_or(energy.len());
+            let cut = (search_start + quietest * frame_len).max(1).min(max_len);
+            report.truncated = (samples.len() - cut) as f32 / sr;
+            samples.truncate(cut);
+        }
+    }
+
+    if let Some(min_duration) = cfg.min_duration
+        && (samples.len() as f32 / sr) < min_duration
+    {
+        return Err(anyhow!(format!(
+            "audio is too short after preprocess: {:.2}s < {:.2}s",
+            samples.len() as f32 / sr,
+            min_duration
+        )));
+    }
+
+    let mut audio = samples_to_audio(&samples, audio.device())?;
+    if let Some(norm) = cfg.normalize {
+        let before = samples.iter().map(|s| s * s).sum::<f32>();
+        audio = normalize_loudness(&audio, sample_rate, norm)?;
+        let after = audio.sqr()?.sum_all()?.to_scalar::<f32>()?;
+        if before > 0.0 {
+            report.gain_db = 10.0 * (after / before).log10();
+        }
+    }
+    report.final_duration = samples.len() as f32 / sr;
+    Ok((audio, report))
+}
+
+// 读取hound支持的wav, 多声道取平均, 返回 (1, T) 的音频和采样率
+fn read_wav<R: Read>(mut reader: WavReader<R>, device: &Device) -> Result<(Tensor, usize)> {
     let spec = reader.spec();
     let samples: Vec<f32> = match spec.sample_format {
         SampleFormat::Int => {
@@ -234,6 +626,10 @@ pub fn load_audio<P: AsRef<Path>>(path: P, device: Device) -> Result<(Tensor, us
                     .samples::<i32>()
                     .map(|s| s.map(|sample| sample as f32 / 8388607.0))
                     .collect::<Result<Vec<_>, _>>()?,
+                32 => reader
+                    .samples::<i32>()
+                    .map(|s| s.map(|sample| sample as f32 / i32::MAX as f32))
+                    .collect::<Result<Vec<_>, _>>()?,
                 _ => {
                     return Err(anyhow::anyhow!(
                         "Unsupported bit depth: {}",
@@ -254,7 +650,7 @@ pub fn load_audio<P: AsRef<Path>>(path: P, device: Device) -> Result<(Tensor, us
             samples.len() / spec.channels as usize,
             spec.channels as usize,
         ),
-        &device,
+        device,
     )?
     .t()?;
     if spec.channels > 1 {
@@ -264,12 +660,159 @@ pub fn load_audio<P: AsRef<Path>>(path: P, device: Device) -> Result<(Tensor, us
     Ok((audio_tensor, sample_rate as usize))
 }
 
+// 用ffmpeg解码mp3/flac/ogg/m4a等格式, 解码后统一转为f32 planar再对各声道求平均
+fn decode_audio_with_ffmpeg(path: &Path, device: &Device) -> Result<(Tensor, usize)> {
+    decode_audio_stream(path, device)?.ok_or_else(|| anyhow!(format!("No audio stream found")))
+}
+
+// 文件中没有音频流时返回None, 视频文件可能没有音轨
+fn decode_audio_stream(path: &Path, device: &Device) -> Result<Option<(Tensor, usize)>> {
+    ffmpeg::init().map_err(|e| anyhow!(format!("Failed to initialize ffmpeg: {}", e)))?;
+    let mut ictx = ffmpeg::format::input(path)
+        .map_err(|e| anyhow!(format!("Failed to open audio file: {}", e)))?;
+    let input = match ictx.streams().best(ffmpeg::media::Type::Audio) {
+        Some(input) => input,
+        None => return Ok(None),
+    };
+    let audio_stream_index = input.index();
+    let context_decoder = ffmpeg::codec::context::Context::from_parameters(input.parameters())
+        .map_err(|e| anyhow!(format!("Failed to create decoder context: {}", e)))?;
+    let mut decoder = context_decoder
+        .decoder()
+        .audio()
+        .map_err(|e| anyhow!(format!("Failed to decoder audio: {}", e)))?;
+    let sample_rate = decoder.rate() as usize;
+
+    let mut channels_vec: Vec<Vec<f32>> = Vec::new();
+    // 部分编码格式在解出第一帧之前拿不到声道布局, 所以resampler在收到第一帧时再创建
+    let mut resampler: Option<ffmpeg::software::resampling::context::Context> = None;
+    let mut receive_and_process_decoded_frames =
+        |decoder: &mut ffmpeg::decoder::Audio| -> Result<()> {
+            let mut decoded = ffmpeg::frame::Audio::empty();
+            while decoder.receive_frame(&mut decoded).is_ok() {
+                if resampler.is_none() {
+                    let layout = if decoded.channel_layout().is_empty() {
+                        ffmpeg::ChannelLayout::default(decoded.channels() as i32)
+                    } else {
+                        decoded.channel_layout()
+                    };
+                    decoded.set_channel_layout(layout);
+                    resampler = Some(
+                        ffmpeg::software::resampling::context::Context::get(
+                            decoded.format(),
+                            layout,
+                            decoded.rate(),
+                            ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Planar),
+                            layout,
+                            decoded.rate(),
+                        )
+                        .map_err(|e| anyhow!(format!("Failed to create resampler: {}", e)))?,
+                    );
+                    channels_vec = vec![Vec::new(); decoded.channels() as usize];
+                }
+                if let Some(resampler) = resampler.as_mut() {
+                    let mut converted = ffmpeg::frame::Audio::empty();
+                    resampler
+                        .run(&decoded, &mut converted)
+                        .map_err(|e| anyhow!(format!("Failed to resampler run: {}", e)))?;
+                    for (i, channel) in channels_vec.iter_mut().enumerate() {
+                        channel.extend_from_slice(converted.plane::<f32>(i));
+                    }
+                }
+            }
+            Ok(())
+        };
+
+    for (stream, packet) in ictx.packets() {
+        if stream.index() == audio_stream_index {
+            decoder
+                .send_packet(&packet)
+                .map_err(|e| anyhow!(format!("Failed to send packet: {}", e)))?;
+            receive_and_process_decoded_frames(&mut decoder)?;
+        }
+    }
+    decoder
+        .send_eof()
+        .map_err(|e| anyhow!(format!("Failed to decoder.send_eof(): {}", e)))?;
+    receive_and_process_decoded_frames(&mut decoder)?;
+
+    if channels_vec.is_empty() || channels_vec[0].is_empty() {
+        return Err(anyhow!("No samples decoded from audio".to_string()));
+    }
+    let num_samples = channels_vec.iter().map(|c| c.len()).min().unwrap_or(0);
+    let channels: Vec<Tensor> = channels_vec
+        .iter()
+        .map(|c| Tensor::from_slice(&c[..num_samples], (1, num_samples), device))
+        .collect::<candle_core::Result<Vec<_>>>()?;
+    // 对channel通道求平均， channel维度变为1
+    let audio_tensor = Tensor::cat(&channels, 0)?.mean_keepdim(0)?;
+    Ok(Some((audio_tensor, sample_rate)))
+}
+
+// wav优先用hound读取, 其他格式或hound不支持的wav编码交给ffmpeg
+pub fn load_audio<P: AsRef<Path>>(path: P, device: Device) -> Result<(Tensor, usize)> {
+    let path = path.as_ref();
+    if let Ok(reader) = WavReader::open(path)
+        && let Ok(res) = read_wav(reader, &device)
+    {
+        return Ok(res);
+    }
+    decode_audio_with_ffmpeg(path, &device)
+}
+
+// 内存中的音频数据, ffmpeg需要从文件读取, 非wav数据先写到临时文件
+pub fn load_audio_from_bytes(data: &[u8], device: Device) -> Result<(Tensor, usize)> {
+    if data.starts_with(b"RIFF")
+        && let Ok(reader) = WavReader::new(Cursor::new(data))
+        && let Ok(res) = read_wav(reader, &device)
+    {
+        return Ok(res);
+    }
+    let temp_path = std::env::temp_dir().join(format!("aha_audio_{}", uuid::Uuid::new_v4()));
+    std::fs::write(&temp_path, data)?;
+    let res = decode_audio_with_ffmpeg(&temp_path, &device);
+    let _ = std::fs::remove_file(&temp_path);
+    res
+}
+
+pub fn load_audio_from_url(url: &str, device: Device) -> Result<(Tensor, usize)> {
+    let response = reqwest::blocking::get(url)
+        .map_err(|e| anyhow!(format!("Failed to fetch audio from url: {}", e)))?;
+    let bytes = response
+        .bytes()
+        .map_err(|e| anyhow!(format!("Failed to get audio bytes: {}", e)))?;
+    load_audio_from_bytes(&bytes, device)
+}
+
+pub fn load_audio_from_base64(base64_data: &str, device: Device) -> Result<(Tensor, usize)> {
+    let audio_data = general_purpose::STANDARD
+        .decode(base64_data)
+        .map_err(|e| anyhow!(format!("Failed to decode audio: {}", e)))?;
+    load_audio_from_bytes(&audio_data, device)
+}
+
+// 支持 http(s) url, file://, data:audio/xxx;base64, 以及本地路径
+pub fn get_audio(file: &str, device: Device) -> Result<(Tensor, usize)> {
+    if file.starts_with("http://") || file.starts_with("https://") {
+        return load_audio_from_url(file, device);
+    }
+    if file.starts_with("data:audio") && file.contains("base64,") {
+        let data: Vec<&str> = file.split("base64,").collect();
+        return load_audio_from_base64(data[1], device);
+    }
+    match file.strip_prefix("file://") {
+        Some(path) => load_audio(path, device),
+        None => load_audio(file, device),
+    }
+}
+
+// 路径也可以是get_audio支持的url/base64/data url
 pub fn load_audio_with_resample<P: AsRef<Path>>(
     path: P,
     device: Device,
     target_sample_rate: Option<usize>,
 ) -> Result<Tensor> {
-    let (mut audio, sr) = load_audio(path, device)?;
+    let (mut audio, sr) = get_audio(&path.as_ref().to_string_lossy(), device)?;
     if let Some(target_sample_rate) = target_sample_rate
         && target_sample_rate != sr
     {
@@ -278,24 +821,437 @@ pub fn load_audio_with_resample<P: AsRef<Path>>(
     Ok(audio)
 }
 
-pub fn save_wav(audio: &Tensor, save_path: &str) -> Result<()> {
+// 按起止时间(秒)截取(1, T)音频, 超出音频长度的部分忽略
+pub fn clip_audio(
+    audio: &Tensor,
+    sample_rate: usize,
+    start: Option<f32>,
+    end: Option<f32>,
+) -> Result<Tensor> {
+    let num_samples = audio.dim(D::Minus1)?;
+    let to_sample = |t: f32| ((t.max(0.0) * sample_rate as f32) as usize).min(num_samples);
+    let start = start.map_or(0, to_sample);
+    let end = end.map_or(num_samples, to_sample);
+    if end <= start {
+        return Err(anyhow!(format!(
+            "audio clip end sample {} must be larger than start sample {}",
+            end, start
+        )));
+    }
+    Ok(audio.narrow(D::Minus1, start, end - start)?)
+}
+
+// 视频中的音轨, 单声道并重采样到sample_rate, 与视频帧使用相同的video_start/video_end截取
+// 没有音轨或输入为图片帧列表时返回None
+pub fn get_video_audio(
+    video: &VideoSource,
+    sample_rate: usize,
+    video_start: Option<f32>,
+    video_end: Option<f32>,
+    device: &Device,
+) -> Result<Option<Tensor>> {
+    let media_file = match video {
+        VideoSource::File(file) => file,
+        VideoSource::Frames(_) => return Ok(None),
+    };
+    let (audio, orig_sample_rate) = match decode_audio_stream(media_file.path(), device)? {
+        Some(res) => res,
+        None => return Ok(None),
+    };
+    let audio = clip_audio(&audio, orig_sample_rate, video_start, video_end)?;
+    let audio = resample_simple(&audio, orig_sample_rate as i64, sample_rate as i64)?;
+    Ok(Some(audio))
+}
+
+// 音频输出格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioFormat {
+    // bits_per_sample 支持 8/16/24/32, float为true时写32位浮点
+    Wav { bits_per_sample: u16, float: bool },
+    Flac,
+    // bit_rate 单位 bit/s
+    Mp3 { bit_rate: usize },
+    Opus { bit_rate: usize },
+}
+
+impl AudioFormat {
+    // 根据文件后缀推断输出格式, 使用各格式的默认参数
+    pub fn from_path(path: &str) -> Result<Self> {
+        let ext = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "wav" => Ok(AudioFormat::Wav {
+                bits_per_sample: 16,
+                float: false,
+            }),
+            "flac" => Ok(AudioFormat::Flac),
+            "mp3" => Ok(AudioFormat::Mp3 { bit_rate: 128000 }),
+            "opus" | "ogg" => Ok(AudioFormat::Opus { bit_rate: 64000 }),
+            _ => Err(anyhow!(format!("Unsupported audio format: {}", ext))),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Wav { .. } => "wav",
+            AudioFormat::Flac => "flac",
+            AudioFormat::Mp3 { .. } => "mp3",
+            AudioFormat::Opus { .. } => "opus",
+        }
+    }
+}
+
+// (1, T) 音频转为样本数组, 峰值超过1时整体缩放, 避免溢出
+fn audio_to_samples(audio: &Tensor) -> Result<Vec<f32>> {
+    let mut samples = audio_to_vec(audio)?;
+    let max = samples.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+    if max > 1.0 {
+        samples.iter_mut().for_each(|s| *s /= max);
+    }
+    Ok(samples)
+}
+
+fn write_wav<W: Write + Seek>(
+    writer: W,
+    samples: &[f32],
+    sample_rate: usize,
+    bits_per_sample: u16,
+    float: bool,
+) -> Result<()> {
     let spec = hound::WavSpec {
         channels: 1,
-        sample_rate: 16000,
-        bits_per_sample: 16,
-        sample_format: hound::SampleFormat::Int,
+        sample_rate: sample_rate as u32,
+        bits_per_sample: if float { 32 } else { bits_per_sample },
+        sample_format: if float {
+            hound::SampleFormat::Float
+        } else {
+            hound::SampleFormat::Int
+        },
+    };
+    let mut writer = hound::WavWriter::new(writer, spec)?;
+    match (float, bits_per_sample) {
+        (true, _) => {
+            for &s in samples {
+                writer.write_sample(s)?;
+            }
+        }
+        (false, 8) => {
+            for &s in samples {
+                writer.write_sample((s * i8::MAX as f32).round() as i8)?;
+            }
+        }
+        (false, 16) => {
+            for &s in samples {
+                writer.write_sample((s * i16::MAX as f32).round() as i16)?;
+            }
+        }
+        (false, 24) => {
+            for &s in samples {
+                writer.write_sample((s * 8388607.0).round() as i32)?;
+            }
+        }
+        (false, 32) => {
+            for &s in samples {
+                writer.write_sample((s as f64 * i32::MAX as f64).round() as i32)?;
+            }
+        }
+        _ => {
+            return Err(anyhow!(format!(
+                "Unsupported bit depth: {}",
+                bits_per_sample
+            )));
+        }
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+// 用ffmpeg编码flac/mp3/opus, 采样格式和采样率按编码器支持的情况自动选择
+fn encode_audio_with_ffmpeg(
+    samples: &[f32],
+    sample_rate: usize,
+    format: AudioFormat,
+    save_path: &Path,
+) -> Result<()> {
+    ffmpeg::init().map_err(|e| anyhow!(format!("Failed to initialize ffmpeg: {}", e)))?;
+    let (codec, muxer, bit_rate) = match format {
+        AudioFormat::Flac => (ffmpeg::encoder::find(ffmpeg::codec::Id::FLAC), "flac", None),
+        AudioFormat::Mp3 { bit_rate } => (
+            ffmpeg::encoder::find(ffmpeg::codec::Id::MP3),
+            "mp3",
+            Some(bit_rate),
+        ),
+        AudioFormat::Opus { bit_rate } => (
+            // 内置的opus编码器是实验性的且只支持48k, 优先使用libopus
+            ffmpeg::encoder::find_by_name("libopus")
+                .or_else(|| ffmpeg::encoder::find(ffmpeg::codec::Id::OPUS)),
+            "ogg",
+            Some(bit_rate),
+        ),
+        AudioFormat::Wav { .. } => return Err(anyhow!("wav is written by hound")),
+    };
+    let codec = codec.ok_or_else(|| anyhow!(format!("No encoder found for {:?}", format)))?;
+    let codec_audio = codec
+        .audio()
+        .map_err(|e| anyhow!(format!("Failed to get audio codec: {}", e)))?;
+
+    // 编码器不支持当前采样率时, 重采样到不低于当前采样率的最小可用采样率
+    let mut rate = sample_rate as i32;
+    if let Some(rates) = codec_audio.rates() {
+        let rates: Vec<i32> = rates.collect();
+        if !rates.is_empty() && !rates.contains(&rate) {
+            rate = rates
+                .iter()
+                .filter(|&&r| r >= rate)
+                .min()
+                .or(rates.iter().max())
+                .copied()
+                .unwrap_or(rate);
+        }
+    }
+    let samples = if rate as usize != sample_rate {
+        let audio = Tensor::from_slice(samples, (1, samples.len()), &Device::Cpu)?;
+        resample_simple(&audio, sample_rate as i64, rate as i64)?
+            .squeeze(0)?
+            .to_vec1::<f32>()?
+    } else {
+        samples.to_vec()
+    };
+
+    let packed_f32 = ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Packed);
+    let preferred = [
+        packed_f32,
+        ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Planar),
+        ffmpeg::format::Sample::I16(ffmpeg::format::sample::Type::Packed),
+        ffmpeg::format::Sample::I16(ffmpeg::format::sample::Type::Planar),
+        ffmpeg::format::Sample::I32(ffmpeg::format::sample::Type::Packed),
+        ffmpeg::format::Sample::I32(ffmpeg::format::sample::Type::Planar),
+    ];
+    let sample_format = match codec_audio.formats() {
+        Some(formats) => {
+            let formats: Vec<ffmpeg::format::Sample> = formats.collect();
+            preferred
+                .into_iter()
+                .find(|f| formats.contains(f))
+                .or(formats.first().copied())
+                .unwrap_or(packed_f32)
+        }
+        None => packed_f32,
     };
-    assert_eq!(audio.dim(0)?, 1, "audio channel must be 1");
-    let max = audio.abs()?.max_all()?;
-    let max = max.to_scalar::<f32>()?;
-    let ratio = if max > 1.0 { 32767.0 / max } else { 32767.0 };
-    let audio = audio.squeeze(0)?;
-    let audio_vec = audio.to_vec1::<f32>()?;
-    let mut writer = hound::WavWriter::create(save_path, spec).unwrap();
-    for i in audio_vec {
-        let sample_i16 = (i * ratio).round() as i16;
-        writer.write_sample(sample_i16).unwrap();
-    }
-    writer.finalize().unwrap();
+
+    let mut octx = ffmpeg::format::output_as(save_path, muxer)
+        .map_err(|e| anyhow!(format!("Failed to create output file: {}", e)))?;
+    let global_header = octx
+        .format()
+        .flags()
+        .contains(ffmpeg::format::Flags::GLOBAL_HEADER);
+    let context = ffmpeg::codec::context::Context::new_with_codec(codec);
+    let mut encoder = context
+        .encoder()
+        .audio()
+        .map_err(|e| anyhow!(format!("Failed to create audio encoder: {}", e)))?;
+    encoder.set_rate(rate);
+    encoder.set_channel_layout(ffmpeg::ChannelLayout::MONO);
+    encoder.set_format(sample_format);
+    encoder.set_time_base((1, rate));
+    if let Some(bit_rate) = bit_rate {
+        encoder.set_bit_rate(bit_rate);
+    }
+    if global_header {
+        encoder.set_flags(ffmpeg::codec::Flags::GLOBAL_HEADER);
+    }
+    let mut encoder = encoder
+        .open_as(codec)
+        .map_err(|e| anyhow!(format!("Failed to open audio encoder: {}", e)))?;
+    {
+        let mut ost = octx
+            .add_stream(codec)
+            .map_err(|e| anyhow!(format!("Failed to add output stream: {}", e)))?;
+        ost.set_parameters(&encoder);
+        ost.set_time_base((1, rate));
+    }
+    octx.write_header()
+        .map_err(|e| anyhow!(format!("Failed to write header: {}", e)))?;
+    let stream_time_base = octx
+        .stream(0)
+        .ok_or_else(|| anyhow!("No output stream"))?
+        .time_base();
+
+    let mut resampler = ffmpeg::software::resampling::context::Context::get(
+        packed_f32,
+        ffmpeg::ChannelLayout::MONO,
+        rate as u32,
+        sample_format,
+        ffmpeg::ChannelLayout::MONO,
+        rate as u32,
+    )
+    .map_err(|e| anyhow!(format!("Failed to create resampler: {}", e)))?;
+
+    let write_packets = |encoder: &mut ffmpeg::encoder::Audio,
+                         octx: &mut ffmpeg::format::context::Output|
+     -> Result<()> {
+        let mut packet = ffmpeg::Packet::empty();
+        while encoder.receive_packet(&mut packet).is_ok() {
+            packet.set_stream(0);
+            packet.rescale_ts((1, rate), stream_time_base);
+            packet
+                .write_interleaved(octx)
+                .map_err(|e| anyhow!(format!("Failed to write packet: {}", e)))?;
+        }
+        Ok(())
+    };
+
+    // frame_size为0表示编码器支持任意帧长
+    let frame_size = match encoder.frame_size() {
+        0 => 1024,
+        n => n as usize,
+    };
+    let mut pts = 0;
+    for chunk in samples.chunks(frame_size) {
+        let mut frame =
+            ffmpeg::frame::Audio::new(packed_f32, chunk.len(), ffmpeg::ChannelLayout::MONO);
+        frame.set_rate(rate as u32);
+        frame.plane_mut::<f32>(0).copy_from_slice(chunk);
+        let mut converted = ffmpeg::frame::Audio::empty();
+        resampler
+            .run(&frame, &mut converted)
+            .map_err(|e| anyhow!(format!("Failed to resampler run: {}", e)))?;
+        converted.set_pts(Some(pts));
+        pts += chunk.len() as i64;
+        encoder
+            .send_frame(&converted)
+            .map_err(|e| anyhow!(format!("Failed to send frame: {}", e)))?;
+        write_packets(&mut encoder, &mut octx)?;
+    }
+    encoder
+        .send_eof()
+        .map_err(|e| anyhow!(format!("Failed to encoder.send_eof(): {}", e)))?;
+    write_packets(&mut encoder, &mut octx)?;
+    octx.write_trailer()
+        .map_err(|e| anyhow!(format!("Failed to write trailer: {}", e)))?;
     Ok(())
 }
+
+// audio: (1, T), sample_rate为audio本身的采样率
+pub fn save_audio(
+    audio: &Tensor,
+    save_path: &str,
+    sample_rate: usize,
+    format: AudioFormat,
+) -> Result<()> {
+    let samples = audio_to_samples(audio)?;
+    match format {
+        AudioFormat::Wav {
+            bits_per_sample,
+            float,
+        } => {
+            let file = BufWriter::new(std::fs::File::create(save_path)?);
+            write_wav(file, &samples, sample_rate, bits_per_sample, float)
+        }
+        _ => encode_audio_with_ffmpeg(&samples, sample_rate, format, Path::new(save_path)),
+    }
+}
+
+// 编码到内存, 用于接口直接返回音频数据
+pub fn encode_audio(audio: &Tensor, sample_rate: usize, format: AudioFormat) -> Result<Vec<u8>> {
+    let samples = audio_to_samples(audio)?;
+    match format {
+        AudioFormat::Wav {
+            bits_per_sample,
+            float,
+        } => {
+            let mut cursor = Cursor::new(Vec::new());
+            write_wav(&mut cursor, &samples, sample_rate, bits_per_sample, float)?;
+            Ok(cursor.into_inner())
+        }
+        _ => {
+            let temp_path = std::env::temp_dir().join(format!(
+                "aha_audio_{}.{}",
+                uuid::Uuid::new_v4(),
+                format.extension()
+            ));
+            let res = encode_audio_with_ffmpeg(&samples, sample_rate, format, &temp_path)
+                .and_then(|_| Ok(std::fs::read(&temp_path)?));
+            let _ = std::fs::remove_file(&temp_path);
+            res
+        }
+    }
+}
+
+pub fn save_wav(audio: &Tensor, save_path: &str, sample_rate: usize) -> Result<()> {
+    save_audio(audio, save_path, sample_rate, AudioFormat::Wav {
+        bits_per_sample: 16,
+        float: false,
+    })
+}
+
+// 多段音频拼接方式
+#[derive(Debug, Clone, Copy)]
+pub enum AudioJoinMode {
+    // 段与段之间插入静音, 单位ms
+    Silence(usize),
+    // 段与段之间线性交叉淡入淡出, 单位ms
+    Crossfade(usize),
+}
+
+// 拼接多段 (1, T) 音频, 返回拼接结果和每段在结果中的 [start, end) 采样点位置
+pub fn concat_audio_segments(
+    segments: &[Tensor],
+    join_mode: AudioJoinMode,
+    sample_rate: usize,
+) -> Result<(Tensor, Vec<(usize, usize)>)> {
+    if segments.is_empty() {
+        return Err(anyhow!("audio segments is empty"));
+    }
+    let device = segments[0].device();
+    let mut merged = segments[0].to_dtype(DType::F32)?;
+    let mut spans = vec![(0, merged.dim(D::Minus1)?)];
+    for seg in segments.iter().skip(1) {
+        let seg = seg.to_dtype(DType::F32)?;
+        let seg_len = seg.dim(D::Minus1)?;
+        let merged_len = merged.dim(D::Minus1)?;
+        match join_mode {
+            AudioJoinMode::Silence(ms) => {
+                let silence_len = ms * sample_rate / 1000;
+                let silence = Tensor::zeros((1, silence_len), DType::F32, device)?;
+                let start = merged_len + silence_len;
+                merged = Tensor::cat(&[&merged, &silence, &seg], D::Minus1)?;
+                spans.push((start, start + seg_len));
+            }
+            AudioJoinMode::Crossfade(ms) => {
+                // 重叠长度不能超过前后任一段的长度
+                let fade_len = (ms * sample_rate / 1000).min(merged_len).min(seg_len);
+                let start = merged_len - fade_len;
+                if fade_len == 0 {
+                    merged = Tensor::cat(&[&merged, &seg], D::Minus1)?;
+                } else {
+                    let fade_in = Tensor::arange(0u32, fade_len as u32, device)?
+                        .to_dtype(DType::F32)?
+                        .affine(1.0 / fade_len as f64, 0.0)?
+                        .unsqueeze(0)?;
+                    let fade_out = fade_in.affine(-1.0, 1.0)?;
+                    let overlap = merged
+                        .narrow(D::Minus1, start, fade_len)?
+                        .broadcast_mul(&fade_out)?
+                        .add(
+                            &seg.narrow(D::Minus1, 0, fade_len)?
+                                .broadcast_mul(&fade_in)?,
+                        )?;
+                    merged = Tensor::cat(
+                        &[
+                            &merged.narrow(D::Minus1, 0, start)?,
+                            &overlap,
+                            &seg.narrow(D::Minus1, fade_len, seg_len - fade_len)?,
+                        ],
+                        D::Minus1,
+                    )?;
+                }
+                spans.push((start, start + seg_len));
+            }
+        }
+    }
+    Ok((merged, spans))
+}
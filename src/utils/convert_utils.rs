@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+use anyhow::{Result, anyhow};
+use candle_core::{DType, Tensor, pickle::read_all_with_key};
+
+use crate::utils::find_type_files;
+
+// pth/bin转safetensors的参数
+#[derive(Debug, Clone)]
+pub struct ConvertConfig {
+    // 浮点权重转换的目标类型, None保持原类型, 整型权重不转换
+    pub dtype: Option<DType>,
+    // 单个分片的最大字节数, 超过则分片并写入index.json
+    pub max_shard_size: usize,
+    // pickle中权重所在的key, 如 "state_dict"
+    pub key: Option<String>,
+}
+
+impl Default for ConvertConfig {
+    fn default() -> Self {
+        Self {
+            dtype: None,
+            max_shard_size: 2 * 1024 * 1024 * 1024,
+            key: Some("state_dict".to_string()),
+        }
+    }
+}
+
+// "2GB" / "500MB" / "1024" -> 字节数
+pub fn parse_shard_size(size: &str) -> Result<usize> {
+    let size = size.trim().to_uppercase();
+    let (num, unit) = match size.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(idx) => size.split_at(idx),
+        None => (size.as_str(), ""),
+    };
+    let num: f64 = num
+        .trim()
+        .parse()
+        .map_err(|e| anyhow!(format!("invalid shard size {}: {}", size, e)))?;
+    let unit = match unit {
+        "" | "B" => 1,
+        "KB" | "K" => 1024,
+        "MB" | "M" => 1024 * 1024,
+        "GB" | "G" => 1024 * 1024 * 1024,
+        _ => return Err(anyhow!(format!("invalid shard size unit: {}", unit))),
+    };
+    Ok((num * unit as f64) as usize)
+}
+
+// 与transformers一致, pytorch_model.bin -> model.safetensors, 其他保留文件名
+fn output_stem(file: &std::path::Path) -> String {
+    let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+    match stem.as_ref() {
+        "pytorch_model" => "model".to_string(),
+        s => s.to_string(),
+    }
+}
+
+// 转换单个pickle文件, 返回写出的safetensors文件路径
+pub fn convert_pickle_file(
+    input_file: &str,
+    save_path: &str,
+    cfg: &ConvertConfig,
+) -> Result<Vec<String>> {
+    std::fs::create_dir_all(save_path)?;
+    let mut tensors = read_all_with_key(input_file, cfg.key.as_deref())?;
+    if tensors.is_empty() {
+        return Err(anyhow!(format!("no tensors found in {}", input_file)));
+    }
+    tensors.sort_by(|a, b| a.0.cmp(&b.0));
+    let tensors = tensors
+        .into_iter()
+        .map(|(name, t)| {
+            let t = match cfg.dtype {
+                Some(dtype) if t.dtype().is_float() && t.dtype() != dtype => t.to_dtype(dtype)?,
+                _ => t,
+            };
+            Ok((name, t))
+        })
+        .collect::<Result<Vec<(String, Tensor)>>>()?;
+
+    // 按顺序装入分片, 单个权重超过max_shard_size时独占一个分片
+    let mut shards: Vec<Vec<(String, Tensor)>> = vec![];
+    let mut shard_bytes = 0;
+    let mut total_size = 0;
+    for (name, t) in tensors {
+        let bytes = t.elem_count() * t.dtype().size_in_bytes();
+        if shards.is_empty() || (shard_bytes > 0 && shard_bytes + bytes > cfg.max_shard_size) {
+            shards.push(vec![]);
+            shard_bytes = 0;
+        }
+        shard_bytes += bytes;
+        total_size += bytes;
+        if let Some(shard) = shards.last_mut() {
+            shard.push((name, t));
+        }
+    }
+
+    let stem = output_stem(std::path::Path::new(input_file));
+    let num_shards = shards.len();
+    let mut weight_map = serde_json::Map::new();
+    let mut files = vec![];
+    for (i, shard) in shards.into_iter().enumerate() {
+        let file_name = if num_shards == 1 {
+            format!("{}.safetensors", stem)
+        } else {
+            format!("{}-{:05}-of-{:05}.safetensors", stem, i + 1, num_shards)
+        };
+        let shard: HashMap<String, Tensor> = shard.into_iter().collect();
+        for name in shard.keys() {
+            weight_map.insert(name.clone(), serde_json::json!(file_name));
+        }
+        let save_file = std::path::Path::new(save_path).join(&file_name);
+        candle_core::safetensors::save(&shard, &save_file)?;
+        files.push(save_file.to_string_lossy().to_string());
+    }
+    if num_shards > 1 {
+        let index = serde_json::json!({
+            "metadata": {"total_size": total_size},
+            "weight_map": weight_map,
+        });
+        let index_file =
+            std::path::Path::new(save_path).join(format!("{}.safetensors.index.json", stem));
+        std::fs::write(index_file, serde_json::to_string_pretty(&index)?)?;
+    }
+    Ok(files)
+}
+
+// 将目录下所有pth/bin权重转为safetensors保存到save_path,
+// save_path与model_path不同时, 其他配置/tokenizer等文件原样复制
+pub fn convert_checkpoint(model_path: &str, save_path: &str, cfg: &ConvertConfig) -> Result<()> {
+    let mut model_list = find_type_files(model_path, "pth")?;
+    model_list.extend(find_type_files(model_path, "bin")?);
+    if model_list.is_empty() {
+        return Err(anyhow!(format!("no pth/bin found in {}", model_path)));
+    }
+    for m in model_list {
+        convert_pickle_file(&m, save_path, cfg)?;
+    }
+    if std::fs::canonicalize(model_path)? == std::fs::canonicalize(save_path)? {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(model_path)? {
+        let file_path = entry?.path();
+        let file_name = file_path.file_name().unwrap_or_default().to_string_lossy();
+        if !file_path.is_file() || file_name.ends_with(".pth") || file_name.ends_with(".bin") {
+            continue;
+        }
+        std::fs::copy(
+            &file_path,
+            std::path::Path::new(save_path).join(&*file_name),
+        )?;
+    }
+    Ok(())
+}
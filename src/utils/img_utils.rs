@@ -2,7 +2,9 @@ use std::io::Cursor;
 
 use anyhow::{Result, anyhow};
 use base64::{Engine, engine::general_purpose};
-use image::{DynamicImage, ImageReader};
+use image::{DynamicImage, ImageReader, Rgb, RgbImage};
+
+use crate::utils::media_utils::MediaSource;
 
 pub fn load_image_from_url(url: &str) -> Result<DynamicImage> {
     let response = reqwest::blocking::get(url)
@@ -33,28 +35,96 @@ pub fn load_image_from_base64(base64_data: &str) -> Result<DynamicImage> {
     Ok(img)
 }
 
+// 支持 http(s) url, file://, data:image/xxx;base64, 以及本地路径
 pub fn get_image(file: &str) -> Result<DynamicImage> {
-    let mut img = None;
-    if file.starts_with("http://") || file.starts_with("https://") {
-        img = Some(load_image_from_url(file)?);
-    }
-    if file.starts_with("file://") {
-        let mut path = file.to_owned();
-        path = path.split_off(7);
-        img = Some(
-            ImageReader::open(path)
-                .map_err(|e| anyhow!(format!("Failed to open file: {}", e)))?
-                .decode()
-                .map_err(|e| anyhow!(format!("Failed to decode image: {}", e)))?,
-        );
+    MediaSource::parse(file)?
+        .load_image()
+        .map_err(|e| anyhow!(format!("get image from message failed: {}", e)))
+}
+
+// 与Pillow的Image.resize(BICUBIC)一致: libImaging/Resample.c的定点实现, 先水平后垂直两次一维卷积
+// transformers的Qwen2VLImageProcessor用PIL缩放, 这里逐像素对齐
+const PRECISION_BITS: u32 = 32 - 8 - 2;
+
+fn bicubic_filter(x: f64) -> f64 {
+    let a = -0.5;
+    let x = x.abs();
+    if x < 1.0 {
+        ((a + 2.0) * x - (a + 3.0)) * x * x + 1.0
+    } else if x < 2.0 {
+        (((x - 5.0) * x + 8.0) * x - 4.0) * a
+    } else {
+        0.0
     }
-    if file.starts_with("data:image") && file.contains("base64,") {
-        let data: Vec<&str> = file.split("base64,").collect();
-        let data = data[1];
-        img = Some(load_image_from_base64(data)?);
+}
+
+// 每个输出位置的起始输入下标和定点权重
+fn precompute_coeffs(in_size: u32, out_size: u32) -> Vec<(usize, Vec<i32>)> {
+    let scale = in_size as f64 / out_size as f64;
+    let filterscale = scale.max(1.0);
+    let support = 2.0 * filterscale;
+    (0..out_size)
+        .map(|xx| {
+            let center = (xx as f64 + 0.5) * scale;
+            let ss = 1.0 / filterscale;
+            let xmin = ((center - support + 0.5) as i64).max(0) as usize;
+            let xmax = ((center + support + 0.5) as i64).min(in_size as i64) as usize - xmin;
+            let mut k: Vec<f64> = (0..xmax)
+                .map(|x| bicubic_filter((x as f64 + xmin as f64 - center + 0.5) * ss))
+                .collect();
+            let ww: f64 = k.iter().sum();
+            if ww != 0.0 {
+                k.iter_mut().for_each(|w| *w /= ww);
+            }
+            let k = k
+                .iter()
+                .map(|&w| {
+                    let w = w * (1u32 << PRECISION_BITS) as f64;
+                    if w < 0.0 {
+                        (-0.5 + w) as i32
+                    } else {
+                        (0.5 + w) as i32
+                    }
+                })
+                .collect();
+            (xmin, k)
+        })
+        .collect()
+}
+
+fn clip8(v: i64) -> u8 {
+    (v >> PRECISION_BITS).clamp(0, 255) as u8
+}
+
+pub fn resize_bicubic(img: &DynamicImage, width: u32, height: u32) -> RgbImage {
+    let mut img = img.to_rgb8();
+    if width != img.width() {
+        let coeffs = precompute_coeffs(img.width(), width);
+        img = RgbImage::from_fn(width, img.height(), |x, y| {
+            let (xmin, k) = &coeffs[x as usize];
+            let mut ss = [1i64 << (PRECISION_BITS - 1); 3];
+            for (i, &w) in k.iter().enumerate() {
+                let p = img.get_pixel((xmin + i) as u32, y);
+                for c in 0..3 {
+                    ss[c] += p[c] as i64 * w as i64;
+                }
+            }
+            Rgb(ss.map(clip8))
+        });
     }
-    if let Some(img) = img {
-        return Ok(img);
+    if height != img.height() {
+        let coeffs = precompute_coeffs(img.height(), height);
+        img = RgbImage::from_fn(img.width(), height, |x, y| {
+            let (ymin, k) = &coeffs[y as usize];
+            let mut ss = [1i64 << (PRECISION_BITS - 1); 3];
+            for (i, &w) in k.iter().enumerate() {
+                let p = img.get_pixel(x, (ymin + i) as u32);
+                for c in 0..3 {
+                    ss[c] += p[c] as i64 * w as i64;
+                }
+            }
+            Rgb(ss.map(clip8))
+        });
     }
-    Err(anyhow!("get image from message failed".to_string()))
+    img
 }
@@ -0,0 +1,239 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use base64::{Engine, engine::general_purpose};
+use candle_core::{Device, Tensor};
+use image::{DynamicImage, ImageReader};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::utils::img_utils::resize_bicubic;
+
+// 图片/视频的来源: http(s) url, file://路径, data:xxx;base64,数据
+// 没有这些前缀的字符串一律按本地路径处理, 相对路径相对于当前工作目录
+// 视频还可以是图片帧列表, 原始请求中video_url.url写成数组, 每一帧可以是上面任意一种来源
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaSource {
+    Path(PathBuf),
+    Url(String),
+    Bytes(Vec<u8>),
+    Frames(Vec<MediaSource>),
+}
+
+// ffmpeg只能从文件读取, 内存中的数据写到临时文件, drop时删除
+pub struct MediaFile {
+    path: PathBuf,
+    temp: bool,
+}
+
+impl MediaFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for MediaFile {
+    fn drop(&mut self) {
+        if self.temp {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+// 打开后的视频, 视频帧和音轨从同一个文件解码, url只下载一次
+pub enum VideoSource {
+    Frames(Vec<MediaSource>),
+    File(MediaFile),
+}
+
+// ChatCompletionParameters中video_url.url只能是字符串, 帧列表在其中写成FRAMES_SCHEME加每一帧url的json数组
+const FRAMES_SCHEME: &str = "frames:";
+
+// 原始请求中的url, 字符串或帧列表
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum SourceValue {
+    Single(String),
+    Frames(Vec<String>),
+}
+
+impl MediaSource {
+    // 单个来源的url, 帧列表见from_frames
+    pub fn parse(source: &str) -> Result<Self> {
+        let source = source.trim();
+        if let Some(frames) = source.strip_prefix(FRAMES_SCHEME) {
+            let frames: Vec<String> = serde_json::from_str(frames)
+                .map_err(|e| anyhow!(format!("Failed to parse frame list: {}", e)))?;
+            return Self::from_frames(&frames);
+        }
+        if source.starts_with("http://") || source.starts_with("https://") {
+            return Ok(Self::Url(source.to_string()));
+        }
+        if source.starts_with("data:") {
+            let (_, data) = source
+                .split_once("base64,")
+                .ok_or_else(|| anyhow!("only base64 data url is supported".to_string()))?;
+            let bytes = general_purpose::STANDARD
+                .decode(data)
+                .map_err(|e| anyhow!(format!("Failed to decode base64 data: {}", e)))?;
+            return Ok(Self::Bytes(bytes));
+        }
+        match source.strip_prefix("file://") {
+            Some(path) => Ok(Self::Path(PathBuf::from(path))),
+            None => Ok(Self::Path(PathBuf::from(source))),
+        }
+    }
+
+    pub fn from_frames(frames: &[String]) -> Result<Self> {
+        if frames.is_empty() {
+            return Err(anyhow!("frame list is empty".to_string()));
+        }
+        let frames = frames
+            .iter()
+            .map(|frame| match Self::parse(frame)? {
+                Self::Frames(_) => Err(anyhow!("frame list can not be nested".to_string())),
+                frame => Ok(frame),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::Frames(frames))
+    }
+
+    // 原始请求中的url, 字符串或字符串数组
+    pub fn from_value(value: &Value) -> Result<Self> {
+        match serde_json::from_value(value.clone())
+            .map_err(|e| anyhow!(format!("media url must be a string or a list: {}", e)))?
+        {
+            SourceValue::Single(source) => Self::parse(&source),
+            SourceValue::Frames(frames) => Self::from_frames(&frames),
+        }
+    }
+
+    // 写入ChatCompletionParameters的url字符串, parse可以还原
+    pub fn to_url(&self) -> String {
+        match self {
+            Self::Path(path) => format!("file://{}", path.display()),
+            Self::Url(url) => url.clone(),
+            Self::Bytes(bytes) => format!(
+                "data:application/octet-stream;base64,{}",
+                general_purpose::STANDARD.encode(bytes)
+            ),
+            Self::Frames(frames) => {
+                let frames: Vec<String> = frames.iter().map(|f| f.to_url()).collect();
+                format!("{}{}", FRAMES_SCHEME, serde_json::json!(frames))
+            }
+        }
+    }
+
+    pub fn read_bytes(&self) -> Result<Vec<u8>> {
+        match self {
+            Self::Path(path) => std::fs::read(path)
+                .map_err(|e| anyhow!(format!("Failed to open file {}: {}", path.display(), e))),
+            Self::Url(url) => {
+                let response = reqwest::blocking::get(url)
+                    .map_err(|e| anyhow!(format!("Failed to fetch media from url: {}", e)))?;
+                let bytes = response
+                    .bytes()
+                    .map_err(|e| anyhow!(format!("Failed to get media bytes: {}", e)))?;
+                Ok(bytes.to_vec())
+            }
+            Self::Bytes(bytes) => Ok(bytes.clone()),
+            Self::Frames(_) => Err(anyhow!("frame list has no single file".to_string())),
+        }
+    }
+
+    // 本地文件直接使用, url和base64数据写到临时文件
+    pub fn to_file(&self) -> Result<MediaFile> {
+        if let Self::Path(path) = self {
+            return Ok(MediaFile {
+                path: path.clone(),
+                temp: false,
+            });
+        }
+        let bytes = self.read_bytes()?;
+        let path = std::env::temp_dir().join(format!("aha_media_{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, bytes)?;
+        Ok(MediaFile { path, temp: true })
+    }
+
+    pub fn open_video(&self) -> Result<VideoSource> {
+        match self {
+            Self::Frames(frames) => Ok(VideoSource::Frames(frames.clone())),
+            _ => Ok(VideoSource::File(self.to_file()?)),
+        }
+    }
+
+    // 用于缓存的来源标识, 读取后对内容做sha256, 相同内容不论来源是文件/url/base64都一致
+    // 帧列表对每帧的摘要再做一次摘要
+    pub fn identity(&self) -> Result<String> {
+        let digest = match self {
+            Self::Frames(frames) => {
+                let mut hasher = Sha256::new();
+                for frame in frames {
+                    hasher.update(frame.identity()?);
+                }
+                hasher.finalize()
+            }
+            _ => Sha256::digest(self.read_bytes()?),
+        };
+        Ok(format!("sha256:{:x}", digest))
+    }
+
+    pub fn load_image(&self) -> Result<DynamicImage> {
+        let img = ImageReader::new(Cursor::new(self.read_bytes()?))
+            .with_guessed_format()
+            .map_err(|e| anyhow!(format!("Failed to read image format: {}", e)))?
+            .decode()
+            .map_err(|e| anyhow!(format!("Failed to decode image: {}", e)))?;
+        Ok(img)
+    }
+}
+
+pub fn load_frames(frames: &[MediaSource]) -> Result<Vec<DynamicImage>> {
+    frames.iter().map(|frame| frame.load_image()).collect()
+}
+
+// 图片帧统一resize到(resize_h, resize_w), 返回与ffmpeg解码一致的u8 (t, c, h, w)
+pub fn frames_to_tensor(
+    frames: &[DynamicImage],
+    resize_h: u32,
+    resize_w: u32,
+    device: &Device,
+) -> Result<Tensor> {
+    let frames_vec = frames
+        .iter()
+        .map(|frame| {
+            Ok(Tensor::from_slice(
+                &resize_bicubic(frame, resize_w, resize_h).into_raw(),
+                (resize_h as usize, resize_w as usize, 3),
+                device,
+            )?
+            .permute((2, 0, 1))?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Tensor::stack(&frames_vec, 0)?.contiguous()?)
+}
+
+// 原始请求中video_url.url为帧列表数组时转为MediaSource::to_url的字符串, 之后可以解析为ChatCompletionParameters
+pub fn normalize_video_frames(request: &mut Value) -> Result<()> {
+    let messages = match request.get_mut("messages").and_then(|m| m.as_array_mut()) {
+        Some(messages) => messages,
+        None => return Ok(()),
+    };
+    for message in messages {
+        let parts = match message.get_mut("content").and_then(|c| c.as_array_mut()) {
+            Some(parts) => parts,
+            None => continue,
+        };
+        for part in parts {
+            let url = match part.get_mut("video_url").and_then(|v| v.get_mut("url")) {
+                Some(url) => url,
+                None => continue,
+            };
+            if url.is_array() {
+                *url = Value::String(MediaSource::from_value(url)?.to_url());
+            }
+        }
+    }
+    Ok(())
+}
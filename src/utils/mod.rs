@@ -1,7 +1,11 @@
 pub mod audio_utils;
+pub mod convert_utils;
 pub mod img_utils;
+pub mod media_utils;
 pub mod tensor_utils;
+pub mod text_utils;
 pub mod video_utils;
+pub mod vision_utils;
 
 use aha_openai_dive::v1::resources::{
     chat::{
@@ -9,7 +13,7 @@ use aha_openai_dive::v1::resources::{
         ChatCompletionResponse, ChatMessage, ChatMessageContent, DeltaChatMessage, DeltaFunction,
         DeltaToolCall, Function, ToolCall,
     },
-    shared::FinishReason,
+    shared::{FinishReason, Usage},
 };
 use anyhow::Result;
 use candle_core::{DType, Device};
@@ -176,6 +180,15 @@ pub fn build_completion_response(res: String, model_name: &str) -> ChatCompletio
     response
 }
 
+// Usage在不同版本的aha_openai_dive中还有其他可选字段, 从json构建, 未给出的字段为None
+pub fn completion_usage(prompt_tokens: usize, completion_tokens: usize) -> Result<Usage> {
+    Ok(serde_json::from_value(serde_json::json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": prompt_tokens + completion_tokens
+    }))?)
+}
+
 pub fn build_completion_chunk_response(
     res: String,
     model_name: &str,
@@ -0,0 +1,111 @@
+// 句末标点, 优先在这里切分
+const SENTENCE_PUNCTUATION: [char; 10] = ['。', '！', '？', '；', '…', '!', '?', ';', '\n', '.'];
+// 句中停顿标点, 句子过长时在这里切分
+const CLAUSE_PUNCTUATION: [char; 7] = ['，', '、', '：', ',', ':', '—', '～'];
+
+fn is_boundary(chars: &[char], i: usize, puncts: &[char]) -> bool {
+    let c = chars[i];
+    if !puncts.contains(&c) {
+        return false;
+    }
+    // 英文句号后面需要跟空白或者结尾, 避免把 3.14 / e.g. 这种切开
+    if c == '.' {
+        return i + 1 == chars.len() || chars[i + 1].is_whitespace();
+    }
+    true
+}
+
+// 按标点切分, 标点保留在前一段的末尾, 连续的标点(如 "？！" "……")不拆开
+fn split_by(text: &str, puncts: &[char]) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut pieces = Vec::new();
+    let mut current = String::new();
+    for i in 0..chars.len() {
+        current.push(chars[i]);
+        let next_is_punct = i + 1 < chars.len() && puncts.contains(&chars[i + 1]);
+        if is_boundary(&chars, i, puncts) && !next_is_punct {
+            let piece = current.trim().to_string();
+            if !piece.is_empty() {
+                pieces.push(piece);
+            }
+            current.clear();
+        }
+    }
+    let piece = current.trim().to_string();
+    if !piece.is_empty() {
+        pieces.push(piece);
+    }
+    pieces
+}
+
+// 没有标点可用时按字符数硬切, 尽量落在空白处, 避免把英文单词切断
+fn split_by_length(text: &str, max_chars: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    while start < chars.len() {
+        let mut end = (start + max_chars).min(chars.len());
+        if end < chars.len()
+            && let Some(pos) = chars[start..end].iter().rposition(|c| c.is_whitespace())
+            && pos > 0
+        {
+            end = start + pos;
+        }
+        let piece: String = chars[start..end].iter().collect();
+        let piece = piece.trim().to_string();
+        if !piece.is_empty() {
+            pieces.push(piece);
+        }
+        start = end;
+    }
+    pieces
+}
+
+fn join_piece(current: &mut String, piece: &str) {
+    // 英文片段之间补一个空格, 中文直接拼接
+    if let (Some(last), Some(first)) = (current.chars().last(), piece.chars().next())
+        && last.is_ascii()
+        && first.is_ascii_alphanumeric()
+    {
+        current.push(' ');
+    }
+    current.push_str(piece);
+}
+
+// 合并相邻的短片段, 每段不超过max_chars个字符
+fn merge_pieces(pieces: Vec<String>, max_chars: usize) -> Vec<String> {
+    let mut merged = Vec::new();
+    let mut current = String::new();
+    for piece in pieces {
+        let cur_len = current.chars().count();
+        if cur_len > 0 && cur_len + piece.chars().count() > max_chars {
+            merged.push(std::mem::take(&mut current));
+        }
+        join_piece(&mut current, &piece);
+    }
+    if !current.is_empty() {
+        merged.push(current);
+    }
+    merged
+}
+
+// 长文本切分: 先按句末标点切, 过长的句子再按逗号等停顿切, 仍然过长的按长度硬切,
+// 最后把短句合并, 使每段尽量接近但不超过max_chars
+pub fn split_text_by_punctuation(text: &str, max_chars: usize) -> Vec<String> {
+    let max_chars = max_chars.max(1);
+    let mut pieces = Vec::new();
+    for sentence in split_by(text, &SENTENCE_PUNCTUATION) {
+        if sentence.chars().count() <= max_chars {
+            pieces.push(sentence);
+            continue;
+        }
+        for clause in split_by(&sentence, &CLAUSE_PUNCTUATION) {
+            if clause.chars().count() <= max_chars {
+                pieces.push(clause);
+            } else {
+                pieces.extend(split_by_length(&clause, max_chars));
+            }
+        }
+    }
+    merge_pieces(pieces, max_chars)
+}
@@ -1,5 +1,7 @@
-use std::{fs::File, io::Write};
+use std::{fs::File, io::Write, path::Path};
 
+use anyhow::{Result, anyhow};
+use candle_core::{Device, Tensor};
 use ffmpeg_next as ffmpeg;
 
 #[allow(unused)]
@@ -12,3 +14,345 @@ fn save_file(
     file.write_all(frame.data(0))?;
     Ok(())
 }
+
+// 下一个目标帧比当前解码位置晚这么多秒以上时, seek到目标之前的关键帧, 不再逐帧解码
+const SEEK_GAP_SECONDS: f32 = 2.0;
+// ffmpeg容器时长和seek使用的时间单位, 微秒
+const AV_TIME_BASE: f64 = 1_000_000.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoStreamInfo {
+    pub width: u32,
+    pub height: u32,
+    pub fps: f32,
+    // 秒
+    pub duration: f32,
+    pub total_frames: u32,
+}
+
+impl VideoStreamInfo {
+    // 帧数/帧率/时长缺失或为0时互相推算, 可变帧率的文件以平均帧率计算
+    pub fn from_metadata(
+        width: u32,
+        height: u32,
+        fps: Option<f32>,
+        duration: Option<f32>,
+        total_frames: Option<u32>,
+    ) -> Result<Self> {
+        let fps = fps.filter(|f| f.is_finite() && *f > 0.0);
+        let duration = duration.filter(|d| d.is_finite() && *d > 0.0);
+        let total_frames = total_frames.filter(|n| *n > 0);
+        let (fps, duration, total_frames) = match (fps, duration, total_frames) {
+            (Some(fps), Some(duration), _) => (
+                fps,
+                duration,
+                total_frames.unwrap_or(((duration * fps).round() as u32).max(1)),
+            ),
+            (Some(fps), None, Some(n)) => (fps, n as f32 / fps, n),
+            (None, Some(duration), Some(n)) => (n as f32 / duration, duration, n),
+            _ => {
+                return Err(anyhow!(format!(
+                    "video metadata is incomplete: fps {:?}, duration {:?}, frames {:?}",
+                    fps, duration, total_frames
+                )));
+            }
+        };
+        Ok(Self {
+            width,
+            height,
+            fps,
+            duration,
+            total_frames,
+        })
+    }
+
+    // video_start/video_end(秒)截取后的首尾帧号, 包含两端
+    pub fn clip(&self, video_start: Option<f32>, video_end: Option<f32>) -> Result<(u32, u32)> {
+        let last_frame = self.total_frames - 1;
+        let start_frame = match video_start {
+            Some(start) => ((start.max(0.0) * self.fps).ceil() as u32).min(last_frame),
+            None => 0,
+        };
+        let end_frame = match video_end {
+            Some(end) => ((end.max(0.0) * self.fps).floor() as u32).min(last_frame),
+            None => last_frame,
+        };
+        if end_frame < start_frame {
+            return Err(anyhow!(format!(
+                "video_end {:?} must be later than video_start {:?}",
+                video_end, video_start
+            )));
+        }
+        Ok((start_frame, end_frame))
+    }
+}
+
+// [start_frame, end_frame]中均匀取nframes帧, 与torch.linspace(start, end, n).round()一致
+pub fn uniform_indices(start_frame: u32, end_frame: u32, nframes: u32) -> Vec<u32> {
+    if nframes <= 1 {
+        return vec![start_frame];
+    }
+    let step = (end_frame - start_frame) as f32 / (nframes - 1) as f32;
+    (0..nframes)
+        .map(|i| (start_frame as f32 + step * i as f32).round() as u32)
+        .collect()
+}
+
+// 按时间戳把解码出的帧分配给目标帧: 目标时间在当前帧的半帧间隔之内(或更早)时取当前帧
+pub struct FrameSelector {
+    targets: Vec<f32>,
+    half_frame: f32,
+    next: usize,
+    seeked: Option<usize>,
+}
+
+impl FrameSelector {
+    pub fn new(targets: Vec<f32>, fps: f32) -> Self {
+        Self {
+            targets,
+            half_frame: 0.5 / fps,
+            next: 0,
+            seeked: None,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next >= self.targets.len()
+    }
+
+    // 当前帧满足的目标帧数量
+    pub fn take(&mut self, timestamp: f32) -> usize {
+        let start = self.next;
+        while self.next < self.targets.len()
+            && self.targets[self.next] <= timestamp + self.half_frame
+        {
+            self.next += 1;
+        }
+        self.next - start
+    }
+
+    // 下一个目标离当前位置较远时返回需要seek到的时间, 每个目标只seek一次, 避免关键帧间隔很长时反复seek
+    pub fn seek_target(&mut self, timestamp: Option<f32>) -> Option<f32> {
+        let target = *self.targets.get(self.next)?;
+        let far = match timestamp {
+            Some(ts) => target - ts > SEEK_GAP_SECONDS,
+            None => target > SEEK_GAP_SECONDS,
+        };
+        if !far || self.seeked == Some(self.next) {
+            return None;
+        }
+        self.seeked = Some(self.next);
+        Some(target)
+    }
+
+    // 解码结束后还没有满足的目标帧数量, 使用最后一帧
+    pub fn remaining(&mut self) -> usize {
+        let remaining = self.targets.len() - self.next;
+        self.next = self.targets.len();
+        remaining
+    }
+}
+
+pub struct VideoReader {
+    ictx: ffmpeg::format::context::Input,
+    decoder: ffmpeg::decoder::Video,
+    stream_index: usize,
+    // 每个时间单位的秒数
+    time_base: f64,
+    start_time: f64,
+    info: VideoStreamInfo,
+}
+
+impl VideoReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        ffmpeg::init().map_err(|e| anyhow!(format!("Failed to initialize ffmpeg: {}", e)))?;
+        let mut ictx = ffmpeg::format::input(path)
+            .map_err(|e| anyhow!(format!("Failed to open video file: {}", e)))?;
+        let input = ictx
+            .streams()
+            .best(ffmpeg::media::Type::Video)
+            .ok_or_else(|| anyhow!(format!("No video stream found")))?;
+        let stream_index = input.index();
+        let context_decoder = ffmpeg::codec::context::Context::from_parameters(input.parameters())
+            .map_err(|e| anyhow!(format!("Failed to crate decoder context: {}", e)))?;
+        let decoder = context_decoder
+            .decoder()
+            .video()
+            .map_err(|e| anyhow!(format!("Failed to decoder video: {}", e)))?;
+        let time_base = rational_to_f64(input.time_base()).unwrap_or(0.0);
+        let start_time = match input.start_time() {
+            i64::MIN => 0.0,
+            start => start as f64 * time_base,
+        };
+        let fps = rational_to_f64(input.avg_frame_rate())
+            .or_else(|| rational_to_f64(input.rate()))
+            .map(|f| f as f32);
+        let stream_duration = Some(input.duration() as f64 * time_base).filter(|d| *d > 0.0);
+        let frames = Some(input.frames()).filter(|n| *n > 0).map(|n| n as u32);
+        let mut duration = stream_duration
+            .or_else(|| Some(ictx.duration() as f64 / AV_TIME_BASE).filter(|d| *d > 0.0))
+            .map(|d| d as f32);
+        // 容器中也没有时长时, 只读取packet(不解码)得到最后的时间戳
+        if duration.is_none() && (fps.is_none() || frames.is_none()) {
+            let mut last_pts = None;
+            for (stream, packet) in ictx.packets() {
+                if stream.index() == stream_index
+                    && let Some(pts) = packet.pts()
+                {
+                    last_pts = Some(last_pts.map_or(pts, |last: i64| last.max(pts)));
+                }
+            }
+            duration = last_pts.map(|pts| (pts as f64 * time_base - start_time) as f32);
+            ictx.seek(0, ..=0)
+                .map_err(|e| anyhow!(format!("Failed to seek video: {}", e)))?;
+        }
+        let info = VideoStreamInfo::from_metadata(
+            decoder.width(),
+            decoder.height(),
+            fps,
+            duration,
+            frames,
+        )?;
+        Ok(Self {
+            ictx,
+            decoder,
+            stream_index,
+            time_base,
+            start_time,
+            info,
+        })
+    }
+
+    pub fn info(&self) -> &VideoStreamInfo {
+        &self.info
+    }
+
+    // 按帧号对应的时间戳读取帧并resize, 返回u8 (t, c, h, w), frame_indices需要升序
+    pub fn read_frames(
+        &mut self,
+        frame_indices: &[u32],
+        resize_h: u32,
+        resize_w: u32,
+        device: &Device,
+    ) -> Result<Tensor> {
+        let fps = self.info.fps;
+        let targets = frame_indices.iter().map(|&i| i as f32 / fps).collect();
+        let mut selector = FrameSelector::new(targets, fps);
+        let mut scaler = ffmpeg::software::scaling::context::Context::get(
+            self.decoder.format(),
+            self.decoder.width(),
+            self.decoder.height(),
+            ffmpeg::format::Pixel::RGB24,
+            resize_w,
+            resize_h,
+            ffmpeg::software::scaling::flag::Flags::BILINEAR
+                | ffmpeg::software::scaling::flag::Flags::ACCURATE_RND,
+        )
+        .map_err(|e| anyhow!(format!("Failed to crate scaler: {}", e)))?;
+        let mut frames_vec: Vec<Tensor> = Vec::with_capacity(frame_indices.len());
+        let mut last_ts: Option<f32> = None;
+        let mut last_frame: Option<Tensor> = None;
+        let (time_base, start_time) = (self.time_base, self.start_time);
+
+        let mut receive_frames = |decoder: &mut ffmpeg::decoder::Video,
+                                  selector: &mut FrameSelector,
+                                  frames_vec: &mut Vec<Tensor>,
+                                  last_ts: &mut Option<f32>,
+                                  last_frame: &mut Option<Tensor>|
+         -> Result<()> {
+            let mut decoded = ffmpeg::frame::Video::empty();
+            while !selector.is_done() && decoder.receive_frame(&mut decoded).is_ok() {
+                // 没有时间戳的帧按上一帧加一帧间隔估计
+                let ts = match decoded.timestamp().or(decoded.pts()) {
+                    Some(ts) => (ts as f64 * time_base - start_time) as f32,
+                    None => last_ts.map_or(0.0, |ts| ts + 1.0 / fps),
+                };
+                *last_ts = Some(ts);
+                let count = selector.take(ts);
+                if count == 0 {
+                    continue;
+                }
+                let mut rgb_frame = ffmpeg::frame::Video::empty();
+                scaler
+                    .run(&decoded, &mut rgb_frame)
+                    .map_err(|e| anyhow!(format!("Failed to scaler run decoded: {}", e)))?;
+                let frame_tensor = Tensor::from_slice(
+                    rgb_frame.data(0),
+                    (resize_h as usize, resize_w as usize, 3),
+                    device,
+                )?
+                .permute((2, 0, 1))?;
+                for _ in 0..count {
+                    frames_vec.push(frame_tensor.clone());
+                }
+                *last_frame = Some(frame_tensor);
+            }
+            Ok(())
+        };
+
+        let mut seek = selector.seek_target(None);
+        while !selector.is_done() {
+            if let Some(target) = seek.take() {
+                let ts = ((target as f64 + start_time) * AV_TIME_BASE) as i64;
+                // seek失败(不支持seek的流)时继续顺序解码
+                if self.ictx.seek(ts, ..=ts).is_ok() {
+                    self.decoder.flush();
+                    last_ts = None;
+                }
+            }
+            for (stream, packet) in self.ictx.packets() {
+                if stream.index() != self.stream_index {
+                    continue;
+                }
+                self.decoder
+                    .send_packet(&packet)
+                    .map_err(|e| anyhow!(format!("Failed to send packet: {}", e)))?;
+                receive_frames(
+                    &mut self.decoder,
+                    &mut selector,
+                    &mut frames_vec,
+                    &mut last_ts,
+                    &mut last_frame,
+                )?;
+                if selector.is_done() {
+                    break;
+                }
+                seek = selector.seek_target(last_ts);
+                if seek.is_some() {
+                    break;
+                }
+            }
+            if seek.is_some() || selector.is_done() {
+                continue;
+            }
+            self.decoder
+                .send_eof()
+                .map_err(|e| anyhow!(format!("Failed to decoder.send_eof(): {}", e)))?;
+            receive_frames(
+                &mut self.decoder,
+                &mut selector,
+                &mut frames_vec,
+                &mut last_ts,
+                &mut last_frame,
+            )?;
+            // 元数据中的时长比实际长时, 末尾的目标帧使用最后解码的帧
+            let remaining = selector.remaining();
+            if let Some(frame) = last_frame.as_ref() {
+                for _ in 0..remaining {
+                    frames_vec.push(frame.clone());
+                }
+            }
+        }
+        if frames_vec.is_empty() {
+            return Err(anyhow!("No frames extracted from video".to_string()));
+        }
+        Ok(Tensor::stack(&frames_vec, 0)?.contiguous()?)
+    }
+}
+
+fn rational_to_f64(rational: ffmpeg::Rational) -> Option<f64> {
+    if rational.numerator() <= 0 || rational.denominator() <= 0 {
+        return None;
+    }
+    Some(f64::from(rational))
+}
@@ -0,0 +1,168 @@
+use aha_openai_dive::v1::resources::chat::ChatCompletionParameters;
+use anyhow::{Result, anyhow};
+use serde_json::Value;
+
+use crate::utils::media_utils::MediaSource;
+
+// 与qwen-vl-utils的图片/视频参数一致, 未设置的使用preprocessor_config中的默认值
+// 视频的min_pixels/max_pixels为每帧的像素数, video_start/video_end为截取的起止时间(秒)
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct VisionOverrides {
+    pub min_pixels: Option<u32>,
+    pub max_pixels: Option<u32>,
+    pub resized_height: Option<u32>,
+    pub resized_width: Option<u32>,
+    pub fps: Option<f32>,
+    pub video_start: Option<f32>,
+    pub video_end: Option<f32>,
+}
+
+impl VisionOverrides {
+    // self中未设置的字段取fallback的值
+    pub fn or(&self, fallback: &VisionOverrides) -> VisionOverrides {
+        VisionOverrides {
+            min_pixels: self.min_pixels.or(fallback.min_pixels),
+            max_pixels: self.max_pixels.or(fallback.max_pixels),
+            resized_height: self.resized_height.or(fallback.resized_height),
+            resized_width: self.resized_width.or(fallback.resized_width),
+            fps: self.fps.or(fallback.fps),
+            video_start: self.video_start.or(fallback.video_start),
+            video_end: self.video_end.or(fallback.video_end),
+        }
+    }
+
+    // 返回传给smart_resize的(height, width, min_pixels, max_pixels)
+    // 指定了resized_height和resized_width时只对齐到factor的倍数, 不放大, 仍受默认max_pixels限制
+    pub fn resize_args(
+        &self,
+        height: u32,
+        width: u32,
+        min_pixels: u32,
+        max_pixels: u32,
+    ) -> Result<(u32, u32, u32, u32)> {
+        if let (Some(h), Some(w)) = (self.resized_height, self.resized_width) {
+            if h == 0 || w == 0 {
+                return Err(anyhow!(format!(
+                    "resized_height {} and resized_width {} must be positive",
+                    h, w
+                )));
+            }
+            return Ok((h, w, 0, max_pixels));
+        }
+        // 只覆盖一边时, 另一边的默认值跟着调整
+        let (min_pixels, max_pixels) = match (self.min_pixels, self.max_pixels) {
+            (Some(min), Some(max)) => (min, max),
+            (Some(min), None) => (min, max_pixels.max(min)),
+            (None, Some(max)) => (min_pixels.min(max), max),
+            (None, None) => (min_pixels, max_pixels),
+        };
+        if min_pixels > max_pixels {
+            return Err(anyhow!(format!(
+                "min_pixels {} must not be larger than max_pixels {}",
+                min_pixels, max_pixels
+            )));
+        }
+        Ok((height, width, min_pixels, max_pixels))
+    }
+
+    pub fn fps(&self, default: f32) -> Result<f32> {
+        let fps = self.fps.unwrap_or(default);
+        if fps <= 0.0 || !fps.is_finite() {
+            return Err(anyhow!(format!("fps must be positive, got {}", fps)));
+        }
+        Ok(fps)
+    }
+
+    fn from_object(value: &Value) -> Result<Self> {
+        Ok(serde_json::from_value(value.clone())?)
+    }
+}
+
+// 一次请求的设置: 请求级别的mm_processor_kwargs, 以及按出现顺序的每张图片/每个视频的设置
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RequestVisionOverrides {
+    pub default: VisionOverrides,
+    pub images: Vec<VisionOverrides>,
+    pub videos: Vec<VisionOverrides>,
+}
+
+impl RequestVisionOverrides {
+    // 从请求中读取, request为原始的请求json; ChatCompletionParameters反序列化时会丢掉扩展字段, 读到的是默认值
+    // 设置可以写在content part上, 也可以写在image_url/video_url对象里, content part上的优先
+    pub fn from_request(request: &impl serde::Serialize) -> Result<Self> {
+        let request = serde_json::to_value(request)?;
+        let default = match request.get("mm_processor_kwargs") {
+            Some(kwargs) => VisionOverrides::from_object(kwargs)?,
+            None => VisionOverrides::default(),
+        };
+        let mut images = vec![];
+        let mut videos = vec![];
+        let messages = request
+            .get("messages")
+            .and_then(|m| m.as_array())
+            .cloned()
+            .unwrap_or_default();
+        // 与processor一致, 只有user消息中的图片/视频会被处理
+        for message in messages
+            .iter()
+            .filter(|m| m.get("role").and_then(|r| r.as_str()) == Some("user"))
+        {
+            let parts = match message.get("content").and_then(|c| c.as_array()) {
+                Some(parts) => parts,
+                None => continue,
+            };
+            for part in parts {
+                let (is_image, url_key) = if part.get("image_url").is_some() {
+                    (true, "image_url")
+                } else if part.get("video_url").is_some() {
+                    (false, "video_url")
+                } else {
+                    continue;
+                };
+                let mut overrides = VisionOverrides::from_object(part)?;
+                if let Some(url) = part.get(url_key).filter(|u| u.is_object()) {
+                    overrides = overrides.or(&VisionOverrides::from_object(url)?);
+                }
+                if is_image {
+                    images.push(overrides);
+                } else {
+                    videos.push(overrides);
+                }
+            }
+        }
+        Ok(Self {
+            default,
+            images,
+            videos,
+        })
+    }
+
+    // 解析原始请求json, 返回ChatCompletionParameters和其中丢掉的图片/视频设置
+    // 设置通过模型的set_request_overrides传给下一次请求
+    pub fn parse_request(request: Value) -> Result<(ChatCompletionParameters, Self)> {
+        let overrides = Self::from_request(&request)?;
+        Ok((serde_json::from_value(request)?, overrides))
+    }
+
+    pub fn image(&self, index: usize) -> VisionOverrides {
+        match self.images.get(index) {
+            Some(overrides) => overrides.or(&self.default),
+            None => self.default.clone(),
+        }
+    }
+
+    pub fn video(&self, index: usize) -> VisionOverrides {
+        match self.videos.get(index) {
+            Some(overrides) => overrides.or(&self.default),
+            None => self.default.clone(),
+        }
+    }
+}
+
+// 请求中的一张图片或一个视频, overrides为按出现顺序取得的预处理设置
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaRequest {
+    pub source: MediaSource,
+    pub video: bool,
+    pub overrides: VisionOverrides,
+}
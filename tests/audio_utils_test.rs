@@ -0,0 +1,164 @@
+use aha::utils::audio_utils::{
+    AudioFormat, AudioPreprocessConfig, LoudnessNorm, encode_audio, get_audio, integrated_loudness,
+    load_audio, load_audio_from_bytes, normalize_loudness, preprocess_audio, save_audio,
+    time_stretch,
+};
+use anyhow::Result;
+use base64::{Engine, engine::general_purpose};
+use candle_core::{D, Device};
+
+#[test]
+fn audio_wav_roundtrip() -> Result<()> {
+    // cargo test audio_wav_roundtrip -- --nocapture
+    let device = Device::Cpu;
+    let (audio, sr) = load_audio("./assets/audio/voice_01.wav", device.clone())?;
+    println!("audio: {:?}, sample_rate: {}", audio.shape(), sr);
+    for format in [
+        AudioFormat::Wav {
+            bits_per_sample: 16,
+            float: false,
+        },
+        AudioFormat::Wav {
+            bits_per_sample: 24,
+            float: false,
+        },
+        AudioFormat::Wav {
+            bits_per_sample: 32,
+            float: true,
+        },
+    ] {
+        let bytes = encode_audio(&audio, 22050, format)?;
+        let (decoded, decoded_sr) = load_audio_from_bytes(&bytes, device.clone())?;
+        assert_eq!(decoded_sr, 22050);
+        assert_eq!(decoded.dims(), audio.dims());
+        let diff = (decoded - &audio)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        println!("{:?} max diff: {}", format, diff);
+        assert!(diff < 1e-3);
+    }
+    // data:audio base64
+    let bytes = encode_audio(&audio, sr, AudioFormat::from_path("x.wav")?)?;
+    let url = format!(
+        "data:audio/wav;base64,{}",
+        general_purpose::STANDARD.encode(&bytes)
+    );
+    let (decoded, decoded_sr) = get_audio(&url, device)?;
+    assert_eq!(decoded_sr, sr);
+    assert_eq!(decoded.dim(D::Minus1)?, audio.dim(D::Minus1)?);
+    Ok(())
+}
+
+#[test]
+fn audio_ffmpeg_roundtrip() -> Result<()> {
+    // cargo test audio_ffmpeg_roundtrip -- --nocapture
+    let device = Device::Cpu;
+    let (audio, sr) = load_audio("./assets/audio/voice_01.wav", device.clone())?;
+    let len = audio.dim(D::Minus1)?;
+    let dir = std::env::temp_dir().join(format!("aha_audio_test_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    for name in ["voice_01.flac", "voice_01.mp3", "voice_01.opus"] {
+        let path = dir.join(name).to_string_lossy().to_string();
+        save_audio(&audio, &path, sr, AudioFormat::from_path(&path)?)?;
+        let (decoded, decoded_sr) = load_audio(&path, device.clone())?;
+        println!(
+            "{}: {:?}, sample_rate: {}",
+            name,
+            decoded.shape(),
+            decoded_sr
+        );
+        // opus解码固定输出48k
+        if name.ends_with(".opus") {
+            assert_eq!(decoded_sr, 48000);
+        } else {
+            assert_eq!(decoded_sr, sr);
+        }
+        // 换算到原采样率后长度相差不超过5%(编码器的padding)
+        let decoded_len = decoded.dim(D::Minus1)? as f64 * sr as f64 / decoded_sr as f64;
+        assert!(
+            (decoded_len - len as f64).abs() < len as f64 * 0.05,
+            "{}: {} vs {}",
+            name,
+            decoded_len,
+            len
+        );
+    }
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn audio_time_stretch_and_loudness() -> Result<()> {
+    // cargo test audio_time_stretch_and_loudness -- --nocapture
+    let device = Device::Cpu;
+    let (audio, sr) = load_audio("./assets/audio/voice_01.wav", device)?;
+    let len = audio.dim(D::Minus1)?;
+    for speed in [0.5f32, 0.8, 1.25, 2.0] {
+        let stretched = time_stretch(&audio, sr, speed)?;
+        let expect = (len as f32 / speed).round() as usize;
+        println!("speed {}: {} -> {}", speed, len, stretched.dim(D::Minus1)?);
+        assert_eq!(stretched.dim(D::Minus1)?, expect);
+    }
+
+    let before = integrated_loudness(&audio, sr)?;
+    let normed = normalize_loudness(&audio, sr, LoudnessNorm::Lufs(-23.0))?;
+    let after = integrated_loudness(&normed, sr)?;
+    println!("loudness: {} LUFS -> {} LUFS", before, after);
+    let peak = normed.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!(peak <= 1.0);
+    if peak < 0.999 {
+        assert!((after + 23.0).abs() < 0.1);
+    }
+    let normed = normalize_loudness(&audio, sr, LoudnessNorm::Peak(-1.0))?;
+    let peak = normed.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!((20.0 * peak.log10() + 1.0).abs() < 1e-3);
+
+    // 短于400ms的片段退回RMS归一化
+    let short = audio.narrow(D::Minus1, sr / 2, sr / 5)?;
+    let normed = normalize_loudness(&short, sr, LoudnessNorm::Lufs(-23.0))?;
+    assert_eq!(normed.dim(D::Minus1)?, sr / 5);
+    let peak = normed.abs()?.max_all()?.to_scalar::<f32>()?;
+    let rms = normed.sqr()?.mean_all()?.sqrt()?.to_scalar::<f32>()?;
+    println!("short clip rms: {} dB", 20.0 * rms.log10());
+    assert!(peak <= 1.0);
+    if peak < 0.999 {
+        assert!((20.0 * rms.log10() + 23.0).abs() < 1e-3);
+    }
+    Ok(())
+}
+
+#[test]
+fn audio_preprocess_prompt() -> Result<()> {
+    // cargo test audio_preprocess_prompt -- --nocapture
+    let device = Device::Cpu;
+    let (audio, sr) = load_audio("./assets/audio/voice_01.wav", device.clone())?;
+    let len = audio.dim(D::Minus1)?;
+    // 首尾各补2s静音, 并加上直流偏置
+    let padded = audio.pad_with_zeros(D::Minus1, 2 * sr, 2 * sr)?;
+    let padded = (padded + 0.05)?;
+    let cfg = AudioPreprocessConfig {
+        max_duration: None,
+        ..Default::default()
+    };
+    let (processed, report) = preprocess_audio(&padded, sr, &cfg)?;
+    println!("report: {:?}", report);
+    assert!((report.dc_offset - 0.05).abs() < 0.01);
+    assert!(report.trimmed_start > 1.5 && report.trimmed_end > 1.5);
+    assert!(processed.dim(D::Minus1)? <= len + sr);
+    let peak = processed.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!((20.0 * peak.log10() + 1.0).abs() < 1e-3);
+
+    let cfg = AudioPreprocessConfig {
+        max_duration: Some(1.5),
+        ..Default::default()
+    };
+    let (processed, report) = preprocess_audio(&padded, sr, &cfg)?;
+    println!("truncated report: {:?}", report);
+    assert!(processed.dim(D::Minus1)? <= sr * 3 / 2);
+    assert!(report.truncated > 0.0);
+
+    let cfg = AudioPreprocessConfig {
+        min_duration: Some(60.0),
+        ..Default::default()
+    };
+    assert!(preprocess_audio(&padded, sr, &cfg).is_err());
+    Ok(())
+}
@@ -0,0 +1,303 @@
+use aha::models::{
+    GenerateModel,
+    common::{
+        fixture::TinyModel,
+        vision_cache::{VisionCacheConfig, VisionCacheKey, VisionEmbeddingCache},
+    },
+    qwen3vl::{
+        config::Qwen3VLConfig,
+        generate::Qwen3VLGenerateModel,
+        model::Qwen3VLModel,
+        session::{ChatSession, MediaItem},
+    },
+};
+use aha_openai_dive::v1::resources::chat::ChatCompletionParameters;
+use anyhow::Result;
+use candle_core::{D, DType, Device, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+
+const VISION_START: u32 = 3;
+const VISION_END: u32 = 4;
+const IMAGE: u32 = 6;
+
+fn tiny_qwen3vl(device: &Device) -> Result<Qwen3VLModel> {
+    let cfg: Qwen3VLConfig = serde_json::from_value(serde_json::json!({
+        "image_token_id": IMAGE,
+        "video_token_id": 7,
+        "vision_start_token_id": VISION_START,
+        "vision_end_token_id": VISION_END,
+        "tie_word_embeddings": true,
+        "text_config": {
+            "attention_bias": false,
+            "attention_dropout": 0.0,
+            "bos_token_id": 1,
+            "dtype": "float32",
+            "eos_token_id": 2,
+            "head_dim": 8,
+            "hidden_act": "silu",
+            "hidden_size": 32,
+            "initializer_range": 0.02,
+            "intermediate_size": 64,
+            "max_position_embeddings": 256,
+            "num_attention_heads": 4,
+            "num_hidden_layers": 2,
+            "num_key_value_heads": 2,
+            "rms_norm_eps": 1e-6,
+            "rope_scaling": {"rope_type": "default", "mrope_section": [2, 1, 1], "mrope_interleaved": true},
+            "rope_theta": 10000.0,
+            "tie_word_embeddings": true,
+            "use_cache": true,
+            "vocab_size": 64
+        },
+        "vision_config": {
+            "deepstack_visual_indexes": [0],
+            "depth": 1,
+            "hidden_act": "gelu_pytorch_tanh",
+            "hidden_size": 16,
+            "in_channels": 3,
+            "initializer_range": 0.02,
+            "intermediate_size": 32,
+            "num_heads": 2,
+            "num_position_embeddings": 16,
+            "out_hidden_size": 32,
+            "patch_size": 2,
+            "spatial_merge_size": 2,
+            "temporal_patch_size": 2
+        }
+    }))?;
+    let varmap = VarMap::new();
+    let model = Qwen3VLModel::new(cfg, VarBuilder::from_varmap(&varmap, DType::F32, device))?;
+    for var in varmap.all_vars() {
+        var.set(&Tensor::randn(0f32, 0.5, var.shape(), device)?)?;
+    }
+    Ok(model)
+}
+
+// grid为(1, 4, 4)的图片, 合并后为4个token
+fn image_tokens() -> Vec<u32> {
+    vec![VISION_START, IMAGE, IMAGE, IMAGE, IMAGE, VISION_END]
+}
+
+fn full_logits(
+    model: &mut Qwen3VLModel,
+    ids: &[u32],
+    pixel_values: &Tensor,
+    grid_thw: &Tensor,
+) -> Result<Tensor> {
+    let device = Device::Cpu;
+    model.clear_kv_cache();
+    let input_ids = Tensor::from_slice(ids, (1, ids.len()), &device)?;
+    let cache_position = Tensor::arange(0u32, ids.len() as u32, &device)?;
+    let logits = model.forward(
+        &input_ids,
+        Some(pixel_values),
+        Some(grid_thw),
+        None,
+        None,
+        Some(&cache_position),
+        0,
+    )?;
+    model.clear_kv_cache();
+    Ok(logits)
+}
+
+// id相同表示同一来源和预处理设置的图片
+fn media_items(model: &Qwen3VLModel, images: &[(u64, &Tensor)]) -> Result<Vec<MediaItem>> {
+    let device = Device::Cpu;
+    images
+        .iter()
+        .map(|(id, pixel_values)| {
+            let key = VisionCacheKey {
+                namespace: "tiny".to_string(),
+                source: format!("image{}", id),
+                video: false,
+                params: "{}".to_string(),
+            };
+            let mut features =
+                model.vision_features(pixel_values, &Tensor::new(&[[1u32, 4, 4]], &device)?)?;
+            MediaItem::new(key, &[1, 4, 4], features.remove(0), 2)
+        })
+        .collect()
+}
+
+fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    Ok((a - b)?.abs()?.max_all()?.to_scalar::<f32>()?)
+}
+
+fn decode(model: &mut Qwen3VLModel, tokens: &mut Vec<u32>, new_tokens: &[u32]) -> Result<()> {
+    let device = Device::Cpu;
+    for &token in new_tokens {
+        let offset = tokens.len();
+        model.forward(
+            &Tensor::new(&[[token]], &device)?,
+            None,
+            None,
+            None,
+            None,
+            Some(&Tensor::new(&[offset as u32], &device)?),
+            offset,
+        )?;
+        tokens.push(token);
+    }
+    Ok(())
+}
+
+#[test]
+fn chat_session_delta_prefill() -> Result<()> {
+    // cargo test chat_session_delta_prefill -- --nocapture
+    let device = Device::Cpu;
+    let mut model = tiny_qwen3vl(&device)?;
+    let image1 = Tensor::randn(0f32, 1.0, (16, 24), &device)?;
+    let image2 = Tensor::randn(0f32, 1.0, (16, 24), &device)?;
+    let image3 = Tensor::randn(0f32, 1.0, (16, 24), &device)?;
+    let grid = Tensor::new(&[[1u32, 4, 4]], &device)?;
+    let grid2 = Tensor::new(&[[1u32, 4, 4], [1, 4, 4]], &device)?;
+    let mut session = ChatSession::new();
+
+    // 第一轮: 一张图片, 从头prefill
+    let mut ids1 = vec![10u32, 11];
+    ids1.extend(image_tokens());
+    ids1.extend([12, 13, 14]);
+    let expect = full_logits(&mut model, &ids1, &image1, &grid)?;
+    let images = media_items(&model, &[(1, &image1)])?;
+    let (start, image_features, _) = session.prepare(&mut model, &ids1, &images, &[])?;
+    assert_eq!(start, 0);
+    let input_ids = Tensor::from_slice(&ids1, (1, ids1.len()), &device)?;
+    let logits = model.forward_delta(&input_ids, start, Some(&grid), None, image_features, None)?;
+    println!("turn 1 diff: {}", max_diff(&logits, &expect)?);
+    assert!(max_diff(&logits, &expect)? < 1e-4);
+    // 模拟生成的回复
+    let mut tokens = ids1.clone();
+    decode(&mut model, &mut tokens, &[20, 21, 22])?;
+    session.update(&model, tokens.clone())?;
+    model.clear_kv_cache();
+
+    // 第二轮: 历史不变, 追加一张新图片, 只prefill新增部分
+    let mut ids2 = tokens.clone();
+    ids2.extend([2, 15]);
+    ids2.extend(image_tokens());
+    ids2.extend([16, 17]);
+    let pixel_values = Tensor::cat(&[&image1, &image2], 0)?;
+    let expect = full_logits(&mut model, &ids2, &pixel_values, &grid2)?;
+    let images = media_items(&model, &[(1, &image1), (2, &image2)])?;
+    let (start, image_features, _) = session.prepare(&mut model, &ids2, &images, &[])?;
+    assert_eq!(start, tokens.len());
+    assert_eq!(image_features.as_ref().unwrap().0.dim(0)?, 4);
+    let input_ids = Tensor::from_slice(&ids2, (1, ids2.len()), &device)?;
+    let logits =
+        model.forward_delta(&input_ids, start, Some(&grid2), None, image_features, None)?;
+    println!(
+        "turn 2 reused {} / {}, diff: {}",
+        session.reused_tokens(),
+        ids2.len(),
+        max_diff(&logits, &expect)?
+    );
+    assert!(max_diff(&logits, &expect)? < 1e-4);
+    let next = logits
+        .flatten_all()?
+        .argmax(D::Minus1)?
+        .to_scalar::<u32>()?;
+    let mut tokens = ids2.clone();
+    decode(&mut model, &mut tokens, &[next])?;
+    session.update(&model, tokens)?;
+    model.clear_kv_cache();
+
+    // 第三轮: 修改第一张图片之后的文本, 从分叉处重新prefill, 第二张图片使用缓存的视觉特征
+    let mut ids3 = ids2.clone();
+    ids3[9] = 30;
+    let expect = full_logits(&mut model, &ids3, &pixel_values, &grid2)?;
+    let (start, image_features, _) = session.prepare(&mut model, &ids3, &images, &[])?;
+    assert_eq!(start, 9);
+    assert_eq!(image_features.as_ref().unwrap().0.dim(0)?, 4);
+    let input_ids = Tensor::from_slice(&ids3, (1, ids3.len()), &device)?;
+    let logits =
+        model.forward_delta(&input_ids, start, Some(&grid2), None, image_features, None)?;
+    println!("turn 3 diff: {}", max_diff(&logits, &expect)?);
+    assert!(max_diff(&logits, &expect)? < 1e-4);
+    session.update(&model, ids3.clone())?;
+    model.clear_kv_cache();
+
+    // 第四轮: 文本相同但第二张图片内容变化, 从该图片的占位token处重新prefill
+    let pixel_values = Tensor::cat(&[&image1, &image3], 0)?;
+    let expect = full_logits(&mut model, &ids3, &pixel_values, &grid2)?;
+    let images = media_items(&model, &[(1, &image1), (3, &image3)])?;
+    let (start, image_features, _) = session.prepare(&mut model, &ids3, &images, &[])?;
+    assert_eq!(start, 17);
+    assert_eq!(image_features.as_ref().unwrap().0.dim(0)?, 4);
+    let logits =
+        model.forward_delta(&input_ids, start, Some(&grid2), None, image_features, None)?;
+    println!("turn 4 diff: {}", max_diff(&logits, &expect)?);
+    assert!(max_diff(&logits, &expect)? < 1e-4);
+
+    // 输入与kv cache完全相同时至少重新输入最后一个token
+    session.update(&model, ids3.clone())?;
+    let (start, _, _) = session.prepare(&mut model, &ids3, &images, &[])?;
+    assert_eq!(start, ids3.len() - 1);
+    Ok(())
+}
+
+fn chat_message() -> Result<ChatCompletionParameters> {
+    Ok(serde_json::from_value(serde_json::json!({
+        "model": "tiny",
+        "max_tokens": 4,
+        "messages": [{
+            "role": "user",
+            "content": [
+                {"type": "image_url", "image_url": {"url": "file://./assets/img/ocr_test1.png"}},
+                {"type": "text", "text": "describe the image"}
+            ]
+        }]
+    }))?)
+}
+
+#[test]
+fn chat_session_reuse_media() -> Result<()> {
+    // cargo test chat_session_reuse_media -- --nocapture
+    let dir = std::env::temp_dir().join(format!("aha_chat_session_{}", uuid::Uuid::new_v4()));
+    let path = dir.to_string_lossy().to_string();
+    TinyModel::Qwen3VL.write(&path)?;
+    let mut model = Qwen3VLGenerateModel::init(&path, Some(&Device::Cpu), None)?;
+    std::fs::remove_dir_all(&dir)?;
+    let mut session = ChatSession::new();
+
+    // 第一轮预处理图片
+    let mut mes = chat_message()?;
+    let res = model.chat(&mut session, mes.clone())?;
+    println!("turn 1: processed {}", session.processed_media());
+    assert_eq!(session.processed_media(), 1);
+    assert_eq!(session.reused_tokens(), 0);
+
+    // 第二轮历史中的图片不再预处理, 也不重新计算视觉特征
+    mes.messages.push(res.choices[0].message.clone());
+    mes.messages.push(serde_json::from_value(serde_json::json!({
+        "role": "user",
+        "content": "more details"
+    }))?);
+    model.chat(&mut session, mes)?;
+    println!(
+        "turn 2: processed {}, reused {} / {}",
+        session.processed_media(),
+        session.reused_tokens(),
+        session.tokens().len()
+    );
+    assert_eq!(session.processed_media(), 0);
+    assert_eq!(session.cached_vision_features(), 1);
+    assert!(session.reused_tokens() > 0);
+
+    // 与模型共用缓存时, 单轮generate计算过的图片在新会话中直接命中
+    let cache = VisionEmbeddingCache::shared(VisionCacheConfig::default());
+    model.set_vision_cache(Some(cache.clone()));
+    model.generate(chat_message()?)?;
+    let mut session = ChatSession::with_vision_cache(cache.clone());
+    model.chat(&mut session, chat_message()?)?;
+    let stats = cache.lock().unwrap().stats();
+    println!(
+        "shared cache: processed {}, {:?}",
+        session.processed_media(),
+        stats
+    );
+    assert_eq!(session.processed_media(), 0);
+    assert_eq!(stats.entries, 1);
+    assert_eq!(stats.hits, 1);
+    Ok(())
+}
@@ -0,0 +1,38 @@
+use aha::models::minicpm4::{config::MiniCPM4Config, model::MiniCPMModel};
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+
+// 多个测试共用的随机权重MiniCPM4小模型
+pub fn tiny_minicpm(device: &Device, vocab_size: usize) -> Result<MiniCPMModel> {
+    let cfg: MiniCPM4Config = serde_json::from_value(serde_json::json!({
+        "bos_token_id": 1,
+        "eos_token_id": [2, 3],
+        "hidden_act": "silu",
+        "hidden_size": 32,
+        "intermediate_size": 64,
+        "max_position_embeddings": 256,
+        "num_attention_heads": 4,
+        "num_hidden_layers": 2,
+        "num_key_value_heads": 2,
+        "rms_norm_eps": 1e-5,
+        "rope_scaling": {
+            "rope_type": "longrope",
+            "long_factor": [1.0, 1.0, 1.0, 1.0],
+            "short_factor": [1.0, 1.0, 1.0, 1.0],
+            "original_max_position_embeddings": 256
+        },
+        "torch_dtype": "float32",
+        "vocab_size": vocab_size,
+        "scale_emb": 12,
+        "dim_model_base": 8,
+        "scale_depth": 1.4
+    }))?;
+    let varmap = VarMap::new();
+    let model = MiniCPMModel::new(VarBuilder::from_varmap(&varmap, DType::F32, device), cfg)?;
+    // 默认初始化下输出区分度太小, 贪心解码总是重复同一个token, 重新随机化所有权重
+    for var in varmap.all_vars() {
+        var.set(&Tensor::randn(0f32, 0.5, var.shape(), device)?)?;
+    }
+    Ok(model)
+}
@@ -0,0 +1,288 @@
+use aha::{
+    api::embeddings_response,
+    models::{
+        EmbeddingModel,
+        common::embedding::{
+            EMBEDDING_BATCH_SIZE, EmbeddingOutput, EmbeddingParameters, EmbeddingResponse, Pooling,
+            cosine_similarity, create_embeddings, pad_batch, pool_batch, pool_hidden_states,
+        },
+        common::fixture::TinyModel,
+        minicpm4::{generate::MiniCPMGenerateModel, model::MiniCPMModel},
+        qwen3vl::generate::Qwen3VLGenerateModel,
+    },
+};
+use anyhow::Result;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use candle_core::{Device, Tensor};
+use rocket::http::Status;
+
+mod common;
+use common::tiny_minicpm;
+
+// 用字节作为token id, 不需要tokenizer
+struct ByteEmbedder {
+    model: MiniCPMModel,
+    // 每次forward的batch大小
+    batches: Vec<usize>,
+}
+
+impl ByteEmbedder {
+    fn hidden_states(&mut self, text: &str) -> Result<Tensor> {
+        let ids: Vec<u32> = text.bytes().map(|b| b as u32).collect();
+        let input_ids = Tensor::from_slice(&ids, (1, ids.len()), &Device::Cpu)?;
+        Ok(self.model.forward_hidden(&input_ids, 0)?.squeeze(0)?)
+    }
+}
+
+impl EmbeddingModel for ByteEmbedder {
+    fn hidden_size(&self) -> usize {
+        self.model.config().hidden_size
+    }
+
+    fn embed_text(&mut self, text: &str, pooling: Pooling) -> Result<(Vec<f32>, usize)> {
+        let hidden_states = self.hidden_states(text)?;
+        self.batches.push(1);
+        Ok((pool_hidden_states(&hidden_states, pooling)?, text.len()))
+    }
+
+    fn embed_texts(&mut self, texts: &[&str], pooling: Pooling) -> Result<Vec<(Vec<f32>, usize)>> {
+        let ids: Vec<Vec<u32>> = texts
+            .iter()
+            .map(|text| text.bytes().map(|b| b as u32).collect())
+            .collect();
+        let input_ids = pad_batch(&ids, &Device::Cpu)?;
+        let hidden_states = self.model.forward_hidden(&input_ids, 0)?;
+        self.batches.push(texts.len());
+        let lens: Vec<usize> = ids.iter().map(|i| i.len()).collect();
+        pool_batch(&hidden_states, &lens, pooling)
+    }
+}
+
+fn params(json: serde_json::Value) -> Result<EmbeddingParameters> {
+    Ok(serde_json::from_value(json)?)
+}
+
+fn float_embedding(output: &EmbeddingOutput) -> Vec<f32> {
+    match output {
+        EmbeddingOutput::Float(v) => v.clone(),
+        EmbeddingOutput::Base64(s) => STANDARD
+            .decode(s)
+            .unwrap()
+            .chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+    }
+}
+
+#[test]
+fn embedding_pooling_and_options() -> Result<()> {
+    // cargo test embedding_pooling_and_options -- --nocapture
+    let device = Device::Cpu;
+    let mut embedder = ByteEmbedder {
+        model: tiny_minicpm(&device, 256)?,
+        batches: vec![],
+    };
+
+    // 因果注意力: 前缀的hidden states与完整输入的对应位置一致
+    let full = embedder.hidden_states("hello world")?;
+    let prefix = embedder.hidden_states("hello")?;
+    let diff = (full.narrow(0, 0, 5)? - &prefix)?
+        .abs()?
+        .max_all()?
+        .to_scalar::<f32>()?;
+    println!("prefix hidden diff: {}", diff);
+    assert!(diff < 1e-4);
+    assert_eq!(full.dims(), &[11, 32]);
+
+    // 三种池化方式
+    let mean = pool_hidden_states(&full, Pooling::Mean)?;
+    let last = pool_hidden_states(&full, Pooling::LastToken)?;
+    let cls = pool_hidden_states(&full, Pooling::Cls)?;
+    assert_eq!(mean, full.mean(0)?.to_vec1::<f32>()?);
+    assert_eq!(last, full.get(10)?.to_vec1::<f32>()?);
+    assert_eq!(cls, full.get(0)?.to_vec1::<f32>()?);
+
+    // 默认last_token池化并归一化
+    let response = create_embeddings(
+        &mut embedder,
+        params(serde_json::json!({
+            "model": "tiny",
+            "input": ["hello world", "hello"]
+        }))?,
+    )?;
+    assert_eq!(response.object, "list");
+    assert_eq!(response.data.len(), 2);
+    assert_eq!(response.usage.prompt_tokens, 16);
+    let e0 = float_embedding(&response.data[0].embedding);
+    let norm = e0.iter().map(|v| v * v).sum::<f32>().sqrt();
+    assert!((norm - 1.0).abs() < 1e-5);
+    assert!((cosine_similarity(&e0, &last) - 1.0).abs() < 1e-5);
+
+    // dimensions截断后归一化, base64与float结果一致
+    let response = create_embeddings(
+        &mut embedder,
+        params(serde_json::json!({
+            "model": "tiny",
+            "input": "hello world",
+            "pooling": "mean",
+            "dimensions": 8,
+            "encoding_format": "base64"
+        }))?,
+    )?;
+    let e = float_embedding(&response.data[0].embedding);
+    assert_eq!(e.len(), 8);
+    let mut expect = mean[..8].to_vec();
+    let norm = expect.iter().map(|v| v * v).sum::<f32>().sqrt();
+    expect.iter_mut().for_each(|v| *v /= norm);
+    for (a, b) in e.iter().zip(expect.iter()) {
+        assert!((a - b).abs() < 1e-6);
+    }
+
+    // 不归一化时返回原始池化结果
+    let response = create_embeddings(
+        &mut embedder,
+        params(serde_json::json!({
+            "model": "tiny",
+            "input": "hello world",
+            "pooling": "cls",
+            "normalize": false
+        }))?,
+    )?;
+    assert_eq!(float_embedding(&response.data[0].embedding), cls);
+
+    // 错误输入
+    assert!(
+        create_embeddings(
+            &mut embedder,
+            params(serde_json::json!({"model": "tiny", "input": []}))?
+        )
+        .is_err()
+    );
+    assert!(
+        create_embeddings(
+            &mut embedder,
+            params(serde_json::json!({"model": "tiny", "input": "hi", "dimensions": 64}))?
+        )
+        .is_err()
+    );
+    assert!(
+        create_embeddings(
+            &mut embedder,
+            params(serde_json::json!({
+                "model": "tiny",
+                "input": [{"type": "image_url", "image_url": {"url": "file:///tmp/a.png"}}]
+            }))?
+        )
+        .is_err()
+    );
+    Ok(())
+}
+
+#[test]
+fn embedding_batch() -> Result<()> {
+    // cargo test embedding_batch -- --nocapture
+    let device = Device::Cpu;
+    let mut embedder = ByteEmbedder {
+        model: tiny_minicpm(&device, 256)?,
+        batches: vec![],
+    };
+    let texts: Vec<String> = (0..EMBEDDING_BATCH_SIZE + 3)
+        .map(|i| "a cat ".repeat(i % 4 + 1))
+        .collect();
+    let response = create_embeddings(
+        &mut embedder,
+        params(serde_json::json!({"model": "tiny", "input": texts, "pooling": "mean"}))?,
+    )?;
+    println!("batches: {:?}", embedder.batches);
+    assert_eq!(embedder.batches, vec![EMBEDDING_BATCH_SIZE, 3]);
+    // 补齐的token不影响结果, 与逐条计算一致
+    for (text, data) in texts.iter().zip(response.data.iter()) {
+        let (mut expect, _) = embedder.embed_text(text, Pooling::Mean)?;
+        let norm = expect.iter().map(|v| v * v).sum::<f32>().sqrt();
+        expect.iter_mut().for_each(|v| *v /= norm);
+        let e = float_embedding(&data.embedding);
+        let diff = e
+            .iter()
+            .zip(expect.iter())
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0f32, f32::max);
+        assert!(diff < 1e-5, "diff {} for {:?}", diff, text);
+    }
+    assert_eq!(
+        response.usage.prompt_tokens,
+        texts.iter().map(|t| t.len()).sum::<usize>()
+    );
+    Ok(())
+}
+
+// 批量输入与逐条输入的结果一致
+fn check_batch(model: &mut dyn EmbeddingModel) -> Result<()> {
+    let texts = ["hi", "a longer sentence to embed", "你好"];
+    let batch = model.embed_texts(&texts, Pooling::Mean)?;
+    for (text, (embedding, num_tokens)) in texts.iter().zip(batch.iter()) {
+        let (expect, expect_tokens) = model.embed_text(text, Pooling::Mean)?;
+        let diff = embedding
+            .iter()
+            .zip(expect.iter())
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0f32, f32::max);
+        println!("{:?}: tokens {}, diff {}", text, num_tokens, diff);
+        assert_eq!(*num_tokens, expect_tokens);
+        assert!(diff < 1e-4);
+    }
+    Ok(())
+}
+
+#[test]
+fn embedding_batch_tiny_models() -> Result<()> {
+    // cargo test embedding_batch_tiny_models -- --nocapture
+    let dir = std::env::temp_dir().join(format!("aha_embedding_{}", uuid::Uuid::new_v4()));
+    let minicpm_path = dir.join("minicpm4").to_string_lossy().to_string();
+    let qwen3vl_path = dir.join("qwen3vl").to_string_lossy().to_string();
+    TinyModel::MiniCPM4.write(&minicpm_path)?;
+    TinyModel::Qwen3VL.write(&qwen3vl_path)?;
+    let mut minicpm = MiniCPMGenerateModel::init(&minicpm_path, Some(&Device::Cpu), None)?;
+    let mut qwen3vl = Qwen3VLGenerateModel::init(&qwen3vl_path, Some(&Device::Cpu), None)?;
+    std::fs::remove_dir_all(&dir)?;
+    check_batch(&mut minicpm)?;
+    check_batch(&mut qwen3vl)
+}
+
+#[test]
+fn embedding_json_handler() -> Result<()> {
+    // cargo test embedding_json_handler -- --nocapture
+    let device = Device::Cpu;
+    let mut embedder = ByteEmbedder {
+        model: tiny_minicpm(&device, 256)?,
+        batches: vec![],
+    };
+    let body = r#"{"model": "tiny", "input": ["a cat", "a dog", "a cat"]}"#;
+    let (status, json) = embeddings_response(&mut embedder, body);
+    println!("{}", &json[..json.len().min(200)]);
+    assert_eq!(status, Status::Ok);
+    let response: EmbeddingResponse = serde_json::from_str(&json)?;
+    assert_eq!(response.model, "tiny");
+    let embeddings: Vec<Vec<f32>> = response
+        .data
+        .iter()
+        .map(|d| float_embedding(&d.embedding))
+        .collect();
+    assert!((cosine_similarity(&embeddings[0], &embeddings[2]) - 1.0).abs() < 1e-5);
+    for (i, d) in response.data.iter().enumerate() {
+        assert_eq!(d.index, i);
+        assert_eq!(d.object, "embedding");
+    }
+    // 请求错误为400, 推理出错为500
+    let (status, _) = embeddings_response(&mut embedder, r#"{"input": "no model"}"#);
+    assert_eq!(status, Status::BadRequest);
+    let (status, _) = embeddings_response(
+        &mut embedder,
+        r#"{"model": "tiny", "input": "hi", "dimensions": 64}"#,
+    );
+    assert_eq!(status, Status::BadRequest);
+    let body = r#"{"model": "tiny", "input": [{"type": "image_url", "image_url": {"url": "file:///tmp/a.png"}}]}"#;
+    let (status, json) = embeddings_response(&mut embedder, body);
+    println!("{}", json);
+    assert_eq!(status, Status::InternalServerError);
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use aha::{
+    chat_template::get_template,
+    models::common::{
+        gguf::{GgufFile, gguf_name_to_hf},
+        quant::QuantizedLinear,
+    },
+    tokenizer::TokenizerModel,
+};
+use anyhow::Result;
+use candle_core::{
+    DType, Device, Tensor,
+    quantized::{GgmlDType, QTensor, gguf_file},
+};
+use candle_nn::Module;
+
+#[test]
+fn gguf_load() -> Result<()> {
+    // cargo test gguf_load -- --nocapture
+    assert_eq!(
+        gguf_name_to_hf("blk.3.attn_q.weight", "model.", "lm_head"),
+        Some("model.layers.3.self_attn.q_proj.weight".to_string())
+    );
+    assert_eq!(
+        gguf_name_to_hf(
+            "blk.0.ffn_down.weight",
+            "model.language_model.",
+            "model.lm_head"
+        ),
+        Some("model.language_model.layers.0.mlp.down_proj.weight".to_string())
+    );
+    assert_eq!(
+        gguf_name_to_hf("output.weight", "model.language_model.", "model.lm_head"),
+        Some("model.lm_head.weight".to_string())
+    );
+    assert_eq!(
+        gguf_name_to_hf("rope_freqs.weight", "model.", "lm_head"),
+        None
+    );
+
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir().join(format!("aha_gguf_test_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    let (num_heads, head_dim, hidden) = (2, 4, 8);
+    let q_proj = Tensor::arange(0f32, (num_heads * head_dim * hidden) as f32, &device)?
+        .reshape((num_heads * head_dim, hidden))?;
+    // llama.cpp转换时的重排: (n_head, 2, head_dim/2, in) -> (n_head, head_dim/2, 2, in)
+    let q_gguf = q_proj
+        .reshape((num_heads, 2, head_dim / 2, hidden))?
+        .transpose(1, 2)?
+        .reshape((num_heads * head_dim, hidden))?;
+    let embed = Tensor::randn(0f32, 1.0, (4, hidden), &device)?;
+    let q_tensor = QTensor::quantize(&q_gguf, GgmlDType::F32)?;
+    let embed_tensor = QTensor::quantize(&embed, GgmlDType::F32)?;
+    // 量化的k_proj, 输入维度需要是块大小(32)的倍数
+    let k_in = 32;
+    let k_proj = Tensor::randn(0f32, 1.0, (num_heads * head_dim, k_in), &device)?;
+    let k_gguf = k_proj
+        .reshape((num_heads, 2, head_dim / 2, k_in))?
+        .transpose(1, 2)?
+        .reshape((num_heads * head_dim, k_in))?;
+    let k_tensor = QTensor::quantize(&k_gguf, GgmlDType::Q8_0)?;
+    let k_expect = k_tensor
+        .dequantize(&device)?
+        .reshape((num_heads, head_dim / 2, 2, k_in))?
+        .transpose(1, 2)?
+        .reshape((num_heads * head_dim, k_in))?;
+
+    let str_array = |items: &[&str]| {
+        gguf_file::Value::Array(
+            items
+                .iter()
+                .map(|s| gguf_file::Value::String(s.to_string()))
+                .collect(),
+        )
+    };
+    let arch = gguf_file::Value::String("llama".to_string());
+    let head_count = gguf_file::Value::U32(num_heads as u32);
+    let model = gguf_file::Value::String("gpt2".to_string());
+    let tokens = str_array(&["a", "b", "ab", "<|im_end|>"]);
+    let token_type = gguf_file::Value::Array(
+        [1, 1, 1, 3]
+            .into_iter()
+            .map(gguf_file::Value::I32)
+            .collect(),
+    );
+    let merges = str_array(&["a b"]);
+    let template = gguf_file::Value::String(
+        "{% for message in messages %}{{ message.content }}<|im_end|>{% endfor %}".to_string(),
+    );
+    let gguf_path = dir.join("model-f32.gguf");
+    let mut file = std::fs::File::create(&gguf_path)?;
+    gguf_file::write(
+        &mut file,
+        &[
+            ("general.architecture", &arch),
+            ("llama.attention.head_count", &head_count),
+            ("tokenizer.ggml.model", &model),
+            ("tokenizer.ggml.tokens", &tokens),
+            ("tokenizer.ggml.token_type", &token_type),
+            ("tokenizer.ggml.merges", &merges),
+            ("tokenizer.chat_template", &template),
+        ],
+        &[
+            ("token_embd.weight", &embed_tensor),
+            ("blk.0.attn_q.weight", &q_tensor),
+            ("blk.0.attn_k.weight", &k_tensor),
+        ],
+    )?;
+    drop(file);
+
+    let model_path = dir.to_string_lossy().to_string();
+    let gguf = GgufFile::find(&model_path)?.expect("gguf not found");
+    assert_eq!(gguf.architecture()?, "llama");
+    assert_eq!(gguf.arch_usize("attention.head_count"), Some(num_heads));
+    let vb = gguf.var_builder("model.", "lm_head", &model_path, DType::F32, &device)?;
+    assert!(vb.contains_tensor("model.layers.0.self_attn.q_proj.weight"));
+    assert!(!vb.contains_tensor("model.layers.0.self_attn.v_proj.weight"));
+    let q_load = vb.get(
+        (num_heads * head_dim, hidden),
+        "model.layers.0.self_attn.q_proj.weight",
+    )?;
+    let q_diff = (q_load - &q_proj)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    let embed_load = vb.get((4, hidden), "model.embed_tokens.weight")?;
+    let embed_diff = (embed_load - &embed)?
+        .abs()?
+        .max_all()?
+        .to_scalar::<f32>()?;
+    println!("q_proj diff: {}, embed diff: {}", q_diff, embed_diff);
+    assert_eq!(q_diff, 0.0);
+    assert_eq!(embed_diff, 0.0);
+    assert!(vb.get((3, hidden), "model.embed_tokens.weight").is_err());
+
+    // Linear的量化权重保持ggml格式读取, 只有Linear在其中
+    let linears = GgufFile::find(&model_path)?
+        .expect("gguf not found")
+        .linears("model.", "lm_head")?;
+    assert!(!linears.contains("model.embed_tokens.weight"));
+    let k_name = "model.layers.0.self_attn.k_proj.weight";
+    let k_qtensor = linears.qtensor(k_name, &device)?.expect("k_proj not found");
+    assert_eq!(k_qtensor.dtype(), GgmlDType::Q8_0);
+    let k_diff = (k_qtensor.dequantize(&device)? - &k_expect)?
+        .abs()?
+        .max_all()?
+        .to_scalar::<f32>()?;
+    println!("k_proj diff: {}", k_diff);
+    assert_eq!(k_diff, 0.0);
+    let xs = Tensor::randn(0f32, 1.0, (1, 3, k_in), &device)?;
+    let k_linear = QuantizedLinear::from_qtensor(k_qtensor, None)?;
+    let expect = xs.broadcast_matmul(&k_expect.t()?)?;
+    // cpu上输入也会量化为8bit
+    let rel_err = ((k_linear.forward(&xs)? - &expect)?
+        .sqr()?
+        .sum_all()?
+        .sqrt()?
+        / expect.sqr()?.sum_all()?.sqrt()?)?
+    .to_scalar::<f32>()?;
+    println!("k_proj relative output err: {}", rel_err);
+    assert!(rel_err < 0.02);
+
+    let tokenizer = TokenizerModel::init(&model_path)?;
+    let ids = tokenizer.text_encode("abab<|im_end|>".to_string(), &device)?;
+    println!("ids: {}", ids);
+    assert_eq!(ids.squeeze(0)?.to_vec1::<u32>()?, vec![2, 2, 3]);
+    assert_eq!(tokenizer.token_decode(vec![0, 2, 3])?, "aab");
+
+    let chat_template = get_template(model_path)?;
+    assert!(chat_template.contains("<|im_end|>"));
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,426 @@
+use aha::models::{
+    common::{fixture::TinyModel, search::SearchLM, speculative::SpeculativeLM},
+    minicpm4::{config::SparseConfig, model::MiniCPMModel},
+    qwen2_5vl::{
+        config::Qwen2_5VLConfig,
+        model::{Qwen2_5VLModel, Qwen2_5VLTextModel},
+    },
+    qwen3vl::{
+        config::{Qwen3VLConfig, Qwen3VLTextConfig},
+        model::{Qwen3VLModel, Qwen3VLTextModel},
+    },
+    voxcpm::{config::VoxMiniCPM4Config, minicpm4::MiniCPMModel as VoxMiniCPMModel},
+};
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+
+mod common;
+use common::tiny_minicpm;
+
+// 随机初始化所有权重, 默认初始化下输出区分度太小
+fn randomize(varmap: &VarMap, device: &Device) -> Result<()> {
+    for var in varmap.all_vars() {
+        var.set(&Tensor::randn(0f32, 0.5, var.shape(), device)?)?;
+    }
+    Ok(())
+}
+
+fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    Ok((a - b)?.abs()?.max_all()?.to_scalar::<f32>()?)
+}
+
+fn greedy(model: &mut MiniCPMModel, token: u32, position: usize, n: usize) -> Result<Vec<u32>> {
+    let mut token = token;
+    let mut output = vec![];
+    for i in 0..n {
+        let logits = model.forward_last(&[token], position + i)?;
+        token = logits
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(i, _)| i as u32)
+            .unwrap();
+        output.push(token);
+    }
+    Ok(output)
+}
+
+#[test]
+fn kv_cache_minicpm4() -> Result<()> {
+    // cargo test kv_cache_minicpm4 -- --nocapture
+    let device = Device::Cpu;
+    let mut model = tiny_minicpm(&device, 64)?;
+    let prompt = [5u32, 9, 17, 33, 4, 21];
+    model.forward_last(&prompt[..prompt.len() - 1], 0)?;
+    let snapshot = model.snapshot();
+    assert_eq!(snapshot.seq_len(), prompt.len() - 1);
+
+    let expect = greedy(&mut model, prompt[prompt.len() - 1], prompt.len() - 1, 12)?;
+    println!("generate: {:?}", expect);
+    assert_eq!(model.kv_cache_len(), prompt.len() - 1 + 12);
+
+    // 恢复快照后继续生成结果一致
+    model.restore(&snapshot)?;
+    assert_eq!(model.kv_cache_len(), prompt.len() - 1);
+    let output = greedy(&mut model, prompt[prompt.len() - 1], prompt.len() - 1, 12)?;
+    assert_eq!(output, expect);
+
+    // 从中间分叉: 保存第4个token处的状态, 走另一条路径后回到该状态
+    model.truncate_kv_cache(prompt.len() + 3)?;
+    let fork = model.snapshot();
+    greedy(&mut model, 0, prompt.len() + 3, 5)?;
+    model.restore(&fork)?;
+    let output = greedy(&mut model, expect[3], prompt.len() + 3, 8)?;
+    assert_eq!(output, expect[4..].to_vec());
+
+    // 快照的层数与模型不一致时报错
+    let mut bad = snapshot.clone();
+    bad.layers.pop();
+    assert!(model.restore(&bad).is_err());
+    model.clear_kv_cache();
+    assert_eq!(model.kv_cache_len(), 0);
+    Ok(())
+}
+
+#[test]
+fn kv_cache_minicpm4_sparse() -> Result<()> {
+    // cargo test kv_cache_minicpm4_sparse -- --nocapture
+    let device = Device::Cpu;
+    let mut model = tiny_minicpm(&device, 64)?;
+    let sparse = SparseConfig {
+        kernel_size: 8,
+        kernel_stride: 4,
+        block_size: 16,
+        window_size: 32,
+        topk: 3,
+        dense_len: 16,
+        prefill_chunk_size: 1024,
+        ..Default::default()
+    };
+    let prompt: Vec<u32> = (0..100u32).map(|i| (i * 7 + 3) % 64).collect();
+    let input = Tensor::new(prompt.as_slice(), &device)?.unsqueeze(0)?;
+
+    // 一次prefill与按块prefill(kv写入预留空间)的logits一致
+    model.set_sparse_config(Some(sparse.clone()));
+    let expect = model.forward_all_with_cache(&input, 0)?;
+    model.clear_kv_cache();
+    model.set_sparse_config(Some(SparseConfig {
+        prefill_chunk_size: 32,
+        ..sparse
+    }));
+    let logits = model.forward_all_with_cache(&input, 0)?;
+    let diff = max_diff(&logits, &expect)?;
+    println!("chunked prefill diff: {}", diff);
+    assert!(diff < 1e-4);
+    assert_eq!(model.kv_cache_len(), prompt.len());
+
+    // 解码时压缩key增量更新, 恢复快照或回退后重新生成结果一致
+    let snapshot = model.snapshot();
+    let expect = greedy(&mut model, 5, prompt.len(), 20)?;
+    println!("generate: {:?}", expect);
+    model.restore(&snapshot)?;
+    let output = greedy(&mut model, 5, prompt.len(), 20)?;
+    assert_eq!(output, expect);
+    model.truncate_kv_cache(prompt.len() + 6)?;
+    greedy(&mut model, 0, prompt.len() + 6, 10)?;
+    model.truncate_kv_cache(prompt.len() + 6)?;
+    let output = greedy(&mut model, expect[5], prompt.len() + 6, 14)?;
+    assert_eq!(output, expect[6..].to_vec());
+    Ok(())
+}
+
+#[test]
+fn kv_cache_voxcpm_lm() -> Result<()> {
+    // cargo test kv_cache_voxcpm_lm -- --nocapture
+    let device = Device::Cpu;
+    let cfg: VoxMiniCPM4Config = serde_json::from_value(serde_json::json!({
+        "bos_token_id": 1,
+        "eos_token_id": 2,
+        "hidden_size": 32,
+        "intermediate_size": 64,
+        "max_position_embeddings": 256,
+        "num_attention_heads": 4,
+        "num_hidden_layers": 2,
+        "num_key_value_heads": 2,
+        "rms_norm_eps": 1e-5,
+        "rope_theta": 10000.0,
+        "rope_scaling": {
+            "type": "longrope",
+            "long_factor": [1.0, 1.0, 1.0, 1.0],
+            "short_factor": [1.0, 1.0, 1.0, 1.0],
+            "original_max_position_embeddings": 256
+        },
+        "vocab_size": 0,
+        "scale_emb": 12,
+        "dim_model_base": 8,
+        "scale_depth": 1.4,
+        "use_mup": true
+    }))?;
+    let varmap = VarMap::new();
+    let mut lm = VoxMiniCPMModel::new(VarBuilder::from_varmap(&varmap, DType::F32, &device), cfg)?;
+    randomize(&varmap, &device)?;
+    let prefix = Tensor::randn(0f32, 1.0, (1, 7, 32), &device)?;
+    let steps: Vec<Tensor> = (0..4)
+        .map(|_| Tensor::randn(0f32, 1.0, (1, 32), &device))
+        .collect::<candle_core::Result<_>>()?;
+    lm.forward_with_cache(&prefix, 0)?;
+    let snapshot = lm.snapshot();
+    let mut expect = vec![];
+    for (i, x) in steps.iter().enumerate() {
+        expect.push(lm.forward_with_cache(x, 7 + i)?);
+    }
+    lm.restore(&snapshot)?;
+    for (i, x) in steps.iter().enumerate() {
+        let out = lm.forward_with_cache(x, 7 + i)?;
+        assert_eq!(max_diff(&out, &expect[i])?, 0.0);
+    }
+    // 回退两步后重新输入
+    lm.truncate_kv_cache(9)?;
+    assert_eq!(lm.kv_cache_len(), 9);
+    for (i, x) in steps.iter().enumerate().skip(2) {
+        let out = lm.forward_with_cache(x, 7 + i)?;
+        assert_eq!(max_diff(&out, &expect[i])?, 0.0);
+    }
+    Ok(())
+}
+
+#[test]
+fn kv_cache_qwen_text() -> Result<()> {
+    // cargo test kv_cache_qwen_text -- --nocapture
+    let device = Device::Cpu;
+    let cfg: Qwen2_5VLConfig = serde_json::from_value(serde_json::json!({
+        "attention_dropout": 0.0,
+        "bos_token_id": 1,
+        "eos_token_id": 2,
+        "vision_start_token_id": 3,
+        "vision_end_token_id": 4,
+        "vision_token_id": 5,
+        "image_token_id": 6,
+        "video_token_id": 7,
+        "hidden_act": "silu",
+        "hidden_size": 32,
+        "initializer_range": 0.02,
+        "intermediate_size": 64,
+        "max_position_embeddings": 256,
+        "max_window_layers": 2,
+        "num_attention_heads": 4,
+        "num_hidden_layers": 2,
+        "num_key_value_heads": 2,
+        "rms_norm_eps": 1e-6,
+        "rope_theta": 10000.0,
+        "sliding_window": 256,
+        "tie_word_embeddings": true,
+        "torch_dtype": "float32",
+        "use_sliding_window": false,
+        "vision_config": {
+            "depth": 1,
+            "hidden_act": "silu",
+            "hidden_size": 16,
+            "intermediate_size": 32,
+            "num_heads": 2,
+            "in_chans": 3,
+            "out_hidden_size": 32,
+            "patch_size": 14,
+            "spatial_merge_size": 2,
+            "spatial_patch_size": 14,
+            "window_size": 112,
+            "fullatt_block_indexes": [0],
+            "tokens_per_second": 2,
+            "temporal_patch_size": 2
+        },
+        "rope_scaling": {"type": "mrope", "mrope_section": [1, 1, 2]},
+        "vocab_size": 64
+    }))?;
+    let varmap = VarMap::new();
+    let mut qwen2_5 =
+        Qwen2_5VLTextModel::new(&cfg, VarBuilder::from_varmap(&varmap, DType::F32, &device))?;
+    randomize(&varmap, &device)?;
+
+    let cfg: Qwen3VLTextConfig = serde_json::from_value(serde_json::json!({
+        "attention_bias": false,
+        "attention_dropout": 0.0,
+        "bos_token_id": 1,
+        "dtype": "float32",
+        "eos_token_id": 2,
+        "head_dim": 8,
+        "hidden_act": "silu",
+        "hidden_size": 32,
+        "initializer_range": 0.02,
+        "intermediate_size": 64,
+        "max_position_embeddings": 256,
+        "num_attention_heads": 4,
+        "num_hidden_layers": 2,
+        "num_key_value_heads": 2,
+        "rms_norm_eps": 1e-6,
+        "rope_scaling": {"rope_type": "default", "mrope_section": [2, 1, 1], "mrope_interleaved": true},
+        "rope_theta": 10000.0,
+        "tie_word_embeddings": true,
+        "use_cache": true,
+        "vocab_size": 64
+    }))?;
+    let varmap = VarMap::new();
+    let mut qwen3 =
+        Qwen3VLTextModel::new(cfg, VarBuilder::from_varmap(&varmap, DType::F32, &device))?;
+    randomize(&varmap, &device)?;
+
+    let prefix = Tensor::randn(0f32, 1.0, (1, 7, 32), &device)?;
+    let steps: Vec<Tensor> = (0..4)
+        .map(|_| Tensor::randn(0f32, 1.0, (1, 1, 32), &device))
+        .collect::<candle_core::Result<_>>()?;
+
+    qwen2_5.forward(&prefix, 0, None)?;
+    let snapshot = qwen2_5.kv_caches();
+    let mut expect = vec![];
+    for (i, x) in steps.iter().enumerate() {
+        expect.push(qwen2_5.forward(x, 7 + i, None)?);
+    }
+    qwen2_5.set_kv_caches(&snapshot)?;
+    assert_eq!(qwen2_5.kv_cache_len(), 7);
+    for (i, x) in steps.iter().enumerate() {
+        assert_eq!(
+            max_diff(&qwen2_5.forward(x, 7 + i, None)?, &expect[i])?,
+            0.0
+        );
+    }
+    qwen2_5.truncate_kv_cache(8)?;
+    for (i, x) in steps.iter().enumerate().skip(1) {
+        assert_eq!(
+            max_diff(&qwen2_5.forward(x, 7 + i, None)?, &expect[i])?,
+            0.0
+        );
+    }
+
+    qwen3.forward(&prefix, 0, None, None, None)?;
+    let snapshot = qwen3.kv_caches();
+    let mut expect = vec![];
+    for (i, x) in steps.iter().enumerate() {
+        expect.push(qwen3.forward(x, 7 + i, None, None, None)?);
+    }
+    qwen3.set_kv_caches(&snapshot)?;
+    for (i, x) in steps.iter().enumerate() {
+        let out = qwen3.forward(x, 7 + i, None, None, None)?;
+        assert_eq!(max_diff(&out, &expect[i])?, 0.0);
+    }
+    qwen3.truncate_kv_cache(9)?;
+    assert_eq!(qwen3.kv_cache_len(), 9);
+    for (i, x) in steps.iter().enumerate().skip(2) {
+        let out = qwen3.forward(x, 7 + i, None, None, None)?;
+        assert_eq!(max_diff(&out, &expect[i])?, 0.0);
+    }
+    Ok(())
+}
+
+// 随机权重小模型的config和权重, 写入临时目录后读入内存
+fn tiny_fixture(model: TinyModel) -> Result<(serde_json::Value, VarBuilder<'static>)> {
+    let dir = std::env::temp_dir().join(format!("aha_kv_cache_{}", uuid::Uuid::new_v4()));
+    model.write(&dir.to_string_lossy())?;
+    let config = serde_json::from_str(&std::fs::read_to_string(dir.join("config.json"))?)?;
+    let weights = candle_core::safetensors::load(dir.join("model.safetensors"), &Device::Cpu)?;
+    std::fs::remove_dir_all(&dir)?;
+    Ok((
+        config,
+        VarBuilder::from_tensors(weights, DType::F32, &Device::Cpu),
+    ))
+}
+
+fn wave(shape: &[usize]) -> Result<Tensor> {
+    let n = shape.iter().product::<usize>();
+    let data: Vec<f32> = (0..n).map(|i| (i as f32 * 0.37).sin()).collect();
+    Ok(Tensor::from_vec(data, shape, &Device::Cpu)?)
+}
+
+fn decode_all(model: &mut impl SearchLM, tokens: &[u32], position: usize) -> Result<Vec<Vec<f32>>> {
+    tokens
+        .iter()
+        .enumerate()
+        .map(|(i, &token)| model.decode(token, position + i))
+        .collect()
+}
+
+fn max_abs(t: &Tensor) -> Result<f32> {
+    Ok(t.to_dtype(DType::F32)?
+        .abs()?
+        .max_all()?
+        .to_scalar::<f32>()?)
+}
+
+#[test]
+fn kv_cache_qwen_restore_rope_deltas() -> Result<()> {
+    // cargo test kv_cache_qwen_restore_rope_deltas -- --nocapture
+    let device = Device::Cpu;
+    // 4x4的patch合并后为4个image token, mrope位置只占2个, rope_deltas不为0
+    let ids = [257u32, 259, 262, 262, 262, 262, 260, 104, 105];
+    let input_ids = Tensor::new(&ids, &device)?.unsqueeze(0)?;
+    let cache_position = Tensor::arange(0u32, ids.len() as u32, &device)?;
+    let grid = Tensor::new(&[[1u32, 4, 4]], &device)?;
+    let text_ids = Tensor::new(&[257u32, 104, 105, 106], &device)?.unsqueeze(0)?;
+    let text_position = Tensor::arange(0u32, 4, &device)?;
+    let tokens = [104u32, 105, 106];
+
+    let (config, vb) = tiny_fixture(TinyModel::Qwen2_5VL)?;
+    let cfg: Qwen2_5VLConfig = serde_json::from_value(config)?;
+    let mut qwen2_5 = Qwen2_5VLModel::new(cfg, vb)?;
+    let pixel_values = wave(&[16, 3 * 2 * 14 * 14])?;
+    qwen2_5.forward(
+        &input_ids,
+        Some(&pixel_values),
+        Some(&grid),
+        None,
+        None,
+        &Tensor::ones_like(&input_ids)?,
+        Some(&cache_position),
+        0,
+        None,
+    )?;
+    let snapshot = qwen2_5.snapshot();
+    let deltas = snapshot
+        .rope_deltas
+        .as_ref()
+        .expect("rope_deltas after prefill");
+    println!("qwen2.5vl rope_deltas: {}", deltas);
+    assert!(max_abs(deltas)? > 0.0);
+    let expect = decode_all(&mut qwen2_5, &tokens, ids.len())?;
+    // 纯文本的prefill把rope_deltas重置为0, 恢复快照后按快照中的rope_deltas计算位置
+    qwen2_5.clear_kv_cache();
+    qwen2_5.forward(
+        &text_ids,
+        None,
+        None,
+        None,
+        None,
+        &Tensor::ones_like(&text_ids)?,
+        Some(&text_position),
+        0,
+        None,
+    )?;
+    qwen2_5.restore(&snapshot)?;
+    assert_eq!(decode_all(&mut qwen2_5, &tokens, ids.len())?, expect);
+
+    let (config, vb) = tiny_fixture(TinyModel::Qwen3VL)?;
+    let cfg: Qwen3VLConfig = serde_json::from_value(config)?;
+    let mut qwen3 = Qwen3VLModel::new(cfg, vb.pp("model"))?;
+    let pixel_values = wave(&[16, 3 * 2 * 16 * 16])?;
+    qwen3.forward(
+        &input_ids,
+        Some(&pixel_values),
+        Some(&grid),
+        None,
+        None,
+        Some(&cache_position),
+        0,
+    )?;
+    let snapshot = qwen3.snapshot();
+    let deltas = snapshot
+        .rope_deltas
+        .as_ref()
+        .expect("rope_deltas after prefill");
+    println!("qwen3vl rope_deltas: {}", deltas);
+    assert!(max_abs(deltas)? > 0.0);
+    let expect = decode_all(&mut qwen3, &tokens, ids.len())?;
+    qwen3.clear_kv_cache();
+    qwen3.forward(&text_ids, None, None, None, None, Some(&text_position), 0)?;
+    qwen3.restore(&snapshot)?;
+    assert_eq!(decode_all(&mut qwen3, &tokens, ids.len())?, expect);
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+use aha::models::minicpm4::generate::MiniCPMGenerateModel;
+use anyhow::Result;
+use candle_core::{Device, Tensor};
+
+#[test]
+fn manifest_check_minicpm4() -> Result<()> {
+    // cargo test manifest_check_minicpm4 -- --nocapture
+    let device = Device::Cpu;
+    let dir = std::env::temp_dir().join("aha_manifest_test");
+    std::fs::create_dir_all(&dir)?;
+    let model_path = dir.to_string_lossy().to_string();
+    let config = serde_json::json!({
+        "bos_token_id": 1,
+        "eos_token_id": [2, 3],
+        "hidden_act": "silu",
+        "hidden_size": 16,
+        "intermediate_size": 32,
+        "max_position_embeddings": 64,
+        "num_attention_heads": 2,
+        "num_hidden_layers": 1,
+        "num_key_value_heads": 1,
+        "rms_norm_eps": 1e-5,
+        "rope_scaling": {
+            "rope_type": "longrope",
+            "long_factor": [1.0, 1.0, 1.0, 1.0],
+            "short_factor": [1.0, 1.0, 1.0, 1.0],
+            "original_max_position_embeddings": 64
+        },
+        "torch_dtype": "float32",
+        "vocab_size": 32,
+        "scale_emb": 12,
+        "dim_model_base": 8,
+        "scale_depth": 1.4
+    });
+    std::fs::write(dir.join("config.json"), serde_json::to_string(&config)?)?;
+
+    // 只有一个无关权重时, 所有权重都缺失
+    let mut weights = HashMap::new();
+    weights.insert(
+        "unused.weight".to_string(),
+        Tensor::zeros(4, candle_core::DType::F32, &device)?,
+    );
+    candle_core::safetensors::save(&weights, dir.join("model.safetensors"))?;
+    let report = MiniCPMGenerateModel::check_model(&model_path)?;
+    println!("{}", report);
+    assert!(!report.is_ok());
+    assert!(report.mismatched.is_empty());
+    assert_eq!(report.unexpected, vec!["unused.weight".to_string()]);
+    assert!(report.missing.iter().any(|(name, shape)| name
+        == "model.layers.0.self_attn.k_proj.weight"
+        && shape == &vec![8, 16]));
+
+    // 按报告中的shape生成权重后检查通过
+    let mut weights = HashMap::new();
+    for (name, shape) in &report.missing {
+        weights.insert(
+            name.clone(),
+            Tensor::randn(0f32, 0.02, shape.clone(), &device)?,
+        );
+    }
+    candle_core::safetensors::save(&weights, dir.join("model.safetensors"))?;
+    let report = MiniCPMGenerateModel::check_model(&model_path)?;
+    println!("{}", report);
+    assert!(report.is_ok());
+    assert!(report.unexpected.is_empty());
+
+    // 删掉一个权重, 改错一个shape
+    weights.remove("model.norm.weight");
+    weights.insert(
+        "model.layers.0.mlp.up_proj.weight".to_string(),
+        Tensor::zeros((16, 16), candle_core::DType::F32, &device)?,
+    );
+    candle_core::safetensors::save(&weights, dir.join("model.safetensors"))?;
+    let report = MiniCPMGenerateModel::check_model(&model_path)?;
+    println!("{}", report);
+    assert_eq!(report.missing, vec![(
+        "model.norm.weight".to_string(),
+        vec![16]
+    )]);
+    assert_eq!(report.mismatched.len(), 1);
+    assert_eq!(
+        report.mismatched[0].name,
+        "model.layers.0.mlp.up_proj.weight"
+    );
+    assert_eq!(report.mismatched[0].expected, vec![32, 16]);
+    assert_eq!(report.mismatched[0].found, vec![16, 16]);
+    assert!(report.ensure_ok().is_err());
+
+    // 配置错误在检查权重之前报告
+    let mut config = config;
+    config["num_key_value_heads"] = serde_json::json!(3);
+    config["rope_scaling"]["long_factor"] = serde_json::json!([1.0, 1.0]);
+    std::fs::write(dir.join("config.json"), serde_json::to_string(&config)?)?;
+    let report = MiniCPMGenerateModel::check_model(&model_path)?;
+    println!("{}", report);
+    assert_eq!(report.config_errors.len(), 2);
+    assert!(report.missing.is_empty());
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
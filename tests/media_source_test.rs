@@ -0,0 +1,198 @@
+use std::io::Cursor;
+
+use aha::models::qwen2_5vl::processor::Qwen2_5VLProcessor;
+use aha::models::qwen3vl::processor::Qwen3VLProcessor;
+use aha::utils::img_utils::get_image;
+use aha::utils::media_utils::{MediaSource, normalize_video_frames};
+use aha::utils::vision_utils::RequestVisionOverrides;
+use aha_openai_dive::v1::resources::chat::ChatCompletionParameters;
+use anyhow::Result;
+use base64::{Engine, engine::general_purpose};
+use candle_core::{DType, Device, Tensor};
+
+fn png_bytes(width: u32, height: u32, value: u8) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+        image::Rgb([x as u8, y as u8, value])
+    }))
+    .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)?;
+    Ok(bytes)
+}
+
+fn grids(grid_thw: &Tensor) -> Result<Vec<Vec<u32>>> {
+    Ok(grid_thw.to_vec2::<u32>()?)
+}
+
+#[test]
+fn media_source_parse() -> Result<()> {
+    // cargo test media_source_parse -- --nocapture
+    let dir = std::env::temp_dir().join("aha_media_source_test");
+    std::fs::create_dir_all(&dir)?;
+    let png = png_bytes(30, 20, 7)?;
+    let image_path = dir.join("image.png");
+    std::fs::write(&image_path, &png)?;
+    let path = image_path.to_string_lossy().to_string();
+
+    assert_eq!(
+        MediaSource::parse(&format!("file://{}", path))?,
+        MediaSource::Path(image_path.clone())
+    );
+    assert_eq!(
+        MediaSource::parse(&path)?,
+        MediaSource::Path(image_path.clone())
+    );
+    assert_eq!(
+        MediaSource::parse("https://example.com/a.mp4")?,
+        MediaSource::Url("https://example.com/a.mp4".to_string())
+    );
+    let data_url = format!(
+        "data:image/png;base64,{}",
+        general_purpose::STANDARD.encode(&png)
+    );
+    assert_eq!(
+        MediaSource::parse(&data_url)?,
+        MediaSource::Bytes(png.clone())
+    );
+    assert!(MediaSource::parse("data:image/png,abc").is_err());
+
+    // 没有前缀的字符串都是本地路径
+    assert_eq!(
+        MediaSource::parse("[a.png]")?,
+        MediaSource::Path("[a.png]".into())
+    );
+
+    // 帧列表, 每一帧可以是任意来源, 原始请求中为数组
+    let expect = MediaSource::Frames(vec![
+        MediaSource::Path(image_path.clone()),
+        MediaSource::Bytes(png.clone()),
+    ]);
+    let frames = serde_json::json!([format!("file://{}", path), data_url.clone()]);
+    assert_eq!(MediaSource::from_value(&frames)?, expect);
+    assert!(MediaSource::from_value(&serde_json::json!([])).is_err());
+    assert!(MediaSource::from_value(&serde_json::json!([["a.png"]])).is_err());
+    assert!(MediaSource::from_value(&serde_json::json!({"url": "a.png"})).is_err());
+    // 写入url字符串后还原为相同的来源
+    let url = expect.to_url();
+    println!("frames url: {}...", &url[..60]);
+    assert_eq!(MediaSource::parse(&url)?, expect);
+    for source in [
+        MediaSource::Path(image_path.clone()),
+        MediaSource::Url("https://example.com/a.mp4".to_string()),
+        MediaSource::Bytes(png.clone()),
+    ] {
+        assert_eq!(MediaSource::parse(&source.to_url())?, source);
+    }
+
+    // get_image支持本地路径, file://和base64
+    for source in [path.clone(), format!("file://{}", path), data_url] {
+        let img = get_image(&source)?;
+        assert_eq!((img.width(), img.height()), (30, 20));
+    }
+    assert!(get_image(&dir.join("missing.png").to_string_lossy()).is_err());
+
+    // 内存数据写到临时文件, drop时删除; 本地文件不删除
+    let file = MediaSource::Bytes(png.clone()).to_file()?;
+    let temp_path = file.path().to_path_buf();
+    assert_eq!(std::fs::read(&temp_path)?, png);
+    drop(file);
+    assert!(!temp_path.exists());
+    drop(MediaSource::Path(image_path.clone()).to_file()?);
+    assert!(image_path.exists());
+
+    // 原始请求中的帧列表数组转为字符串
+    let mut request = serde_json::json!({
+        "model": "qwen3vl",
+        "messages": [{"role": "user", "content": [
+            {"type": "video_url", "video_url": {"url": [path.clone(), path.clone()]}},
+            {"type": "text", "text": "hi"}
+        ]}]
+    });
+    normalize_video_frames(&mut request)?;
+    let url = request["messages"][0]["content"][0]["video_url"]["url"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    println!("normalized url: {}", url);
+    match MediaSource::parse(&url)? {
+        MediaSource::Frames(frames) => assert_eq!(frames.len(), 2),
+        other => panic!("expect frames, got {:?}", other),
+    }
+    serde_json::from_value::<ChatCompletionParameters>(request)?;
+    Ok(())
+}
+
+#[test]
+fn media_source_video_frames() -> Result<()> {
+    // cargo test media_source_video_frames -- --nocapture
+    let dir = std::env::temp_dir().join("aha_media_frames_test");
+    std::fs::create_dir_all(&dir)?;
+    let mut frames = vec![];
+    for i in 0..3 {
+        let frame_path = dir.join(format!("frame{}.png", i));
+        std::fs::write(&frame_path, png_bytes(64, 64, i * 80)?)?;
+        frames.push(format!("file://{}", frame_path.to_string_lossy()));
+    }
+    let mut request = serde_json::json!({
+        "model": "qwen3vl",
+        "messages": [{"role": "user", "content": [
+            {"type": "video_url", "video_url": {"url": frames}},
+            {"type": "text", "text": "describe"}
+        ]}]
+    });
+    normalize_video_frames(&mut request)?;
+    let mes: ChatCompletionParameters = serde_json::from_value(request)?;
+    let device = Device::Cpu;
+    let text = "<|vision_start|><|video_pad|><|vision_end|>describe";
+
+    // qwen3vl: 3帧补齐到4帧, 总像素数小于默认min_pixels 65536, 64x64放大到128x128
+    let preprocessor = serde_json::json!({
+        "size": {"longest_edge": 16777216, "shortest_edge": 65536},
+        "patch_size": 16,
+        "temporal_patch_size": 2,
+        "merge_size": 2,
+        "image_mean": [0.5, 0.5, 0.5],
+        "image_std": [0.5, 0.5, 0.5]
+    });
+    std::fs::write(
+        dir.join("preprocessor_config.json"),
+        serde_json::to_string(&preprocessor)?,
+    )?;
+    let mut video_preprocessor = preprocessor.clone();
+    video_preprocessor["fps"] = serde_json::json!(1.0);
+    std::fs::write(
+        dir.join("video_preprocessor_config.json"),
+        serde_json::to_string(&video_preprocessor)?,
+    )?;
+    let processor = Qwen3VLProcessor::new(&dir.to_string_lossy(), &device, DType::F32)?;
+    let input = processor.process_info(&mes, text, &RequestVisionOverrides::default())?;
+    let grid = grids(input.video_grid_thw.as_ref().unwrap())?;
+    println!("qwen3vl frames grid: {:?}", grid);
+    assert_eq!(grid, vec![vec![2, 8, 8]]);
+    assert_eq!(input.pixel_values_video.unwrap().dim(0)?, 2 * 8 * 8);
+    // 帧号[0, 1, 2, 2], fps为1, 每两帧取中间时间
+    assert!(input.replace_text.contains("<0.5 seconds>"));
+    assert!(input.replace_text.contains("<2.0 seconds>"));
+    assert_eq!(input.replace_text.matches("<|video_pad|>").count(), 2 * 16);
+
+    // qwen2.5vl: 视频使用video_min_pixels 128*28*28, 64x64放大到336x336
+    let preprocessor = serde_json::json!({
+        "min_pixels": 3136,
+        "max_pixels": 28 * 28 * 64,
+        "patch_size": 14,
+        "temporal_patch_size": 2,
+        "merge_size": 2,
+        "image_mean": [0.5, 0.5, 0.5],
+        "image_std": [0.5, 0.5, 0.5]
+    });
+    std::fs::write(
+        dir.join("preprocessor_config.json"),
+        serde_json::to_string(&preprocessor)?,
+    )?;
+    let processor = Qwen2_5VLProcessor::new(&dir.to_string_lossy(), &device, DType::F32)?;
+    let input = processor.process_info(&mes, text, &RequestVisionOverrides::default())?;
+    let grid = grids(input.video_grid_thw.as_ref().unwrap())?;
+    println!("qwen2.5vl frames grid: {:?}", grid);
+    assert_eq!(grid, vec![vec![2, 24, 24]]);
+    assert_eq!(input.second_per_grid_ts.as_ref().unwrap().len(), 1);
+    Ok(())
+}
@@ -0,0 +1,344 @@
+use std::{path::PathBuf, sync::LazyLock, time::Instant};
+
+use aha::models::{
+    ParityModel,
+    common::{
+        fixture::TinyModel,
+        parity::{GoldenData, ParityReport, ParityTolerance},
+    },
+    minicpm4::generate::MiniCPMGenerateModel,
+    qwen3vl::generate::Qwen3VLGenerateModel,
+    voxcpm::generate::VoxCPMGenerate,
+};
+use anyhow::{Result, anyhow};
+use candle_core::{DType, Device, Tensor};
+
+// snapshots下是回归快照: 由当前Rust实现自己生成, 不是参考实现的结果, 只能发现rope/mask/deepstack等改动引入的数值变化
+// 有意修改数值后重新生成: AHA_UPDATE_GOLDEN=1 cargo test parity_tiny -- --nocapture
+const SNAPSHOT_DIR: &str = "./assets/parity/snapshots";
+// reference下是transformers对同一份小模型权重导出的golden, 由assets/parity/dump_reference.py生成
+const REFERENCE_DIR: &str = "./assets/parity/reference";
+
+// 随机权重的小模型写到本进程独有的临时目录, 同时运行的测试进程互不影响
+static TINY_MODEL_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
+    std::env::temp_dir().join(format!("aha_tiny_models_{}", uuid::Uuid::new_v4()))
+});
+
+fn tiny_model_path(model: TinyModel, name: &str) -> Result<String> {
+    let path = TINY_MODEL_DIR.join(name).to_string_lossy().to_string();
+    model.write(&path)?;
+    Ok(path)
+}
+
+// 固定的输入, 取值在[-1, 1]
+fn wave(shape: &[usize], phase: f32) -> Result<Tensor> {
+    let n = shape.iter().product::<usize>();
+    let data: Vec<f32> = (0..n).map(|i| (i as f32 * 0.37 + phase).sin()).collect();
+    Ok(Tensor::from_vec(data, shape, &Device::Cpu)?)
+}
+
+fn check_golden(model: &mut impl ParityModel, inputs: GoldenData, name: &str) -> Result<()> {
+    let path = format!("{}/{}.safetensors", SNAPSHOT_DIR, name);
+    if std::env::var("AHA_UPDATE_GOLDEN").is_ok() {
+        let outputs = model.parity_outputs(&inputs)?;
+        let mut golden = inputs;
+        // 只有最后位置的输出不保存, 检查时与logits的最后位置对比
+        for (name, t) in &outputs {
+            if !name.contains(".last") {
+                golden.insert(name, t)?;
+            }
+        }
+        golden.save(&path)?;
+        println!("write golden: {}", path);
+    }
+    let golden = GoldenData::load(&path, &Device::Cpu)?;
+    let report = model.parity(&golden, ParityTolerance::default())?;
+    println!("{}", report);
+    report.ensure_ok()
+}
+
+#[test]
+fn parity_report() -> Result<()> {
+    // cargo test parity_report -- --nocapture
+    let mut golden = GoldenData::default();
+    golden.insert("input_ids", &Tensor::new(&[[1u32, 2, 3]], &Device::Cpu)?)?;
+    let mut outputs = vec![];
+    for i in 0..4 {
+        let hidden = wave(&[1, 3, 8], i as f32)?;
+        golden.insert(&format!("hidden_states.{}", i), &hidden)?;
+        outputs.push((format!("hidden_states.{}", i), hidden));
+    }
+    let logits = wave(&[1, 3, 16], 10.0)?;
+    golden.insert("logits", &logits)?;
+    outputs.push(("logits".to_string(), logits.clone()));
+    // 只有最后位置的输出与logits的最后位置对比, 多出的batch维不影响
+    outputs.push(("logits.last".to_string(), logits.narrow(1, 2, 1)?));
+    outputs.push((
+        "logits.last_squeezed".to_string(),
+        logits.narrow(1, 2, 1)?.squeeze(0)?,
+    ));
+    // golden中没有的输出跳过
+    outputs.push(("image_embeds".to_string(), wave(&[4, 8], 0.0)?));
+
+    let report = ParityReport::compare_outputs(&outputs, &golden, ParityTolerance::default())?;
+    println!("{}", report);
+    assert!(report.is_ok());
+    assert_eq!(report.entries.len(), 7);
+    assert!(report.entries.iter().all(|e| e.max_abs == 0.0));
+
+    // 第2层开始出现偏差, 之后的层都超出误差
+    let mut shifted = outputs.clone();
+    for (name, t) in shifted.iter_mut() {
+        if name == "hidden_states.2" || name == "hidden_states.3" || name.starts_with("logits") {
+            *t = (t.clone() + 0.01)?;
+        }
+    }
+    let report = ParityReport::compare_outputs(&shifted, &golden, ParityTolerance::default())?;
+    println!("{}", report);
+    assert!(!report.is_ok());
+    assert_eq!(report.first_failure().unwrap().name, "hidden_states.2");
+    assert!((report.entries[2].max_abs - 0.01).abs() < 1e-4);
+    assert!(report.entries[1].ok);
+    assert!(report.ensure_ok().is_err());
+    // rtol按参考值的最大绝对值放宽
+    let report = ParityReport::compare_outputs(&shifted, &golden, ParityTolerance::new(0.0, 0.02))?;
+    assert!(report.is_ok(), "{}", report);
+
+    // 层数和shape不一致
+    let mut missing_layer = outputs[..3].to_vec();
+    missing_layer.push(("logits".to_string(), wave(&[1, 3, 15], 10.0)?));
+    let report =
+        ParityReport::compare_outputs(&missing_layer, &golden, ParityTolerance::default())?;
+    println!("{}", report);
+    assert_eq!(report.first_failure().unwrap().name, "hidden_states");
+    assert!(report.entries.iter().any(|e| e.name == "logits" && !e.ok));
+    // nan不通过
+    let nan = (wave(&[1, 3, 8], 0.0)? * f64::NAN)?;
+    let report = ParityReport::compare_outputs(
+        &[("hidden_states.0".to_string(), nan)],
+        &golden,
+        ParityTolerance::default(),
+    )?;
+    assert!(!report.entries[0].ok);
+    // golden中一项都没有时不算通过
+    let report = ParityReport::compare_outputs(
+        &[("lm_hidden".to_string(), wave(&[1, 8], 0.0)?)],
+        &golden,
+        ParityTolerance::default(),
+    )?;
+    assert!(!report.is_ok());
+    Ok(())
+}
+
+#[test]
+fn parity_golden_file() -> Result<()> {
+    // cargo test parity_golden_file -- --nocapture
+    // numpy导出的npz, input_ids为int64
+    let dir = TINY_MODEL_DIR.join("golden_file");
+    std::fs::create_dir_all(&dir)?;
+    let npz = dir.join("golden.npz").to_string_lossy().to_string();
+    let input_ids = Tensor::new(&[[5i64, 6, 7, 8]], &Device::Cpu)?;
+    let logits = wave(&[1, 4, 16], 1.0)?.to_dtype(DType::F64)?;
+    Tensor::write_npz(&[("input_ids", &input_ids), ("logits", &logits)], &npz)?;
+    let golden = GoldenData::load(&npz, &Device::Cpu)?;
+    assert_eq!(golden.len(), 2);
+    let ids = golden.input_ids("input_ids", &Device::Cpu)?;
+    assert_eq!(ids.dtype(), DType::U32);
+    assert_eq!(ids.to_vec2::<u32>()?, vec![vec![5, 6, 7, 8]]);
+    assert!(
+        golden
+            .input("pixel_values", DType::F32, &Device::Cpu)?
+            .is_none()
+    );
+    assert!(
+        golden
+            .require("pixel_values", DType::F32, &Device::Cpu)
+            .is_err()
+    );
+    let last = golden.expected("logits.last_with_cache")?.unwrap();
+    assert_eq!(last.dims(), &[1, 1, 16]);
+
+    // 没有batch维的input_ids补上batch维, safetensors读写
+    let mut golden = GoldenData::default();
+    golden.insert("input_ids", &Tensor::new(&[5u32, 6, 7], &Device::Cpu)?)?;
+    golden.insert("hidden_states.0", &wave(&[1, 3, 8], 0.0)?)?;
+    golden.insert("hidden_states.1", &wave(&[1, 3, 8], 1.0)?)?;
+    let st = dir.join("golden.safetensors").to_string_lossy().to_string();
+    golden.save(&st)?;
+    let golden = GoldenData::load(&st, &Device::Cpu)?;
+    assert_eq!(golden.input_ids("input_ids", &Device::Cpu)?.dims(), &[1, 3]);
+    assert_eq!(golden.count("hidden_states"), 2);
+    assert!(GoldenData::load("golden.json", &Device::Cpu).is_err());
+    Ok(())
+}
+
+#[test]
+fn parity_tiny_minicpm4() -> Result<()> {
+    // cargo test parity_tiny_minicpm4 -- --nocapture
+    let path = tiny_model_path(TinyModel::MiniCPM4, "parity_minicpm4")?;
+    let mut model = MiniCPMGenerateModel::init(&path, Some(&Device::Cpu), Some(DType::F32))?;
+    let mut inputs = GoldenData::default();
+    let ids: Vec<u32> = vec![257, 117, 115, 101, 114, 10, 104, 105, 258, 10, 257, 97];
+    inputs.insert("input_ids", &Tensor::new(ids, &Device::Cpu)?.unsqueeze(0)?)?;
+    check_golden(&mut model, inputs, "tiny_minicpm4")
+}
+
+#[test]
+fn parity_tiny_qwen3vl() -> Result<()> {
+    // cargo test parity_tiny_qwen3vl -- --nocapture
+    let path = tiny_model_path(TinyModel::Qwen3VL, "parity_qwen3vl")?;
+    let mut model = Qwen3VLGenerateModel::init(&path, Some(&Device::Cpu), Some(DType::F32))?;
+    // grid (1, 2, 2)合并后为1个image token, 第0层后加deepstack
+    let ids: Vec<u32> = vec![
+        257, 117, 115, 101, 114, 10, 259, 262, 260, 104, 105, 258, 10,
+    ];
+    let mut inputs = GoldenData::default();
+    inputs.insert("input_ids", &Tensor::new(ids, &Device::Cpu)?.unsqueeze(0)?)?;
+    inputs.insert("pixel_values", &wave(&[4, 3 * 2 * 16 * 16], 0.5)?)?;
+    inputs.insert(
+        "image_grid_thw",
+        &Tensor::new(&[[1u32, 2, 2]], &Device::Cpu)?,
+    )?;
+    check_golden(&mut model, inputs, "tiny_qwen3vl")
+}
+
+#[test]
+fn parity_tiny_voxcpm() -> Result<()> {
+    // cargo test parity_tiny_voxcpm -- --nocapture
+    let path = tiny_model_path(TinyModel::VoxCPM, "parity_voxcpm")?;
+    let mut model = VoxCPMGenerate::init(&path, Some(&Device::Cpu), Some(DType::F32))?;
+    // 5个文本token加audio_start, 之后3个位置为参考音频特征
+    let text_token = Tensor::new(&[30u32, 31, 32, 33, 34, 101, 0, 0, 0], &Device::Cpu)?;
+    let text_mask = Tensor::new(&[1f32, 1., 1., 1., 1., 1., 0., 0., 0.], &Device::Cpu)?;
+    let audio_mask = (1.0 - &text_mask)?;
+    let audio_feat = wave(&[9, 2, 8], 0.3)?.broadcast_mul(&audio_mask.reshape((9, 1, 1))?)?;
+    let mut inputs = GoldenData::default();
+    inputs.insert("text_token", &text_token)?;
+    inputs.insert("text_mask", &text_mask)?;
+    inputs.insert("audio_feat", &audio_feat)?;
+    inputs.insert("audio_mask", &audio_mask)?;
+    check_golden(&mut model, inputs, "tiny_voxcpm")
+}
+
+// 小模型与参考实现导出的golden对比, golden还没有导出时失败并给出导出命令
+// 导出需要torch/transformers(VoxCPM另需voxcpm包), golden提交到assets/parity/reference后去掉ignore
+fn check_reference(
+    model: &mut impl ParityModel,
+    path: &str,
+    name: &str,
+    dump_args: &str,
+) -> Result<()> {
+    let golden_path = format!("{}/tiny_{}.safetensors", REFERENCE_DIR, name);
+    if !std::path::Path::new(&golden_path).exists() {
+        let command = format!(
+            "python3 assets/parity/dump_reference.py {} {} {} {}",
+            name, path, golden_path, dump_args
+        );
+        return Err(anyhow!(format!(
+            "reference golden {} not found, export it from the tiny model written to {}:\n\
+             pip install torch transformers safetensors pillow\n\
+             {}\n\
+             then commit {}",
+            golden_path,
+            path,
+            command.trim_end(),
+            golden_path
+        )));
+    }
+    let golden = GoldenData::load(&golden_path, &Device::Cpu)?;
+    let report = model.parity(&golden, ParityTolerance::new(1e-4, 1e-3))?;
+    println!("{}", report);
+    report.ensure_ok()
+}
+
+#[test]
+#[ignore = "reference golden not exported yet, see check_reference"]
+fn parity_reference_tiny_minicpm4() -> Result<()> {
+    // cargo test parity_reference_tiny_minicpm4 -- --ignored --nocapture
+    let path = tiny_model_path(TinyModel::MiniCPM4, "reference_minicpm4")?;
+    let mut model = MiniCPMGenerateModel::init(&path, Some(&Device::Cpu), Some(DType::F32))?;
+    check_reference(&mut model, &path, "minicpm4", "")
+}
+
+#[test]
+#[ignore = "reference golden not exported yet, see check_reference"]
+fn parity_reference_tiny_qwen3vl() -> Result<()> {
+    // cargo test parity_reference_tiny_qwen3vl -- --ignored --nocapture
+    let path = tiny_model_path(TinyModel::Qwen3VL, "reference_qwen3vl")?;
+    let mut model = Qwen3VLGenerateModel::init(&path, Some(&Device::Cpu), Some(DType::F32))?;
+    check_reference(
+        &mut model,
+        &path,
+        "qwen3vl",
+        "./assets/preprocess/images/at_max.png",
+    )
+}
+
+#[test]
+#[ignore = "reference golden not exported yet, see check_reference"]
+fn parity_reference_tiny_voxcpm() -> Result<()> {
+    // cargo test parity_reference_tiny_voxcpm -- --ignored --nocapture
+    let path = tiny_model_path(TinyModel::VoxCPM, "reference_voxcpm")?;
+    let mut model = VoxCPMGenerate::init(&path, Some(&Device::Cpu), Some(DType::F32))?;
+    check_reference(&mut model, &path, "voxcpm", "")
+}
+
+// 真实模型的目录: AHA_MODEL_DIR下的<name>
+fn model_path(name: &str) -> Result<String> {
+    let dir = std::env::var("AHA_MODEL_DIR").map_err(|_| {
+        anyhow!(format!(
+            "set AHA_MODEL_DIR to the directory containing {}",
+            name
+        ))
+    })?;
+    Ok(format!("{}/{}/", dir.trim_end_matches('/'), name))
+}
+
+// 真实模型与参考实现对比, golden由assets/parity/dump_reference.py导出, 模型用F32加载
+#[test]
+fn parity_qwen3vl() -> Result<()> {
+    // python3 assets/parity/dump_reference.py qwen3vl $AHA_MODEL_DIR/Qwen/Qwen3-VL-2B-Instruct $AHA_MODEL_DIR/Qwen/Qwen3-VL-2B-Instruct/parity_golden.safetensors ./assets/img/ocr_test1.png
+    // AHA_MODEL_DIR=<dir> cargo test parity_qwen3vl -- --nocapture
+    let model_path = &model_path("Qwen/Qwen3-VL-2B-Instruct")?;
+    let golden = GoldenData::load(
+        &format!("{}parity_golden.safetensors", model_path),
+        &Device::Cpu,
+    )?;
+    let i_start = Instant::now();
+    let mut model = Qwen3VLGenerateModel::init(model_path, None, Some(DType::F32))?;
+    let report = model.parity(&golden, ParityTolerance::new(1e-3, 1e-2))?;
+    println!("{}time used: {:?}", report, i_start.elapsed());
+    report.ensure_ok()
+}
+
+#[test]
+fn parity_minicpm4() -> Result<()> {
+    // python3 assets/parity/dump_reference.py minicpm4 $AHA_MODEL_DIR/OpenBMB/MiniCPM4-0.5B $AHA_MODEL_DIR/OpenBMB/MiniCPM4-0.5B/parity_golden.safetensors
+    // AHA_MODEL_DIR=<dir> cargo test parity_minicpm4 -- --nocapture
+    let model_path = &model_path("OpenBMB/MiniCPM4-0.5B")?;
+    let golden = GoldenData::load(
+        &format!("{}parity_golden.safetensors", model_path),
+        &Device::Cpu,
+    )?;
+    let i_start = Instant::now();
+    let mut model = MiniCPMGenerateModel::init(model_path, None, Some(DType::F32))?;
+    let report = model.parity(&golden, ParityTolerance::new(1e-3, 1e-2))?;
+    println!("{}time used: {:?}", report, i_start.elapsed());
+    report.ensure_ok()
+}
+
+#[test]
+fn parity_voxcpm() -> Result<()> {
+    // python3 assets/parity/dump_reference.py voxcpm $AHA_MODEL_DIR/openbmb/VoxCPM-0.5B $AHA_MODEL_DIR/openbmb/VoxCPM-0.5B/parity_golden.safetensors
+    // AHA_MODEL_DIR=<dir> cargo test parity_voxcpm -- --nocapture
+    let model_path = &model_path("openbmb/VoxCPM-0.5B")?;
+    let golden = GoldenData::load(
+        &format!("{}parity_golden.safetensors", model_path),
+        &Device::Cpu,
+    )?;
+    let i_start = Instant::now();
+    let mut model = VoxCPMGenerate::init(model_path, None, Some(DType::F32))?;
+    let report = model.parity(&golden, ParityTolerance::new(1e-3, 1e-2))?;
+    println!("{}time used: {:?}", report, i_start.elapsed());
+    report.ensure_ok()
+}
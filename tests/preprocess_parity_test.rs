@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use aha::models::qwen2_5vl::processor::Qwen2_5VLProcessor;
+use aha::models::qwen3vl::processor::Qwen3VLProcessor;
+use aha::utils::vision_utils::VisionOverrides;
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+
+// fixture由assets/preprocess/gen_fixtures.py生成, 默认为脚本中移植的Qwen2VLImageProcessor预处理步骤(纯python)
+// 加--hf时直接用transformers + Pillow生成, 生成方式写在safetensors的metadata中
+// 当前提交的fixture是不加--hf生成的, 用--hf重新生成并提交后去掉preprocess_fixtures_from_hf的ignore
+const FIXTURE_DIR: &str = "./assets/preprocess";
+const IMAGES: [&str; 4] = ["odd_aspect", "tiny", "at_max", "over_max"];
+// resize与Pillow的定点bicubic逐像素一致, 剩下的误差来自fixture保存为f16
+// 误差按0-255灰度级计算, 平均误差用来发现排布或归一化错误
+const MAX_PIXEL_DIFF: f32 = 1.0;
+const MEAN_PIXEL_DIFF: f32 = 0.5;
+
+fn load_fixture(model: &str) -> Result<(Tensor, Tensor, f32, HashMap<String, Tensor>)> {
+    let dir = format!("{}/{}", FIXTURE_DIR, model);
+    let config: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(format!("{}/preprocessor_config.json", dir))?)?;
+    let mean: Vec<f32> = serde_json::from_value(config["image_mean"].clone())?;
+    let std: Vec<f32> = serde_json::from_value(config["image_std"].clone())?;
+    // 归一化后的误差换算回灰度级
+    let scale = 255.0 * std.iter().cloned().fold(f32::MAX, f32::min);
+    let mean = Tensor::from_slice(&mean, (3, 1, 1), &Device::Cpu)?;
+    let std = Tensor::from_slice(&std, (3, 1, 1), &Device::Cpu)?;
+    let expected =
+        candle_core::safetensors::load(format!("{}/expected.safetensors", dir), &Device::Cpu)?;
+    Ok((mean, std, scale, expected))
+}
+
+// safetensors头部的__metadata__.generator, "hf"或"port"
+fn fixture_generator(model: &str) -> Result<String> {
+    let bytes = std::fs::read(format!("{}/{}/expected.safetensors", FIXTURE_DIR, model))?;
+    let len = u64::from_le_bytes(bytes[..8].try_into()?) as usize;
+    let header: serde_json::Value = serde_json::from_slice(&bytes[8..8 + len])?;
+    Ok(header["__metadata__"]["generator"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string())
+}
+
+fn load_images() -> Result<Vec<image::DynamicImage>> {
+    let mut imgs = vec![];
+    for name in IMAGES {
+        imgs.push(image::open(format!("{}/images/{}.png", FIXTURE_DIR, name))?);
+    }
+    Ok(imgs)
+}
+
+fn compare(
+    name: &str,
+    data: &Tensor,
+    grid_thw: &Tensor,
+    scale: f32,
+    expected: &HashMap<String, Tensor>,
+) -> Result<()> {
+    let expected_grid = &expected[&format!("{}.grid_thw", name)];
+    let expected_data = expected[&format!("{}.pixel_values", name)].to_dtype(DType::F32)?;
+    assert_eq!(
+        grid_thw.to_vec2::<u32>()?,
+        expected_grid.to_vec2::<u32>()?,
+        "{} grid_thw",
+        name
+    );
+    assert_eq!(
+        data.dims(),
+        expected_data.dims(),
+        "{} pixel_values shape",
+        name
+    );
+    let diff = (data.to_dtype(DType::F32)? - expected_data)?.abs()?;
+    let max_diff = diff.max_all()?.to_scalar::<f32>()? * scale;
+    let mean_diff = diff.mean_all()?.to_scalar::<f32>()? * scale;
+    println!(
+        "{}: grid {:?}, max diff {:.2}, mean diff {:.3}",
+        name,
+        grid_thw.to_vec2::<u32>()?,
+        max_diff,
+        mean_diff
+    );
+    assert!(max_diff < MAX_PIXEL_DIFF, "{} max diff {}", name, max_diff);
+    assert!(
+        mean_diff < MEAN_PIXEL_DIFF,
+        "{} mean diff {}",
+        name,
+        mean_diff
+    );
+    Ok(())
+}
+
+#[test]
+fn preprocess_parity_qwen3vl() -> Result<()> {
+    // cargo test preprocess_parity_qwen3vl -- --nocapture
+    let model_path = format!("{}/qwen3vl", FIXTURE_DIR);
+    let processor = Qwen3VLProcessor::new(&model_path, &Device::Cpu, DType::F32)?;
+    let (mean, std, scale, expected) = load_fixture("qwen3vl")?;
+    for (name, img) in IMAGES.iter().zip(load_images()?) {
+        let input =
+            processor.process_images(vec![(img, VisionOverrides::default())], &mean, &std)?;
+        compare(name, &input.data, &input.grid_thw, scale, &expected)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn preprocess_parity_qwen2_5vl() -> Result<()> {
+    // cargo test preprocess_parity_qwen2_5vl -- --nocapture
+    let model_path = format!("{}/qwen2_5vl", FIXTURE_DIR);
+    let processor = Qwen2_5VLProcessor::new(&model_path, &Device::Cpu, DType::F32)?;
+    let (mean, std, scale, expected) = load_fixture("qwen2_5vl")?;
+    for (name, img) in IMAGES.iter().zip(load_images()?) {
+        let input =
+            processor.process_images(vec![(img, VisionOverrides::default())], &mean, &std)?;
+        compare(name, &input.data, &input.grid_thw, scale, &expected)?;
+    }
+    Ok(())
+}
+
+#[test]
+#[ignore = "fixtures are generated without --hf, regenerate them with transformers + Pillow"]
+fn preprocess_fixtures_from_hf() -> Result<()> {
+    // python3 assets/preprocess/gen_fixtures.py --hf
+    // cargo test preprocess_fixtures_from_hf -- --ignored --nocapture
+    for model in ["qwen3vl", "qwen2_5vl"] {
+        let generator = fixture_generator(model)?;
+        println!("{}: {}", model, generator);
+        assert_eq!(
+            generator, "hf",
+            "{} fixture is not generated with --hf",
+            model
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use aha::models::common::quant::{QuantType, QuantizedLinear, dequantize_weight, quantize_weight};
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{Linear, Module};
+
+#[test]
+fn quant_linear() -> Result<()> {
+    // cargo test quant_linear -- --nocapture
+    let device = Device::Cpu;
+    let (out_dim, in_dim) = (64, 256);
+    let weight = Tensor::randn(0f32, 0.02, (out_dim, in_dim), &device)?;
+    let xs = Tensor::randn(0f32, 1.0, (2, 3, in_dim), &device)?;
+    let expect = Linear::new(weight.clone(), None).forward(&xs)?;
+    // cpu上QMatMul把输入也量化为8bit, 误差比只量化权重时略大
+    for (quant, tol) in [
+        (QuantType::Int8, 0.02f32),
+        (QuantType::Int4 { group_size: 32 }, 0.15),
+        (QuantType::Int4 { group_size: 64 }, 0.15),
+        // group_size不是32的倍数, 加载时反量化
+        (QuantType::Int4 { group_size: 16 }, 0.15),
+    ] {
+        let (qweight, scales) = quantize_weight(&weight, quant)?;
+        assert_eq!(qweight.dtype(), DType::U8);
+        let expect_weight = dequantize_weight(&qweight, &scales, quant, in_dim, out_dim)?;
+        let linear = QuantizedLinear::new(qweight, scales, None, quant, in_dim, out_dim)?;
+        // 转换为ggml块后数值不变, 只有scale转为f16的误差
+        let block_err = (linear.dequantize(DType::F32)? - &expect_weight)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(block_err < 1e-4, "{:?}: block err {}", quant, block_err);
+        let weight_err = (linear.dequantize(DType::F32)? - &weight)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        let output = linear.forward(&xs)?;
+        let rel_err = ((&output - &expect)?.sqr()?.sum_all()?.sqrt()?
+            / expect.sqr()?.sum_all()?.sqrt()?)?
+        .to_scalar::<f32>()?;
+        println!(
+            "{:?}: max weight err {}, relative output err {}",
+            quant, weight_err, rel_err
+        );
+        assert_eq!(output.dims(), expect.dims());
+        assert!(rel_err < tol);
+    }
+    assert!(quantize_weight(&weight, QuantType::Int4 { group_size: 100 }).is_err());
+    Ok(())
+}
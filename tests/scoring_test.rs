@@ -0,0 +1,102 @@
+use aha::models::common::{
+    scoring::{SCORING_CHUNK_SIZE, score_tokens, token_logprobs},
+    search::{SearchLM, log_softmax},
+};
+use anyhow::Result;
+use candle_core::{D, Device, Tensor};
+
+mod common;
+use common::tiny_minicpm;
+
+#[test]
+fn scoring_single_prefill() -> Result<()> {
+    // cargo test scoring_single_prefill -- --nocapture
+    let device = Device::Cpu;
+    let mut model = tiny_minicpm(&device, 64)?;
+    let ids = [1u32, 9, 17, 33, 4, 21, 40, 7, 12];
+    let input_ids = Tensor::from_slice(&ids, (1, ids.len()), &device)?;
+
+    // 一次prefill得到所有位置的logits, 最后位置与forward一致
+    let logits = model.forward_logits(&input_ids, 0)?.squeeze(0)?;
+    assert_eq!(logits.dims(), &[ids.len(), 64]);
+    let last = model.forward(&input_ids, 0)?.flatten_all()?;
+    let diff = (logits.get(ids.len() - 1)? - last)?
+        .abs()?
+        .max_all()?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-4);
+
+    // 与逐个token解码累计的logprob一致
+    let hidden_states = model.forward_hidden(&input_ids, 0)?.squeeze(0)?;
+    let logprobs = token_logprobs(&hidden_states, &ids, |h| model.hidden_logits(h))?;
+    assert_eq!(logprobs.len(), ids.len() - 1);
+    model.clear_kv_cache();
+    let mut step_logits = model
+        .forward_with_cache(&input_ids.narrow(1, 0, 1)?, 0)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    for i in 1..ids.len() {
+        let expect = log_softmax(&step_logits)[ids[i] as usize];
+        println!("token {}: {:.4} / {:.4}", ids[i], logprobs[i - 1], expect);
+        assert!((logprobs[i - 1] - expect).abs() < 1e-4);
+        if i + 1 < ids.len() {
+            step_logits = model.decode(ids[i], i)?;
+        }
+    }
+    model.clear_kv_cache();
+
+    // 困惑度为平均负logprob的指数, 跳过的token不参与计算
+    let output = score_tokens(&ids, &logprobs, &[], |id| Ok(id.to_string()))?;
+    let total: f32 = logprobs.iter().sum();
+    assert_eq!(output.tokens.len(), ids.len());
+    assert_eq!(output.tokens[0].logprob, None);
+    assert_eq!(output.num_scored, ids.len() - 1);
+    assert!((output.total_logprob - total).abs() < 1e-4);
+    assert!((output.perplexity - (-total / 8.0).exp()).abs() < 1e-3);
+    println!("perplexity: {}", output.perplexity);
+
+    let output = score_tokens(&ids, &logprobs, &[4, 7], |id| Ok(id.to_string()))?;
+    assert_eq!(output.num_scored, ids.len() - 3);
+    assert_eq!(output.tokens[4].logprob, None);
+    assert_eq!(output.tokens[5].logprob, Some(logprobs[4]));
+    let total = total - logprobs[3] - logprobs[6];
+    assert!((output.total_logprob - total).abs() < 1e-4);
+
+    // 少于2个token无法打分
+    let one = Tensor::new(&[[1u32]], &device)?;
+    let hidden_states = model.forward_hidden(&one, 0)?.squeeze(0)?;
+    assert!(token_logprobs(&hidden_states, &[1], |h| model.hidden_logits(h)).is_err());
+    Ok(())
+}
+
+#[test]
+fn scoring_chunked() -> Result<()> {
+    // cargo test scoring_chunked -- --nocapture
+    let device = Device::Cpu;
+    let mut model = tiny_minicpm(&device, 64)?;
+    // 跨越多个块, 最后一块不满
+    let ids: Vec<u32> = (0..SCORING_CHUNK_SIZE * 2 + 7)
+        .map(|i| (i * 37 % 64) as u32)
+        .collect();
+    let input_ids = Tensor::from_slice(&ids, (1, ids.len()), &device)?;
+    let hidden_states = model.forward_hidden(&input_ids, 0)?.squeeze(0)?;
+    let logprobs = token_logprobs(&hidden_states, &ids, |h| model.hidden_logits(h))?;
+    assert_eq!(logprobs.len(), ids.len() - 1);
+
+    // 与所有位置的logits一起做log_softmax的结果一致
+    let logits = model.forward_logits(&input_ids, 0)?.squeeze(0)?;
+    let full = candle_nn::ops::log_softmax(&logits.narrow(0, 0, ids.len() - 1)?, D::Minus1)?;
+    let targets = Tensor::from_slice(&ids[1..], (ids.len() - 1, 1), &device)?;
+    let expect = full
+        .gather(&targets, D::Minus1)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    let diff = logprobs
+        .iter()
+        .zip(expect.iter())
+        .map(|(a, b)| (a - b).abs())
+        .fold(0.0f32, f32::max);
+    println!("chunked logprob diff: {}", diff);
+    assert!(diff < 1e-4);
+    Ok(())
+}
@@ -0,0 +1,183 @@
+use aha::{
+    models::{
+        common::search::{BeamSearchConfig, SearchLM, beam_search, best_of_n, log_softmax},
+        minicpm4::model::MiniCPMModel,
+    },
+    utils::get_logit_processor,
+};
+use anyhow::Result;
+use candle_core::{Device, Tensor};
+
+mod common;
+use common::tiny_minicpm;
+
+fn prefill(model: &mut MiniCPMModel, prompt: &[u32]) -> Result<Vec<f32>> {
+    model.clear_kv_cache();
+    let input_ids = Tensor::from_slice(prompt, (1, prompt.len()), &Device::Cpu)?;
+    let logits = model.forward_with_cache(&input_ids, 0)?;
+    Ok(logits.flatten_all()?.to_vec1::<f32>()?)
+}
+
+// 从prefill后的状态重新计算生成序列的累计logprob
+fn sequence_logprob(model: &mut MiniCPMModel, prompt: &[u32], tokens: &[u32]) -> Result<f32> {
+    let mut logits = prefill(model, prompt)?;
+    let mut logprob = 0f32;
+    for (i, &token) in tokens.iter().enumerate() {
+        logprob += log_softmax(&logits)[token as usize];
+        if i + 1 < tokens.len() {
+            logits = model.decode(token, prompt.len() + i)?;
+        }
+    }
+    Ok(logprob)
+}
+
+#[test]
+fn beam_search_exact_on_small_vocab() -> Result<()> {
+    // cargo test beam_search_exact_on_small_vocab -- --nocapture
+    let device = Device::Cpu;
+    let mut model = tiny_minicpm(&device, 8)?;
+    let prompt = [1u32, 5, 6, 7];
+
+    // 穷举所有长度为3的序列
+    let logits = prefill(&mut model, &prompt)?;
+    let root = SearchLM::snapshot(&model);
+    let mut all = vec![];
+    let lp0 = log_softmax(&logits);
+    for t0 in 0..8u32 {
+        SearchLM::restore(&mut model, &root)?;
+        let lp1 = log_softmax(&model.decode(t0, prompt.len())?);
+        let s1 = SearchLM::snapshot(&model);
+        for t1 in 0..8u32 {
+            SearchLM::restore(&mut model, &s1)?;
+            let lp2 = log_softmax(&model.decode(t1, prompt.len() + 1)?);
+            for t2 in 0..8u32 {
+                let logprob = lp0[t0 as usize] + lp1[t1 as usize] + lp2[t2 as usize];
+                all.push((vec![t0, t1, t2], logprob));
+            }
+        }
+    }
+    all.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    // 束宽覆盖所有前缀时束搜索等价于穷举
+    let logits = prefill(&mut model, &prompt)?;
+    let cfg = BeamSearchConfig {
+        num_beams: 64,
+        length_penalty: 0.0,
+        early_stopping: false,
+        max_new_tokens: 3,
+        num_return_sequences: 5,
+    };
+    let hyps = beam_search(&mut model, logits, prompt.len(), &[], &cfg)?;
+    assert_eq!(hyps.len(), 5);
+    for (hyp, (tokens, logprob)) in hyps.iter().zip(all.iter()) {
+        println!(
+            "beam: {:?} {:.4}, exhaustive: {:?} {:.4}",
+            hyp.tokens, hyp.score, tokens, logprob
+        );
+        assert_eq!(&hyp.tokens, tokens);
+        assert!((hyp.logprob - logprob).abs() < 1e-4);
+    }
+    Ok(())
+}
+
+#[test]
+fn beam_search_and_best_of_n() -> Result<()> {
+    // cargo test beam_search_and_best_of_n -- --nocapture
+    let device = Device::Cpu;
+    let mut model = tiny_minicpm(&device, 64)?;
+    let prompt = [1u32, 9, 17, 33, 4, 21];
+    let eos = [2u32, 3];
+
+    // 贪心解码
+    let mut logits = prefill(&mut model, &prompt)?;
+    let mut greedy = vec![];
+    for i in 0..16 {
+        let token = logits
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(i, _)| i as u32)
+            .unwrap();
+        greedy.push(token);
+        if eos.contains(&token) {
+            break;
+        }
+        logits = model.decode(token, prompt.len() + i)?;
+    }
+    println!("greedy: {:?}", greedy);
+
+    // 束宽为1时与贪心解码一致
+    let logits = prefill(&mut model, &prompt)?;
+    let cfg = BeamSearchConfig {
+        num_beams: 1,
+        max_new_tokens: 16,
+        ..Default::default()
+    };
+    let hyps = beam_search(&mut model, logits, prompt.len(), &eos, &cfg)?;
+    assert_eq!(hyps[0].tokens, greedy);
+
+    // 返回的结果按得分排序, logprob与重新计算的一致
+    for early_stopping in [true, false] {
+        let logits = prefill(&mut model, &prompt)?;
+        let cfg = BeamSearchConfig {
+            num_beams: 4,
+            length_penalty: 1.0,
+            early_stopping,
+            max_new_tokens: 16,
+            num_return_sequences: 4,
+        };
+        let hyps = beam_search(&mut model, logits, prompt.len(), &eos, &cfg)?;
+        assert_eq!(hyps.len(), 4);
+        for (i, hyp) in hyps.iter().enumerate() {
+            println!(
+                "early_stopping {}: {:?} logprob {:.4} score {:.4}",
+                early_stopping, hyp.tokens, hyp.logprob, hyp.score
+            );
+            let logprob = sequence_logprob(&mut model, &prompt, &hyp.tokens)?;
+            assert!((hyp.logprob - logprob).abs() < 1e-3);
+            assert!((hyp.score - hyp.logprob / hyp.tokens.len() as f32).abs() < 1e-5);
+            if i > 0 {
+                assert!(hyps[i - 1].score >= hyp.score);
+            }
+            let eos_count = hyp.tokens.iter().filter(|t| eos.contains(t)).count();
+            assert!(eos_count == 0 || (eos_count == 1 && eos.contains(hyp.tokens.last().unwrap())));
+        }
+    }
+
+    // best-of-n: 贪心采样时n个结果都与贪心解码一致
+    let logits = prefill(&mut model, &prompt)?;
+    let mut logit_processor = get_logit_processor(None, None, None);
+    let hyps = best_of_n(
+        &mut model,
+        logits,
+        prompt.len(),
+        &eos,
+        3,
+        16,
+        &mut logit_processor,
+    )?;
+    assert!(hyps.iter().all(|hyp| hyp.tokens == greedy));
+
+    // 随机采样时按累计logprob排序
+    let logits = prefill(&mut model, &prompt)?;
+    let mut logit_processor = get_logit_processor(Some(1.0), None, None);
+    let hyps = best_of_n(
+        &mut model,
+        logits,
+        prompt.len(),
+        &eos,
+        6,
+        16,
+        &mut logit_processor,
+    )?;
+    assert_eq!(hyps.len(), 6);
+    for (i, hyp) in hyps.iter().enumerate() {
+        println!("best_of_n: {:?} logprob {:.4}", hyp.tokens, hyp.logprob);
+        let logprob = sequence_logprob(&mut model, &prompt, &hyp.tokens)?;
+        assert!((hyp.logprob - logprob).abs() < 1e-3);
+        if i > 0 {
+            assert!(hyps[i - 1].logprob >= hyp.logprob);
+        }
+    }
+    Ok(())
+}
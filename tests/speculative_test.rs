@@ -0,0 +1,123 @@
+use aha::models::common::speculative::{SpeculativeLM, SpeculativeSampler};
+use anyhow::Result;
+use candle_core::Device;
+
+mod common;
+use common::tiny_minicpm;
+
+#[test]
+fn speculative_greedy_matches_target() -> Result<()> {
+    // cargo test speculative_greedy_matches_target -- --nocapture
+    let device = Device::Cpu;
+    let mut target = tiny_minicpm(&device, 64)?;
+    let mut draft = tiny_minicpm(&device, 64)?;
+    let prompt = vec![5u32, 9, 17, 33, 4, 21];
+    let num_tokens = 24;
+
+    // 只用目标模型贪心解码
+    let mut expect = vec![];
+    let mut logits = target.forward_last(&prompt, 0)?;
+    for i in 0..num_tokens {
+        let next = logits
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(i, _)| i as u32)
+            .unwrap();
+        expect.push(next);
+        logits = target.forward_last(&[next], prompt.len() + i)?;
+    }
+    println!("target greedy: {:?}", expect);
+    target.clear_kv_cache();
+
+    // 多个token一起输入时每个位置的logits与逐个输入一致
+    let all = target.forward_all(&prompt, 0)?;
+    target.clear_kv_cache();
+    let last = target.forward_last(&prompt[..3], 0)?;
+    let diff = last
+        .iter()
+        .zip(all[2].iter())
+        .map(|(a, b)| (a - b).abs())
+        .fold(0f32, f32::max);
+    println!("forward_all diff: {}", diff);
+    assert!(diff < 1e-4);
+    target.clear_kv_cache();
+
+    for num_draft in [1, 3, 5] {
+        let mut sampler = SpeculativeSampler::new(None, None, num_draft);
+        let mut position = prompt.len();
+        let mut token = sampler.prefill(&mut target, &mut draft, &prompt)?;
+        let mut generate = vec![token];
+        while generate.len() < num_tokens {
+            let tokens = sampler.step(&mut target, &mut draft, token, position)?;
+            position += tokens.len();
+            token = *tokens.last().unwrap();
+            generate.extend(tokens);
+        }
+        generate.truncate(num_tokens);
+        println!(
+            "num_draft {}: accepted {}/{}",
+            num_draft,
+            sampler.accepted(),
+            sampler.drafted()
+        );
+        assert_eq!(generate, expect);
+        target.clear_kv_cache();
+        draft.clear_kv_cache();
+    }
+
+    // 截断kv cache后重新输入, 结果不变
+    target.forward_last(&prompt, 0)?;
+    let logits = target.forward_last(&[7, 8, 9], prompt.len())?;
+    target.truncate_kv_cache(prompt.len())?;
+    let logits2 = target.forward_last(&[7, 8, 9], prompt.len())?;
+    assert_eq!(logits, logits2);
+    assert!(target.truncate_kv_cache(100).is_err());
+    Ok(())
+}
+
+// 与位置无关的固定分布
+struct FixedLM {
+    logits: Vec<f32>,
+}
+
+impl SpeculativeLM for FixedLM {
+    fn forward_last(&mut self, _input_ids: &[u32], _position_id: usize) -> Result<Vec<f32>> {
+        Ok(self.logits.clone())
+    }
+
+    fn forward_all(&mut self, input_ids: &[u32], _position_id: usize) -> Result<Vec<Vec<f32>>> {
+        Ok(vec![self.logits.clone(); input_ids.len()])
+    }
+
+    fn truncate_kv_cache(&mut self, _len: usize) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn speculative_rejection_sampling_distribution() -> Result<()> {
+    // cargo test speculative_rejection_sampling_distribution -- --nocapture
+    let mut target = FixedLM {
+        logits: vec![2.0, 1.0, 0.5, -1.0, 0.0],
+    };
+    let mut draft = FixedLM {
+        logits: vec![-1.0, 0.5, 2.0, 1.0, 0.0],
+    };
+    let mut sampler = SpeculativeSampler::new(Some(1.0), None, 3);
+    let expect = sampler.probs(&target.logits);
+    let rounds = 20000;
+    let mut counts = [0usize; 5];
+    for _ in 0..rounds {
+        // 每轮第一个输出token的分布应与目标模型一致
+        let tokens = sampler.step(&mut target, &mut draft, 0, 1)?;
+        counts[tokens[0] as usize] += 1;
+    }
+    for (i, (&c, &p)) in counts.iter().zip(expect.iter()).enumerate() {
+        let freq = c as f32 / rounds as f32;
+        println!("token {}: freq {:.4}, target prob {:.4}", i, freq, p);
+        assert!((freq - p).abs() < 0.015);
+    }
+    assert!(sampler.accepted() < sampler.drafted());
+    Ok(())
+}
@@ -1,8 +1,19 @@
 use std::{pin::pin, time::Instant};
 
-use aha::models::{GenerateModel, minicpm4::generate::MiniCPMGenerateModel};
+use aha::models::{
+    GenerateModel, ScoringModel,
+    common::quant::{QuantType, quantize_checkpoint},
+    minicpm4::{
+        config::{MiniCPM4Config, SparseConfig},
+        generate::MiniCPMGenerateModel,
+        model::MiniCPMLongRoPE,
+        sparse::{CompressedKeyCache, infllm_v2_attention, infllm_v2_attention_with_cache},
+        speculative::MiniCPMSpeculativeModel,
+    },
+};
 use aha_openai_dive::v1::resources::chat::ChatCompletionParameters;
 use anyhow::Result;
+use candle_core::{Device, Tensor};
 use rocket::futures::StreamExt;
 
 #[test]
@@ -74,3 +85,361 @@ async fn minicpm_stream() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn minicpm_generate_quant() -> Result<()> {
+    // test with cpu: RUST_BACKTRACE=1 cargo test minicpm_generate_quant -- --nocapture
+    let model_path = "/home/jhq/huggingface_model/OpenBMB/MiniCPM4-0.5B/";
+    let save_dir = std::env::temp_dir().join(format!("aha_minicpm4_int4_{}", uuid::Uuid::new_v4()));
+    let save_path = save_dir.to_string_lossy().to_string();
+    let message = r#"
+    {
+        "temperature": 0.3,
+        "top_p": 0.8,
+        "model": "minicpm4",
+        "messages": [
+            {
+                "role": "user",
+                "content": "你吃饭了没"
+            }
+        ]
+    }
+    "#;
+    let mes: ChatCompletionParameters = serde_json::from_str(message)?;
+    // 加载时量化
+    let i_start = Instant::now();
+    let mut model =
+        MiniCPMGenerateModel::init_with_quant(model_path, None, None, Some(QuantType::Int8))?;
+    println!(
+        "Time elapsed in load int8 model is: {:?}",
+        i_start.elapsed()
+    );
+    let result = model.generate(mes.clone())?;
+    println!("int8 generate: \n {:?}", result);
+
+    // 保存量化后的checkpoint再加载
+    quantize_checkpoint(model_path, &save_path, QuantType::Int4 { group_size: 64 })?;
+    let i_start = Instant::now();
+    let mut model = MiniCPMGenerateModel::init(&save_path, None, None)?;
+    println!(
+        "Time elapsed in load int4 model is: {:?}",
+        i_start.elapsed()
+    );
+    let result = model.generate(mes)?;
+    println!("int4 generate: \n {:?}", result);
+    std::fs::remove_dir_all(&save_dir)?;
+    Ok(())
+}
+
+#[test]
+fn minicpm_generate_gguf() -> Result<()> {
+    // 目录下只有gguf文件, 配置/tokenizer/chat_template均从gguf元数据读取
+    // RUST_BACKTRACE=1 cargo test -F cuda minicpm_generate_gguf -- --nocapture
+    let model_path = "/home/jhq/huggingface_model/OpenBMB/MiniCPM4-0.5B-GGUF/";
+    let message = r#"
+    {
+        "temperature": 0.3,
+        "top_p": 0.8,
+        "model": "minicpm4",
+        "messages": [
+            {
+                "role": "user",
+                "content": "你吃饭了没"
+            }
+        ]
+    }
+    "#;
+    let mes: ChatCompletionParameters = serde_json::from_str(message)?;
+    let i_start = Instant::now();
+    let mut model = MiniCPMGenerateModel::init(model_path, None, None)?;
+    let i_duration = i_start.elapsed();
+    println!("Time elapsed in load model is: {:?}", i_duration);
+    let result = model.generate(mes)?;
+    println!("generate: \n {:?}", result);
+    Ok(())
+}
+
+#[test]
+fn minicpm_longrope() -> Result<()> {
+    // cargo test minicpm_longrope -- --nocapture
+    let device = Device::Cpu;
+    let (head_dim, original, max_pos, rope_theta) = (8usize, 16usize, 64usize, 1000.0f64);
+    let short_factor = [1.0f64, 1.5, 2.0, 3.0];
+    let long_factor = [2.0f64, 4.0, 8.0, 16.0];
+    let cfg: MiniCPM4Config = serde_json::from_value(serde_json::json!({
+        "bos_token_id": 1,
+        "eos_token_id": [2, 3],
+        "hidden_act": "silu",
+        "hidden_size": 16,
+        "intermediate_size": 32,
+        "max_position_embeddings": max_pos,
+        "num_attention_heads": 2,
+        "num_hidden_layers": 1,
+        "num_key_value_heads": 1,
+        "rms_norm_eps": 1e-5,
+        "rope_theta": rope_theta,
+        "rope_scaling": {
+            "rope_type": "longrope",
+            "long_factor": long_factor,
+            "short_factor": short_factor,
+            "original_max_position_embeddings": original
+        },
+        "torch_dtype": "float32",
+        "vocab_size": 32,
+        "scale_emb": 12,
+        "dim_model_base": 8,
+        "scale_depth": 1.4
+    }))?;
+    // 参考实现: transformers中MiniCPMLongRoPE._set_cos_sin_cache
+    let scaling = (1.0 + (max_pos as f64 / original as f64).ln() / (original as f64).ln()).sqrt();
+    let reference = |pos: usize, factor: &[f64]| -> (Vec<f32>, Vec<f32>) {
+        let half = head_dim / 2;
+        let freqs: Vec<f64> = (0..head_dim)
+            .map(|j| {
+                let i = j % half;
+                let inv_freq = 1.0 / rope_theta.powf((2 * i) as f64 / head_dim as f64);
+                pos as f64 / factor[i] * inv_freq
+            })
+            .collect();
+        (
+            freqs.iter().map(|f| (f.cos() * scaling) as f32).collect(),
+            freqs.iter().map(|f| (f.sin() * scaling) as f32).collect(),
+        )
+    };
+    let max_diff = |cos: &Tensor, sin: &Tensor, start: usize, factor: &[f64]| -> Result<f32> {
+        let cos = cos.to_vec2::<f32>()?;
+        let sin = sin.to_vec2::<f32>()?;
+        let mut diff = 0f32;
+        for (i, (c, s)) in cos.iter().zip(sin.iter()).enumerate() {
+            let (rc, rs) = reference(start + i, factor);
+            for j in 0..head_dim {
+                diff = diff.max((c[j] - rc[j]).abs()).max((s[j] - rs[j]).abs());
+            }
+        }
+        Ok(diff)
+    };
+
+    let mut rope = MiniCPMLongRoPE::new(&cfg, &device)?;
+    // 初始只缓存到original_max_position_embeddings
+    assert_eq!(rope.max_seq_len_cached(), original);
+    let (cos, sin) = rope.forward(0, 10)?;
+    let diff = max_diff(&cos, &sin, 0, &short_factor)?;
+    println!("short factor diff: {}", diff);
+    assert!(diff < 1e-5);
+
+    // 超过original后切换到long_factor, 缓存按需增长
+    let (cos, sin) = rope.forward(0, 40)?;
+    let diff = max_diff(&cos, &sin, 0, &long_factor)?;
+    println!("long factor diff: {}", diff);
+    assert!(diff < 1e-5);
+    assert_eq!(rope.max_seq_len_cached(), 40);
+    let (cos, sin) = rope.forward(40, 1)?;
+    assert!(max_diff(&cos, &sin, 40, &long_factor)? < 1e-5);
+    assert_eq!(rope.max_seq_len_cached(), 80);
+
+    // 新一轮短输入切回short_factor
+    let (cos, sin) = rope.forward(0, 8)?;
+    assert!(max_diff(&cos, &sin, 0, &short_factor)? < 1e-5);
+    assert_eq!(rope.max_seq_len_cached(), original);
+    Ok(())
+}
+
+#[test]
+fn minicpm_generate_long_context() -> Result<()> {
+    // 32k以上的输入, 位置超过original_max_position_embeddings后使用long_factor
+    // RUST_BACKTRACE=1 cargo test -F cuda minicpm_generate_long_context -- --nocapture
+    let model_path = "/home/jhq/huggingface_model/OpenBMB/MiniCPM4-8B/";
+    let long_text = "太阳当空照，花儿对我笑，小鸟说早早早，你为什么背上炸药包。".repeat(1500);
+    let message = serde_json::json!({
+        "model": "minicpm4",
+        "max_tokens": 64,
+        "messages": [
+            {
+                "role": "user",
+                "content": format!("{}\n上面这段话重复的是哪首儿歌？", long_text)
+            }
+        ]
+    });
+    let mes: ChatCompletionParameters = serde_json::from_value(message)?;
+    let mut model = MiniCPMGenerateModel::init(model_path, None, None)?;
+    let i_start = Instant::now();
+    let result = model.generate(mes)?;
+    println!("Time elapsed in generate is: {:?}", i_start.elapsed());
+    println!("generate: \n {:?}", result);
+    Ok(())
+}
+
+#[test]
+fn minicpm_sparse_attention() -> Result<()> {
+    // cargo test minicpm_sparse_attention -- --nocapture
+    let device = Device::Cpu;
+    let (num_heads, num_kv_heads, head_dim, kv_len, q_len) = (4usize, 2usize, 8usize, 300, 70);
+    let groups = num_heads / num_kv_heads;
+    let q = Tensor::randn(0f32, 1.0, (1, num_heads, q_len, head_dim), &device)?;
+    let k = Tensor::randn(0f32, 1.0, (1, num_kv_heads, kv_len, head_dim), &device)?;
+    let v = Tensor::randn(0f32, 1.0, (1, num_kv_heads, kv_len, head_dim), &device)?;
+    // 参考实现: 稠密因果注意力
+    let k_rep = k
+        .unsqueeze(2)?
+        .broadcast_as((1, num_kv_heads, groups, kv_len, head_dim))?
+        .reshape((1, num_heads, kv_len, head_dim))?;
+    let v_rep = v
+        .unsqueeze(2)?
+        .broadcast_as((1, num_kv_heads, groups, kv_len, head_dim))?
+        .reshape((1, num_heads, kv_len, head_dim))?;
+    let mut mask = vec![0f32; q_len * kv_len];
+    for i in 0..q_len {
+        for j in (kv_len - q_len + i + 1)..kv_len {
+            mask[i * kv_len + j] = f32::NEG_INFINITY;
+        }
+    }
+    let mask = Tensor::from_vec(mask, (1, 1, q_len, kv_len), &device)?;
+    let attn = (q.matmul(&k_rep.t()?)? / (head_dim as f64).sqrt())?.broadcast_add(&mask)?;
+    let dense = candle_nn::ops::softmax_last_dim(&attn)?.matmul(&v_rep)?;
+
+    // topk覆盖所有block时与稠密注意力一致
+    let mut cfg = SparseConfig {
+        kernel_size: 8,
+        kernel_stride: 4,
+        block_size: 16,
+        window_size: 32,
+        topk: 64,
+        ..Default::default()
+    };
+    let out = infllm_v2_attention(&q, &k, &v, &cfg, groups)?;
+    let diff = (&out - &dense)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    println!("full topk diff: {}", diff);
+    assert!(diff < 1e-5);
+
+    // 局部窗口覆盖全部输入时也一致
+    cfg.topk = 4;
+    cfg.window_size = kv_len;
+    let out = infllm_v2_attention(&q, &k, &v, &cfg, groups)?;
+    let diff = (&out - &dense)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    println!("full window diff: {}", diff);
+    assert!(diff < 1e-5);
+
+    // 只选部分block
+    cfg.window_size = 32;
+    let out = infllm_v2_attention(&q, &k, &v, &cfg, groups)?;
+    assert_eq!(out.dims(), dense.dims());
+    let diff = (&out - &dense)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    println!("sparse diff: {}", diff);
+    assert!(diff.is_finite() && diff > 1e-5);
+    // 解码单个token
+    let out = infllm_v2_attention(&q.narrow(2, q_len - 1, 1)?, &k, &v, &cfg, groups)?;
+    assert_eq!(out.dims(), &[1, num_heads, 1, head_dim]);
+    // 压缩key随kv增量更新, 与一次计算的结果相同
+    let mut compressed_key = CompressedKeyCache::default();
+    for len in [5, 37, 38, kv_len - 1, kv_len] {
+        let q_last = q.narrow(2, q_len - 1, 1)?;
+        infllm_v2_attention_with_cache(
+            &q_last,
+            &k.narrow(2, 0, len)?,
+            &v.narrow(2, 0, len)?,
+            &mut compressed_key,
+            &cfg,
+            groups,
+        )?;
+    }
+    compressed_key.truncate(40)?;
+    let cached = infllm_v2_attention_with_cache(&q, &k, &v, &mut compressed_key, &cfg, groups)?;
+    let fresh = infllm_v2_attention(&q, &k, &v, &cfg, groups)?;
+    assert!((&cached - &fresh)?.abs()?.max_all()?.to_scalar::<f32>()? < 1e-6);
+    Ok(())
+}
+
+#[test]
+fn minicpm_generate_sparse() -> Result<()> {
+    // 64k的输入在cpu上使用InfLLM v2稀疏注意力, 按块prefill
+    // RUST_BACKTRACE=1 cargo test -r minicpm_generate_sparse -- --nocapture
+    let model_path = "/home/jhq/huggingface_model/OpenBMB/MiniCPM4-8B/";
+    let long_text = "太阳当空照，花儿对我笑，小鸟说早早早，你为什么背上炸药包。".repeat(3000);
+    let message = serde_json::json!({
+        "model": "minicpm4",
+        "max_tokens": 64,
+        "messages": [
+            {
+                "role": "user",
+                "content": format!("{}\n上面这段话重复的是哪首儿歌？", long_text)
+            }
+        ]
+    });
+    let mes: ChatCompletionParameters = serde_json::from_value(message)?;
+    let mut model = MiniCPMGenerateModel::init(model_path, Some(&Device::Cpu), None)?;
+    // config.json中没有sparse_config时使用默认配置
+    model.set_sparse_config(Some(SparseConfig::default()))?;
+    let i_start = Instant::now();
+    let result = model.generate(mes)?;
+    println!("Time elapsed in generate is: {:?}", i_start.elapsed());
+    println!("generate: \n {:?}", result);
+    Ok(())
+}
+
+#[test]
+fn minicpm_generate_speculative() -> Result<()> {
+    // MiniCPM4-0.5B作为草稿模型, MiniCPM4-8B验证
+    // RUST_BACKTRACE=1 cargo test -F cuda minicpm_generate_speculative -- --nocapture
+    let target_path = "/home/jhq/huggingface_model/OpenBMB/MiniCPM4-8B/";
+    let draft_path = "/home/jhq/huggingface_model/OpenBMB/MiniCPM4-0.5B/";
+    let message = r#"
+    {
+        "temperature": 0.3,
+        "top_p": 0.8,
+        "model": "minicpm4",
+        "max_tokens": 256,
+        "messages": [
+            {
+                "role": "user",
+                "content": "用三句话介绍一下北京"
+            }
+        ]
+    }
+    "#;
+    let mes: ChatCompletionParameters = serde_json::from_str(message)?;
+    let mut model = MiniCPMSpeculativeModel::init(target_path, draft_path, None, None)?;
+    for num_draft in [2, 4, 6] {
+        model.set_num_draft_tokens(num_draft);
+        let i_start = Instant::now();
+        let result = model.generate(mes.clone())?;
+        println!(
+            "num_draft {}: time elapsed {:?}, acceptance rate {}",
+            num_draft,
+            i_start.elapsed(),
+            model.acceptance_rate()
+        );
+        println!("generate: \n {:?}", result);
+    }
+    Ok(())
+}
+
+#[test]
+fn minicpm_score() -> Result<()> {
+    // RUST_BACKTRACE=1 cargo test -F cuda minicpm_score -- --nocapture
+    let model_path = "/home/jhq/huggingface_model/OpenBMB/MiniCPM4-0.5B/";
+    let mut model = MiniCPMGenerateModel::init(model_path, None, None)?;
+    let fluent = model.score_text("今天天气很好, 我们一起去公园散步吧。")?;
+    let shuffled = model.score_text("公园散步很好吧, 一起天气我们去今天。")?;
+    println!(
+        "fluent ppl: {}, shuffled ppl: {}",
+        fluent.perplexity, shuffled.perplexity
+    );
+    assert!(fluent.perplexity < shuffled.perplexity);
+
+    let message = r#"
+    {
+        "model": "minicpm4",
+        "messages": [
+            {"role": "user", "content": "1+1等于几?"},
+            {"role": "assistant", "content": "1+1等于2。"}
+        ]
+    }
+    "#;
+    let mes: ChatCompletionParameters = serde_json::from_str(message)?;
+    let output = model.score(mes)?;
+    for token in &output.tokens {
+        println!("{:?} {:?}", token.token, token.logprob);
+    }
+    println!("chat ppl: {}", output.perplexity);
+    Ok(())
+}
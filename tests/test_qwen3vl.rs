@@ -1,7 +1,11 @@
 use std::{pin::pin, time::Instant};
 
-use aha::models::{GenerateModel, qwen3vl::generate::Qwen3VLGenerateModel};
-use aha_openai_dive::v1::resources::chat::ChatCompletionParameters;
+use aha::models::{
+    GenerateModel,
+    common::search::BeamSearchConfig,
+    qwen3vl::{generate::Qwen3VLGenerateModel, session::ChatSession},
+};
+use aha_openai_dive::v1::resources::chat::{ChatCompletionParameters, ChatMessage};
 use anyhow::Result;
 use rocket::futures::StreamExt;
 
@@ -94,3 +98,113 @@ async fn qwen3vl_stream() -> Result<()> {
     println!("Time elapsed in generate is: {:?}", i_duration);
     Ok(())
 }
+
+#[test]
+fn qwen3vl_chat_session() -> Result<()> {
+    // test with cuda: RUST_BACKTRACE=1 cargo test -F cuda qwen3vl_chat_session -- --nocapture
+
+    let model_path = "/home/jhq/huggingface_model/Qwen/Qwen3-VL-2B-Instruct/";
+
+    let message = r#"
+    {
+        "model": "qwen3vl",
+        "messages": [
+            {
+                "role": "user",
+                "content": [
+                    {
+                        "type": "image",
+                        "image_url":
+                        {
+                            "url": "file://./assets/img/ocr_test1.png"
+                        }
+                    },
+                    {
+                        "type": "text",
+                        "text": "请描述这张图片"
+                    }
+                ]
+            }
+        ]
+    }
+    "#;
+    let mut mes: ChatCompletionParameters = serde_json::from_str(message)?;
+    let mut qwen3vl = Qwen3VLGenerateModel::init(model_path, None, None)?;
+    let mut session = ChatSession::new();
+    let questions = ["图片中有哪些文字？", "用一句话总结"];
+    for i in 0..=questions.len() {
+        let i_start = Instant::now();
+        let res = qwen3vl.chat(&mut session, mes.clone())?;
+        let i_duration = i_start.elapsed();
+        println!("turn {} generate: \n {:?}", i, res);
+        println!(
+            "reused tokens: {} / {}, Time elapsed in generate is: {:?}",
+            session.reused_tokens(),
+            session.tokens().len(),
+            i_duration
+        );
+        if i == questions.len() {
+            break;
+        }
+        mes.messages.push(res.choices[0].message.clone());
+        let user: ChatMessage = serde_json::from_value(serde_json::json!({
+            "role": "user",
+            "content": questions[i]
+        }))?;
+        mes.messages.push(user);
+    }
+    Ok(())
+}
+
+#[test]
+fn qwen3vl_beam_search() -> Result<()> {
+    // test with cuda: RUST_BACKTRACE=1 cargo test -F cuda qwen3vl_beam_search -- --nocapture
+
+    let model_path = "/home/jhq/huggingface_model/Qwen/Qwen3-VL-2B-Instruct/";
+
+    let message = r#"
+    {
+        "model": "qwen3vl",
+        "max_tokens": 256,
+        "messages": [
+            {
+                "role": "user",
+                "content": [
+                    {
+                        "type": "image",
+                        "image_url":
+                        {
+                            "url": "file://./assets/img/ocr_test1.png"
+                        }
+                    },
+                    {
+                        "type": "text",
+                        "text": "识别图片中的文字"
+                    }
+                ]
+            }
+        ]
+    }
+    "#;
+    let mes: ChatCompletionParameters = serde_json::from_str(message)?;
+    let mut qwen3vl = Qwen3VLGenerateModel::init(model_path, None, None)?;
+    let cfg = BeamSearchConfig {
+        num_beams: 4,
+        max_new_tokens: 256,
+        num_return_sequences: 2,
+        ..Default::default()
+    };
+    let i_start = Instant::now();
+    let hyps = qwen3vl.beam_search(mes.clone(), &cfg)?;
+    println!("Time elapsed in beam search is: {:?}", i_start.elapsed());
+    for hyp in &hyps {
+        println!("score {:.4}: {}", hyp.score, hyp.text);
+    }
+    let i_start = Instant::now();
+    let hyps = qwen3vl.best_of_n(mes, 3)?;
+    println!("Time elapsed in best of n is: {:?}", i_start.elapsed());
+    for hyp in &hyps {
+        println!("logprob {:.4}: {}", hyp.logprob, hyp.text);
+    }
+    Ok(())
+}
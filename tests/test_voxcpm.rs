@@ -1,8 +1,16 @@
 use std::time::Instant;
 
 use aha::{
-    models::voxcpm::{generate::VoxCPMGenerate, tokenizer::SingleChineseTokenizer},
-    utils::audio_utils::save_wav,
+    models::voxcpm::{
+        codec::AudioVAECodec,
+        generate::{LongTextConfig, VoxCPMGenerate},
+        tokenizer::SingleChineseTokenizer,
+    },
+    utils::{
+        audio_utils::save_wav,
+        convert_utils::{ConvertConfig, convert_checkpoint, parse_shard_size},
+        text_utils::split_text_by_punctuation,
+    },
 };
 use anyhow::{Ok, Result};
 
@@ -50,7 +58,7 @@ fn voxcpm_generate() -> Result<()> {
 
     let i_duration = i_start.elapsed();
     println!("Time elapsed in generate is: {:?}", i_duration);
-    save_wav(&generate, "voxcpm.wav")?;
+    save_wav(&generate, "voxcpm.wav", voxcpm_generate.sample_rate())?;
     Ok(())
 }
 
@@ -62,3 +70,108 @@ fn voxcpm_tokenizer() -> Result<()> {
     println!("ids: {:?}", ids);
     Ok(())
 }
+
+#[test]
+fn voxcpm_generate_long() -> Result<()> {
+    // RUST_BACKTRACE=1 cargo test -F cuda,flash-attn voxcpm_generate_long -- --nocapture
+    let model_path = "/home/jhq/huggingface_model/openbmb/VoxCPM-0.5B/";
+    let mut voxcpm_generate = VoxCPMGenerate::init(model_path, None, None)?;
+    let text = "太阳当空照，花儿对我笑，小鸟说早早早，你为什么背上炸药包？我去炸学校，老师不知道，一拉线我就跑，轰的一声学校炸没了。";
+    let i_start = Instant::now();
+    let long_cfg = LongTextConfig {
+        max_segment_chars: 20,
+        ..Default::default()
+    };
+    let res = voxcpm_generate.generate_long(
+        text.to_string(),
+        Some("一定被灰太狼给吃了，我已经为他准备好了花圈了".to_string()),
+        Some("./assets/audio/voice_05.wav".to_string()),
+        &long_cfg,
+    )?;
+    let i_duration = i_start.elapsed();
+    println!("Time elapsed in generate is: {:?}", i_duration);
+    for seg in &res.segments {
+        println!("{:.2}s - {:.2}s: {}", seg.start, seg.end, seg.text);
+    }
+    save_wav(&res.audio, "voxcpm_long.wav", res.sample_rate)?;
+    Ok(())
+}
+
+#[test]
+fn voxcpm_split_text() -> Result<()> {
+    // cargo test voxcpm_split_text -- --nocapture
+    let text =
+        "太阳当空照，花儿对我笑。小鸟说早早早！你为什么背上炸药包？？Pi is 3.14. It is a number.";
+    let segments = split_text_by_punctuation(text, 12);
+    println!("segments: {:?}", segments);
+    assert!(segments.iter().all(|s| s.chars().count() <= 12));
+    assert_eq!(segments.concat().replace(' ', ""), text.replace(' ', ""));
+    assert!(segments.iter().any(|s| s.contains("3.14")));
+    Ok(())
+}
+
+#[test]
+fn voxcpm_audio_vae_codec() -> Result<()> {
+    // RUST_BACKTRACE=1 cargo test -F cuda voxcpm_audio_vae_codec -- --nocapture
+    let model_path = "/home/jhq/huggingface_model/openbmb/VoxCPM-0.5B/";
+    let codec = AudioVAECodec::init(model_path, None, None)?;
+    println!(
+        "sample_rate: {}, latent_dim: {}, hop_length: {}",
+        codec.sample_rate(),
+        codec.latent_dim(),
+        codec.hop_length()
+    );
+    let i_start = Instant::now();
+    let latents = codec.encode_file("./assets/audio/voice_01.wav")?;
+    println!("latents: {:?}", latents.shape());
+    assert_eq!(latents.dim(1)?, codec.latent_dim());
+    let save_dir = std::env::temp_dir().join(format!("aha_vae_codec_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&save_dir)?;
+    let latents_path = save_dir.join("voice_01_latents.safetensors");
+    let latents_path = latents_path.to_string_lossy();
+    codec.save_latents(&latents, &latents_path)?;
+    let loaded = codec.load_latents(&latents_path)?;
+    let audio = codec.decode(&loaded)?;
+    println!("Time elapsed in encode/decode is: {:?}", i_start.elapsed());
+    assert_eq!(audio.dim(1)?, latents.dim(2)? * codec.hop_length());
+    let wav_path = save_dir.join("voice_01_vae.wav");
+    save_wav(&audio, &wav_path.to_string_lossy(), codec.sample_rate())?;
+    std::fs::remove_dir_all(&save_dir)?;
+    Ok(())
+}
+
+#[test]
+fn voxcpm_convert_safetensors() -> Result<()> {
+    // RUST_BACKTRACE=1 cargo test -F cuda voxcpm_convert_safetensors -- --nocapture
+    assert_eq!(parse_shard_size("2GB")?, 2 * 1024 * 1024 * 1024);
+    assert_eq!(parse_shard_size("500mb")?, 500 * 1024 * 1024);
+    assert_eq!(parse_shard_size("1024")?, 1024);
+    assert!(parse_shard_size("1PB").is_err());
+
+    let model_path = "/home/jhq/huggingface_model/openbmb/VoxCPM-0.5B/";
+    let save_path = "/home/jhq/huggingface_model/openbmb/VoxCPM-0.5B-safetensors/";
+    let cfg = ConvertConfig {
+        max_shard_size: parse_shard_size("500MB")?,
+        ..Default::default()
+    };
+    let i_start = Instant::now();
+    convert_checkpoint(model_path, save_path, &cfg)?;
+    println!("Time elapsed in convert is: {:?}", i_start.elapsed());
+    assert!(
+        std::path::Path::new(save_path)
+            .join("model.safetensors.index.json")
+            .exists()
+    );
+
+    let i_start = Instant::now();
+    let mut voxcpm_generate = VoxCPMGenerate::init(save_path, None, None)?;
+    println!("Time elapsed in load model is: {:?}", i_start.elapsed());
+    let generate =
+        voxcpm_generate.generate_simple("太阳当空照，花儿对我笑，小鸟说早早早".to_string())?;
+    save_wav(
+        &generate,
+        "voxcpm_safetensors.wav",
+        voxcpm_generate.sample_rate(),
+    )?;
+    Ok(())
+}
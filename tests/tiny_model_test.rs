@@ -0,0 +1,309 @@
+use std::{path::PathBuf, pin::pin, sync::LazyLock, time::Instant};
+
+use aha::models::{
+    GenerateModel,
+    common::fixture::TinyModel,
+    minicpm4::generate::MiniCPMGenerateModel,
+    qwen2_5vl::generate::Qwen2_5VLGenerateModel,
+    qwen3vl::generate::Qwen3VLGenerateModel,
+    voxcpm::{
+        codec::AudioVAECodec,
+        generate::{LongTextConfig, VoxCPMGenerate},
+    },
+};
+use aha::utils::{
+    audio_utils::{AudioJoinMode, AudioPostProcess},
+    vision_utils::{RequestVisionOverrides, VisionOverrides},
+};
+use aha_openai_dive::v1::resources::chat::ChatCompletionParameters;
+use anyhow::Result;
+use candle_core::{Device, Tensor};
+use rocket::futures::StreamExt;
+
+// 随机权重的小模型写到本进程独有的临时目录, 同时运行的测试进程互不影响
+static TINY_MODEL_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
+    std::env::temp_dir().join(format!("aha_tiny_models_{}", uuid::Uuid::new_v4()))
+});
+
+fn tiny_model_path(model: TinyModel, name: &str) -> Result<String> {
+    let path = TINY_MODEL_DIR.join(name).to_string_lossy().to_string();
+    let i_start = Instant::now();
+    model.write(&path)?;
+    println!("write {:?} to {} in {:?}", model, path, i_start.elapsed());
+    Ok(path)
+}
+
+fn text_message() -> Result<ChatCompletionParameters> {
+    let message = r#"
+    {
+        "model": "tiny",
+        "max_tokens": 8,
+        "messages": [
+            {
+                "role": "user",
+                "content": "你好, who are you?"
+            }
+        ]
+    }
+    "#;
+    Ok(serde_json::from_str(message)?)
+}
+
+fn image_message() -> Result<ChatCompletionParameters> {
+    let message = r#"
+    {
+        "model": "tiny",
+        "max_tokens": 8,
+        "messages": [
+            {
+                "role": "user",
+                "content": [
+                    {
+                        "type": "image_url",
+                        "image_url": {"url": "file://./assets/img/ocr_test1.png"}
+                    },
+                    {
+                        "type": "text",
+                        "text": "describe the image"
+                    }
+                ]
+            }
+        ]
+    }
+    "#;
+    Ok(serde_json::from_str(message)?)
+}
+
+fn message_text(message: &impl serde::Serialize) -> Result<String> {
+    let value = serde_json::to_value(message)?;
+    Ok(value["content"].as_str().unwrap_or_default().to_string())
+}
+
+// fixture权重和采样的随机种子都是固定的, 同一个请求的generate结果不变, 拼接generate_stream的输出与之一致
+async fn check_generate(
+    model: &mut impl GenerateModel,
+    mes: ChatCompletionParameters,
+) -> Result<()> {
+    let max_tokens = mes.max_tokens.unwrap_or(1024);
+    let result = model.generate(mes.clone())?;
+    println!("generate: {:?}", result);
+    assert_eq!(result.choices.len(), 1);
+    let text = message_text(&result.choices[0].message)?;
+    let usage = result.usage.clone().unwrap();
+    let completion_tokens = usage.completion_tokens.unwrap();
+    assert!(completion_tokens >= 1 && completion_tokens <= max_tokens);
+    assert_eq!(
+        usage.total_tokens,
+        usage.prompt_tokens.unwrap() + completion_tokens
+    );
+    let again = model.generate(mes.clone())?;
+    assert_eq!(message_text(&again.choices[0].message)?, text);
+
+    let mut stream = pin!(model.generate_stream(mes)?);
+    let mut streamed = String::new();
+    let mut chunks = 0;
+    while let Some(item) = stream.next().await {
+        let chunk = item?;
+        assert_eq!(chunk.choices.len(), 1);
+        streamed.push_str(&message_text(&chunk.choices[0].delta)?);
+        chunks += 1;
+    }
+    println!("stream chunks: {}, text: {:?}", chunks, streamed);
+    // 每个chunk至少对应一个token, 不完整的utf-8字节会攒到后面的chunk中
+    assert!(chunks <= completion_tokens);
+    assert_eq!(streamed, text);
+    Ok(())
+}
+
+#[test]
+fn tiny_model_fixture() -> Result<()> {
+    // cargo test tiny_model_fixture -- --nocapture
+    // 同一个模型重复生成的权重不变
+    let path1 = tiny_model_path(TinyModel::MiniCPM4, "fixture_a")?;
+    let path2 = tiny_model_path(TinyModel::MiniCPM4, "fixture_b")?;
+    let w1 = candle_core::safetensors::load(format!("{}/model.safetensors", path1), &Device::Cpu)?;
+    let w2 = candle_core::safetensors::load(path2 + "/model.safetensors", &Device::Cpu)?;
+    assert_eq!(w1.len(), w2.len());
+    for (name, t) in &w1 {
+        let diff = (t - &w2[name])?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert_eq!(diff, 0.0, "{}", name);
+    }
+    // 参考实现按model_type/architectures加载
+    let config: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(format!("{}/config.json", path1))?)?;
+    assert_eq!(config["model_type"], "minicpm");
+    assert_eq!(config["architectures"][0], "MiniCPMForCausalLM");
+    assert!(TinyModel::from_name("qwen2.5vl")? == TinyModel::Qwen2_5VL);
+    assert!(TinyModel::from_name("llama").is_err());
+
+    // 每个fixture都能通过check_model
+    let report = MiniCPMGenerateModel::check_model(&tiny_model_path(
+        TinyModel::MiniCPM4,
+        "check_minicpm4",
+    )?)?;
+    assert!(report.is_ok() && report.unexpected.is_empty(), "{}", report);
+    let report = Qwen2_5VLGenerateModel::check_model(&tiny_model_path(
+        TinyModel::Qwen2_5VL,
+        "check_qwen2_5vl",
+    )?)?;
+    assert!(report.is_ok() && report.unexpected.is_empty(), "{}", report);
+    let report =
+        Qwen3VLGenerateModel::check_model(&tiny_model_path(TinyModel::Qwen3VL, "check_qwen3vl")?)?;
+    assert!(report.is_ok() && report.unexpected.is_empty(), "{}", report);
+    let report = VoxCPMGenerate::check_model(&tiny_model_path(TinyModel::VoxCPM, "check_voxcpm")?)?;
+    assert!(report.is_ok() && report.unexpected.is_empty(), "{}", report);
+    // audio vae构建失败时仍然检查语言模型的权重
+    let path = tiny_model_path(TinyModel::VoxCPM, "check_voxcpm_bad_vae")?;
+    let config_path = format!("{}/config.json", path);
+    let mut config: serde_json::Value = serde_json::from_slice(&std::fs::read(&config_path)?)?;
+    config["audio_vae_config"]["decoder_dim"] = serde_json::json!(2);
+    std::fs::write(&config_path, serde_json::to_string(&config)?)?;
+    let report = VoxCPMGenerate::check_model(&path)?;
+    println!("{}", report);
+    assert!(
+        report
+            .build_error
+            .as_ref()
+            .is_some_and(|e| e.contains("audio vae"))
+    );
+    assert!(report.missing.is_empty());
+    assert!(
+        report
+            .unexpected
+            .iter()
+            .all(|n| n.starts_with("encoder.") || n.starts_with("decoder."))
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn tiny_model_minicpm4() -> Result<()> {
+    // cargo test tiny_model_minicpm4 -- --nocapture
+    let path = tiny_model_path(TinyModel::MiniCPM4, "minicpm4")?;
+    let mut model = MiniCPMGenerateModel::init(&path, Some(&Device::Cpu), None)?;
+    check_generate(&mut model, text_message()?).await
+}
+
+#[tokio::test]
+async fn tiny_model_qwen2_5vl() -> Result<()> {
+    // cargo test tiny_model_qwen2_5vl -- --nocapture
+    let path = tiny_model_path(TinyModel::Qwen2_5VL, "qwen2_5vl")?;
+    let mut model = Qwen2_5VLGenerateModel::init(&path, Some(&Device::Cpu), None)?;
+    check_generate(&mut model, text_message()?).await?;
+    check_generate(&mut model, image_message()?).await
+}
+
+#[tokio::test]
+async fn tiny_model_qwen3vl() -> Result<()> {
+    // cargo test tiny_model_qwen3vl -- --nocapture
+    let path = tiny_model_path(TinyModel::Qwen3VL, "qwen3vl")?;
+    let mut model = Qwen3VLGenerateModel::init(&path, Some(&Device::Cpu), None)?;
+    check_generate(&mut model, text_message()?).await?;
+    check_generate(&mut model, image_message()?).await?;
+
+    // 原始请求中的图片设置只作用于下一次请求
+    let prompt_tokens = |model: &mut Qwen3VLGenerateModel| -> Result<u32> {
+        let usage = model.generate(image_message()?)?.usage.unwrap();
+        Ok(usage.prompt_tokens.unwrap())
+    };
+    let default_tokens = prompt_tokens(&mut model)?;
+    model.set_request_overrides(RequestVisionOverrides {
+        default: VisionOverrides {
+            resized_height: Some(256),
+            resized_width: Some(256),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    let resized_tokens = prompt_tokens(&mut model)?;
+    println!("prompt tokens: {} -> {}", default_tokens, resized_tokens);
+    assert_ne!(resized_tokens, default_tokens);
+    assert_eq!(prompt_tokens(&mut model)?, default_tokens);
+    Ok(())
+}
+
+#[test]
+fn tiny_model_voxcpm() -> Result<()> {
+    // cargo test tiny_model_voxcpm -- --nocapture
+    let path = tiny_model_path(TinyModel::VoxCPM, "voxcpm")?;
+    let mut model = VoxCPMGenerate::init(&path, Some(&Device::Cpu), None)?;
+    let audio: Tensor = model.generate(
+        "太阳当空照".to_string(),
+        None,
+        None,
+        2,
+        10,
+        4,
+        2.0,
+        false,
+        6.0,
+    )?;
+    println!("audio: {:?}", audio.dims());
+    // 每步生成patch_size(2) * hop_length(4)个采样点
+    let samples = audio.dim(audio.rank() - 1)?;
+    assert!(samples > 0 && samples <= 10 * 8);
+    assert!(
+        audio
+            .flatten_all()?
+            .to_vec1::<f32>()?
+            .iter()
+            .all(|v| v.is_finite())
+    );
+    Ok(())
+}
+
+#[test]
+fn tiny_model_voxcpm_long() -> Result<()> {
+    // cargo test tiny_model_voxcpm_long -- --nocapture
+    let path = tiny_model_path(TinyModel::VoxCPM, "voxcpm_long")?;
+    let mut model = VoxCPMGenerate::init(&path, Some(&Device::Cpu), None)?;
+    // 没有参考音频时之后各段以第一段为prompt, 变速在拼接后处理
+    model.set_post_process(AudioPostProcess {
+        speed: 2.0,
+        loudness: None,
+    });
+    let long_cfg = LongTextConfig {
+        max_segment_chars: 4,
+        join_mode: AudioJoinMode::Silence(1),
+        min_len: 2,
+        max_len: 6,
+        inference_timesteps: 2,
+        ..Default::default()
+    };
+    let res = model.generate_long(
+        "太阳当空照，花儿对我笑。".to_string(),
+        None,
+        None,
+        &long_cfg,
+    )?;
+    let duration = res.audio.dim(res.audio.rank() - 1)? as f32 / res.sample_rate as f32;
+    for seg in &res.segments {
+        println!("{:.4}s - {:.4}s: {}", seg.start, seg.end, seg.text);
+    }
+    assert!(res.segments.len() >= 2);
+    assert!(
+        res.segments
+            .windows(2)
+            .all(|w| w[0].start <= w[0].end && w[0].end <= w[1].start)
+    );
+    assert!((res.segments.last().unwrap().end - duration).abs() < 1e-3);
+    Ok(())
+}
+
+#[test]
+fn tiny_model_audio_vae_codec() -> Result<()> {
+    // cargo test tiny_model_audio_vae_codec -- --nocapture
+    // 目录下还有语言模型的model.safetensors, codec只加载audiovae的权重
+    let path = tiny_model_path(TinyModel::VoxCPM, "audio_vae_codec")?;
+    let codec = AudioVAECodec::init(&path, Some(&Device::Cpu), None)?;
+    let audio = Tensor::randn(0f32, 0.1, (1, 32), &Device::Cpu)?;
+    let latents = codec.encode(&audio, codec.sample_rate())?;
+    println!("latents: {:?}", latents.dims());
+    assert_eq!(latents.dim(1)?, codec.latent_dim());
+    let latents_path = format!("{}/latents.safetensors", path);
+    codec.save_latents(&latents, &latents_path)?;
+    let loaded = codec.load_latents(&latents_path)?;
+    let decoded = codec.decode(&loaded)?;
+    assert_eq!(decoded.dim(1)?, latents.dim(2)? * codec.hop_length());
+    Ok(())
+}
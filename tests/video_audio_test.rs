@@ -0,0 +1,135 @@
+use std::io::Cursor;
+
+use aha::models::qwen2_5vl::processor::Qwen2_5VLProcessor;
+use aha::models::qwen3vl::processor::{Qwen3VLProcessor, get_video_data};
+use aha::utils::audio_utils::{clip_audio, get_video_audio};
+use aha::utils::media_utils::{MediaSource, normalize_video_frames};
+use aha::utils::vision_utils::{RequestVisionOverrides, VisionOverrides};
+use aha_openai_dive::v1::resources::chat::ChatCompletionParameters;
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+
+#[test]
+fn video_audio_clip() -> Result<()> {
+    // cargo test video_audio_clip -- --nocapture
+    let device = Device::Cpu;
+    let audio = Tensor::arange(0f32, 16000.0, &device)?.unsqueeze(0)?;
+    let clip = clip_audio(&audio, 8000, Some(0.5), Some(1.5))?;
+    assert_eq!(clip.dims(), &[1, 8000]);
+    assert_eq!(clip.flatten_all()?.to_vec1::<f32>()?[0], 4000.0);
+    // 超出音频长度的部分忽略
+    let clip = clip_audio(&audio, 8000, Some(1.0), Some(10.0))?;
+    assert_eq!(clip.dims(), &[1, 8000]);
+    assert_eq!(clip_audio(&audio, 8000, None, None)?.dims(), &[1, 16000]);
+    assert!(clip_audio(&audio, 8000, Some(1.5), Some(0.5)).is_err());
+    assert!(clip_audio(&audio, 8000, Some(3.0), None).is_err());
+    Ok(())
+}
+
+#[test]
+fn video_audio_processor() -> Result<()> {
+    // cargo test video_audio_processor -- --nocapture
+    let dir = std::env::temp_dir().join("aha_video_audio_test");
+    std::fs::create_dir_all(&dir)?;
+    let mut frames = vec![];
+    for i in 0..2 {
+        let mut bytes = Vec::new();
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([x as u8, y as u8, i * 100])
+        }))
+        .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)?;
+        let frame_path = dir.join(format!("frame{}.png", i));
+        std::fs::write(&frame_path, bytes)?;
+        frames.push(frame_path.to_string_lossy().to_string());
+    }
+    let mut request = serde_json::json!({
+        "model": "qwen3vl",
+        "messages": [{"role": "user", "content": [
+            {"type": "video_url", "video_url": {"url": frames}},
+            {"type": "text", "text": "describe"}
+        ]}]
+    });
+    normalize_video_frames(&mut request)?;
+    let mes: ChatCompletionParameters = serde_json::from_value(request)?;
+    let text = "<|vision_start|><|video_pad|><|vision_end|>describe";
+    let device = Device::Cpu;
+
+    let preprocessor = serde_json::json!({
+        "size": {"longest_edge": 16777216, "shortest_edge": 4096},
+        "patch_size": 16,
+        "temporal_patch_size": 2,
+        "merge_size": 2,
+        "image_mean": [0.5, 0.5, 0.5],
+        "image_std": [0.5, 0.5, 0.5]
+    });
+    std::fs::write(
+        dir.join("preprocessor_config.json"),
+        serde_json::to_string(&preprocessor)?,
+    )?;
+    std::fs::write(
+        dir.join("video_preprocessor_config.json"),
+        serde_json::to_string(&preprocessor)?,
+    )?;
+    let processor = Qwen3VLProcessor::new(&dir.to_string_lossy(), &device, DType::F32)?;
+    let input = processor.process_info(&mes, text, &RequestVisionOverrides::default())?;
+    assert!(input.pixel_values_video.is_some());
+    let processor = Qwen2_5VLProcessor::new(&dir.to_string_lossy(), &device, DType::F32)?;
+    let input = processor.process_info(&mes, text, &RequestVisionOverrides::default())?;
+    assert!(input.pixel_values_video.is_some());
+
+    // 视频帧和音轨从同一个VideoSource解码, 图片帧列表没有音轨
+    let video = MediaSource::from_frames(&frames)?.open_video()?;
+    let (tensor, metadata) = get_video_data(
+        &video,
+        16,
+        2,
+        2,
+        2,
+        768,
+        4096,
+        16777216,
+        &VisionOverrides::default(),
+        &device,
+    )?;
+    println!("frames: {:?}, metadata: {:?}", tensor.dims(), metadata);
+    assert_eq!(tensor.dim(0)?, 2);
+    assert_eq!(metadata.frame_indices, vec![0, 1]);
+    assert!(get_video_audio(&video, 16000, None, None, &device)?.is_none());
+    Ok(())
+}
+
+#[test]
+fn video_audio_extract() -> Result<()> {
+    // cargo test video_audio_extract -- --nocapture
+    let device = Device::Cpu;
+    // 测试视频没有音轨
+    let video = MediaSource::parse("./assets/video/video_test.mp4")?.open_video()?;
+    let (tensor, metadata) = get_video_data(
+        &video,
+        16,
+        2,
+        2,
+        4,
+        768,
+        4096,
+        16777216,
+        &VisionOverrides::default(),
+        &device,
+    )?;
+    println!("frames: {:?}, fps: {}", tensor.dims(), metadata.fps);
+    let audio = get_video_audio(&video, 16000, None, None, &device)?;
+    assert!(audio.is_none());
+    // 有音频流的文件按起止时间截取后重采样到16k
+    let audio = get_video_audio(
+        &MediaSource::parse("file://./assets/audio/voice_01.wav")?.open_video()?,
+        16000,
+        Some(0.5),
+        Some(1.5),
+        &device,
+    )?
+    .unwrap();
+    println!("audio: {:?}", audio.dims());
+    assert_eq!(audio.dim(0)?, 1);
+    assert!((audio.dim(1)? as i64 - 16000).abs() <= 1);
+    Ok(())
+}
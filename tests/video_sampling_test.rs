@@ -0,0 +1,112 @@
+use std::path::Path;
+
+use aha::utils::video_utils::{FrameSelector, VideoReader, VideoStreamInfo, uniform_indices};
+use aha::utils::vision_utils::RequestVisionOverrides;
+use anyhow::Result;
+use candle_core::Device;
+
+#[test]
+fn video_sampling_metadata() -> Result<()> {
+    // cargo test video_sampling_metadata -- --nocapture
+    let info = VideoStreamInfo::from_metadata(640, 360, Some(30.0), Some(10.0), Some(300))?;
+    assert_eq!(
+        (info.fps, info.duration, info.total_frames),
+        (30.0, 10.0, 300)
+    );
+    // 没有帧数时按时长和帧率推算
+    let info = VideoStreamInfo::from_metadata(640, 360, Some(25.0), Some(4.0), Some(0))?;
+    assert_eq!(info.total_frames, 100);
+    // 没有时长
+    let info = VideoStreamInfo::from_metadata(640, 360, Some(25.0), None, Some(50))?;
+    assert_eq!(info.duration, 2.0);
+    // 帧率为0
+    let info = VideoStreamInfo::from_metadata(640, 360, Some(0.0), Some(5.0), Some(120))?;
+    assert_eq!(info.fps, 24.0);
+    assert!(VideoStreamInfo::from_metadata(640, 360, None, Some(5.0), None).is_err());
+    assert!(VideoStreamInfo::from_metadata(640, 360, Some(f32::NAN), None, Some(10)).is_err());
+
+    // video_start/video_end截取
+    let info = VideoStreamInfo::from_metadata(640, 360, Some(30.0), Some(10.0), Some(300))?;
+    assert_eq!(info.clip(None, None)?, (0, 299));
+    assert_eq!(info.clip(Some(2.0), Some(4.0))?, (60, 120));
+    assert_eq!(info.clip(Some(1.01), None)?, (31, 299));
+    assert_eq!(info.clip(None, Some(100.0))?, (0, 299));
+    assert!(info.clip(Some(5.0), Some(4.0)).is_err());
+
+    // 与torch.linspace(0, 299, 20).round()一致
+    let indices = uniform_indices(0, 299, 20);
+    println!("indices: {:?}", indices);
+    assert_eq!(indices.len(), 20);
+    assert_eq!((indices[0], indices[1], indices[19]), (0, 16, 299));
+    assert!(indices.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(uniform_indices(60, 120, 3), vec![60, 90, 120]);
+    assert_eq!(uniform_indices(5, 5, 1), vec![5]);
+    assert_eq!(uniform_indices(0, 2, 4), vec![0, 1, 1, 2]);
+
+    // 请求中的video_start/video_end
+    let request = serde_json::json!({
+        "messages": [{"role": "user", "content": [
+            {"type": "video_url", "video_url": {"url": "file:///tmp/a.mp4"}, "video_start": 1.5, "video_end": 3.0},
+            {"type": "text", "text": "hi"}
+        ]}]
+    });
+    let overrides = RequestVisionOverrides::from_request(&request)?;
+    let video = overrides.video(0);
+    assert_eq!((video.video_start, video.video_end), (Some(1.5), Some(3.0)));
+    Ok(())
+}
+
+#[test]
+fn video_sampling_frame_selector() -> Result<()> {
+    // cargo test video_sampling_frame_selector -- --nocapture
+    // 30fps, 目标为0s, 0.5s, 0.5s(重复帧号), 10s, 10.1s
+    let mut selector = FrameSelector::new(vec![0.0, 0.5, 0.5, 10.0, 10.1], 30.0);
+    // 第一个目标在开头, 不需要seek
+    assert_eq!(selector.seek_target(None), None);
+    assert_eq!(selector.take(0.0), 1);
+    assert_eq!(selector.take(1.0 / 30.0), 0);
+    assert_eq!(selector.seek_target(Some(1.0 / 30.0)), None);
+    // 两个目标使用同一帧
+    assert_eq!(selector.take(0.49), 2);
+    // 下一个目标较远, seek, 同一个目标只seek一次
+    assert_eq!(selector.seek_target(Some(0.49)), Some(10.0));
+    assert_eq!(selector.seek_target(Some(0.49)), None);
+    // seek到的关键帧在目标之前, 继续解码到目标
+    assert_eq!(selector.take(8.0), 0);
+    assert_eq!(selector.seek_target(Some(8.0)), None);
+    assert_eq!(selector.take(10.0), 1);
+    assert!(!selector.is_done());
+    // 帧时间戳稀疏时(可变帧率), 跳过的目标使用之后最近的帧
+    assert_eq!(selector.take(10.3), 1);
+    assert!(selector.is_done());
+
+    // 视频实际比元数据短, 剩余目标用最后一帧
+    let mut selector = FrameSelector::new(vec![0.0, 1.0, 2.0], 10.0);
+    assert_eq!(selector.take(0.0), 1);
+    assert_eq!(selector.take(1.0), 1);
+    assert_eq!(selector.remaining(), 1);
+    assert!(selector.is_done());
+
+    // 第一个目标较晚时先seek
+    let mut selector = FrameSelector::new(vec![30.0, 31.0], 25.0);
+    assert_eq!(selector.seek_target(None), Some(30.0));
+    Ok(())
+}
+
+#[test]
+fn video_sampling_read_frames() -> Result<()> {
+    // cargo test video_sampling_read_frames -- --nocapture
+    let path = Path::new("./assets/video/video_test.mp4");
+    let mut reader = VideoReader::open(path)?;
+    let info = reader.info().clone();
+    println!("info: {:?}", info);
+    let (start_frame, end_frame) = info.clip(Some(1.0), Some(3.0))?;
+    let indices = uniform_indices(start_frame, end_frame, 4);
+    let frames = reader.read_frames(&indices, 224, 224, &Device::Cpu)?;
+    assert_eq!(frames.dims(), &[4, 3, 224, 224]);
+    // 整段视频均匀取8帧
+    let all = uniform_indices(0, info.total_frames - 1, 8);
+    let frames = VideoReader::open(path)?.read_frames(&all, 224, 224, &Device::Cpu)?;
+    assert_eq!(frames.dim(0)?, 8);
+    Ok(())
+}
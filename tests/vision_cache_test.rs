@@ -0,0 +1,228 @@
+use aha::models::{
+    GenerateModel,
+    common::{
+        fixture::TinyModel,
+        vision_cache::{
+            CachedVision, VisionCacheConfig, VisionCacheKey, VisionEmbeddingCache, cached_vision,
+            vision_cache_key,
+        },
+    },
+    qwen2_5vl::generate::Qwen2_5VLGenerateModel,
+    qwen3vl::generate::Qwen3VLGenerateModel,
+};
+use aha::utils::{
+    media_utils::MediaSource,
+    vision_utils::{MediaRequest, VisionOverrides},
+};
+use aha_openai_dive::v1::resources::chat::ChatCompletionParameters;
+use anyhow::Result;
+use base64::{Engine, engine::general_purpose};
+use candle_core::{DType, Device, Tensor};
+
+fn key(id: u32) -> VisionCacheKey {
+    VisionCacheKey {
+        namespace: "tiny".to_string(),
+        source: format!("image{}", id),
+        video: false,
+        params: "{}".to_string(),
+    }
+}
+
+// 每项 4 * 8 * 4 = 128 bytes
+fn cached(rows: usize) -> Result<CachedVision> {
+    Ok(CachedVision {
+        feature: (Tensor::zeros((rows, 8), DType::F32, &Device::Cpu)?, vec![]),
+        grid_thw: vec![1, 4, 4],
+        timestamps: vec![],
+    })
+}
+
+#[test]
+fn vision_cache_lru() -> Result<()> {
+    // cargo test vision_cache_lru -- --nocapture
+    let mut cache = VisionEmbeddingCache::new(VisionCacheConfig {
+        max_entries: 2,
+        max_bytes: 1 << 20,
+    });
+    cache.insert(key(1), cached(4)?);
+    cache.insert(key(2), cached(4)?);
+    // 访问1之后, 2是最久未使用的
+    assert!(cache.get(&key(1)).is_some());
+    cache.insert(key(3), cached(4)?);
+    assert!(cache.contains(&key(1)) && !cache.contains(&key(2)) && cache.contains(&key(3)));
+    assert!(cache.get(&key(2)).is_none());
+    let stats = cache.stats();
+    println!("entries limit: {:?}", stats);
+    assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 1, 1));
+    assert_eq!((stats.entries, stats.bytes), (2, 256));
+
+    // 按字节数淘汰, 超过上限的单项不缓存
+    let mut cache = VisionEmbeddingCache::new(VisionCacheConfig {
+        max_entries: 16,
+        max_bytes: 300,
+    });
+    cache.insert(key(1), cached(4)?);
+    cache.insert(key(2), cached(4)?);
+    cache.insert(key(3), cached(4)?);
+    assert!(!cache.contains(&key(1)) && cache.contains(&key(2)) && cache.contains(&key(3)));
+    cache.insert(key(4), cached(16)?);
+    assert!(!cache.contains(&key(4)));
+    let stats = cache.stats();
+    println!("bytes limit: {:?}", stats);
+    assert_eq!((stats.evictions, stats.entries, stats.bytes), (1, 2, 256));
+    cache.clear();
+    assert_eq!(cache.stats().bytes, 0);
+    Ok(())
+}
+
+#[test]
+fn vision_cache_key_source() -> Result<()> {
+    // cargo test vision_cache_key_source -- --nocapture
+    let dir = std::env::temp_dir().join(format!("aha_vision_cache_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    let file = dir.join("image.png");
+    std::fs::copy("./assets/img/ocr_test1.png", &file)?;
+    let request = |source: String, overrides: VisionOverrides| -> Result<MediaRequest> {
+        Ok(MediaRequest {
+            source: MediaSource::parse(&source)?,
+            video: false,
+            overrides,
+        })
+    };
+    let source = format!("file://{}", file.display());
+    let base = request(source.clone(), VisionOverrides::default())?;
+    let key = vision_cache_key("a", &base)?;
+    println!("key: {:?}", key);
+    // 同一文件, 带不带file://都是同一来源
+    assert_eq!(key, vision_cache_key("a", &base)?);
+    assert_eq!(
+        key,
+        vision_cache_key(
+            "a",
+            &request(file.display().to_string(), VisionOverrides::default())?
+        )?
+    );
+    // 按内容区分来源, 相同内容的base64数据与文件是同一来源, key的长度与数据大小无关
+    let bytes = std::fs::read(&file)?;
+    let data_url = format!(
+        "data:image/png;base64,{}",
+        general_purpose::STANDARD.encode(&bytes)
+    );
+    assert_eq!(
+        key,
+        vision_cache_key("a", &request(data_url, VisionOverrides::default())?)?
+    );
+    assert_eq!(key.source.len(), "sha256:".len() + 64);
+    // namespace, 预处理设置, 图片/视频不同时key不同
+    assert_ne!(key, vision_cache_key("b", &base)?);
+    let resized = VisionOverrides {
+        max_pixels: Some(64 * 28 * 28),
+        ..Default::default()
+    };
+    assert_ne!(
+        key,
+        vision_cache_key("a", &request(source.clone(), resized)?)?
+    );
+    let video = MediaRequest {
+        video: true,
+        ..base.clone()
+    };
+    assert_ne!(key, vision_cache_key("a", &video)?);
+    // 同一路径的文件内容变化后不再命中
+    std::fs::copy("./assets/img/voxcpm.png", &file)?;
+    let changed = vision_cache_key("a", &base)?;
+    println!("changed: {:?}", changed);
+    assert_ne!(key, changed);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn vision_cache_compute_missing() -> Result<()> {
+    // cargo test vision_cache_compute_missing -- --nocapture
+    let cache = VisionEmbeddingCache::shared(VisionCacheConfig::default());
+    cache.lock().unwrap().insert(key(1), cached(1)?);
+    // 已缓存的不计算, 同一请求中重复的只计算一次
+    let keys = [key(1), key(2), key(3), key(2)];
+    let mut computed = vec![];
+    let (values, num) = cached_vision(Some(&cache), &keys, |missing| {
+        computed = missing.to_vec();
+        missing.iter().map(|&i| cached(i + 1)).collect()
+    })?;
+    println!("computed: {:?}", computed);
+    assert_eq!(computed, vec![1, 2]);
+    assert_eq!(num, 2);
+    let rows: Vec<usize> = values
+        .iter()
+        .map(|v| v.feature.0.dim(0))
+        .collect::<candle_core::Result<_>>()?;
+    assert_eq!(rows, vec![1, 2, 3, 2]);
+    assert_eq!(cache.lock().unwrap().stats().entries, 3);
+    // 返回的数量与未命中的不一致时报错
+    assert!(cached_vision(Some(&cache), &[key(4)], |_| Ok(vec![])).is_err());
+    // 不使用缓存时全部计算
+    let (_, num) = cached_vision(None, &keys[..2], |missing| {
+        missing.iter().map(|_| cached(1)).collect()
+    })?;
+    assert_eq!(num, 2);
+    Ok(())
+}
+
+fn image_message(image: &str) -> Result<ChatCompletionParameters> {
+    Ok(serde_json::from_value(serde_json::json!({
+        "model": "tiny",
+        "max_tokens": 4,
+        "messages": [{
+            "role": "user",
+            "content": [
+                {"type": "image_url", "image_url": {"url": format!("file://./assets/img/{}", image)}},
+                {"type": "text", "text": "describe the image"}
+            ]
+        }]
+    }))?)
+}
+
+#[test]
+fn vision_cache_generate() -> Result<()> {
+    // cargo test vision_cache_generate -- --nocapture
+    let dir = std::env::temp_dir().join(format!("aha_vision_cache_{}", uuid::Uuid::new_v4()));
+    let qwen3vl_path = dir.join("qwen3vl").to_string_lossy().to_string();
+    let qwen2_5vl_path = dir.join("qwen2_5vl").to_string_lossy().to_string();
+    TinyModel::Qwen3VL.write(&qwen3vl_path)?;
+    TinyModel::Qwen2_5VL.write(&qwen2_5vl_path)?;
+    let mut qwen3vl = Qwen3VLGenerateModel::init(&qwen3vl_path, Some(&Device::Cpu), None)?;
+    let mut qwen2_5vl = Qwen2_5VLGenerateModel::init(&qwen2_5vl_path, Some(&Device::Cpu), None)?;
+    std::fs::remove_dir_all(&dir)?;
+    let expect3 = qwen3vl.generate(image_message("ocr_test1.png")?)?;
+    let expect2 = qwen2_5vl.generate(image_message("ocr_test1.png")?)?;
+
+    // 两个模型共用一个缓存, namespace为模型路径, 互不命中
+    let cache = VisionEmbeddingCache::shared(VisionCacheConfig::default());
+    qwen3vl.set_vision_cache(Some(cache.clone()));
+    qwen2_5vl.set_vision_cache(Some(cache.clone()));
+    for round in 0..2 {
+        let res3 = qwen3vl.generate(image_message("ocr_test1.png")?)?;
+        let res2 = qwen2_5vl.generate(image_message("ocr_test1.png")?)?;
+        // 命中缓存时不预处理, 结果与不使用缓存时一致
+        assert_eq!(
+            serde_json::to_value(&res3.choices[0].message)?,
+            serde_json::to_value(&expect3.choices[0].message)?
+        );
+        assert_eq!(
+            serde_json::to_value(&res2.choices[0].message)?,
+            serde_json::to_value(&expect2.choices[0].message)?
+        );
+        let stats = cache.lock().unwrap().stats();
+        println!("round {} stats: {:?}", round, stats);
+        assert_eq!((stats.entries, stats.hits), (2, 2 * round));
+    }
+    // 新的图片计算后加入缓存
+    qwen3vl.generate(image_message("voxcpm.png")?)?;
+    let stats = cache.lock().unwrap().stats();
+    assert_eq!((stats.entries, stats.hits), (3, 2));
+    // 关闭缓存后不再访问缓存
+    qwen3vl.set_vision_cache(None);
+    qwen3vl.generate(image_message("ocr_test1.png")?)?;
+    assert_eq!(cache.lock().unwrap().stats().hits, 2);
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use aha::{
+    models::{
+        qwen2_5vl::{config::VisionSetting, processor::Qwen2_5VLProcessor},
+        qwen3vl::processor::Qwen3VLProcessor,
+    },
+    utils::vision_utils::{RequestVisionOverrides, VisionOverrides},
+};
+use anyhow::Result;
+use candle_core::{DType, Device};
+
+fn request(image_path: &str) -> serde_json::Value {
+    let url = format!("file://{}", image_path);
+    serde_json::json!({
+        "model": "qwen-vl",
+        "mm_processor_kwargs": {"max_pixels": 20480},
+        "messages": [
+            {"role": "system", "content": "你是一个助手"},
+            {
+                "role": "user",
+                "content": [
+                    {"type": "image_url", "image_url": {"url": url}},
+                    {"type": "image_url", "image_url": {"url": url}, "min_pixels": 1024, "max_pixels": 8192},
+                    {"type": "image_url", "image_url": {"url": url, "resized_height": 64, "resized_width": 96}},
+                    {"type": "text", "text": "描述这些图片"}
+                ]
+            }
+        ]
+    })
+}
+
+fn grids(grid_thw: &candle_core::Tensor) -> Result<Vec<Vec<u32>>> {
+    Ok(grid_thw.to_vec2::<u32>()?)
+}
+
+#[test]
+fn vision_overrides_from_request() -> Result<()> {
+    // cargo test vision_overrides_from_request -- --nocapture
+    let mut value = request("/tmp/a.png");
+    value["messages"][1]["content"]
+        .as_array_mut()
+        .unwrap()
+        .push(serde_json::json!({"type": "video_url", "video_url": {"url": "file:///tmp/a.mp4"}, "fps": 0.5}));
+    // assistant消息中的图片不会被处理
+    value["messages"].as_array_mut().unwrap().push(serde_json::json!({
+        "role": "assistant",
+        "content": [{"type": "image_url", "image_url": {"url": "file:///tmp/b.png"}, "max_pixels": 1}]
+    }));
+    let overrides = RequestVisionOverrides::from_request(&value)?;
+    println!("{:?}", overrides);
+    assert_eq!(overrides.images.len(), 3);
+    assert_eq!(overrides.videos.len(), 1);
+    assert_eq!(overrides.image(0).max_pixels, Some(20480));
+    assert_eq!(overrides.image(1).min_pixels, Some(1024));
+    assert_eq!(overrides.image(1).max_pixels, Some(8192));
+    assert_eq!(overrides.image(2).resized_height, Some(64));
+    assert_eq!(overrides.image(2).max_pixels, Some(20480));
+    assert_eq!(overrides.video(0).fps(2.0)?, 0.5);
+    assert_eq!(overrides.video(1), overrides.default);
+    assert!(VisionOverrides::default().fps(2.0)? == 2.0);
+
+    // resized_height/resized_width优先, 只受默认max_pixels限制
+    let o = overrides.image(2);
+    assert_eq!(
+        o.resize_args(200, 300, 4096, 1 << 20)?,
+        (64, 96, 0, 1 << 20)
+    );
+    let o = overrides.image(1);
+    assert_eq!(
+        o.resize_args(200, 300, 4096, 1 << 20)?,
+        (200, 300, 1024, 8192)
+    );
+    let bad = VisionOverrides {
+        min_pixels: Some(100),
+        max_pixels: Some(10),
+        ..Default::default()
+    };
+    assert!(bad.resize_args(200, 300, 4096, 1 << 20).is_err());
+    // 只覆盖max_pixels且小于默认min_pixels时, min_pixels跟着减小
+    let o = overrides.image(0);
+    assert_eq!(
+        o.resize_args(200, 300, 65536, 1 << 20)?,
+        (200, 300, 20480, 20480)
+    );
+    let bad_fps = VisionOverrides {
+        fps: Some(0.0),
+        ..Default::default()
+    };
+    assert!(bad_fps.fps(2.0).is_err());
+    Ok(())
+}
+
+#[test]
+fn vision_overrides_processor() -> Result<()> {
+    // cargo test vision_overrides_processor -- --nocapture
+    let dir = std::env::temp_dir().join("aha_vision_overrides_test");
+    std::fs::create_dir_all(&dir)?;
+    let image_path = dir.join("image.png");
+    image::RgbImage::from_fn(300, 200, |x, y| image::Rgb([x as u8, y as u8, 128]))
+        .save(&image_path)?;
+    let value = request(&image_path.to_string_lossy());
+    let overrides = RequestVisionOverrides::from_request(&value)?;
+    // 反序列化成ChatCompletionParameters时扩展字段丢失, 设置从原始json中一起读出
+    let (mes, typed) = RequestVisionOverrides::parse_request(value)?;
+    assert_eq!(typed, overrides);
+    let text = "<|image_pad|><|image_pad|><|image_pad|>";
+    let device = Device::Cpu;
+
+    // qwen3vl: factor 32
+    let preprocessor = serde_json::json!({
+        "size": {"longest_edge": 16777216, "shortest_edge": 65536},
+        "patch_size": 16,
+        "temporal_patch_size": 2,
+        "merge_size": 2,
+        "image_mean": [0.5, 0.5, 0.5],
+        "image_std": [0.5, 0.5, 0.5]
+    });
+    std::fs::write(
+        dir.join("preprocessor_config.json"),
+        serde_json::to_string(&preprocessor)?,
+    )?;
+    let mut video_preprocessor = preprocessor.clone();
+    video_preprocessor["fps"] = serde_json::json!(1.0);
+    std::fs::write(
+        dir.join("video_preprocessor_config.json"),
+        serde_json::to_string(&video_preprocessor)?,
+    )?;
+    let processor = Qwen3VLProcessor::new(&dir.to_string_lossy(), &device, DType::F32)?;
+    let input = processor.process_info(&mes, text, &RequestVisionOverrides::default())?;
+    // 默认min_pixels为65536, 300x200放大到320x224
+    let default_grid = grids(input.image_grid_thw.as_ref().unwrap())?;
+    println!("qwen3vl default: {:?}", default_grid);
+    assert!(default_grid.iter().all(|g| g == &vec![1, 14, 20]));
+    let input = processor.process_info(&mes, text, &typed)?;
+    let grid = grids(input.image_grid_thw.as_ref().unwrap())?;
+    println!("qwen3vl overrides: {:?}", grid);
+    assert_eq!(grid, vec![vec![1, 6, 10], vec![1, 4, 6], vec![1, 4, 6]]);
+    let num_tokens = input.replace_text.matches("<|image_pad|>").count();
+    assert_eq!(num_tokens, (6 * 10 + 4 * 6 + 4 * 6) / 4);
+    assert_eq!(input.pixel_values.unwrap().dim(0)?, 6 * 10 + 4 * 6 + 4 * 6);
+
+    // qwen2.5vl: 读取preprocessor_config.json中的min_pixels/max_pixels, factor 28
+    let preprocessor = serde_json::json!({
+        "min_pixels": 3136,
+        "max_pixels": 28 * 28 * 64,
+        "patch_size": 14,
+        "temporal_patch_size": 2,
+        "merge_size": 2,
+        "image_mean": [0.5, 0.5, 0.5],
+        "image_std": [0.5, 0.5, 0.5]
+    });
+    std::fs::write(
+        dir.join("preprocessor_config.json"),
+        serde_json::to_string(&preprocessor)?,
+    )?;
+    let setting = VisionSetting::from_model_path(&dir.to_string_lossy())?;
+    assert_eq!(setting.max_pixels, 28 * 28 * 64);
+    assert_eq!(setting.image_mean, vec![0.5, 0.5, 0.5]);
+    let processor = Qwen2_5VLProcessor::new(&dir.to_string_lossy(), &device, DType::F32)?;
+    let input = processor.process_info(&mes, text, &RequestVisionOverrides::default())?;
+    let default_grid = grids(input.image_grid_thw.as_ref().unwrap())?;
+    println!("qwen2.5vl default: {:?}", default_grid);
+    let (_, h, w) = (default_grid[0][0], default_grid[0][1], default_grid[0][2]);
+    assert!(h * w * 14 * 14 <= 28 * 28 * 64);
+    let input = processor.process_info(&mes, text, &typed)?;
+    let grid = grids(input.image_grid_thw.as_ref().unwrap())?;
+    println!("qwen2.5vl overrides: {:?}", grid);
+    // 请求级别的max_pixels 20480覆盖配置中的50176; resized 64x96对齐到56x84
+    assert_eq!(grid[1], vec![1, 4, 6]);
+    assert_eq!(grid[2], vec![1, 4, 6]);
+    for g in &grid {
+        assert!(g[1] * g[2] * 14 * 14 <= 20480);
+    }
+    Ok(())
+}
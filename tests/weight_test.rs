@@ -1,6 +1,12 @@
 use std::collections::HashMap;
 
-use aha::utils::{find_type_files, get_device};
+use aha::{
+    models::{
+        minicpm4::generate::MiniCPMGenerateModel, qwen2_5vl::generate::Qwen2_5VLGenerateModel,
+        qwen3vl::generate::Qwen3VLGenerateModel, voxcpm::generate::VoxCPMGenerate,
+    },
+    utils::{find_type_files, get_device},
+};
 use anyhow::Result;
 use candle_core::{Device, pickle::read_all_with_key, safetensors};
 use candle_nn::VarBuilder;
@@ -61,3 +67,31 @@ fn qwen3vl_weight() -> Result<()> {
     println!("model_list: {:?}", model_list);
     Ok(())
 }
+
+#[test]
+fn check_model_weight() -> Result<()> {
+    // 不加载权重, 只检查配置和权重名/shape
+    // cargo test check_model_weight -- --nocapture
+    let report =
+        MiniCPMGenerateModel::check_model("/home/jhq/huggingface_model/OpenBMB/MiniCPM4-0.5B/")?;
+    println!("minicpm4: {}", report);
+    assert!(report.is_ok());
+    let report = Qwen2_5VLGenerateModel::check_model(
+        "/home/jhq/huggingface_model/Qwen/Qwen2.5-VL-3B-Instruct/",
+    )?;
+    println!("qwen2.5vl: {}", report);
+    assert!(report.is_ok());
+    let report = Qwen3VLGenerateModel::check_model(
+        "/home/jhq/huggingface_model/Qwen/Qwen3-VL-4B-Instruct/",
+    )?;
+    println!("qwen3vl: {}", report);
+    assert!(report.is_ok());
+    let report = VoxCPMGenerate::check_model("/home/jhq/huggingface_model/openbmb/VoxCPM-0.5B/")?;
+    println!("voxcpm: {}", report);
+    assert!(report.is_ok());
+    // 用错模型类型时报告缺失的权重
+    let report =
+        MiniCPMGenerateModel::check_model("/home/jhq/huggingface_model/Qwen/Qwen3-VL-4B-Instruct/");
+    println!("minicpm4 on qwen3vl: {:?}", report.map(|r| r.to_string()));
+    Ok(())
+}